@@ -0,0 +1,254 @@
+//! Evaluate platform-specific dependency `target`s, like
+//! `cfg(all(unix, target_arch = "x86_64"))`, against a target's cfgs. Mirrors
+//! `lib/targetCfg.nix`.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// A target's cfgs, from `rustc --print cfg`, plus `target = "<triple>"`.
+pub(crate) struct TargetCfgs {
+    cfgs: Vec<(String, Option<String>)>,
+    /// The raw `rustc --print cfg` output, which build scripts also need.
+    output: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum CfgExpr<'a> {
+    Cfg(&'a str, Option<&'a str>),
+    All(Vec<CfgExpr<'a>>),
+    Any(Vec<CfgExpr<'a>>),
+    Not(Box<CfgExpr<'a>>),
+}
+
+struct Parser<'a> {
+    s: &'a str,
+}
+
+impl TargetCfgs {
+    /// Run `rustc --target <triple> --print cfg`.
+    pub(crate) fn collect(rustc: &Path, triple: &str) -> Self {
+        let output = Command::new(rustc)
+            .args(["--target", triple, "--print", "cfg"])
+            .stderr(Stdio::inherit())
+            .output()
+            .expect("failed to run `rustc`");
+        if !output.status.success() {
+            let code = output.status.code().unwrap_or(1);
+            panic!(
+                "`rustc --target {triple} --print cfg` exited with non-zero exit code: {code}"
+            );
+        }
+        let output = String::from_utf8(output.stdout)
+            .expect("`rustc --print cfg` output is not valid UTF-8");
+        Self::parse(triple, &output)
+    }
+
+    pub(crate) fn parse(triple: &str, output: &str) -> Self {
+        let mut cfgs = vec![("target".to_owned(), Some(triple.to_owned()))];
+        for line in output.lines() {
+            let cfg = match line.split_once('=') {
+                Some((key, value)) => {
+                    let value = value
+                        .strip_prefix('"')
+                        .and_then(|value| value.strip_suffix('"'))
+                        .expect("missing quotes");
+                    (key.to_owned(), Some(value.to_owned()))
+                }
+                None => (line.to_owned(), None),
+            };
+            // Like cargo, ignore `debug_assertions`, which depends on the
+            // profile and not the platform.
+            if cfg.0 != "debug_assertions" && !cfg.0.is_empty() {
+                cfgs.push(cfg);
+            }
+        }
+        Self {
+            cfgs,
+            output: output.to_owned(),
+        }
+    }
+
+    pub(crate) fn rustc_output(&self) -> &str {
+        &self.output
+    }
+
+    /// Whether a dependency with `target = <platform>` is active on this
+    /// target. The platform is either a `cfg(..)` expression or a plain target
+    /// triple.
+    pub(crate) fn matches(&self, platform: &str) -> bool {
+        let expr = CfgExpr::parse(platform).unwrap_or_else(|err| {
+            panic!("invalid dependency target `{platform}`: {err}")
+        });
+        self.eval(&expr)
+    }
+
+    fn eval(&self, expr: &CfgExpr<'_>) -> bool {
+        match expr {
+            CfgExpr::Cfg(key, value) => self
+                .cfgs
+                .iter()
+                .any(|(k, v)| k == key && v.as_deref() == *value),
+            CfgExpr::All(exprs) => exprs.iter().all(|expr| self.eval(expr)),
+            CfgExpr::Any(exprs) => exprs.iter().any(|expr| self.eval(expr)),
+            CfgExpr::Not(expr) => !self.eval(expr),
+        }
+    }
+}
+
+impl<'a> CfgExpr<'a> {
+    fn parse(s: &'a str) -> Result<Self, String> {
+        let is_triple = !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+        if is_triple {
+            return Ok(Self::Cfg("target", Some(s)));
+        }
+
+        let inner = s
+            .strip_prefix("cfg(")
+            .and_then(|s| s.strip_suffix(')'))
+            .ok_or("must be a target triple or `cfg(..)`")?;
+        let mut parser = Parser { s: inner };
+        let expr = parser.expr()?;
+        parser.skip_ws();
+        if !parser.s.is_empty() {
+            return Err(format!("unexpected `{}`", parser.s));
+        }
+        Ok(expr)
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn expr(&mut self) -> Result<CfgExpr<'a>, String> {
+        let ident = self.ident()?;
+        self.skip_ws();
+        match ident {
+            "all" | "any" | "not" if self.eat('(') => {
+                let mut exprs = Vec::new();
+                loop {
+                    self.skip_ws();
+                    if self.eat(')') {
+                        break;
+                    }
+                    exprs.push(self.expr()?);
+                    self.skip_ws();
+                    if !self.eat(',') {
+                        self.skip_ws();
+                        if !self.eat(')') {
+                            return Err(format!(
+                                "expected `)` at `{}`",
+                                self.s
+                            ));
+                        }
+                        break;
+                    }
+                }
+                match ident {
+                    "all" => Ok(CfgExpr::All(exprs)),
+                    "any" => Ok(CfgExpr::Any(exprs)),
+                    _ if exprs.len() == 1 => {
+                        Ok(CfgExpr::Not(Box::new(exprs.pop().unwrap())))
+                    }
+                    _ => Err("`not` must have exactly one argument".to_owned()),
+                }
+            }
+            _ if self.eat('=') => {
+                self.skip_ws();
+                let value = self
+                    .s
+                    .strip_prefix('"')
+                    .and_then(|s| s.split_once('"'))
+                    .map(|(value, rest)| {
+                        self.s = rest;
+                        value
+                    })
+                    .ok_or_else(|| {
+                        format!("expected a string at `{}`", self.s)
+                    })?;
+                Ok(CfgExpr::Cfg(ident, Some(value)))
+            }
+            _ => Ok(CfgExpr::Cfg(ident, None)),
+        }
+    }
+
+    fn ident(&mut self) -> Result<&'a str, String> {
+        self.skip_ws();
+        let len = self
+            .s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(self.s.len());
+        let (ident, rest) = self.s.split_at(len);
+        if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("expected an identifier at `{}`", self.s));
+        }
+        self.s = rest;
+        Ok(ident)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        match self.s.strip_prefix(c) {
+            Some(rest) => {
+                self.s = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        self.s = self.s.trim_start();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        use CfgExpr::*;
+        assert_eq!(
+            CfgExpr::parse("x86_64-unknown-linux-gnu").unwrap(),
+            Cfg("target", Some("x86_64-unknown-linux-gnu")),
+        );
+        assert_eq!(CfgExpr::parse("cfg(unix)").unwrap(), Cfg("unix", None));
+        assert_eq!(
+            CfgExpr::parse(
+                r#"cfg(all(not(windows), any(target_os = "linux", target_arch="wasm32"),))"#
+            )
+            .unwrap(),
+            All(vec![
+                Not(Box::new(Cfg("windows", None))),
+                Any(vec![
+                    Cfg("target_os", Some("linux")),
+                    Cfg("target_arch", Some("wasm32")),
+                ]),
+            ]),
+        );
+        assert_eq!(CfgExpr::parse("cfg(any())").unwrap(), Any(vec![]));
+        assert!(CfgExpr::parse("cfg(not(unix, windows))").is_err());
+        assert!(CfgExpr::parse("cfg(unix").is_err());
+        assert!(CfgExpr::parse("cfg(unix) foo").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let cfgs = TargetCfgs::parse(
+            "x86_64-unknown-linux-gnu",
+            "debug_assertions\n\
+             target_arch=\"x86_64\"\n\
+             target_os=\"linux\"\n\
+             unix\n",
+        );
+        assert!(cfgs.matches("x86_64-unknown-linux-gnu"));
+        assert!(!cfgs.matches("aarch64-apple-darwin"));
+        assert!(cfgs.matches("cfg(unix)"));
+        assert!(cfgs.matches(r#"cfg(all(unix, target_os = "linux"))"#));
+        assert!(!cfgs.matches(r#"cfg(target_os = "macos")"#));
+        assert!(!cfgs.matches("cfg(debug_assertions)"));
+        assert!(!cfgs.matches("cfg(any())"));
+        assert!(cfgs.matches("cfg(all())"));
+    }
+}
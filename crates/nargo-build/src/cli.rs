@@ -0,0 +1,366 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use nargo_core::{fs, logger, time, trace};
+
+use crate::{
+    cfg::TargetCfgs,
+    executor::Executor,
+    graph::{GraphBuilder, Selection},
+    metadata::{Metadata, ResolveFeatures},
+};
+
+const HELP: &str = r#"
+nargo-build
+
+Build workspace packages directly on the local filesystem, without nix. We
+build the same units with the same `rustc` flags as `nargoLib.buildPackage`,
+but run up to `--jobs` of them in parallel, cache their outputs under
+`target/nargo`, and build workspace crates incrementally.
+
+USAGE:
+  nargo-build --resolve-features PATH [OPTIONS]
+
+FLAGS:
+  -h, --help
+      Prints help information
+
+  -V, --version
+      Prints version
+
+  -v, --verbose
+      Verbose logging, including each unit's `nargo-rustc` trace logs
+
+  --lib
+      Only build the selected packages' lib targets (plus any `--bin`s)
+
+  --release
+      Build with the `release` profile
+
+  --check
+      Only check the selected targets, like `cargo check`
+
+OPTIONS:
+  --resolve-features PATH
+      Path to the json output of `nix eval` of `resolveFeatures` for the
+      build and host targets (e.g., `just resolve-features`).
+
+  --metadata PATH
+      Path to the workspace's `Cargo.metadata.json`. The workspace root is its
+      parent dir. Defaults to `Cargo.metadata.json` in the current directory.
+
+  -p, --package NAME
+      Build this workspace package. May be repeated. Defaults to the
+      workspace's default members.
+
+  --bin NAME
+      Only build this bin target (plus the lib, with `--lib`). May be
+      repeated.
+
+  --profile NAME
+      Build with this profile. Defaults to `dev`.
+
+  --target TRIPLE
+      Build for this target triple. Defaults to the host.
+
+  -j, --jobs N
+      Build up to N units in parallel. Defaults to the number of CPUs.
+"#;
+
+const VERSION: &str =
+    concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"), "\n");
+
+pub struct Args {
+    resolve_features: PathBuf,
+    metadata: PathBuf,
+    packages: Vec<String>,
+    lib: bool,
+    bins: Vec<String>,
+    profile: Option<String>,
+    release: bool,
+    check: bool,
+    target: Option<String>,
+    jobs: Option<usize>,
+    verbose: bool,
+}
+
+/// The `rustc` toolchain that `rustup` picks for the workspace.
+struct Rustc {
+    /// The toolchain's `bin` dir, with `rustc`, `rustdoc`, etc...
+    bin_dir: PathBuf,
+    /// `rustc -vV`
+    version_verbose: String,
+    /// The `release: ` line in `rustc -vV`, like `1.86.0`.
+    release: String,
+    /// The `host: ` line in `rustc -vV`.
+    host: String,
+}
+
+impl Args {
+    pub fn from_env() -> Result<Self, lexopt::Error> {
+        use lexopt::prelude::*;
+
+        let mut resolve_features: Option<PathBuf> = None;
+        let mut metadata: Option<PathBuf> = None;
+        let mut packages = Vec::new();
+        let mut lib = false;
+        let mut bins = Vec::new();
+        let mut profile: Option<String> = None;
+        let mut release = false;
+        let mut check = false;
+        let mut target: Option<String> = None;
+        let mut jobs: Option<usize> = None;
+        let mut verbose = false;
+
+        let mut parser = lexopt::Parser::from_env();
+        while let Some(arg) = parser.next()? {
+            match arg {
+                Short('h') | Long("help") => {
+                    print!("{}", HELP);
+                    std::process::exit(0);
+                }
+                Short('V') | Long("version") => {
+                    print!("{}", VERSION);
+                    std::process::exit(0);
+                }
+                Short('v') | Long("verbose") => {
+                    logger::set_level(logger::Level::Trace);
+                    verbose = true;
+                }
+                Long("lib") if !lib => {
+                    lib = true;
+                }
+                Long("release") if !release && profile.is_none() => {
+                    release = true;
+                }
+                Long("check") if !check => {
+                    check = true;
+                }
+                Long("resolve-features") if resolve_features.is_none() => {
+                    resolve_features = Some(PathBuf::from(parser.value()?));
+                }
+                Long("metadata") if metadata.is_none() => {
+                    metadata = Some(PathBuf::from(parser.value()?));
+                }
+                Short('p') | Long("package") => {
+                    packages.push(parser.value()?.string()?);
+                }
+                Long("bin") => {
+                    bins.push(parser.value()?.string()?);
+                }
+                Long("profile") if profile.is_none() && !release => {
+                    profile = Some(parser.value()?.string()?);
+                }
+                Long("target") if target.is_none() => {
+                    target = Some(parser.value()?.string()?);
+                }
+                Short('j') | Long("jobs") if jobs.is_none() => {
+                    let value: usize = parser.value()?.parse()?;
+                    jobs = Some(value.max(1));
+                }
+                _ => return Err(arg.unexpected()),
+            }
+        }
+
+        Ok(Args {
+            resolve_features: resolve_features
+                .ok_or("missing --resolve-features")?,
+            metadata: metadata
+                .unwrap_or_else(|| PathBuf::from("Cargo.metadata.json")),
+            packages,
+            lib,
+            bins,
+            profile,
+            release,
+            check,
+            target,
+            jobs,
+            verbose,
+        })
+    }
+
+    pub fn run(self) {
+        let metadata_buf = time!(
+            "read Cargo.metadata.json",
+            fs::read_existing_file(&self.metadata)
+                .expect("Failed to read `Cargo.metadata.json`")
+        );
+        let metadata: Metadata<'_> = time!(
+            "deserialize Cargo.metadata.json",
+            serde_json::from_slice(&metadata_buf)
+                .expect("Failed to deserialize `Cargo.metadata.json`")
+        );
+
+        let resolve_features_buf = time!(
+            "read --resolve-features",
+            fs::read_existing_file(&self.resolve_features)
+                .expect("Failed to read `--resolve-features`")
+        );
+        let resolved: ResolveFeatures<'_> = time!(
+            "deserialize --resolve-features",
+            serde_json::from_slice(&resolve_features_buf)
+                .expect("Failed to deserialize `--resolve-features`")
+        );
+
+        let workspace_root = match self.metadata.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let workspace_root = fs::canonicalize(workspace_root)
+            .expect("Failed to canonicalize workspace root");
+
+        let rustc = time!("query rustc", Rustc::query(&workspace_root));
+        let build_triple = rustc.host.as_str();
+        let host_triple = self.target.as_deref().unwrap_or(build_triple);
+        let rustc_path = rustc.bin_dir.join("rustc");
+        let build_cfgs = time!(
+            "collect build target cfgs",
+            TargetCfgs::collect(&rustc_path, build_triple)
+        );
+        let host_cfgs = time!(
+            "collect host target cfgs",
+            TargetCfgs::collect(&rustc_path, host_triple)
+        );
+
+        let profile_name = match (&self.profile, self.release) {
+            (Some(profile), _) => profile.as_str(),
+            (None, true) => "release",
+            (None, false) => "dev",
+        };
+        let profile =
+            metadata.profiles.get(profile_name).unwrap_or_else(|| {
+                panic!(
+                    "profile `{profile_name}` is not in `Cargo.metadata.json`"
+                )
+            });
+
+        let packages = if self.packages.is_empty() {
+            metadata.workspace_default_members.clone()
+        } else {
+            self.packages.iter().map(String::as_str).collect()
+        };
+        let selection = Selection {
+            packages,
+            lib: self.lib || self.bins.is_empty(),
+            bins: (self.lib || !self.bins.is_empty())
+                .then(|| self.bins.iter().map(String::as_str).collect()),
+        };
+
+        let graph = time!(
+            "build unit graph",
+            GraphBuilder {
+                metadata: &metadata,
+                resolved: &resolved,
+                profile,
+                mode: if self.check { "check" } else { "build" },
+                build_triple,
+                host_triple,
+                build_cfgs: &build_cfgs,
+                host_cfgs: &host_cfgs,
+                rustc_version: &rustc.release,
+            }
+            .build(&selection)
+        );
+        if graph.roots.is_empty() {
+            panic!("no targets selected to build");
+        }
+
+        let exe = std::env::current_exe()
+            .expect("Failed to find the `nargo-build` binary");
+        let cache_dir = workspace_root.join("target").join("nargo");
+        let jobs = self.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, usize::from)
+        });
+
+        // Build scripts read their target's cfgs from these, instead of each
+        // one running `rustc --print cfg`.
+        let target_cfg_dir = cache_dir.join("target-cfg");
+        std::fs::create_dir_all(&target_cfg_dir)
+            .expect("Failed to create target cfg dir");
+        let write_target_cfg = |triple: &str, cfgs: &TargetCfgs| {
+            let path = target_cfg_dir.join(triple);
+            fs::write_file(&path, cfgs.rustc_output().as_bytes())
+                .expect("Failed to write target cfg");
+            path
+        };
+        let build_target_cfg = write_target_cfg(build_triple, &build_cfgs);
+        let host_target_cfg = write_target_cfg(host_triple, &host_cfgs);
+
+        let outs = Executor {
+            graph: &graph,
+            workspace_root: &workspace_root,
+            cache_dir: &cache_dir,
+            exe: &exe,
+            rustc_bin_dir: &rustc.bin_dir,
+            rustc_version_verbose: &rustc.version_verbose,
+            build_triple,
+            host_triple,
+            build_target_cfg: &build_target_cfg,
+            host_target_cfg: &host_target_cfg,
+            jobs,
+            log: if self.verbose { "trace" } else { "off" },
+        }
+        .run();
+
+        // Link each root's out dir into `target/nargo/<profile>`, by the same
+        // name as its unit.
+        let profile_dir = cache_dir.join(profile_name);
+        std::fs::create_dir_all(&profile_dir)
+            .expect("Failed to create profile dir");
+        for (&id, out) in graph.roots.iter().zip(&outs) {
+            let link = profile_dir.join(graph.units[id].name());
+            let _ = std::fs::remove_file(&link);
+            fs::symlink(out, &link).expect("Failed to link unit out dir");
+            println!("{}", link.display());
+        }
+    }
+}
+
+impl Rustc {
+    /// Find the toolchain that `rustup` picks when run from `dir`. We then
+    /// pin all units to it, since units outside the workspace (like
+    /// `~/.cargo/registry`) would otherwise get the default toolchain.
+    fn query(dir: &Path) -> Self {
+        let run = |args: &[&str]| -> String {
+            let output = Command::new("rustc")
+                .args(args)
+                .current_dir(dir)
+                .stderr(Stdio::inherit())
+                .output()
+                .expect("Failed to run `rustc`");
+            if !output.status.success() {
+                let code = output.status.code().unwrap_or(1);
+                panic!(
+                    "`rustc {}` exited with non-zero exit code: {code}",
+                    args.join(" ")
+                );
+            }
+            String::from_utf8(output.stdout)
+                .expect("`rustc` output is not valid UTF-8")
+        };
+
+        let sysroot = run(&["--print", "sysroot"]);
+        let bin_dir = Path::new(sysroot.trim_end()).join("bin");
+        let version_verbose = run(&["-vV"]);
+        trace!("rustc: {}, {version_verbose}", bin_dir.display());
+
+        let field = |key: &str| {
+            version_verbose
+                .lines()
+                .find_map(|line| line.strip_prefix(key))
+                .unwrap_or_else(|| panic!("`rustc -vV` is missing `{key}`"))
+                .to_owned()
+        };
+        let release = field("release: ");
+        let host = field("host: ");
+
+        Self {
+            bin_dir,
+            version_verbose,
+            release,
+            host,
+        }
+    }
+}
@@ -0,0 +1,731 @@
+//! Build a [`UnitGraph`] on the local filesystem, without nix.
+//!
+//! Each unit gets the same unit spec that `buildCrate.nix` would write, and we
+//! build it by running ourselves as `nargo-build build-unit <spec>`, which is
+//! just `nargo-rustc --unit <spec>`. Everything lives under
+//! `<workspace>/target/nargo`:
+//!
+//! ```text
+//! target/nargo/
+//! ├── store/<hash>-<name>        each unit's `$out`
+//! ├── tmp/<hash>-<name>/         each running unit's `$TMPDIR` and unit spec
+//! ├── target-cfg/<triple>        `rustc --print cfg` for build scripts
+//! └── incremental/<name>-<meta>  `-C incremental` for workspace crates
+//! ```
+//!
+//! Like a nix store path, `<hash>` covers everything that goes into the unit:
+//! its spec (which includes its deps' out dirs), the `rustc` toolchain, and,
+//! for workspace crates, the package's source files. A unit is fresh if its
+//! out dir exists and it has no leftover tmp dir from an interrupted or failed
+//! build.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc,
+    time::Instant,
+};
+
+use nargo_core::{fs, trace};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    graph::{UnitGraph, UnitId},
+    metadata::{FeatFor, GitSource},
+};
+
+pub(crate) struct Executor<'a> {
+    pub(crate) graph: &'a UnitGraph<'a>,
+    pub(crate) workspace_root: &'a Path,
+    /// `<workspace>/target/nargo`
+    pub(crate) cache_dir: &'a Path,
+    /// Our own binary, which builds each unit via `build-unit`.
+    pub(crate) exe: &'a Path,
+    /// The toolchain's `bin` dir, which we put first in each unit's `PATH`.
+    pub(crate) rustc_bin_dir: &'a Path,
+    /// `rustc -vV`, which goes into each unit's hash.
+    pub(crate) rustc_version_verbose: &'a str,
+    pub(crate) build_triple: &'a str,
+    pub(crate) host_triple: &'a str,
+    /// The saved `rustc --print cfg` output for the build and host triples,
+    /// which we pass to build scripts.
+    pub(crate) build_target_cfg: &'a Path,
+    pub(crate) host_target_cfg: &'a Path,
+    pub(crate) jobs: usize,
+    /// The unit's log level: `"off"`, `"info"`, or `"trace"`.
+    pub(crate) log: &'a str,
+}
+
+/// A unit we need for the selected roots.
+struct Planned {
+    out: PathBuf,
+    tmp: PathBuf,
+    spec: String,
+    fresh: bool,
+}
+
+impl Executor<'_> {
+    /// Build all the root units and their deps. Returns each root's out dir.
+    pub(crate) fn run(&self) -> Vec<PathBuf> {
+        let start = Instant::now();
+        let needed = self.needed();
+        let store = self.cache_dir.join("store");
+        std::fs::create_dir_all(&store).expect("failed to create store dir");
+
+        let mut plan: Vec<Option<Planned>> = Vec::new();
+        for (id, needed) in needed.into_iter().enumerate() {
+            let planned = needed.then(|| self.plan(id, &plan));
+            plan.push(planned);
+        }
+
+        let num_fresh = plan.iter().flatten().filter(|p| p.fresh).count();
+        let num_built = self.build_stale(&plan);
+        eprintln!(
+            "{:>12} {num_built} units ({num_fresh} fresh) in {:.2?}",
+            "Finished",
+            start.elapsed()
+        );
+
+        self.graph
+            .roots
+            .iter()
+            .map(|&id| plan[id].as_ref().unwrap().out.clone())
+            .collect()
+    }
+
+    /// Which units the roots (transitively) depend on.
+    fn needed(&self) -> Vec<bool> {
+        let mut needed = vec![false; self.graph.units.len()];
+        let mut stack = self.graph.roots.clone();
+        while let Some(id) = stack.pop() {
+            if std::mem::replace(&mut needed[id], true) {
+                continue;
+            }
+            stack.extend(self.unit_deps(id));
+        }
+        needed
+    }
+
+    /// All the units that must finish before we can build unit `id`.
+    fn unit_deps(&self, id: UnitId) -> impl Iterator<Item = UnitId> + '_ {
+        let unit = &self.graph.units[id];
+        unit.deps
+            .iter()
+            .map(|dep| dep.unit)
+            .chain(unit.build_script_dep)
+            .chain(unit.build_script_bin)
+            .chain(unit.link_deps.iter().copied())
+            .chain(unit.links_deps.iter().map(|(_, id)| *id))
+    }
+
+    /// Write out unit `id`'s spec and find its out dir. All of its deps are
+    /// already planned.
+    fn plan(&self, id: UnitId, plan: &[Option<Planned>]) -> Planned {
+        let unit = &self.graph.units[id];
+        let pkg = unit.pkg;
+        let out = |id: UnitId| plan[id].as_ref().unwrap().out.as_path();
+
+        let src = self.pkg_src(id);
+        let (target_triple, target_cfg) = match unit.feat_for {
+            FeatFor::Build => (self.build_triple, self.build_target_cfg),
+            FeatFor::Normal => (self.host_triple, self.host_target_cfg),
+        };
+
+        // Like `buildCrate.nix`, except we use the `cc` and `ar` from `PATH`.
+        let mut spec = json!({
+            "schema_version": 2,
+            "pkg_name": pkg.name,
+            "version": pkg.version,
+            "src": src,
+            "target": {
+                "name": unit.target.name,
+                "kind": unit.target.kind.as_str(),
+                "crate_types": unit.crate_types,
+                "path": unit.target.path,
+                "edition": unit.target.edition,
+                "harness": unit.target.harness,
+                "features": unit.features,
+            },
+            "mode": unit.mode,
+            "pipelined": unit.pipelined,
+            "metadata": unit.metadata,
+            "is_workspace_pkg": pkg.is_workspace_pkg(),
+            "is_primary_pkg": unit.is_primary_pkg,
+            "package": {
+                "authors": pkg.authors,
+                "description": pkg.description,
+                "homepage": pkg.homepage,
+                "license": pkg.license,
+                "license_file": pkg.license_file,
+                "readme": pkg.readme,
+                "repository": pkg.repository,
+                "rust_version": pkg.rust_version,
+            },
+            "lto_needs_object": unit.lto_needs_object,
+            "profile": unit.profile,
+            "target_triple": target_triple,
+            "host_triple": self.build_triple,
+            "deps": unit.deps.iter().map(|dep| json!({
+                "dep_name": dep.dep_name,
+                "crate_name": dep.crate_name,
+                "out": out(dep.unit),
+            })).collect::<Vec<_>>(),
+            "build_script_dep": unit.build_script_dep.map(out),
+            "build_script_bin": unit.build_script_bin.map(out),
+            "rustc_target_cfg": unit.build_script_bin.map(|_| target_cfg),
+            "link_deps": unit.link_deps.iter().map(|&id| out(id)).collect::<Vec<_>>(),
+            "links": pkg.links,
+            "links_deps": unit.links_deps.iter().map(|&(links, id)| json!({
+                "links": links,
+                "out": out(id),
+            })).collect::<Vec<_>>(),
+            "lints": pkg.lints,
+        });
+
+        let mut hasher = Sha256::new();
+        hasher.update(
+            concat!("nargo-build ", env!("CARGO_PKG_VERSION"), "\n").as_bytes(),
+        );
+        hasher.update(self.rustc_version_verbose.as_bytes());
+        hasher.update(spec.to_string().as_bytes());
+        if pkg.is_workspace_pkg() {
+            hash_pkg_src(&mut hasher, &src, Path::new(""));
+            // Plus any files elsewhere in the workspace that its targets read,
+            // like `buildGraph.nix::_srcForWorkspacePkg`.
+            let outside_pkg_sources = pkg
+                .targets
+                .iter()
+                .flat_map(|target| &target.sources)
+                .filter(|source| source.starts_with("../"))
+                .collect::<BTreeSet<_>>();
+            for source in outside_pkg_sources {
+                hash_pkg_src(&mut hasher, &src, Path::new(source));
+            }
+        }
+        let mut hash = format!("{:x}", hasher.finalize());
+        hash.truncate(32);
+
+        let name = format!("{hash}-{}", unit.name());
+        let out = self.cache_dir.join("store").join(&name);
+        let tmp = self.cache_dir.join("tmp").join(&name);
+
+        let fields = spec.as_object_mut().unwrap();
+        fields.insert("out".to_owned(), json!(out));
+        fields.insert("log".to_owned(), json!(self.log));
+        if pkg.is_workspace_pkg() {
+            let incremental = self.cache_dir.join("incremental").join(format!(
+                "{}-{}",
+                unit.name(),
+                unit.metadata
+            ));
+            fields.insert("incremental".to_owned(), json!(incremental));
+            fields
+                .insert("workspace_src".to_owned(), json!(self.workspace_root));
+        }
+
+        let fresh = out.is_dir() && !tmp.exists();
+        Planned {
+            out,
+            tmp,
+            spec: spec.to_string(),
+            fresh,
+        }
+    }
+
+    /// The package source dir for unit `id`.
+    fn pkg_src(&self, id: UnitId) -> PathBuf {
+        let pkg = self.graph.units[id].pkg;
+        match (&pkg.path, pkg.is_workspace_pkg()) {
+            (Some(path), true) => self.workspace_root.join(path),
+            (Some(path), false) => PathBuf::from(path),
+            (None, true) => self.workspace_root.to_path_buf(),
+            (None, false) => match &pkg.git {
+                Some(git) => git_checkout_src(pkg.name, git),
+                None => registry_src(pkg.name, pkg.version),
+            },
+        }
+    }
+
+    /// Build all the stale units in `plan`, up to `self.jobs` at a time.
+    /// Returns the number of units we built.
+    fn build_stale(&self, plan: &[Option<Planned>]) -> usize {
+        let is_stale = |id: UnitId| plan[id].as_ref().is_some_and(|p| !p.fresh);
+
+        // How many stale deps each stale unit is still waiting on, and the
+        // reverse edges.
+        let mut waiting = vec![0usize; plan.len()];
+        let mut dependents = vec![Vec::new(); plan.len()];
+        let mut ready = VecDeque::new();
+        for id in (0..plan.len()).filter(|&id| is_stale(id)) {
+            let mut deps = self.unit_deps(id).collect::<Vec<_>>();
+            deps.sort_unstable();
+            deps.dedup();
+            for dep in deps.into_iter().filter(|&dep| is_stale(dep)) {
+                waiting[id] += 1;
+                dependents[dep].push(id);
+            }
+            if waiting[id] == 0 {
+                ready.push_back(id);
+            }
+        }
+
+        let (tx, rx) = mpsc::channel::<(UnitId, bool)>();
+        let mut running = 0;
+        let mut num_built = 0;
+        let mut failed = Vec::new();
+        loop {
+            // After a failure, let the running units finish, but don't start
+            // any new ones.
+            while failed.is_empty() && running < self.jobs {
+                let Some(id) = ready.pop_front() else { break };
+                self.spawn(id, plan[id].as_ref().unwrap(), tx.clone());
+                running += 1;
+            }
+            if running == 0 {
+                break;
+            }
+
+            let (id, ok) = rx.recv().expect("unit build thread panicked");
+            running -= 1;
+            let planned = plan[id].as_ref().unwrap();
+            if !ok {
+                failed.push(id);
+                continue;
+            }
+
+            num_built += 1;
+            let _ = std::fs::remove_dir_all(&planned.tmp);
+            for &dependent in &dependents[id] {
+                waiting[dependent] -= 1;
+                if waiting[dependent] == 0 {
+                    ready.push_back(dependent);
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            for &id in &failed {
+                let tmp = &plan[id].as_ref().unwrap().tmp;
+                eprintln!(
+                    "error: failed to build `{}`. Its unit spec and repro \
+                     script are in: {}",
+                    self.graph.units[id].name(),
+                    tmp.display()
+                );
+            }
+            panic!("failed to build {} unit(s)", failed.len());
+        }
+        num_built
+    }
+
+    /// Build unit `id` in a child process, then send its result over `tx`.
+    fn spawn(
+        &self,
+        id: UnitId,
+        planned: &Planned,
+        tx: mpsc::Sender<(UnitId, bool)>,
+    ) {
+        let unit = &self.graph.units[id];
+        let verb = match unit.mode {
+            "check" => "Checking",
+            "run-custom-build" => "Running",
+            _ => "Compiling",
+        };
+        eprintln!(
+            "{verb:>12} {} v{} ({}{})",
+            unit.pkg.name,
+            unit.pkg.version,
+            unit.target.kind,
+            if unit.mode == "metadata" {
+                ", rmeta"
+            } else {
+                ""
+            }
+        );
+
+        // Start from scratch, like a nix build.
+        remove_dir_all(&planned.out);
+        remove_dir_all(&planned.tmp);
+        std::fs::create_dir_all(&planned.tmp)
+            .expect("failed to create unit tmp dir");
+        let spec_path = planned.tmp.join("unit.json");
+        fs::write_file(&spec_path, planned.spec.as_bytes())
+            .expect("failed to write unit spec");
+
+        let mut path = self.rustc_bin_dir.as_os_str().to_owned();
+        if let Some(env_path) = std::env::var_os("PATH") {
+            path.push(":");
+            path.push(env_path);
+        }
+
+        let mut cmd = Command::new(self.exe);
+        cmd.arg("build-unit")
+            .arg(&spec_path)
+            .current_dir(&planned.tmp)
+            .env("PATH", path)
+            .env("TMPDIR", &planned.tmp)
+            .stdin(Stdio::null());
+        trace!("{cmd:?}");
+        let mut child = cmd.spawn().unwrap_or_else(|err| {
+            panic!("failed to run `{} build-unit`: {err}", self.exe.display())
+        });
+
+        std::thread::spawn(move || {
+            let ok = child.wait().is_ok_and(|status| status.success());
+            let _ = tx.send((id, ok));
+        });
+    }
+}
+
+fn cargo_home() -> PathBuf {
+    match std::env::var_os("CARGO_HOME") {
+        Some(cargo_home) => PathBuf::from(cargo_home),
+        None => {
+            let home = std::env::var_os("HOME").expect("missing `HOME` env");
+            PathBuf::from(home).join(".cargo")
+        }
+    }
+}
+
+/// The source of a git package, inside the repo checkout that `cargo fetch`
+/// puts in `$CARGO_HOME/git/checkouts/<repo>-<hash>/<short-rev>`.
+fn git_checkout_src(name: &str, git: &GitSource<'_>) -> PathBuf {
+    let checkouts = cargo_home().join("git").join("checkouts");
+    let mut checkout_dirs = std::fs::read_dir(&checkouts)
+        .map(|repos| {
+            repos
+                .filter_map(|repo| repo.ok())
+                .filter_map(|repo| std::fs::read_dir(repo.path()).ok())
+                .flatten()
+                .filter_map(|checkout| checkout.ok())
+                .filter(|checkout| {
+                    let short_rev = checkout.file_name();
+                    let short_rev = short_rev.to_string_lossy();
+                    short_rev.len() >= 7 && git.rev.starts_with(&*short_rev)
+                })
+                .map(|checkout| checkout.path())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    checkout_dirs.sort_unstable();
+
+    checkout_dirs
+        .into_iter()
+        .map(|checkout| match git.subdir {
+            Some(subdir) => checkout.join(subdir),
+            None => checkout,
+        })
+        .find(|dir| dir.join("Cargo.toml").is_file())
+        .unwrap_or_else(|| {
+            panic!(
+                "missing git checkout for `{name}` at rev {} in {}. Try \
+                 running `cargo fetch` first.",
+                git.rev,
+                checkouts.display()
+            )
+        })
+}
+
+/// The unpacked source of a crates.io package, which `cargo fetch` puts in
+/// `$CARGO_HOME/registry/src/<index>/<name>-<version>`.
+fn registry_src(name: &str, version: &str) -> PathBuf {
+    let registry_src = cargo_home().join("registry").join("src");
+    let mut indexes = std::fs::read_dir(&registry_src)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    indexes.sort_unstable();
+
+    let dir_name = format!("{name}-{version}");
+    indexes
+        .into_iter()
+        .map(|index| index.join(&dir_name))
+        .find(|dir| dir.join("Cargo.toml").is_file())
+        .unwrap_or_else(|| {
+            panic!(
+                "missing source for `{name}@{version}` in {}. Try running \
+                 `cargo fetch` first.",
+                registry_src.display()
+            )
+        })
+}
+
+/// Hash the paths and contents of all the files in a workspace package, which
+/// is the same file set that `buildGraph.nix::_workspacePkgFileset` copies.
+///
+/// Like `cargo package`, that's the whole package dir, minus the `target` dir,
+/// hidden dirs like `.git`, and any nested packages.
+fn hash_pkg_src(hasher: &mut Sha256, root: &Path, rel: &Path) {
+    let path = root.join(rel);
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+
+    if metadata.is_dir() {
+        let is_root = rel.as_os_str().is_empty();
+        let mut names = std::fs::read_dir(&path)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        names.sort_unstable();
+        for name in names {
+            let child = path.join(&name);
+            if child.is_dir()
+                && ((is_root && name == "target")
+                    || name.as_encoded_bytes().starts_with(b".")
+                    || child.join("Cargo.toml").exists())
+            {
+                continue;
+            }
+            hash_pkg_src(hasher, root, &rel.join(name));
+        }
+    } else {
+        let contents = fs::read_file(&path)
+            .expect("failed to read package source file")
+            .unwrap_or_default();
+        hasher.update(rel.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+}
+
+fn remove_dir_all(path: &Path) {
+    match std::fs::remove_dir_all(path) {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => panic!("failed to remove {}: {err}", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::{
+        cfg::TargetCfgs,
+        graph::test::{METADATA, RESOLVED, build_graph, target_cfgs},
+        metadata::{Metadata, ResolveFeatures},
+    };
+
+    /// Stands in for `nargo-build build-unit`. It runs in the unit's tmp dir,
+    /// `target/nargo/tmp/<hash>-<name>`, so it logs the unit name and creates
+    /// the matching out dir.
+    const FAKE_BUILD_UNIT: &str = "#!/bin/sh\n\
+        set -eu\n\
+        name=\"$(basename \"$PWD\")\"\n\
+        mkdir -p \"../../store/$name\"\n\
+        echo \"${name#*-}\" >> ../../build.log\n";
+
+    /// The [`METADATA`] workspace in a temp dir.
+    struct Fixture {
+        dir: PathBuf,
+        metadata: &'static Metadata<'static>,
+        resolved: &'static ResolveFeatures<'static>,
+        cfgs: TargetCfgs,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "nargo-test-executor-{name}-{}",
+                std::process::id()
+            ));
+            remove_dir_all(&dir);
+            for (path, contents) in [
+                ("app/src/main.rs", "fn main() {}\n"),
+                ("greet/src/lib.rs", "pub fn greet() {}\n"),
+                ("greet/build.rs", "fn main() {}\n"),
+                ("shared/banner.txt", "hi\n"),
+                ("fake-build-unit", FAKE_BUILD_UNIT),
+            ] {
+                let path = dir.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, contents).unwrap();
+            }
+            let exe = dir.join("fake-build-unit");
+            std::fs::set_permissions(
+                &exe,
+                std::fs::Permissions::from_mode(0o755),
+            )
+            .unwrap();
+
+            Self {
+                dir,
+                metadata: Box::leak(Box::new(
+                    serde_json::from_str(METADATA).unwrap(),
+                )),
+                resolved: Box::leak(Box::new(
+                    serde_json::from_str(RESOLVED).unwrap(),
+                )),
+                cfgs: target_cfgs(),
+            }
+        }
+
+        /// Build `app` and return the names of the units we (re)built, in the
+        /// order they finished.
+        fn build(&self, jobs: usize) -> Vec<String> {
+            let graph = build_graph(self.metadata, self.resolved, &self.cfgs);
+            let cache_dir = self.dir.join("target/nargo");
+            let log = cache_dir.join("build.log");
+            let _ = std::fs::remove_file(&log);
+            let outs = Executor {
+                graph: &graph,
+                workspace_root: &self.dir,
+                cache_dir: &cache_dir,
+                exe: &self.dir.join("fake-build-unit"),
+                rustc_bin_dir: Path::new("/nonexistent"),
+                rustc_version_verbose: "rustc 1.86.0",
+                build_triple: "x86_64-unknown-linux-gnu",
+                host_triple: "x86_64-unknown-linux-gnu",
+                build_target_cfg: Path::new("/nonexistent"),
+                host_target_cfg: Path::new("/nonexistent"),
+                jobs,
+                log: "off",
+            }
+            .run();
+            assert!(outs.iter().all(|out| out.is_dir()));
+            fs::read_file(&log)
+                .unwrap()
+                .map(|log| String::from_utf8(log).unwrap())
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            remove_dir_all(&self.dir);
+        }
+    }
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn test_build_order() {
+        let fixture = Fixture::new("order");
+        let built = fixture.build(4);
+        let graph =
+            build_graph(fixture.metadata, fixture.resolved, &fixture.cfgs);
+        // Nothing needs `greet`'s `.rmeta` half, since `app` links the rlib.
+        assert_eq!(built.len(), graph.units.len() - 1);
+        assert!(!built.iter().any(|name| name == "greet-lib-rmeta-0.1.0"));
+
+        // Every unit finishes after all of its deps.
+        let pos = |id: UnitId| {
+            let name = graph.units[id].name();
+            built.iter().position(|built| *built == name)
+        };
+        for (id, unit) in graph.units.iter().enumerate() {
+            if pos(id).is_none() {
+                continue;
+            }
+            let deps = unit
+                .deps
+                .iter()
+                .map(|dep| dep.unit)
+                .chain(unit.build_script_dep)
+                .chain(unit.build_script_bin)
+                .chain(unit.link_deps.iter().copied());
+            for dep in deps {
+                assert!(
+                    pos(dep) < pos(id),
+                    "{} finished before its dep {}",
+                    unit.name(),
+                    graph.units[dep].name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let fixture = Fixture::new("rebuild");
+        assert_eq!(fixture.build(1).len(), 6);
+
+        // Nothing changed, so everything is fresh.
+        assert_eq!(fixture.build(1), Vec::<String>::new());
+
+        // Changing `greet` rebuilds it (and its build script) and `app`, but
+        // not `itoa`.
+        std::fs::write(
+            fixture.dir.join("greet/src/lib.rs"),
+            "pub fn hi() {}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            sorted(fixture.build(1)),
+            [
+                "app-bin-0.1.0",
+                "greet-custom-build-0.1.0",
+                "greet-custom-build-run-0.1.0",
+                "greet-lib-0.1.0",
+            ]
+        );
+        assert_eq!(fixture.build(1), Vec::<String>::new());
+
+        // So does any other file in the package, like an `include_str!`'d
+        // README...
+        std::fs::write(fixture.dir.join("greet/README.md"), "# greet\n")
+            .unwrap();
+        assert_eq!(
+            sorted(fixture.build(1)),
+            [
+                "app-bin-0.1.0",
+                "greet-custom-build-0.1.0",
+                "greet-custom-build-run-0.1.0",
+                "greet-lib-0.1.0",
+            ]
+        );
+
+        // ...or a recorded source outside the package...
+        std::fs::write(fixture.dir.join("shared/banner.txt"), "hello\n")
+            .unwrap();
+        assert_eq!(
+            sorted(fixture.build(1)),
+            [
+                "app-bin-0.1.0",
+                "greet-custom-build-0.1.0",
+                "greet-custom-build-run-0.1.0",
+                "greet-lib-0.1.0",
+            ]
+        );
+
+        // ...but not its `target` dir, hidden dirs, or nested packages.
+        for path in ["target/foo", ".git/HEAD", "nested/Cargo.toml"] {
+            let path = fixture.dir.join("greet").join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        assert_eq!(fixture.build(1), Vec::<String>::new());
+
+        // A leftover tmp dir from an interrupted build makes its unit stale.
+        // (The store also still has `app`'s out dir from the first build.)
+        let store = fixture.dir.join("target/nargo/store");
+        for entry in std::fs::read_dir(&store).unwrap() {
+            let name = entry.unwrap().file_name();
+            if name.to_string_lossy().ends_with("-app-bin-0.1.0") {
+                let tmp = fixture.dir.join("target/nargo/tmp").join(name);
+                std::fs::create_dir_all(tmp).unwrap();
+            }
+        }
+        assert_eq!(fixture.build(1), ["app-bin-0.1.0"]);
+    }
+}
@@ -0,0 +1,966 @@
+//! Build the unit graph for a `cargo build`-like invocation. This mirrors
+//! `lib/buildGraph.nix` (and the target selection in `lib/buildPackage.nix`),
+//! so each unit gets the same deps, profile, mode, and `-C metadata` as its
+//! nix derivation.
+//!
+//! Only the plain target units are supported for now, not the `test-*`,
+//! `doctest-*`, or `doc-*` units.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use nargo_core::nargo::TargetKind;
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cfg::TargetCfgs,
+    metadata::{
+        FeatFor, Metadata, Package, PkgFeatForActivation, ResolveFeatures,
+        Target,
+    },
+};
+
+pub(crate) type UnitId = usize;
+
+/// All the units we might build, in dependency order: every unit comes after
+/// all the units it depends on.
+pub(crate) struct UnitGraph<'a> {
+    pub(crate) units: Vec<Unit<'a>>,
+    /// The selected root units.
+    pub(crate) roots: Vec<UnitId>,
+}
+
+/// A single unit. Like a `buildCrate` derivation's `target` attrset.
+pub(crate) struct Unit<'a> {
+    pub(crate) pkg: &'a Package<'a>,
+    pub(crate) target: &'a Target<'a>,
+    /// The target's crate types, plus `dylib` for normal workspace libs built
+    /// with `prefer_dynamic`.
+    pub(crate) crate_types: Vec<&'a str>,
+    /// Whether this unit is part of the "build" feature set, i.e., it runs on
+    /// the build platform.
+    pub(crate) feat_for: FeatFor,
+    /// `"build"`, `"check"`, `"metadata"` for the `.rmeta` half of a
+    /// pipelined lib, or `"run-custom-build"` to run a compiled build script.
+    pub(crate) mode: &'static str,
+    /// Whether we're the rlib half of a pipelined lib.
+    pub(crate) pipelined: bool,
+    pub(crate) features: Vec<&'a str>,
+    pub(crate) deps: Vec<UnitDep>,
+    pub(crate) build_script_dep: Option<UnitId>,
+    /// For a `run-custom-build` unit, the unit that compiled the build script.
+    pub(crate) build_script_bin: Option<UnitId>,
+    pub(crate) link_deps: Vec<UnitId>,
+    pub(crate) links_deps: Vec<(&'a str, UnitId)>,
+    pub(crate) profile: Map<String, Value>,
+    pub(crate) lto_needs_object: bool,
+    /// The `-C metadata` hash.
+    pub(crate) metadata: String,
+    /// Whether our package was selected to build, like `cargo build -p`.
+    pub(crate) is_primary_pkg: bool,
+    /// The `.rmeta` half, if we're the rlib half of a pipelined lib.
+    pub(crate) rmeta: Option<UnitId>,
+}
+
+#[derive(Clone)]
+pub(crate) struct UnitDep {
+    pub(crate) dep_name: String,
+    pub(crate) crate_name: String,
+    pub(crate) unit: UnitId,
+    /// If `unit` is the `.rmeta` half of a pipelined lib, the full rlib unit.
+    pub(crate) link: Option<UnitId>,
+}
+
+/// Which targets of the selected packages to build, like
+/// `buildPackage { lib = ..; bins = ..; }`.
+pub(crate) struct Selection<'a> {
+    pub(crate) packages: Vec<&'a str>,
+    pub(crate) lib: bool,
+    /// `None` selects all bins.
+    pub(crate) bins: Option<Vec<&'a str>>,
+}
+
+pub(crate) struct GraphBuilder<'a> {
+    pub(crate) metadata: &'a Metadata<'a>,
+    pub(crate) resolved: &'a ResolveFeatures<'a>,
+    /// The selected profile, from `Cargo.metadata.json`.
+    pub(crate) profile: &'a Map<String, Value>,
+    /// The compile mode for normal units, `"build"` or `"check"`.
+    pub(crate) mode: &'static str,
+    pub(crate) build_triple: &'a str,
+    pub(crate) host_triple: &'a str,
+    pub(crate) build_cfgs: &'a TargetCfgs,
+    pub(crate) host_cfgs: &'a TargetCfgs,
+    /// The `rustc` version, like `"1.86.0"`.
+    pub(crate) rustc_version: &'a str,
+}
+
+type UnitKey<'a> = (&'a str, FeatFor, usize);
+
+#[derive(Default)]
+struct State<'a> {
+    units: Vec<Unit<'a>>,
+    memo: BTreeMap<UnitKey<'a>, UnitId>,
+    /// The units that compile each package's build script, by their
+    /// `-C metadata`, so the build and normal run units can share one.
+    build_script_bins: BTreeMap<(&'a str, String), UnitId>,
+    visiting: BTreeSet<UnitKey<'a>>,
+    /// The selected workspace packages.
+    primary_pkgs: BTreeSet<&'a str>,
+}
+
+impl<'a> GraphBuilder<'a> {
+    pub(crate) fn build(&self, selection: &Selection<'_>) -> UnitGraph<'a> {
+        let mut state = State {
+            primary_pkgs: selection
+                .packages
+                .iter()
+                .map(|&pkg_id| self.pkg(pkg_id).0)
+                .collect(),
+            ..State::default()
+        };
+
+        let mut roots = Vec::new();
+        for &pkg_id in &selection.packages {
+            let (pkg_id, pkg) = self.pkg(pkg_id);
+            let is_resolved = self
+                .resolved
+                .get(pkg_id)
+                .is_some_and(|pkg| pkg.contains_key(&FeatFor::Normal));
+            if !is_resolved {
+                continue;
+            }
+            for (idx, target) in pkg.targets.iter().enumerate() {
+                if selection.selects(target) {
+                    roots.push(self.unit(
+                        &mut state,
+                        pkg_id,
+                        FeatFor::Normal,
+                        idx,
+                    ));
+                }
+            }
+        }
+
+        let lto = self.profile.get("lto").and_then(Value::as_str);
+        if !matches!(lto, None | Some("false" | "off")) {
+            let needs_object = self.lto_needs_object(&mut state);
+            for unit in &mut state.units {
+                let key = format!("{}@{}", unit.pkg.name, unit.pkg.version);
+                unit.lto_needs_object = unit.target.kind.is_lib()
+                    && unit.feat_for == FeatFor::Normal
+                    && needs_object.contains(&key);
+            }
+        }
+
+        UnitGraph {
+            units: state.units,
+            roots,
+        }
+    }
+
+    fn pkg(&self, pkg_id: &str) -> (&'a str, &'a Package<'a>) {
+        self.metadata
+            .packages
+            .get_key_value(pkg_id)
+            .map(|(pkg_id, pkg)| (*pkg_id, pkg))
+            .unwrap_or_else(|| {
+                panic!("package `{pkg_id}` is not in `Cargo.metadata.json`")
+            })
+    }
+
+    fn resolved(
+        &self,
+        pkg_id: &str,
+        feat_for: FeatFor,
+    ) -> &'a PkgFeatForActivation<'a> {
+        self.resolved
+            .get(pkg_id)
+            .and_then(|pkg| pkg.get(&feat_for))
+            .unwrap_or_else(|| {
+                panic!(
+                    "package `{pkg_id}` ({feat_for}) is missing from the \
+                     resolved features",
+                    feat_for = feat_for.as_str(),
+                )
+            })
+    }
+
+    /// The unit for the `idx`'th target of package `pkg_id`. For a pipelined
+    /// lib, this is the full rlib unit. For a build script, this is the unit
+    /// that runs it.
+    fn unit(
+        &self,
+        state: &mut State<'a>,
+        pkg_id: &'a str,
+        feat_for: FeatFor,
+        idx: usize,
+    ) -> UnitId {
+        let key = (pkg_id, feat_for, idx);
+        if let Some(&unit) = state.memo.get(&key) {
+            return unit;
+        }
+        if !state.visiting.insert(key) {
+            panic!("dependency cycle through package `{pkg_id}`");
+        }
+
+        let (_, pkg) = self.pkg(pkg_id);
+        let target = &pkg.targets[idx];
+        let resolved = self.resolved(pkg_id, feat_for);
+        let is_lib = target.kind.is_lib();
+        let is_build = target.kind.is_custom_build();
+
+        // The build-script unit, unless we're the build script.
+        let build_script_dep = if is_build {
+            None
+        } else {
+            pkg.targets
+                .iter()
+                .position(|target| target.kind.is_custom_build())
+                .map(|idx| self.unit(state, pkg_id, feat_for, idx))
+        };
+
+        // bins, examples, tests, etc... depend on the lib target if it
+        // exists and is linkable.
+        let mut unit_deps = Vec::new();
+        if !is_lib && !is_build {
+            let lib_idx = pkg.targets.iter().position(|target| {
+                target.kind.is_lib()
+                    && target.crate_types.iter().any(|crate_type| {
+                        matches!(
+                            *crate_type,
+                            "lib" | "proc-macro" | "dylib" | "rlib"
+                        )
+                    })
+            });
+            if let Some(lib_idx) = lib_idx {
+                let dep_name = crate_name(pkg.targets[lib_idx].name);
+                unit_deps.push(
+                    self.target_dep(state, dep_name, pkg_id, feat_for, lib_idx),
+                );
+            }
+        }
+
+        // Dependencies on libs in other packages.
+        unit_deps
+            .extend(self.pkg_deps(state, pkg, feat_for, resolved, is_build));
+
+        // Build scripts get `DEP_<links>_<key>` envs from the build scripts of
+        // their direct deps that declare a `links` key.
+        let links_deps = if is_build {
+            self.pkg_deps(state, pkg, feat_for, resolved, false)
+                .into_iter()
+                .filter_map(|dep| {
+                    let dep_unit = &state.units[dep.unit];
+                    match (dep_unit.pkg.links, dep_unit.build_script_dep) {
+                        (Some(links), Some(build_script)) => {
+                            Some((links, build_script))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let profile = self.resolve_profile(pkg, feat_for == FeatFor::Build);
+        let host_profile = is_build.then(|| self.resolve_profile(pkg, true));
+
+        let mode = if feat_for == FeatFor::Build || is_build {
+            "build"
+        } else {
+            self.mode
+        };
+        let crate_types = self.unit_crate_types(pkg, target, feat_for, mode);
+        let is_pipelined = mode == "build"
+            && is_lib
+            && crate_types
+                .iter()
+                .all(|crate_type| matches!(*crate_type, "lib" | "rlib"));
+
+        let link_deps = if mode == "build" && !is_pipelined {
+            pipelined_link_deps(&state.units, &unit_deps)
+        } else {
+            Vec::new()
+        };
+
+        let metadata = self.unit_metadata(
+            state,
+            pkg_id,
+            pkg,
+            target,
+            feat_for,
+            resolved,
+            &profile,
+            host_profile.as_ref(),
+            &unit_deps,
+            build_script_dep,
+        );
+
+        let deps = if is_pipelined {
+            unit_deps
+                .into_iter()
+                .map(|dep| pipelined_dep(&state.units, dep))
+                .collect()
+        } else {
+            unit_deps
+        };
+
+        let unit = Unit {
+            pkg,
+            target,
+            crate_types,
+            feat_for,
+            mode,
+            pipelined: false,
+            features: resolved.feats.keys().copied().collect(),
+            deps,
+            build_script_dep,
+            build_script_bin: None,
+            link_deps,
+            links_deps,
+            profile,
+            lto_needs_object: false,
+            metadata,
+            is_primary_pkg: pkg.is_workspace_pkg()
+                && state.primary_pkgs.contains(pkg_id),
+            rmeta: None,
+        };
+
+        // Build scripts: compile `build_script_build` for the build platform
+        // with the build-override profile, then run it for our profile and
+        // target in a separate unit. Like `buildGraph.nix`, only the run unit
+        // depends on those, so the compiled unit can be shared.
+        let unit = if is_build {
+            let compile_profile = host_profile.unwrap();
+            let compile_metadata = self.unit_metadata(
+                state,
+                pkg_id,
+                pkg,
+                target,
+                FeatFor::Build,
+                resolved,
+                &compile_profile,
+                None,
+                &unit.deps,
+                None,
+            );
+            let compile_key = (pkg_id, compile_metadata.clone());
+            let compile = match state.build_script_bins.get(&compile_key) {
+                Some(&compile) => compile,
+                None => {
+                    let compile_unit = Unit {
+                        crate_types: unit.crate_types.clone(),
+                        feat_for: FeatFor::Build,
+                        deps: unit.deps.clone(),
+                        link_deps: unit.link_deps.clone(),
+                        links_deps: Vec::new(),
+                        profile: compile_profile,
+                        features: unit.features.clone(),
+                        metadata: compile_metadata,
+                        ..unit
+                    };
+                    let compile = push(state, compile_unit);
+                    state.build_script_bins.insert(compile_key, compile);
+                    compile
+                }
+            };
+            Unit {
+                mode: "run-custom-build",
+                deps: Vec::new(),
+                link_deps: Vec::new(),
+                build_script_bin: Some(compile),
+                ..unit
+            }
+        } else {
+            unit
+        };
+
+        // Pipelining: split each plain rlib unit into a `.rmeta` unit and the
+        // full rlib unit. Both use the same `-C metadata`.
+        let id = if is_pipelined {
+            let rmeta_unit = Unit {
+                mode: "metadata",
+                crate_types: unit.crate_types.clone(),
+                deps: unit.deps.clone(),
+                link_deps: Vec::new(),
+                links_deps: unit.links_deps.clone(),
+                profile: unit.profile.clone(),
+                features: unit.features.clone(),
+                metadata: unit.metadata.clone(),
+                ..unit
+            };
+            let rmeta = push(state, rmeta_unit);
+            push(
+                state,
+                Unit {
+                    pipelined: true,
+                    rmeta: Some(rmeta),
+                    ..unit
+                },
+            )
+        } else {
+            push(state, unit)
+        };
+
+        state.visiting.remove(&key);
+        state.memo.insert(key, id);
+        id
+    }
+
+    fn target_dep(
+        &self,
+        state: &mut State<'a>,
+        dep_name: String,
+        pkg_id: &'a str,
+        feat_for: FeatFor,
+        idx: usize,
+    ) -> UnitDep {
+        let (_, pkg) = self.pkg(pkg_id);
+        UnitDep {
+            dep_name,
+            crate_name: crate_name(pkg.targets[idx].name),
+            unit: self.unit(state, pkg_id, feat_for, idx),
+            link: None,
+        }
+    }
+
+    /// The lib units of our activated deps in other packages. Like
+    /// `buildGraph.nix::_pkgDeps`.
+    fn pkg_deps(
+        &self,
+        state: &mut State<'a>,
+        pkg: &'a Package<'a>,
+        feat_for: FeatFor,
+        resolved: &'a PkgFeatForActivation<'a>,
+        is_build: bool,
+    ) -> Vec<UnitDep> {
+        let cfgs = match feat_for {
+            FeatFor::Build => self.build_cfgs,
+            FeatFor::Normal => self.host_cfgs,
+        };
+        let desired_kind = if is_build { Some("build") } else { None };
+
+        let mut deps = Vec::new();
+        for (&dep_pkg_id, pkg_dep) in &pkg.deps {
+            let is_relevant = pkg_dep.kinds.iter().any(|dep_kind| {
+                dep_kind.kind == desired_kind
+                    // only select optional deps that are activated
+                    && (!dep_kind.optional
+                        || resolved.deps.contains_key(pkg_dep.name))
+                    // make sure the dep is activated for this target cfg
+                    && dep_kind
+                        .target
+                        .as_deref()
+                        .is_none_or(|platform| cfgs.matches(platform))
+            });
+            if !is_relevant {
+                continue;
+            }
+
+            let (dep_pkg_id, dep_pkg) = self.pkg(dep_pkg_id);
+            let lib_idx = dep_pkg
+                .targets
+                .iter()
+                .position(|target| target.kind.is_lib())
+                .unwrap_or_else(|| {
+                    panic!("dependency `{dep_pkg_id}` has no lib target")
+                });
+
+            // proc-macros always build for the build platform
+            let is_proc_macro = self
+                .resolved
+                .get(dep_pkg_id)
+                .is_some_and(|dep| dep.contains_key(&FeatFor::Build))
+                && dep_pkg.targets[lib_idx].crate_types.contains(&"proc-macro");
+            let dep_feat_for = if is_proc_macro || is_build {
+                FeatFor::Build
+            } else {
+                feat_for
+            };
+
+            deps.push(self.target_dep(
+                state,
+                pkg_dep.name.to_owned(),
+                dep_pkg_id,
+                dep_feat_for,
+                lib_idx,
+            ));
+        }
+        deps
+    }
+
+    /// With `prefer_dynamic`, every normal workspace rlib also builds as a Rust
+    /// dylib. Like `buildGraph.nix::unitCrateTypes`.
+    fn unit_crate_types(
+        &self,
+        pkg: &Package<'_>,
+        target: &Target<'a>,
+        feat_for: FeatFor,
+        mode: &str,
+    ) -> Vec<&'a str> {
+        let prefer_dynamic = self
+            .profile
+            .get("prefer_dynamic")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let mut crate_types = target.crate_types.clone();
+        let is_dynamic_lib = prefer_dynamic
+            && pkg.is_workspace_pkg()
+            && mode == "build"
+            && target.kind.is_lib()
+            && feat_for == FeatFor::Normal
+            && crate_types
+                .iter()
+                .all(|crate_type| matches!(*crate_type, "lib" | "rlib"));
+        if is_dynamic_lib {
+            crate_types.push("dylib");
+        }
+        crate_types
+    }
+
+    /// Apply the selected profile's overrides for a single package. Like
+    /// `buildGraph.nix::_resolveProfile`.
+    fn resolve_profile(
+        &self,
+        pkg: &Package<'_>,
+        is_build: bool,
+    ) -> Map<String, Value> {
+        let mut profile = self.profile.clone();
+        profile.remove("build_override");
+        let overrides = profile.remove("package");
+
+        let mut merge = |value: Option<&Value>| {
+            if let Some(Value::Object(fields)) = value {
+                for (key, value) in fields {
+                    profile.insert(key.clone(), value.clone());
+                }
+            }
+        };
+
+        if is_build {
+            merge(self.profile.get("build_override"));
+            merge(Some(&json!({ "lto": "false", "panic": "unwind" })));
+        }
+        if let Some(overrides) = overrides {
+            if !pkg.is_workspace_pkg() {
+                merge(overrides.get("*"));
+            }
+            merge(overrides.get(pkg.name));
+            merge(overrides.get(format!("{}@{}", pkg.name, pkg.version)));
+        }
+        profile
+    }
+
+    /// The unit's `-C metadata` hash. Like `buildGraph.nix::_unitMetadata`,
+    /// this is the first 16 hex chars of the SHA-256 of the unit's identity
+    /// as (sorted, compact) JSON.
+    #[allow(clippy::too_many_arguments)]
+    fn unit_metadata(
+        &self,
+        state: &State<'a>,
+        pkg_id: &str,
+        pkg: &Package<'_>,
+        target: &Target<'_>,
+        feat_for: FeatFor,
+        resolved: &PkgFeatForActivation<'_>,
+        profile: &Map<String, Value>,
+        host_profile: Option<&Map<String, Value>>,
+        deps: &[UnitDep],
+        build_script_dep: Option<UnitId>,
+    ) -> String {
+        let mut dep_metadata = deps
+            .iter()
+            .map(|dep| state.units[dep.unit].metadata.as_str())
+            .chain(
+                build_script_dep
+                    .map(|unit| state.units[unit].metadata.as_str()),
+            )
+            .collect::<Vec<_>>();
+        dep_metadata.sort_unstable();
+
+        let identity = json!({
+            "package": {
+                "id": pkg_id,
+                "name": pkg.name,
+                "version": pkg.version,
+                "source": pkg.source,
+            },
+            "target": {
+                "name": target.name,
+                "kind": target.kind.as_str(),
+                "crate_types": target.crate_types,
+            },
+            "featFor": feat_for.as_str(),
+            "variant": null,
+            "features": resolved.feats.keys().collect::<Vec<_>>(),
+            "profile": profile,
+            "host_profile": host_profile,
+            "build_triple": self.build_triple,
+            "host_triple": self.host_triple,
+            "rustc": self.rustc_version,
+            "deps": dep_metadata,
+        });
+        metadata_hash(&identity)
+    }
+
+    /// The `"<name>@<version>"` of all normal lib units that are a
+    /// (transitive) dep of a lib that needs object code when building with
+    /// LTO. Like `buildGraph.nix::_ltoNeedsObject`.
+    fn lto_needs_object(&self, state: &mut State<'a>) -> BTreeSet<String> {
+        fn needs_object(crate_types: &[&str]) -> bool {
+            let can_lto = |crate_type: &&str| {
+                matches!(*crate_type, "bin" | "cdylib" | "staticlib")
+            };
+            let is_dynamic = |crate_type: &&str| {
+                matches!(*crate_type, "dylib" | "cdylib" | "proc-macro")
+            };
+            crate_types.iter().any(|t| can_lto(t) || is_dynamic(t))
+                && !crate_types.iter().all(can_lto)
+        }
+
+        let mut queue = VecDeque::new();
+        for (&pkg_id, pkg) in self.resolved {
+            if !pkg.contains_key(&FeatFor::Normal) {
+                continue;
+            }
+            let (pkg_id, metadata_pkg) = self.pkg(pkg_id);
+            let lib_idx = metadata_pkg.targets.iter().position(|target| {
+                target.kind == TargetKind::Lib
+                    && needs_object(&self.unit_crate_types(
+                        metadata_pkg,
+                        target,
+                        FeatFor::Normal,
+                        self.mode,
+                    ))
+            });
+            if let Some(lib_idx) = lib_idx {
+                queue.push_back(self.unit(
+                    state,
+                    pkg_id,
+                    FeatFor::Normal,
+                    lib_idx,
+                ));
+            }
+        }
+
+        // proc-macros and their deps are always built without LTO, so we can
+        // stop there.
+        let mut needs_object = BTreeSet::new();
+        while let Some(unit) = queue.pop_front() {
+            for dep in &state.units[unit].deps {
+                let dep_unit = &state.units[dep.unit];
+                if dep_unit.crate_types.contains(&"proc-macro") {
+                    continue;
+                }
+                let key =
+                    format!("{}@{}", dep_unit.pkg.name, dep_unit.pkg.version);
+                if needs_object.insert(key) {
+                    queue.push_back(dep.unit);
+                }
+            }
+        }
+        needs_object
+    }
+}
+
+impl Selection<'_> {
+    /// Whether we build the plain (non-test) unit for `target`. Like
+    /// `buildPackage.nix::selectPkgFeatForTargets`.
+    fn selects(&self, target: &Target<'_>) -> bool {
+        match (&self.bins, target.kind) {
+            // `lib = true; bins = true;` selects everything
+            (None, _) if self.lib => true,
+            (_, TargetKind::Lib) => self.lib,
+            (None, TargetKind::Bin) => true,
+            (Some(bins), TargetKind::Bin) => bins.contains(&target.name),
+            _ => false,
+        }
+    }
+}
+
+impl Unit<'_> {
+    /// The unit's derivation name, like `buildCrate.nix`.
+    pub(crate) fn name(&self) -> String {
+        let suffix = match self.mode {
+            "metadata" => "-rmeta",
+            "run-custom-build" => "-run",
+            _ => "",
+        };
+        format!(
+            "{}-{}{suffix}-{}",
+            self.pkg.name, self.target.kind, self.pkg.version
+        )
+    }
+}
+
+fn push<'a>(state: &mut State<'a>, unit: Unit<'a>) -> UnitId {
+    state.units.push(unit);
+    state.units.len() - 1
+}
+
+fn crate_name(target_name: &str) -> String {
+    target_name.replace('-', "_")
+}
+
+/// The first 16 hex chars of the SHA-256 of `identity` as compact JSON. Like
+/// `builtins.hashString "sha256" (builtins.toJSON identity)`, since both sort
+/// object keys.
+fn metadata_hash(identity: &Value) -> String {
+    let digest = Sha256::digest(identity.to_string().as_bytes());
+    let mut metadata = format!("{digest:x}");
+    metadata.truncate(16);
+    metadata
+}
+
+/// Point a pipelined lib's dep at the dep's `.rmeta` unit, if it has one.
+fn pipelined_dep(units: &[Unit<'_>], dep: UnitDep) -> UnitDep {
+    match units[dep.unit].rmeta {
+        Some(rmeta) => UnitDep {
+            unit: rmeta,
+            link: Some(dep.unit),
+            ..dep
+        },
+        None => dep,
+    }
+}
+
+/// All the transitive rlib units that we can only reach through pipelined lib
+/// deps. Like `buildGraph.nix::_pipelinedLinkDeps`.
+fn pipelined_link_deps(units: &[Unit<'_>], deps: &[UnitDep]) -> Vec<UnitId> {
+    let mut seen = BTreeSet::new();
+    let mut link_deps = Vec::new();
+    let mut queue = VecDeque::new();
+    let mut visit = |deps: &[UnitDep], queue: &mut VecDeque<UnitId>| {
+        for link in deps.iter().filter_map(|dep| dep.link) {
+            if seen.insert(link) {
+                link_deps.push(link);
+                queue.push_back(link);
+            }
+        }
+    };
+
+    for dep in deps {
+        visit(&units[dep.unit].deps, &mut queue);
+    }
+    while let Some(unit) = queue.pop_front() {
+        visit(&units[unit].deps, &mut queue);
+    }
+    link_deps
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// A small workspace: `app` (bin) -> `greet` (lib, with a build script)
+    /// -> `itoa` (crates.io lib).
+    pub(crate) const METADATA: &str = r#"{
+        "workspace_default_members": ["app", "greet"],
+        "profiles": {
+            "dev": {
+                "root": "dev",
+                "opt_level": "0",
+                "debug": "2",
+                "debug_assertions": true,
+                "overflow_checks": true,
+                "lto": "false",
+                "panic": "unwind",
+                "rpath": false,
+                "prefer_dynamic": false,
+                "build_override": {
+                    "opt_level": "0",
+                    "debug": "0",
+                    "codegen_units": 256
+                }
+            }
+        },
+        "packages": {
+            "app": {
+                "name": "app",
+                "version": "0.1.0",
+                "path": "app",
+                "deps": {
+                    "greet": { "name": "greet", "kinds": [{}] }
+                },
+                "targets": [
+                    { "name": "app", "kind": "bin", "crate_types": ["bin"], "path": "src/main.rs", "edition": "2024" }
+                ]
+            },
+            "greet": {
+                "name": "greet",
+                "version": "0.1.0",
+                "path": "greet",
+                "deps": {
+                    "itoa@1.0.18": { "name": "itoa", "kinds": [{}] }
+                },
+                "targets": [
+                    { "name": "greet", "kind": "lib", "crate_types": ["lib"], "path": "src/lib.rs", "edition": "2024", "sources": ["src/lib.rs", "../shared/banner.txt"] },
+                    { "name": "build-script-build", "kind": "custom-build", "crate_types": ["bin"], "path": "build.rs", "edition": "2024" }
+                ]
+            },
+            "itoa@1.0.18": {
+                "name": "itoa",
+                "version": "1.0.18",
+                "source": "crates.io",
+                "path": "/nonexistent/itoa-1.0.18",
+                "deps": {},
+                "targets": [
+                    { "name": "itoa", "kind": "lib", "crate_types": ["lib"], "path": "src/lib.rs", "edition": "2021" }
+                ]
+            }
+        }
+    }"#;
+
+    pub(crate) const RESOLVED: &str = r#"{
+        "app": { "normal": { "feats": {}, "deps": {} } },
+        "greet": { "normal": { "feats": { "default": null }, "deps": {} } },
+        "itoa@1.0.18": { "normal": { "feats": {}, "deps": {} } }
+    }"#;
+
+    /// Build the graph for `app`'s bin from [`METADATA`].
+    pub(crate) fn build_graph<'a>(
+        metadata: &'a Metadata<'a>,
+        resolved: &'a ResolveFeatures<'a>,
+        cfgs: &'a TargetCfgs,
+    ) -> UnitGraph<'a> {
+        GraphBuilder {
+            metadata,
+            resolved,
+            profile: &metadata.profiles["dev"],
+            mode: "build",
+            build_triple: "x86_64-unknown-linux-gnu",
+            host_triple: "x86_64-unknown-linux-gnu",
+            build_cfgs: cfgs,
+            host_cfgs: cfgs,
+            rustc_version: "1.86.0",
+        }
+        .build(&Selection {
+            packages: vec!["app"],
+            lib: false,
+            bins: None,
+        })
+    }
+
+    pub(crate) fn target_cfgs() -> TargetCfgs {
+        TargetCfgs::parse(
+            "x86_64-unknown-linux-gnu",
+            "target_arch=\"x86_64\"\ntarget_os=\"linux\"\nunix\n",
+        )
+    }
+
+    #[test]
+    fn test_build_graph() {
+        let metadata: Metadata<'_> = serde_json::from_str(METADATA).unwrap();
+        let resolved: ResolveFeatures<'_> =
+            serde_json::from_str(RESOLVED).unwrap();
+        let cfgs = target_cfgs();
+        let graph = build_graph(&metadata, &resolved, &cfgs);
+
+        let names = graph
+            .units
+            .iter()
+            .map(|unit| unit.name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "itoa-lib-rmeta-1.0.18",
+                "itoa-lib-1.0.18",
+                "greet-custom-build-0.1.0",
+                "greet-custom-build-run-0.1.0",
+                "greet-lib-rmeta-0.1.0",
+                "greet-lib-0.1.0",
+                "app-bin-0.1.0",
+            ]
+        );
+        let id = |name: &str| names.iter().position(|n| n == name).unwrap();
+        assert_eq!(graph.roots, [id("app-bin-0.1.0")]);
+
+        // Every unit comes after all of its deps.
+        for (unit_id, unit) in graph.units.iter().enumerate() {
+            let deps = unit
+                .deps
+                .iter()
+                .flat_map(|dep| [Some(dep.unit), dep.link])
+                .flatten()
+                .chain(unit.build_script_dep)
+                .chain(unit.build_script_bin)
+                .chain(unit.link_deps.iter().copied())
+                .chain(unit.rmeta);
+            for dep in deps {
+                assert!(
+                    dep < unit_id,
+                    "{} before {}",
+                    names[dep],
+                    names[unit_id]
+                );
+            }
+        }
+
+        // Pipelined libs only wait on their deps' `.rmeta` halves, which share
+        // the rlib's `-C metadata`.
+        let greet = &graph.units[id("greet-lib-0.1.0")];
+        assert!(greet.pipelined);
+        assert_eq!(greet.rmeta, Some(id("greet-lib-rmeta-0.1.0")));
+        assert_eq!(greet.deps.len(), 1);
+        assert_eq!(greet.deps[0].unit, id("itoa-lib-rmeta-1.0.18"));
+        assert_eq!(greet.deps[0].link, Some(id("itoa-lib-1.0.18")));
+        assert_eq!(
+            greet.build_script_dep,
+            Some(id("greet-custom-build-run-0.1.0"))
+        );
+        assert_eq!(
+            graph.units[id("greet-lib-rmeta-0.1.0")].metadata,
+            greet.metadata
+        );
+        // Only the selected `-p app` is primary.
+        assert!(!greet.is_primary_pkg);
+
+        // The build script is compiled for the build platform, then run.
+        let run = &graph.units[id("greet-custom-build-run-0.1.0")];
+        assert_eq!(run.build_script_bin, Some(id("greet-custom-build-0.1.0")));
+        let compile = &graph.units[id("greet-custom-build-0.1.0")];
+        assert_eq!(compile.feat_for, FeatFor::Build);
+        assert_eq!(compile.profile["debug"], "0");
+        assert_ne!(compile.metadata, run.metadata);
+
+        // The bin links the full rlibs of all its transitive deps.
+        let app = &graph.units[id("app-bin-0.1.0")];
+        assert_eq!(app.deps[0].unit, id("greet-lib-0.1.0"));
+        assert_eq!(app.link_deps, [id("itoa-lib-1.0.18")]);
+        assert!(app.is_primary_pkg);
+    }
+
+    /// `itoa`'s identity, exactly as `buildGraph.nix::_unitMetadata` hashes
+    /// it. Keep in sync with `tests/buildGraph.nix`.
+    const ITOA_IDENTITY: &str = concat!(
+        r#"{"build_triple":"x86_64-unknown-linux-gnu","deps":[],"featFor":"normal","#,
+        r#""features":[],"host_profile":null,"host_triple":"x86_64-unknown-linux-gnu","#,
+        r#""package":{"id":"itoa@1.0.18","name":"itoa","source":"crates.io","version":"1.0.18"},"#,
+        r#""profile":{"debug":"2","debug_assertions":true,"lto":"false","opt_level":"0","#,
+        r#""overflow_checks":true,"panic":"unwind","prefer_dynamic":false,"root":"dev","#,
+        r#""rpath":false},"rustc":"1.86.0","#,
+        r#""target":{"crate_types":["lib"],"kind":"lib","name":"itoa"},"variant":null}"#,
+    );
+
+    #[test]
+    fn test_unit_metadata_golden() {
+        let identity: Value = serde_json::from_str(ITOA_IDENTITY).unwrap();
+        assert_eq!(identity.to_string(), ITOA_IDENTITY);
+        assert_eq!(metadata_hash(&identity), "702df694145bd8b9");
+
+        let metadata: Metadata<'_> = serde_json::from_str(METADATA).unwrap();
+        let resolved: ResolveFeatures<'_> =
+            serde_json::from_str(RESOLVED).unwrap();
+        let cfgs = target_cfgs();
+        let graph = build_graph(&metadata, &resolved, &cfgs);
+        let itoa = graph
+            .units
+            .iter()
+            .find(|unit| unit.name() == "itoa-lib-1.0.18")
+            .unwrap();
+        assert_eq!(itoa.metadata, metadata_hash(&identity));
+    }
+}
@@ -0,0 +1,5 @@
+pub(crate) mod cfg;
+pub mod cli;
+pub(crate) mod executor;
+pub(crate) mod graph;
+pub(crate) mod metadata;
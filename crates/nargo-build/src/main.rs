@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use nargo_core::{info_time, logger, time};
+
+use nargo_rustc::{repro, unit_spec::UnitSpec};
+
+fn main() {
+    // A failed `build-unit` also dumps its repro script
+    nargo_core::panic::set_hook_with(repro::dump);
+
+    // `nargo-build` runs itself as `nargo-build build-unit <path>` to build
+    // each unit. See `executor.rs`.
+    let mut args = std::env::args();
+    if args.nth(1).as_deref() == Some("build-unit") {
+        let path = args.next().expect("usage: nargo-build build-unit <path>");
+        build_unit(Path::new(&path));
+        logger::flush();
+        return;
+    }
+
+    let args = time!("parse cli args", nargo_build::cli::Args::from_env());
+    let args = match args {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    args.run();
+
+    logger::flush();
+}
+
+/// Build a single unit described by the unit spec at `path`, exactly like
+/// `nargo-rustc --unit <path>`.
+fn build_unit(path: &Path) {
+    let spec = time!("read unit spec", UnitSpec::from_file(path));
+    let args =
+        time!("parse args", nargo_rustc::cli::Args::from_unit_spec(&spec));
+
+    let label = args.label();
+    info_time!(&label, args.run());
+}
@@ -0,0 +1,146 @@
+//! The parts of `Cargo.metadata.json` and `resolve.nix::resolveFeatures` that
+//! we need to build the unit graph. See `nargo-metadata/src/output.rs` for the
+//! full `Cargo.metadata.json` format.
+
+use std::collections::BTreeMap;
+
+use nargo_core::nargo::TargetKind;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+#[derive(Deserialize)]
+pub(crate) struct Metadata<'a> {
+    #[serde(borrow)]
+    pub(crate) packages: BTreeMap<&'a str, Package<'a>>,
+    #[serde(borrow)]
+    pub(crate) workspace_default_members: Vec<&'a str>,
+    /// The resolved `[profile.*]` tables, as the raw JSON objects that nix
+    /// also sees.
+    #[serde(default)]
+    pub(crate) profiles: BTreeMap<String, Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Package<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) version: &'a str,
+    /// Only non-workspace packages have a `source`.
+    #[serde(default)]
+    pub(crate) source: Option<Value>,
+    /// The workspace-relative path of a workspace package, or the vendored
+    /// source dir of an external one.
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    /// Only git packages have a `git` repo checkout.
+    #[serde(default, borrow)]
+    pub(crate) git: Option<GitSource<'a>>,
+    #[serde(default)]
+    pub(crate) links: Option<&'a str>,
+    #[serde(default)]
+    pub(crate) lints: Vec<String>,
+    // The `[package]` metadata for the `CARGO_PKG_<FIELD>` envs.
+    #[serde(default)]
+    pub(crate) authors: Vec<String>,
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+    #[serde(default)]
+    pub(crate) homepage: Option<String>,
+    #[serde(default)]
+    pub(crate) license: Option<String>,
+    #[serde(default)]
+    pub(crate) license_file: Option<String>,
+    #[serde(default)]
+    pub(crate) readme: Option<String>,
+    #[serde(default)]
+    pub(crate) repository: Option<String>,
+    #[serde(default)]
+    pub(crate) rust_version: Option<String>,
+    #[serde(borrow)]
+    pub(crate) deps: BTreeMap<&'a str, PkgDep<'a>>,
+    #[serde(borrow)]
+    pub(crate) targets: Vec<Target<'a>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GitSource<'a> {
+    /// The full locked commit hash.
+    pub(crate) rev: &'a str,
+    /// The package dir inside the repo checkout, if not the root.
+    #[serde(default)]
+    pub(crate) subdir: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PkgDep<'a> {
+    pub(crate) name: &'a str,
+    #[serde(borrow)]
+    pub(crate) kinds: Vec<PkgDepKind<'a>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PkgDepKind<'a> {
+    /// `None` for normal deps, otherwise `"build"` or `"dev"`.
+    #[serde(default)]
+    pub(crate) kind: Option<&'a str>,
+    /// A `cfg(..)` expression or a target triple.
+    #[serde(default)]
+    pub(crate) target: Option<String>,
+    #[serde(default)]
+    pub(crate) optional: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Target<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) kind: TargetKind,
+    #[serde(borrow)]
+    pub(crate) crate_types: Vec<&'a str>,
+    pub(crate) path: String,
+    pub(crate) edition: &'a str,
+    #[serde(default = "default_true")]
+    pub(crate) harness: bool,
+    /// The files this target read the last time it was built, relative to
+    /// the package dir. See `nargo-metadata --input-sources`.
+    #[serde(default)]
+    pub(crate) sources: Vec<String>,
+}
+
+/// `nix eval --json` of `resolve.nix::resolveFeatures`, keyed by package id.
+pub(crate) type ResolveFeatures<'a> =
+    BTreeMap<&'a str, BTreeMap<FeatFor, PkgFeatForActivation<'a>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FeatFor {
+    Build,
+    Normal,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PkgFeatForActivation<'a> {
+    /// The activated features.
+    #[serde(borrow)]
+    pub(crate) feats: BTreeMap<&'a str, ()>,
+    /// The activated optional deps, by dep name.
+    #[serde(borrow)]
+    pub(crate) deps: BTreeMap<&'a str, ()>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Package<'_> {
+    pub(crate) fn is_workspace_pkg(&self) -> bool {
+        self.source.is_none()
+    }
+}
+
+impl FeatFor {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Build => "build",
+            Self::Normal => "normal",
+        }
+    }
+}
@@ -1,6 +1,6 @@
 //! nargo types
 
-use std::{fmt, str::FromStr};
+use std::{collections::BTreeSet, fmt, str::FromStr, sync::Mutex};
 
 use crate::{
     error::{Context as _, Error},
@@ -13,8 +13,13 @@ use serde::{Deserialize, Serialize};
 pub const CRATES_IO_REGISTRY: &str =
     "registry+https://github.com/rust-lang/crates.io-index";
 
-/// A nargo-specific compact package ID. Conveniently, it is always a strict
-/// substring of a cargo `PackageIdSpec`.
+/// cargo usually shows crates.io as [`CRATES_IO_REGISTRY`], whichever protocol
+/// it uses, but this is the same registry.
+pub const CRATES_IO_SPARSE_REGISTRY: &str = "sparse+https://index.crates.io/";
+
+/// A nargo-specific compact package ID. Conveniently, except for alternative
+/// registry packages, it is always a strict substring of a cargo
+/// `PackageIdSpec`.
 ///
 /// Why this is not just a cargo `PackageIdSpec`:
 /// 1. the `Cargo.metadata.json` is more compact and easier to read and audit
@@ -54,6 +59,37 @@ pub enum CrateType {
     ProcMacro,
 }
 
+/// What kind of output a unit should produce.
+///
+/// Like `cargo::core::compiler::CompileMode`, but only the modes we support.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum CompileMode {
+    /// Fully compile and link the unit, like `cargo build`.
+    Build,
+    /// Only type-check the unit and emit `.rmeta` metadata, like
+    /// `cargo check`.
+    Check,
+    /// Type-check the unit with `clippy-driver` instead of `rustc` and record
+    /// its lints, like `cargo clippy`.
+    Clippy,
+    /// Only emit the `.rmeta` metadata for a lib unit, as the first half of a
+    /// pipelined `Build`. Unlike `Check`, every other flag must match the
+    /// unit's `Build` exactly, so both halves agree on the crate hash.
+    Metadata,
+    /// Build a test binary for the unit (with `rustc --test` if the target
+    /// uses the libtest harness), like `cargo test --no-run`.
+    Test,
+    /// Build and run a lib target's doctests with `rustdoc --test`, like
+    /// `cargo test --doc`.
+    Doctest,
+    /// Generate a lib target's HTML docs with `rustdoc`, like `cargo doc`.
+    Doc,
+    /// Run a custom-build target's already compiled `build_script_build`
+    /// binary for its package, like cargo's `RunCustomBuild`. The binary
+    /// itself comes from a separate `Build` unit.
+    RunCustomBuild,
+}
+
 //
 // --- impl PkgId ---
 //
@@ -102,12 +138,61 @@ impl<'a> PkgId<'a> {
         if let Some(rest) = id.strip_prefix(CRATES_IO_REGISTRY) {
             return Some(Self(rest.trim_start_matches('#')));
         }
+        if let Some(rest) = id.strip_prefix(CRATES_IO_SPARSE_REGISTRY) {
+            return Some(Self(rest.trim_start_matches('#')));
+        }
+
+        // Alternative registries keep the registry index host and path, plus a
+        // short hash of the full source url, so the same crate from the same
+        // index over `sparse+` and `registry+` (or http and https) never
+        // collide.
+        //
+        // ex: "sparse+https://crates.example.com/index/#foo@1.0.0"
+        // -> "crates.example.com/index~a19fd85d#foo@1.0.0"
+        // ex: "registry+https://github.com/example/crates-index#foo@1.0.0"
+        // -> "github.com/example/crates-index~43501253#foo@1.0.0"
+        if id.starts_with("sparse+") || id.starts_with("registry+") {
+            let (source, name_version) = id.rsplit_once('#')?;
+            let (_scheme, host_path) = source.split_once("://")?;
+            let host_path = host_path.trim_end_matches('/');
+            let compact = format!(
+                "{host_path}~{:08x}#{name_version}",
+                source_url_hash(source)
+            );
+            return Some(Self(intern(compact)));
+        }
 
         // ex: (unchanged) "git+http://github.com/dtolnay/semver?branch=master#a6425e6f41ddc81c6d6dd60c68248e0f0ef046c7"
         Some(Self(id))
     }
 }
 
+/// A short, stable (FNV-1a) hash of a package's full source url.
+fn source_url_hash(source: &str) -> u32 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in source.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    (hash >> 32) as u32
+}
+
+/// Alternative registry [`PkgId`]s aren't a substring of their cargo
+/// `PackageIdSpec`, so we keep one copy of each for the rest of the process.
+fn intern(s: String) -> &'static str {
+    static INTERNED: Mutex<BTreeSet<&'static str>> =
+        Mutex::new(BTreeSet::new());
+    let mut interned = INTERNED.lock().unwrap();
+    match interned.get(s.as_str()) {
+        Some(s) => s,
+        None => {
+            let s: &'static str = Box::leak(s.into_boxed_str());
+            interned.insert(s);
+            s
+        }
+    }
+}
+
 //
 // --- impl TargetKind ---
 //
@@ -220,6 +305,90 @@ impl<'de> serde::Deserialize<'de> for TargetKind {
     }
 }
 
+//
+// --- impl CompileMode ---
+//
+
+impl CompileMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Build => "build",
+            Self::Check => "check",
+            Self::Clippy => "clippy",
+            Self::Metadata => "metadata",
+            Self::Test => "test",
+            Self::Doctest => "doctest",
+            Self::Doc => "doc",
+            Self::RunCustomBuild => "run-custom-build",
+        }
+    }
+
+    /// True if we only type-check the unit, with or without clippy.
+    #[inline]
+    pub fn is_check(&self) -> bool {
+        matches!(self, Self::Check | Self::Clippy)
+    }
+
+    #[inline]
+    pub fn is_clippy(&self) -> bool {
+        matches!(self, Self::Clippy)
+    }
+
+    #[inline]
+    pub fn is_test(&self) -> bool {
+        matches!(self, Self::Test)
+    }
+
+    #[inline]
+    pub fn is_doctest(&self) -> bool {
+        matches!(self, Self::Doctest)
+    }
+
+    #[inline]
+    pub fn is_doc(&self) -> bool {
+        matches!(self, Self::Doc)
+    }
+
+    #[inline]
+    pub fn is_run_custom_build(&self) -> bool {
+        matches!(self, Self::RunCustomBuild)
+    }
+
+    /// True if we only emit `.rmeta` metadata in this mode.
+    #[inline]
+    pub fn is_metadata_only(&self) -> bool {
+        matches!(self, Self::Check | Self::Clippy | Self::Metadata)
+    }
+}
+
+impl FromStr for CompileMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "build" => Ok(Self::Build),
+            "check" => Ok(Self::Check),
+            "clippy" => Ok(Self::Clippy),
+            "metadata" => Ok(Self::Metadata),
+            "test" => Ok(Self::Test),
+            "doctest" => Ok(Self::Doctest),
+            "doc" => Ok(Self::Doc),
+            "run-custom-build" => Ok(Self::RunCustomBuild),
+            _ => Err(format_err!("invalid compile `mode`: '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for CompileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+impl fmt::Debug for CompileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
 //
 // --- impl CrateType ---
 //
@@ -353,10 +522,47 @@ mod test {
             "aes-gcm@0.10.3",
             "registry+https://github.com/rust-lang/crates.io-index#aes-gcm@0.10.3",
         );
+        ok(
+            "aes-gcm@0.10.3",
+            "sparse+https://index.crates.io/#aes-gcm@0.10.3",
+        );
+        ok(
+            "crates.example.com/index~a19fd85d#foo@1.0.0",
+            "sparse+https://crates.example.com/index/#foo@1.0.0",
+        );
+        ok(
+            "127.0.0.1:8080/index~1dde6c48#foo@1.0.0",
+            "sparse+http://127.0.0.1:8080/index/#foo@1.0.0",
+        );
+        ok(
+            "github.com/example/crates-index~43501253#foo@1.0.0",
+            "registry+https://github.com/example/crates-index#foo@1.0.0",
+        );
         let id = "git+http://github.com/dtolnay/semver?branch=master#a6425e6f41ddc81c6d6dd60c68248e0f0ef046c7";
         ok(id, id);
     }
 
+    #[test]
+    fn pkg_id_alt_registries_dont_collide() {
+        let workspace_root =
+            "/nix/store/6y9xxx3m6a1gs9807i2ywz9fhp6f8dm9-source";
+        let ids = [
+            "registry+https://github.com/rust-lang/crates.io-index#foo@1.0.0",
+            "sparse+https://crates.example.com/index/#foo@1.0.0",
+            "sparse+http://crates.example.com/index/#foo@1.0.0",
+            "registry+https://crates.example.com/index/#foo@1.0.0",
+            "sparse+https://crates.example.com/other/#foo@1.0.0",
+            "sparse+https://other.example.com/index/#foo@1.0.0",
+        ];
+        let pkg_ids = ids
+            .iter()
+            .map(|id| PkgId::try_from_cargo_pkg_id_spec(id, workspace_root))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(pkg_ids.len(), ids.len());
+        // ...and they all stay compact.
+        assert!(pkg_ids.iter().all(|pkg_id| !pkg_id.0.contains("://")));
+    }
+
     #[test]
     fn target_kind_from_cargo() {
         use TargetKind::*;
@@ -1,6 +1,7 @@
 use std::{
     io::{self, Write},
     panic::{Location, PanicHookInfo},
+    sync::OnceLock,
 };
 
 use crate::logger;
@@ -13,6 +14,15 @@ pub fn set_hook() {
     std::panic::set_hook(Box::new(nargo_panic_hook));
 }
 
+/// Like [`set_hook`], but also call `before_exit` after printing the panic,
+/// e.g., to print some extra context for debugging.
+pub fn set_hook_with(before_exit: fn()) {
+    let _ = BEFORE_EXIT.set(before_exit);
+    set_hook();
+}
+
+static BEFORE_EXIT: OnceLock<fn()> = OnceLock::new();
+
 fn nargo_panic_hook(panic_info: &PanicHookInfo<'_>) {
     // Flush any pending log messages.
     logger::flush();
@@ -33,6 +43,11 @@ fn nargo_panic_hook(panic_info: &PanicHookInfo<'_>) {
     let mut stderr = io::stderr().lock();
     let _ = stderr.write_all(out.as_bytes());
     let _ = stderr.flush();
+    drop(stderr);
+
+    if let Some(before_exit) = BEFORE_EXIT.get() {
+        before_exit();
+    }
 
     // Use `exit` instead of `abort` to avoid terminating with SIGABRT and
     // generating lots of core dumps.
@@ -102,6 +102,17 @@ impl<'a> input::ManifestDependency<'a> {
     fn clean(&mut self, id: PkgId<'a>, ctx: Context<'a>) {
         self.source.as_mut().map(input::Source::clean);
 
+        // `registry` is the same index url as `source`, or unset for crates.io
+        if let Some(registry) = self.registry {
+            let mut registry = input::Source(registry);
+            registry.clean();
+            self.registry = if registry.is_crates_io() {
+                None
+            } else {
+                Some(registry.0)
+            };
+        }
+
         if let Some(path) = self.path.as_mut() {
             *path = path.strip_prefix(ctx.workspace_root)
                 .with_context(|| format!(
@@ -193,8 +204,12 @@ impl<'a> input::PkgId<'a> {
 //
 
 impl input::Source<'_> {
+    /// crates.io becomes just "crates.io". Alternative registries keep their
+    /// full index url, since we need it to find the registry's `config.json`.
     fn clean(&mut self) {
-        if self.0 == nargo::CRATES_IO_REGISTRY {
+        if self.0 == nargo::CRATES_IO_REGISTRY
+            || self.0 == nargo::CRATES_IO_SPARSE_REGISTRY
+        {
             *self = Self::CRATES_IO
         }
     }
@@ -41,9 +41,12 @@ OPTIONS:
       in the current directory.
 
   --no-nix-prefetch
-      By default, we prefetch and pin dependencies from crates.io using
-      `nix store prefetch-file`. Set this flag to disable prefetching, as it
-      doesn't work inside the `nix build` sandbox.
+      By default, we prefetch and pin dependencies from crates.io and
+      alternative registries using `nix store prefetch-file`, and git
+      dependencies using `builtins.fetchGit`. Private registries read their
+      auth token from `CARGO_REGISTRIES_<NAME>_TOKEN`, like cargo. Set this
+      flag to disable prefetching, as it doesn't work inside the `nix build`
+      sandbox.
 
   --assume-vendored
       Assume all external crate paths in the `--input-raw-metadata` are already
@@ -52,6 +55,13 @@ OPTIONS:
       Typically used when run inside the `nix build` sandbox, where the crates
       are already vendored using something like `crane.vendorCargoDeps`.
 
+  --input-sources PATH
+      Record which source files each workspace target reads, from the
+      `sources.json` that `nargo-rustc` writes into each unit's `$out`. PATH is
+      a `sources.json`, a unit out dir, or a dir of unit out dirs (like
+      `target/nargo/store`). May be repeated. Targets without new recordings
+      keep their `sources` from the current `Cargo.metadata.json`.
+
   --check
       Generate the new `Cargo.metadata.json` but don't write it. Instead just
       check that it matches the current `Cargo.metadata.json`.
@@ -67,6 +77,7 @@ pub struct Args {
     output_metadata: Option<PathBuf>,
     no_nix_prefetch: bool,
     assume_vendored: bool,
+    input_sources: Vec<PathBuf>,
     check: bool,
 }
 
@@ -80,6 +91,7 @@ impl Args {
         let mut output_metadata: Option<PathBuf> = None;
         let mut no_nix_prefetch = false;
         let mut assume_vendored = false;
+        let mut input_sources = Vec::new();
         let mut check = false;
 
         let mut parser = lexopt::Parser::from_env();
@@ -119,6 +131,9 @@ impl Args {
                 Long("assume-vendored") if !assume_vendored => {
                     assume_vendored = true;
                 }
+                Long("input-sources") => {
+                    input_sources.push(PathBuf::from(parser.value()?));
+                }
                 Long("check") if !check => {
                     check = true;
                 }
@@ -133,6 +148,7 @@ impl Args {
             output_metadata,
             no_nix_prefetch,
             assume_vendored,
+            input_sources,
             check,
         })
     }
@@ -165,6 +181,7 @@ impl Args {
             output_metadata,
             nix_prefetch: !self.no_nix_prefetch,
             assume_vendored: self.assume_vendored,
+            input_sources: &self.input_sources,
             check: self.check,
         };
 
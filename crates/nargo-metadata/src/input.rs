@@ -1,6 +1,6 @@
 //! Parse input from `cargo metadata` json.
 
-use std::{cmp, collections::BTreeMap, fmt};
+use std::{borrow::Cow, cmp, collections::BTreeMap, fmt};
 
 use nargo_core::{error::Context as _, nargo};
 use serde::{Deserialize, Serialize};
@@ -53,6 +53,35 @@ pub struct Manifest<'a> {
     pub default_run: Option<&'a str>,
 
     pub rust_version: Option<&'a str>,
+
+    // The `[package]` metadata that crates see as `CARGO_PKG_<FIELD>` envs.
+    // These are free-form, so they may contain JSON escapes.
+    #[serde(borrow)]
+    pub authors: Vec<Cow<'a, str>>,
+
+    #[serde(borrow)]
+    pub description: Option<Cow<'a, str>>,
+
+    #[serde(borrow)]
+    pub homepage: Option<Cow<'a, str>>,
+
+    #[serde(borrow)]
+    pub license: Option<Cow<'a, str>>,
+
+    #[serde(borrow)]
+    pub license_file: Option<Cow<'a, str>>,
+
+    #[serde(borrow)]
+    pub readme: Option<Cow<'a, str>>,
+
+    #[serde(borrow)]
+    pub repository: Option<Cow<'a, str>>,
+
+    /// The package's `[lints]` as `rustc` flags. `cargo metadata` doesn't
+    /// include these, so we read them from the package `Cargo.toml`
+    /// ourselves. See [`crate::manifest`].
+    #[serde(skip)]
+    pub lints: Vec<String>,
 }
 
 #[derive(Deserialize)]
@@ -101,6 +130,24 @@ pub struct ManifestTarget<'a> {
     pub src_path: &'a str,
 
     pub edition: &'a str,
+
+    /// Whether `cargo test` builds this target as a test.
+    #[serde(default = "default_true")]
+    pub test: bool,
+
+    /// Whether `cargo test` runs this target's doctests.
+    #[serde(default)]
+    pub doctest: bool,
+
+    /// Whether `cargo doc` documents this target.
+    #[serde(default)]
+    pub doc: bool,
+
+    /// Whether this test target uses the libtest harness. `cargo metadata`
+    /// doesn't include this, so we read it from the package `Cargo.toml`
+    /// ourselves. See [`crate::manifest`].
+    #[serde(skip, default = "default_true")]
+    pub harness: bool,
 }
 
 #[derive(Deserialize)]
@@ -291,6 +338,21 @@ impl Source<'_> {
     pub fn is_crates_io(&self) -> bool {
         self == &Self::CRATES_IO
     }
+
+    /// Returns true for crates.io and any alternative registry.
+    pub fn is_registry(&self) -> bool {
+        self.is_crates_io() || self.is_alt_registry()
+    }
+
+    /// ex: "sparse+https://crates.example.com/index/"
+    pub fn is_alt_registry(&self) -> bool {
+        self.0.starts_with("sparse+") || self.0.starts_with("registry+")
+    }
+
+    /// ex: "git+https://github.com/dtolnay/semver?branch=master#a6425e6f41ddc81c6d6dd60c68248e0f0ef046c7"
+    pub fn is_git(&self) -> bool {
+        self.0.starts_with("git+")
+    }
 }
 
 //
@@ -316,3 +378,11 @@ impl cmp::PartialOrd for Platform<'_> {
         Some(self.cmp(other))
     }
 }
+
+//
+// --- misc ---
+//
+
+const fn default_true() -> bool {
+    true
+}
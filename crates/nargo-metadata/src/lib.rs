@@ -1,6 +1,10 @@
 pub(crate) mod clean;
 pub mod cli;
 pub(crate) mod input;
+pub(crate) mod manifest;
 pub(crate) mod output;
 pub(crate) mod prefetch;
+pub(crate) mod profile;
+pub(crate) mod registry;
 pub(crate) mod run;
+pub(crate) mod sources;
@@ -0,0 +1,286 @@
+//! Read the per-target settings that `cargo metadata` leaves out directly from
+//! each workspace package's `Cargo.toml`.
+//!
+//! Currently this is each target's `harness = false`, which we need to know
+//! whether to build a test target with `rustc --test`, and the package's
+//! `[lints]` table. Non-workspace packages never get tested and always build
+//! with `--cap-lints=allow`, so we don't bother reading their manifests.
+
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use nargo_core::nargo::TargetKind;
+use serde::Deserialize;
+
+use crate::input;
+
+#[derive(Deserialize)]
+struct TomlManifest {
+    lib: Option<TomlTarget>,
+    #[serde(default)]
+    bin: Vec<TomlTarget>,
+    #[serde(default)]
+    test: Vec<TomlTarget>,
+    #[serde(default)]
+    bench: Vec<TomlTarget>,
+}
+
+#[derive(Deserialize)]
+struct TomlTarget {
+    name: Option<String>,
+    harness: Option<bool>,
+}
+
+/// `[lints]`, or `[workspace.lints]` in the workspace root `Cargo.toml`.
+///
+/// ```toml
+/// [lints.rust]
+/// unsafe_code = "forbid"
+///
+/// [lints.clippy]
+/// pedantic = { level = "warn", priority = -1 }
+/// ```
+#[derive(Default, Deserialize)]
+pub struct TomlLints {
+    /// `lints.workspace = true` inherits `[workspace.lints]`.
+    #[serde(default)]
+    workspace: bool,
+    /// tool (`rust`, `clippy`, `rustdoc`, ...) -> lint name -> level
+    #[serde(flatten)]
+    tools: BTreeMap<String, BTreeMap<String, TomlLint>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TomlLint {
+    Level(String),
+    Config {
+        level: String,
+        #[serde(default)]
+        priority: i8,
+    },
+}
+
+#[derive(Deserialize)]
+struct TomlPkgLints {
+    #[serde(default)]
+    lints: TomlLints,
+}
+
+#[derive(Deserialize)]
+struct TomlWorkspaceLints {
+    #[serde(default)]
+    workspace: TomlWorkspace,
+}
+
+#[derive(Default, Deserialize)]
+struct TomlWorkspace {
+    #[serde(default)]
+    lints: TomlLints,
+}
+
+/// Set `harness = false` on each of `targets` that disables the libtest
+/// harness in the package `Cargo.toml` contents `manifest_toml`.
+pub fn apply_harness(
+    manifest_toml: &str,
+    targets: &mut [input::ManifestTarget<'_>],
+) {
+    let manifest: TomlManifest = toml::from_str(manifest_toml)
+        .expect("Failed to parse target tables in package `Cargo.toml`");
+
+    let tables = [
+        (TargetKind::Bin, manifest.bin.as_slice()),
+        (TargetKind::Test, manifest.test.as_slice()),
+        (TargetKind::Bench, manifest.bench.as_slice()),
+    ];
+
+    for target in targets {
+        let kind = target.target_kind();
+
+        // There's only ever one lib target, and `cargo metadata` may have
+        // normalized its name, so just match on the kind.
+        let toml_target = if kind == TargetKind::Lib {
+            manifest.lib.as_ref()
+        } else {
+            tables
+                .iter()
+                .find(|(table_kind, _)| *table_kind == kind)
+                .and_then(|(_, toml_targets)| {
+                    toml_targets.iter().find(|toml_target| {
+                        toml_target.name.as_deref() == Some(target.name)
+                    })
+                })
+        };
+
+        if let Some(TomlTarget {
+            harness: Some(harness),
+            ..
+        }) = toml_target
+        {
+            target.harness = *harness;
+        }
+    }
+}
+
+/// Parse the `[workspace.lints]` table from the workspace root `Cargo.toml`
+/// contents `workspace_manifest_toml`.
+pub fn workspace_lints(workspace_manifest_toml: &str) -> TomlLints {
+    let manifest: TomlWorkspaceLints = toml::from_str(workspace_manifest_toml)
+        .expect(
+            "Failed to parse `[workspace.lints]` in workspace `Cargo.toml`",
+        );
+    manifest.workspace.lints
+}
+
+/// Convert the package's `[lints]` table (or the inherited
+/// `workspace_lints`) into `rustc` flags, like `--warn=clippy::pedantic`.
+///
+/// Like cargo, the flags are ordered by `priority`, so higher priority lints
+/// come later and override earlier ones.
+pub fn lint_flags(
+    manifest_toml: &str,
+    workspace_lints: &TomlLints,
+) -> Vec<String> {
+    let manifest: TomlPkgLints = toml::from_str(manifest_toml)
+        .expect("Failed to parse `[lints]` in package `Cargo.toml`");
+    let lints = if manifest.lints.workspace {
+        workspace_lints
+    } else {
+        &manifest.lints
+    };
+
+    let mut flags = lints
+        .tools
+        .iter()
+        // `[lints.cargo]` is for cargo itself
+        .filter(|(tool, _)| *tool != "cargo")
+        .flat_map(|(tool, lints)| {
+            lints.iter().map(move |(name, lint)| {
+                let (level, priority) = match lint {
+                    TomlLint::Level(level) => (level, 0),
+                    TomlLint::Config { level, priority } => (level, *priority),
+                };
+                let flag = match level.as_str() {
+                    "forbid" | "deny" | "warn" | "allow" => level,
+                    _ => panic!("Invalid lint level for `{name}`: '{level}'"),
+                };
+                let flag = if tool == "rust" {
+                    format!("--{flag}={name}")
+                } else {
+                    format!("--{flag}={tool}::{name}")
+                };
+                (priority, Reverse(name), flag)
+            })
+        })
+        .collect::<Vec<_>>();
+    flags.sort();
+
+    flags.into_iter().map(|(_, _, flag)| flag).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_harness() {
+        let metadata = r#"[
+            { "name": "foo_bar", "kind": ["lib"], "crate_types": ["lib"], "src_path": "src/lib.rs", "edition": "2021" },
+            { "name": "foo-bar", "kind": ["bin"], "crate_types": ["bin"], "src_path": "src/main.rs", "edition": "2021" },
+            { "name": "it", "kind": ["test"], "crate_types": ["bin"], "src_path": "tests/it.rs", "edition": "2021" },
+            { "name": "other", "kind": ["test"], "crate_types": ["bin"], "src_path": "tests/other.rs", "edition": "2021" },
+            { "name": "it", "kind": ["bench"], "crate_types": ["bin"], "src_path": "benches/it.rs", "edition": "2021", "test": false }
+        ]"#;
+        let mut targets: Vec<input::ManifestTarget<'_>> =
+            serde_json::from_str(metadata).unwrap();
+        assert!(targets.iter().all(|target| target.harness));
+
+        let manifest = r#"
+            [package]
+            name = "foo-bar"
+            version = "0.1.0"
+
+            [lib]
+            harness = false
+
+            [[test]]
+            name = "it"
+            harness = false
+
+            [[bench]]
+            name = "it"
+            harness = true
+        "#;
+        apply_harness(manifest, &mut targets);
+
+        let harness = targets
+            .iter()
+            .map(|target| (target.name, target.harness))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            harness,
+            [
+                ("foo_bar", false),
+                ("foo-bar", true),
+                ("it", false),
+                ("other", true),
+                ("it", true),
+            ]
+        );
+        assert!(!targets[4].test);
+    }
+
+    #[test]
+    fn test_lint_flags() {
+        let workspace_manifest = r#"
+            [workspace]
+            members = ["crates/*"]
+
+            [workspace.lints.rust]
+            unsafe_code = "forbid"
+        "#;
+        let workspace_lints = workspace_lints(workspace_manifest);
+
+        let manifest = r#"
+            [package]
+            name = "foo"
+
+            [lints.rust]
+            unused = "deny"
+            missing_docs = "warn"
+
+            [lints.clippy]
+            pedantic = { level = "warn", priority = -1 }
+            too_many_lines = "allow"
+
+            [lints.cargo]
+            foo = "warn"
+        "#;
+        assert_eq!(
+            lint_flags(manifest, &workspace_lints),
+            [
+                "--warn=clippy::pedantic",
+                "--deny=unused",
+                "--allow=clippy::too_many_lines",
+                "--warn=missing_docs",
+            ]
+        );
+
+        let manifest = r#"
+            [package]
+            name = "foo"
+
+            [lints]
+            workspace = true
+        "#;
+        assert_eq!(
+            lint_flags(manifest, &workspace_lints),
+            ["--forbid=unsafe_code"]
+        );
+
+        let manifest = r#"
+            [package]
+            name = "foo"
+        "#;
+        assert!(lint_flags(manifest, &workspace_lints).is_empty());
+    }
+}
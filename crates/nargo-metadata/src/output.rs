@@ -13,6 +13,7 @@ use serde_json::value::RawValue;
 use crate::{
     clean,
     input::{self, DepKind, PkgId, Source},
+    profile::Profile,
 };
 
 type Manifests<'a> = BTreeMap<PkgId<'a>, input::Manifest<'a>>;
@@ -23,6 +24,9 @@ pub struct Metadata<'a> {
     pub packages: BTreeMap<PkgId<'a>, Package<'a>>,
     pub workspace_members: Vec<PkgId<'a>>,
     pub workspace_default_members: Vec<PkgId<'a>>,
+    /// The resolved `[profile.*]` tables from the workspace root `Cargo.toml`.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
 }
 
 // TODO(phlip9): include extracted crate dir NAR hash so we can more
@@ -37,10 +41,31 @@ pub struct Package<'a> {
     pub source: Option<Source<'a>>,
 
     /// Prefetch'ed crates.io crates pin the content hash of their unpacked
-    /// store directory here.
+    /// store directory here. Git packages pin the content hash of their whole
+    /// repo checkout, which they share with every package in the same
+    /// checkout.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hash: Option<SriHash<'a>>,
 
+    /// Git packages record which repo checkout they live in.
+    #[serde(borrow)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitSource<'a>>,
+
+    /// Prefetch'ed crates from alternative registries pin their download url
+    /// here, from the registry's `config.json` `dl` template. crates.io crates
+    /// always download from `static.crates.io`.
+    #[serde(borrow)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<Cow<'a, str>>,
+
+    /// Crates from an alternative registry named in the cargo config record
+    /// which `CARGO_REGISTRIES_<NAME>_TOKEN` env holds its auth token, so
+    /// `vendorCargoDep` can authenticate its download.
+    #[serde(borrow)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<Cow<'a, str>>,
+
     /// If this is a workspace crate, then this is the package's relative path
     /// inside the workspace.
     ///
@@ -62,6 +87,44 @@ pub struct Package<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub links: Option<&'a str>,
 
+    /// The `[package]` metadata that `nargo-rustc` passes to the crate as
+    /// `CARGO_PKG_<FIELD>` envs.
+    #[serde(borrow, default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<Cow<'a, str>>,
+
+    #[serde(borrow, default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<Cow<'a, str>>,
+
+    #[serde(borrow, default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<Cow<'a, str>>,
+
+    #[serde(borrow, default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<Cow<'a, str>>,
+
+    /// Relative to the package root, like in `Cargo.toml`.
+    #[serde(borrow, default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license_file: Option<Cow<'a, str>>,
+
+    /// Relative to the package root, like in `Cargo.toml`.
+    #[serde(borrow, default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub readme: Option<Cow<'a, str>>,
+
+    #[serde(borrow, default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repository: Option<Cow<'a, str>>,
+
+    /// The package's `[lints]` as `rustc` flags, like `--warn=unused`. Only
+    /// workspace packages have these.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lints: Vec<String>,
+
     #[serde(borrow)]
     #[serde(serialize_with = "compact::features")]
     pub features: Cow<'a, BTreeMap<&'a str, Vec<&'a str>>>,
@@ -127,22 +190,69 @@ pub struct ManifestTarget<'a> {
 
     // TODO(phlip9): is this ever different than the package edition?
     pub edition: &'a str,
+
+    /// Whether `cargo test` builds this target as a test. Only set if it
+    /// differs from the default for this target kind, which is `true` for
+    /// lib, bin, and test targets and `false` otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test: Option<bool>,
+
+    /// Whether `cargo test` runs this target's doctests. Only set if it
+    /// differs from the default for this target kind, which is `true` for lib
+    /// targets and `false` otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doctest: Option<bool>,
+
+    /// Whether `cargo doc` documents this target. Only set if it differs from
+    /// the default for this target kind, which is `true` for lib and bin
+    /// targets and `false` otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doc: Option<bool>,
+
+    /// `false` if this test target doesn't use the libtest harness.
+    #[serde(skip_serializing_if = "bool::is_true")]
+    #[serde(default = "bool::default_true")]
+    pub harness: bool,
+
+    /// The package-relative files this workspace target read the last time we
+    /// built it, from `--input-sources`. See [`crate::sources`].
+    #[serde(borrow)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub sources: Option<Vec<Cow<'a, str>>>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct SriHash<'a>(#[serde(borrow)] pub Cow<'a, str>);
 
+/// A git package's locked repo checkout.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GitSource<'a> {
+    /// The repo url, without cargo's `?branch=`, `?tag=`, or `?rev=` query.
+    pub url: &'a str,
+
+    /// The full locked commit hash.
+    pub rev: &'a str,
+
+    /// The package directory relative to the repo root, unless the package
+    /// lives at the root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<&'a str>,
+}
+
 //
 // --- impl Metadata ---
 //
 
 impl<'a> Metadata<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn from_input<'ctx: 'a>(
         ctx: clean::Context<'ctx>,
         manifests: &'a Manifests<'a>,
         workspace_members: Vec<PkgId<'a>>,
         workspace_default_members: Vec<PkgId<'a>>,
         resolve: input::Resolve<'a>,
+        profiles: BTreeMap<String, Profile>,
         current_metadata: Option<&'a Metadata<'a>>,
         assume_vendored: bool,
     ) -> Self {
@@ -174,6 +284,7 @@ impl<'a> Metadata<'a> {
             workspace_members,
             workspace_default_members,
             packages,
+            profiles,
         }
     }
 
@@ -208,6 +319,23 @@ impl<'a> Metadata<'a> {
             );
         }
 
+        // `links`: like cargo, only one package in the graph may declare that
+        // it links a given native library.
+        let mut links_pkgs: BTreeMap<&str, &PkgId<'_>> = BTreeMap::new();
+        for (pkg_id, pkg) in &self.packages {
+            let links = match pkg.links {
+                Some(links) => links,
+                None => continue,
+            };
+            if let Some(prev_pkg_id) = links_pkgs.insert(links, pkg_id) {
+                panic!(
+                    "invariant: multiple packages link to native library `{links}`: \
+                     '{prev_pkg_id}' and '{pkg_id}'. A native library can be \
+                     linked only once in the package graph."
+                );
+            }
+        }
+
         // `packages`
         for (pkg_id, pkg) in &self.packages {
             // Check `pkg.hash` and `pkg.path` are appropriate for workspace vs
@@ -219,6 +347,15 @@ impl<'a> Metadata<'a> {
                 assert!(pkg.hash.is_some() || pkg.path.is_some(), "{pkg_id}");
             }
 
+            // Check git packages know their repo checkout
+            assert_eq!(pkg.is_git(), pkg.git.is_some(), "{pkg_id}");
+
+            // Check pinned alternative registry crates know where to download
+            // from
+            if pkg.is_alt_registry() && pkg.hash.is_some() {
+                assert_ne!(pkg.url, None, "{pkg_id}");
+            }
+
             // Check `pkg.deps`
             for dep_pkg_id in pkg.deps.keys() {
                 let dep_pkg = self
@@ -268,16 +405,31 @@ impl<'a> Package<'a> {
         //
         // This lets us mostly avoid prefetching unless the actual Cargo.lock
         // deps change.
-        let hash = source.filter(Source::is_crates_io).and(curr_pkg).and_then(
-            |curr_pkg| {
+        // Alternative registry crates also keep their download url and auth
+        // token env. Git
+        // packages pin their locked `rev` in the `source`, so we can reuse
+        // their hash in the same way.
+        let (hash, url, token_env) = source
+            .filter(|source| source.is_registry() || source.is_git())
+            .and(curr_pkg)
+            .map(|curr_pkg| {
                 // Sanity check
                 assert_eq!(name, curr_pkg.name);
                 assert_eq!(version, curr_pkg.version);
                 assert_eq!(source, curr_pkg.source);
 
-                curr_pkg.hash.clone()
-            },
-        );
+                (
+                    curr_pkg.hash.clone(),
+                    curr_pkg.url.clone(),
+                    curr_pkg.token_env.clone(),
+                )
+            })
+            .unwrap_or((None, None, None));
+
+        let git = source.filter(Source::is_git).map(|source| {
+            let curr_git = curr_pkg.and_then(|curr_pkg| curr_pkg.git.as_ref());
+            GitSource::from_input(source, manifest.manifest_dir(), curr_git)
+        });
 
         let is_workspace = source.is_none();
         let path = if is_workspace {
@@ -337,12 +489,23 @@ impl<'a> Package<'a> {
             name,
             version,
             hash,
+            git,
+            url,
+            token_env,
             path,
             source: manifest.source,
             edition: manifest.edition,
             rust_version: manifest.rust_version,
             default_run: manifest.default_run,
             links: manifest.links,
+            authors: manifest.authors.clone(),
+            description: manifest.description.clone(),
+            homepage: manifest.homepage.clone(),
+            license: manifest.license.clone(),
+            license_file: manifest.license_file.clone(),
+            readme: manifest.readme.clone(),
+            repository: manifest.repository.clone(),
+            lints: manifest.lints.clone(),
             features: Cow::Borrowed(&manifest.features),
             deps,
             targets,
@@ -360,6 +523,16 @@ impl<'a> Package<'a> {
         self.source.filter(Source::is_crates_io).is_some()
     }
 
+    /// Returns true if the package is from an alternative registry.
+    pub(crate) fn is_alt_registry(&self) -> bool {
+        self.source.filter(Source::is_alt_registry).is_some()
+    }
+
+    /// Returns true if the package is a git dependency.
+    pub(crate) fn is_git(&self) -> bool {
+        self.source.filter(Source::is_git).is_some()
+    }
+
     /// The package name we'll use when prefetching into the nix store.
     /// TODO(phlip9): point to nix prefetcher
     pub(crate) fn prefetch_name(&self) -> String {
@@ -368,15 +541,64 @@ impl<'a> Package<'a> {
         format!("crate-{name}-{version}")
     }
 
-    /// The crates.io url we download this crate from.
+    /// The crates.io or alternative registry url we download this crate from.
     /// TODO(phlip9): point to nix prefetcher
     pub(crate) fn prefetch_url(&self) -> String {
+        if let Some(url) = &self.url {
+            return url.to_string();
+        }
         let name = self.name;
         let version = &self.version;
         format!("https://static.crates.io/crates/{name}/{version}/download")
     }
 }
 
+//
+// --- impl GitSource ---
+//
+
+impl<'a> GitSource<'a> {
+    fn from_input(
+        source: Source<'a>,
+        manifest_dir: &'a str,
+        // The same package's `git` from the existing Cargo.metadata.json.
+        curr_git: Option<&GitSource<'a>>,
+    ) -> Self {
+        // ex: "git+https://github.com/dtolnay/semver?branch=master#a6425e6f41ddc81c6d6dd60c68248e0f0ef046c7"
+        // -> url: "https://github.com/dtolnay/semver"
+        //    rev: "a6425e6f41ddc81c6d6dd60c68248e0f0ef046c7"
+        let (url, rev) = source
+            .0
+            .strip_prefix("git+")
+            .and_then(|rest| rest.rsplit_once('#'))
+            .with_context(|| source.0.to_owned())
+            .expect("Git package `source` is missing its locked rev");
+        let url = url.split_once('?').map_or(url, |(url, _query)| url);
+
+        // cargo checks out each locked repo rev to
+        // "$CARGO_HOME/git/checkouts/<repo>-<hash>/<short-rev>/", so the
+        // package's manifest dir tells us where it lives inside the repo.
+        //
+        // With `--assume-vendored`, the package is already vendored somewhere
+        // else, so we just keep the current `subdir`.
+        let subdir = match manifest_dir.split_once("/git/checkouts/") {
+            Some((_cargo_home, checkout)) => {
+                let subdir = checkout
+                    .splitn(3, '/')
+                    .nth(2)
+                    .unwrap_or("")
+                    .trim_end_matches('/');
+                Some(subdir).filter(|subdir| !subdir.is_empty())
+            }
+            None => curr_git
+                .filter(|curr_git| curr_git.rev == rev)
+                .and_then(|curr_git| curr_git.subdir),
+        };
+
+        Self { url, rev, subdir }
+    }
+}
+
 //
 // --- impl PkgDep ---
 //
@@ -561,13 +783,27 @@ impl<'a> ManifestTarget<'a> {
             let _ = CrateType::from_str(crate_type).unwrap();
         }
 
+        let kind = target.target_kind();
+        let default_test = matches!(
+            kind,
+            TargetKind::Lib | TargetKind::Bin | TargetKind::Test
+        );
+        let default_doctest = kind == TargetKind::Lib;
+        let default_doc = matches!(kind, TargetKind::Lib | TargetKind::Bin);
+
         Self {
             name: target.name,
-            kind: target.target_kind(),
+            kind,
             crate_types: Cow::Borrowed(&target.crate_types),
             required_features: Cow::Borrowed(&target.required_features),
             path: target.src_path,
             edition: target.edition,
+            test: (target.test != default_test).then_some(target.test),
+            doctest: (target.doctest != default_doctest)
+                .then_some(target.doctest),
+            doc: (target.doc != default_doc).then_some(target.doc),
+            harness: target.harness,
+            sources: None,
         }
     }
 }
@@ -730,10 +966,10 @@ mod dump {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
 
-    use std::fs;
+    use std::{fs, process::Command};
 
     #[test]
     fn test_workspace_metadata_serde_roundtrip() {
@@ -747,4 +983,191 @@ mod test {
 
         assert_eq!(workspace_metadata_json_1, workspace_metadata_json_2);
     }
+
+    #[test]
+    #[should_panic(expected = "multiple packages link to native library `z`")]
+    fn test_assert_invariants_duplicate_links() {
+        let metadata_json = r#"{
+ "packages": {
+  "libz-sys@1.1.8": {
+   "name": "libz-sys", "version": "1.1.8", "source": "crates.io",
+   "hash": "sha256-AAAA", "edition": "2018", "links": "z",
+   "features": {}, "deps": {}, "targets": []
+  },
+  "libz-ng-sys@1.1.8": {
+   "name": "libz-ng-sys", "version": "1.1.8", "source": "crates.io",
+   "hash": "sha256-BBBB", "edition": "2018", "links": "z",
+   "features": {}, "deps": {}, "targets": []
+  }
+ },
+ "workspace_members": [],
+ "workspace_default_members": []
+}"#;
+        let metadata =
+            serde_json::from_str::<Metadata<'_>>(metadata_json).unwrap();
+        metadata.assert_invariants();
+    }
+
+    #[test]
+    fn test_git_source_from_input() {
+        let source = Source(
+            "git+https://github.com/dtolnay/semver?branch=master#a6425e6f41ddc81c6d6dd60c68248e0f0ef046c7",
+        );
+        let rev = "a6425e6f41ddc81c6d6dd60c68248e0f0ef046c7";
+
+        let git = GitSource::from_input(
+            source,
+            "/home/user/.cargo/git/checkouts/semver-5d7a830295e2579c/a6425e6/",
+            None,
+        );
+        let expected = GitSource {
+            url: "https://github.com/dtolnay/semver",
+            rev,
+            subdir: None,
+        };
+        assert_eq!(git, expected);
+
+        let git = GitSource::from_input(
+            source,
+            "/home/user/.cargo/git/checkouts/semver-5d7a830295e2579c/a6425e6/crates/semver/",
+            None,
+        );
+        let expected = GitSource {
+            subdir: Some("crates/semver"),
+            ..expected
+        };
+        assert_eq!(git, expected);
+
+        // Vendored packages keep the current `subdir`
+        let git = GitSource::from_input(
+            source,
+            "/nix/store/k5f4q8yhlqm4jfzhzbp4qnbmfdhh0ikr-vendor/semver/",
+            Some(&expected),
+        );
+        assert_eq!(git, expected);
+    }
+
+    /// Create a bare git repo in `dir` with a workspace of two crates, `foo`
+    /// and `bar`. Returns the repo url.
+    pub(crate) fn local_git_repo(dir: &Path) -> String {
+        let work = dir.join("work");
+        let files = [
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\"]\nresolver = \"2\"\n",
+            ),
+            (
+                "crates/foo/Cargo.toml",
+                "[package]\nname = \"foo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+                 [dependencies]\nbar = { path = \"../bar\" }\n",
+            ),
+            ("crates/foo/src/lib.rs", "pub use bar::bar;\n"),
+            (
+                "crates/bar/Cargo.toml",
+                "[package]\nname = \"bar\"\nversion = \"0.2.0\"\nedition = \"2021\"\n",
+            ),
+            ("crates/bar/src/lib.rs", "pub fn bar() {}\n"),
+        ];
+        for (path, contents) in files {
+            let path = work.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let git = |dir: &Path, args: &[&str]| {
+            let status = Command::new("git")
+                .current_dir(dir)
+                .args(["-c", "user.name=nargo", "-c", "user.email=nargo@test"])
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?}");
+        };
+        git(&work, &["init", "-q"]);
+        git(&work, &["add", "-A"]);
+        git(&work, &["commit", "-q", "-m", "init"]);
+        git(dir, &["clone", "-q", "--bare", "work", "repo.git"]);
+
+        format!("file://{}", dir.join("repo.git").display())
+    }
+
+    #[test]
+    fn test_git_deps_from_local_bare_repo() {
+        let dir = std::env::temp_dir()
+            .join(format!("nargo-metadata-test-git-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let url = local_git_repo(&dir);
+
+        // A workspace that depends on both crates in the repo
+        let app = dir.join("consumer");
+        fs::create_dir_all(app.join("src")).unwrap();
+        fs::write(app.join("src/lib.rs"), "").unwrap();
+        fs::write(
+            app.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+                 [dependencies]\nfoo = {{ git = \"{url}\" }}\nbar = {{ git = \"{url}\" }}\n"
+            ),
+        )
+        .unwrap();
+
+        let out = Command::new("cargo")
+            .args(["metadata", "--format-version=1", "--all-features"])
+            .arg("--manifest-path")
+            .arg(app.join("Cargo.toml"))
+            .env("CARGO_HOME", dir.join("cargo-home"))
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+
+        let mut input =
+            serde_json::from_slice::<input::Metadata<'_>>(&out.stdout).unwrap();
+        let ctx = clean::Context {
+            workspace_root: input.workspace_root,
+        };
+        input.clean(ctx);
+        let manifests: Manifests<'_> = input
+            .packages
+            .into_iter()
+            .map(|pkg| (pkg.id, pkg))
+            .collect();
+        let output = Metadata::from_input(
+            ctx,
+            &manifests,
+            input.workspace_members,
+            input.workspace_default_members,
+            input.resolve,
+            BTreeMap::new(),
+            None,
+            false,
+        );
+
+        let git_pkg = |name: &str| {
+            let pkg = output
+                .packages
+                .values()
+                .find(|pkg| pkg.name == name)
+                .unwrap();
+            assert!(pkg.is_git());
+            pkg.git.clone().unwrap()
+        };
+        let foo = git_pkg("foo");
+        let bar = git_pkg("bar");
+
+        // Both crates share one checkout
+        assert_eq!(foo.url, url);
+        assert_eq!(bar.url, url);
+        assert_eq!(foo.rev, bar.rev);
+        assert_eq!(foo.rev.len(), 40);
+        assert_eq!(foo.subdir, Some("crates/foo"));
+        assert_eq!(bar.subdir, Some("crates/bar"));
+        assert_eq!(output.packages[&PkgId("app")].deps.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
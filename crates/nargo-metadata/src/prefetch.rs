@@ -1,7 +1,15 @@
-//! Prefetching and pinning crates from crates.io using `nix store prefetch-file`.
+//! Prefetching and pinning crates from crates.io and alternative registries
+//! using `nix store prefetch-file`, and git packages using `builtins.fetchGit`.
 
 use std::{
-    borrow::Cow, cmp::min, path::Path, process, str, sync::Mutex, thread,
+    borrow::Cow,
+    cmp::min,
+    collections::{BTreeMap, btree_map::Entry},
+    env,
+    path::Path,
+    process, str,
+    sync::Mutex,
+    thread,
 };
 
 use nargo_core::{
@@ -11,17 +19,26 @@ use nargo_core::{
 };
 use serde::Deserialize;
 
-use crate::output;
+use crate::{
+    input::{PkgId, Source},
+    output,
+    registry::{self, Registries, RegistryConfig},
+};
+
+/// The packages that share one prefetch, i.e., a single registry crate or all
+/// the git packages in one repo checkout.
+type PrefetchGroup<'m, 'a> = Vec<(&'m PkgId<'a>, &'m mut output::Package<'a>)>;
+
+/// Use `nix` to prefetch all crates from crates.io and alternative registries
+/// and all git packages, then fill in the `hash` in their respective
+/// [`output::Package`].
+pub(crate) fn prefetch(
+    output: &mut output::Metadata<'_>,
+    registries: &Registries,
+) {
+    fill_registry_urls(output, registries);
 
-/// Use `nix` to prefetch all crates from crates.io and fill in the `hash` in
-/// their respective [`output::Package`].
-pub fn prefetch(output: &mut output::Metadata<'_>) {
-    // crates.io deps that need prefetching
-    let needs_prefetch = output
-        .packages
-        .iter_mut()
-        .filter(|(_pkg_id, pkg)| pkg.is_crates_io() && pkg.hash.is_none())
-        .collect::<Vec<_>>();
+    let needs_prefetch = needs_prefetch(output);
 
     if needs_prefetch.is_empty() {
         return;
@@ -38,21 +55,28 @@ pub fn prefetch(output: &mut output::Metadata<'_>) {
         for _ in 0..num_threads {
             s.spawn(|| {
                 loop {
-                    // Grab a package that we need to prefetch
-                    let (pkg_id, pkg) =
-                        match needs_prefetch.lock().unwrap().pop() {
-                            Some(x) => x,
-                            None => return,
-                        };
-
-                    let out = nix_store_prefetch_file(&nix, pkg)
+                    // Grab a group of packages that we need to prefetch
+                    let group = match needs_prefetch.lock().unwrap().pop() {
+                        Some(x) => x,
+                        None => return,
+                    };
+
+                    let (pkg_id, pkg) = &group[0];
+                    let out = match &pkg.git {
+                        Some(git) => nix_prefetch_git(&nix, git),
+                        None => prefetch_crate(&nix, registries, pkg),
+                    };
+                    let out = out
                         .with_context(|| pkg_id.to_string())
                         .expect("Failed to prefetch crate");
 
-                    let hash = out.hash;
-                    info!("prefetch: {pkg_id} -> \"{hash}\"");
+                    for (pkg_id, pkg) in group {
+                        let hash = &out.hash;
+                        info!("prefetch: {pkg_id} -> \"{hash}\"");
 
-                    pkg.hash = Some(output::SriHash(Cow::Owned(hash)));
+                        pkg.hash =
+                            Some(output::SriHash(Cow::Owned(hash.clone())));
+                    }
 
                     // flush thead-local log buffer
                     logger::flush();
@@ -62,42 +86,196 @@ pub fn prefetch(output: &mut output::Metadata<'_>) {
     });
 }
 
-/// Ask `nix` to prefetch a crate from crates.io, place it into the /nix/store,
-/// and then return the content hash.
-fn nix_store_prefetch_file(
+/// Collect the crates.io and git packages without a pinned `hash`.
+///
+/// Every git package in the same repo checkout shares one fetch, so we group
+/// them by their locked repo `url` and `rev`. If we already know the hash of
+/// a checkout from one of its packages, the rest just reuse it.
+fn needs_prefetch<'m, 'a>(
+    output: &'m mut output::Metadata<'a>,
+) -> Vec<PrefetchGroup<'m, 'a>> {
+    let checkout_key = |git: &output::GitSource<'a>| (git.url, git.rev);
+
+    let mut checkout_hashes = BTreeMap::new();
+    for pkg in output.packages.values() {
+        if let (Some(git), Some(hash)) = (&pkg.git, &pkg.hash) {
+            checkout_hashes.insert(checkout_key(git), hash.clone());
+        }
+    }
+
+    let mut crates_io_groups = Vec::new();
+    let mut git_groups: BTreeMap<_, PrefetchGroup<'m, 'a>> = BTreeMap::new();
+    for (pkg_id, pkg) in output.packages.iter_mut() {
+        if pkg.hash.is_some() {
+            continue;
+        }
+        if pkg.is_crates_io() || pkg.is_alt_registry() {
+            crates_io_groups.push(vec![(pkg_id, pkg)]);
+            continue;
+        }
+        let key = match &pkg.git {
+            Some(git) => checkout_key(git),
+            None => continue,
+        };
+        match checkout_hashes.get(&key) {
+            Some(hash) => pkg.hash = Some(hash.clone()),
+            None => git_groups.entry(key).or_default().push((pkg_id, pkg)),
+        }
+    }
+
+    crates_io_groups.extend(git_groups.into_values());
+    crates_io_groups
+}
+
+/// Fill in the download `url` of each alternative registry crate that we
+/// need to prefetch, from its registry's `config.json`, and the auth token
+/// env of every alternative registry crate.
+fn fill_registry_urls(
+    output: &mut output::Metadata<'_>,
+    registries: &Registries,
+) {
+    let mut configs: BTreeMap<&str, RegistryConfig> = BTreeMap::new();
+    for (pkg_id, pkg) in output.packages.iter_mut() {
+        if !pkg.is_alt_registry() {
+            continue;
+        }
+        let source = pkg.source.unwrap();
+
+        pkg.token_env = registries.token_env(source).map(Cow::Owned);
+        if pkg.hash.is_some() || pkg.url.is_some() {
+            continue;
+        }
+
+        let config = match configs.entry(source.0) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let curl = which("curl")
+                    .expect("Couldn't find `curl` binary in `$PATH`");
+                let token = registries.token(source);
+                let config =
+                    RegistryConfig::fetch(&curl, source, token.as_deref())
+                        .with_context(|| source.0.to_owned())
+                        .expect("Failed to fetch registry `config.json`");
+                entry.insert(config)
+            }
+        };
+
+        let url = config
+            .download_url(pkg.name, &pkg.version)
+            .with_context(|| pkg_id.to_string())
+            .expect("Failed to get crate download url");
+        pkg.url = Some(Cow::Owned(url));
+    }
+}
+
+/// Prefetch a crate from crates.io or an alternative registry.
+///
+/// `nix` can't send a private registry's auth token, so if we have one, we
+/// download the crate with `curl` first and prefetch the local file instead.
+/// Either way, the crate ends up at the same store path.
+fn prefetch_crate(
     nix: &Path,
+    registries: &Registries,
     pkg: &output::Package<'_>,
 ) -> Result<NixPrefetchOutput> {
     let prefetch_name = pkg.prefetch_name();
     let prefetch_url = pkg.prefetch_url();
 
-    let out = process::Command::new(nix)
-        .args(["store", "prefetch-file"])
+    let token = pkg
+        .source
+        .filter(Source::is_alt_registry)
+        .and_then(|source| registries.token(source));
+    let token = match token {
+        Some(token) => token,
+        None => {
+            return nix_store_prefetch_file(nix, &prefetch_name, &prefetch_url);
+        }
+    };
+
+    let curl =
+        which("curl").context("Couldn't find `curl` binary in `$PATH`")?;
+    let tmp = env::temp_dir().join(format!(
+        "nargo-metadata-{}-{prefetch_name}.crate",
+        process::id()
+    ));
+    registry::curl_get(&curl, &prefetch_url, Some(&token), Some(&tmp))?;
+    let file_url = format!("file://{}", tmp.display());
+    let out = nix_store_prefetch_file(nix, &prefetch_name, &file_url);
+    let _ = std::fs::remove_file(&tmp);
+    out
+}
+
+/// Ask `nix` to prefetch a crate from `url`, place it into the /nix/store as
+/// `name`, and then return the content hash.
+fn nix_store_prefetch_file(
+    nix: &Path,
+    name: &str,
+    url: &str,
+) -> Result<NixPrefetchOutput> {
+    let mut cmd = process::Command::new(nix);
+    cmd.args(["store", "prefetch-file"])
         .arg("--json")
         .arg("--unpack")
         .args(["--hash-type", "sha256"])
-        .args(["--name", prefetch_name.as_str()])
-        .arg(prefetch_url.as_str())
+        .args(["--name", name])
+        .arg(url);
+
+    run_nix_json(cmd, "nix store prefetch-file")
+}
+
+/// Ask `nix` to fetch a git repo checkout into the /nix/store and return its
+/// content hash. This must fetch exactly like `vendorCargoDep` in
+/// `lib/default.nix`, so the hashes match.
+fn nix_prefetch_git(
+    nix: &Path,
+    git: &output::GitSource<'_>,
+) -> Result<NixPrefetchOutput> {
+    let url = nix_string(git.url);
+    let rev = nix_string(git.rev);
+    let expr = format!(
+        "{{ hash = (builtins.fetchGit {{ url = {url}; rev = {rev}; allRefs = true; submodules = true; }}).narHash; }}"
+    );
+
+    let mut cmd = process::Command::new(nix);
+    cmd.args(["eval", "--json", "--expr"]).arg(expr);
+
+    run_nix_json(cmd, "nix eval")
+}
+
+/// Run a `nix` command and deserialize its JSON output.
+fn run_nix_json(
+    mut cmd: process::Command,
+    name: &str,
+) -> Result<NixPrefetchOutput> {
+    let out = cmd
         .output()
-        .context("Failed to run `nix store prefetch-file`")?;
+        .with_context(|| format!("Failed to run `{name}`"))?;
 
     let stdout = str::from_utf8(&out.stdout)
-        .context("`nix store prefetch-file` output is not valid UTF-8")?;
+        .with_context(|| format!("`{name}` output is not valid UTF-8"))?;
 
     if !out.status.success() {
         let status = &out.status;
         let stderr = String::from_utf8_lossy(&out.stderr);
         return Err(format_err!(
-            "`nix store prefetch-file` process errored: {status}\n\nstdout:\n{stdout}\n\nstderr:\n{stderr}\n"
+            "`{name}` process errored: {status}\n\nstdout:\n{stdout}\n\nstderr:\n{stderr}\n"
         ));
     }
 
-    let out: NixPrefetchOutput = serde_json::from_str(stdout)
-        .with_context(|| format!("Failed to deserialize `nix store prefetch-file output`: '{stdout}'"))?;
+    let out: NixPrefetchOutput =
+        serde_json::from_str(stdout).with_context(|| {
+            format!("Failed to deserialize `{name}` output: '{stdout}'")
+        })?;
 
     Ok(out)
 }
 
+/// Quote `s` as a nix string literal. JSON string escapes are also valid nix
+/// escapes, except we also need to escape any `${` interpolations.
+fn nix_string(s: &str) -> String {
+    serde_json::to_string(s).unwrap().replace("${", "\\${")
+}
+
 #[derive(Deserialize)]
 struct NixPrefetchOutput {
     pub hash: String,
@@ -122,22 +300,154 @@ mod test {
             version: semver::Version::parse("1.0.81").unwrap(),
             source: Some(Source::CRATES_IO),
             hash: None,
+            git: None,
+            url: None,
+            token_env: None,
             path: None,
             edition: "2018",
 
             rust_version: None,
             default_run: None,
             links: None,
+            authors: Vec::new(),
+            description: None,
+            homepage: None,
+            license: None,
+            license_file: None,
+            readme: None,
+            repository: None,
+            lints: Vec::new(),
             features: Cow::Owned(BTreeMap::new()),
             deps: BTreeMap::new(),
             targets: Vec::new(),
         };
 
-        let out = nix_store_prefetch_file(&nix, &pkg).unwrap();
+        let out = nix_store_prefetch_file(
+            &nix,
+            &pkg.prefetch_name(),
+            &pkg.prefetch_url(),
+        )
+        .unwrap();
 
         assert_eq!(
             out.hash,
             "sha256-U7BJ1AxtArqA/yMIgPn62rocxs+YSfsgLAwu60ezZ7o=",
         );
     }
+
+    #[test]
+    fn test_needs_prefetch_groups_git_checkouts() {
+        let metadata_json = r#"{
+ "packages": {
+  "anyhow@1.0.81": {
+   "name": "anyhow", "version": "1.0.81", "source": "crates.io",
+   "edition": "2018", "features": {}, "deps": {}, "targets": []
+  },
+  "crates.example.com/index~a19fd85d#baz@1.0.0": {
+   "name": "baz", "version": "1.0.0",
+   "source": "sparse+https://crates.example.com/index/",
+   "url": "https://crates.example.com/api/v1/crates/baz/1.0.0/download",
+   "edition": "2021", "features": {}, "deps": {}, "targets": []
+  },
+  "git+https://github.com/foo/foo#bar@0.2.0": {
+   "name": "bar", "version": "0.2.0",
+   "source": "git+https://github.com/foo/foo#1111111111111111111111111111111111111111",
+   "git": { "url": "https://github.com/foo/foo", "rev": "1111111111111111111111111111111111111111", "subdir": "bar" },
+   "edition": "2021", "features": {}, "deps": {}, "targets": []
+  },
+  "git+https://github.com/foo/foo#foo@0.1.0": {
+   "name": "foo", "version": "0.1.0",
+   "source": "git+https://github.com/foo/foo#1111111111111111111111111111111111111111",
+   "git": { "url": "https://github.com/foo/foo", "rev": "1111111111111111111111111111111111111111", "subdir": "foo" },
+   "edition": "2021", "features": {}, "deps": {}, "targets": []
+  },
+  "git+https://github.com/foo/foo?branch=next#foo@0.2.0": {
+   "name": "foo", "version": "0.2.0",
+   "source": "git+https://github.com/foo/foo?branch=next#2222222222222222222222222222222222222222",
+   "git": { "url": "https://github.com/foo/foo", "rev": "2222222222222222222222222222222222222222", "subdir": "foo" },
+   "edition": "2021", "features": {}, "deps": {}, "targets": []
+  },
+  "git+https://github.com/foo/foo?branch=next#bar@0.3.0": {
+   "name": "bar", "version": "0.3.0",
+   "source": "git+https://github.com/foo/foo?branch=next#2222222222222222222222222222222222222222",
+   "git": { "url": "https://github.com/foo/foo", "rev": "2222222222222222222222222222222222222222", "subdir": "bar" },
+   "hash": "sha256-AAAA",
+   "edition": "2021", "features": {}, "deps": {}, "targets": []
+  }
+ },
+ "workspace_members": [],
+ "workspace_default_members": []
+}"#;
+        let mut metadata =
+            serde_json::from_str::<output::Metadata<'_>>(metadata_json)
+                .unwrap();
+
+        let groups = needs_prefetch(&mut metadata)
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|(pkg_id, _pkg)| pkg_id.0)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            [
+                vec!["anyhow@1.0.81"],
+                vec!["crates.example.com/index~a19fd85d#baz@1.0.0"],
+                vec![
+                    "git+https://github.com/foo/foo#bar@0.2.0",
+                    "git+https://github.com/foo/foo#foo@0.1.0",
+                ],
+            ],
+        );
+
+        // The checkout at rev "2222..." is already pinned
+        let pkg_id =
+            PkgId("git+https://github.com/foo/foo?branch=next#foo@0.2.0");
+        let hash = metadata.packages[&pkg_id].hash.as_ref().unwrap();
+        assert_eq!(hash.0, "sha256-AAAA");
+    }
+
+    #[ignore]
+    #[test]
+    fn test_nix_prefetch_git() {
+        let nix = which("nix").unwrap();
+
+        let dir = std::env::temp_dir().join(format!(
+            "nargo-metadata-test-prefetch-git-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let url = crate::output::test::local_git_repo(&dir);
+
+        let rev = process::Command::new("git")
+            .arg("-C")
+            .arg(dir.join("repo.git"))
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .unwrap()
+            .stdout;
+        let rev = str::from_utf8(&rev).unwrap().trim();
+
+        let git = output::GitSource {
+            url: &url,
+            rev,
+            subdir: Some("crates/foo"),
+        };
+        let out = nix_prefetch_git(&nix, &git).unwrap();
+        assert!(out.hash.starts_with("sha256-"), "{}", out.hash);
+
+        // The same checkout gives the same hash for any package in it
+        let git = output::GitSource {
+            subdir: Some("crates/bar"),
+            ..git
+        };
+        let out2 = nix_prefetch_git(&nix, &git).unwrap();
+        assert_eq!(out.hash, out2.hash);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,693 @@
+//! Resolve the `[profile.*]` tables from the workspace root `Cargo.toml`.
+//!
+//! `cargo metadata` doesn't tell us anything about profiles, so we parse them
+//! from the manifest ourselves. We resolve `inherits` and fill in all the
+//! defaults here, but leave the `package` and `build-override` overrides
+//! unapplied, since those depend on the unit we're building.
+//!
+//! Our own, non-cargo profile settings live in
+//! `[workspace.metadata.nargo.profile.<name>]` (or
+//! `[package.metadata.nargo.profile.<name>]` for a single-package workspace),
+//! so cargo doesn't warn about unused manifest keys:
+//!
+//! ```toml
+//! [workspace.metadata.nargo.profile.dev]
+//! prefer-dynamic = true
+//! ```
+//!
+//! See: <https://doc.rust-lang.org/cargo/reference/profiles.html>
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// A fully resolved `[profile.<name>]`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    /// The built-in profile this profile (transitively) inherits from. Either
+    /// `"dev"` or `"release"`.
+    pub root: String,
+
+    /// One of `0`, `1`, `2`, `3`, `s`, or `z`.
+    pub opt_level: String,
+
+    /// One of `0`, `1`, `2`, `line-tables-only`, or `line-directives-only`.
+    pub debug: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub split_debuginfo: Option<String>,
+
+    /// One of `none`, `debuginfo`, or `symbols`. If unset, this defaults to
+    /// `debuginfo` if `debug = 0` and `none` otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip: Option<String>,
+
+    pub debug_assertions: bool,
+
+    pub overflow_checks: bool,
+
+    /// One of `false`, `off`, `thin`, or `fat`.
+    pub lto: String,
+
+    /// Either `unwind` or `abort`.
+    pub panic: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codegen_units: Option<u32>,
+
+    pub rpath: bool,
+
+    /// Not a cargo setting, see `[workspace.metadata.nargo.profile.<name>]`.
+    /// Build all normal workspace libs as Rust dylibs too, and link our
+    /// workspace bins and tests against them and `libstd` dynamically
+    /// (`-C prefer-dynamic`), so small changes relink faster on very large
+    /// workspaces. Non-workspace deps stay rlibs.
+    #[serde(default)]
+    pub prefer_dynamic: bool,
+
+    /// Applied to build scripts, proc-macros, and their dependencies.
+    pub build_override: ProfileOverride,
+
+    /// `[profile.<name>.package.<spec>]` overrides. `<spec>` is one of
+    /// `"*"` (all non-workspace packages), `<name>`, or `<name>@<version>`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub package: BTreeMap<String, ProfileOverride>,
+}
+
+/// The subset of [`Profile`] fields that a `package` or `build-override`
+/// override may set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileOverride {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opt_level: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub split_debuginfo: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_assertions: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overflow_checks: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codegen_units: Option<u32>,
+}
+
+/// A single, unresolved `[profile.<name>]` table.
+#[derive(Clone, Default)]
+struct PartialProfile {
+    inherits: Option<String>,
+    fields: ProfileOverride,
+    lto: Option<String>,
+    panic: Option<String>,
+    rpath: Option<bool>,
+    prefer_dynamic: Option<bool>,
+    build_override: ProfileOverride,
+    package: BTreeMap<String, ProfileOverride>,
+}
+
+#[derive(Deserialize)]
+struct TomlManifest {
+    #[serde(default)]
+    profile: BTreeMap<String, TomlProfile>,
+    #[serde(default)]
+    workspace: TomlMetadataParent,
+    #[serde(default)]
+    package: TomlMetadataParent,
+}
+
+/// A `[workspace]` or `[package]` table, of which we only read
+/// `metadata.nargo`.
+#[derive(Default, Deserialize)]
+struct TomlMetadataParent {
+    #[serde(default)]
+    metadata: TomlMetadata,
+}
+
+#[derive(Default, Deserialize)]
+struct TomlMetadata {
+    #[serde(default)]
+    nargo: TomlNargoMetadata,
+}
+
+#[derive(Default, Deserialize)]
+struct TomlNargoMetadata {
+    #[serde(default)]
+    profile: BTreeMap<String, TomlNargoProfile>,
+}
+
+/// A `[workspace.metadata.nargo.profile.<name>]` table.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TomlNargoProfile {
+    prefer_dynamic: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TomlProfile {
+    inherits: Option<String>,
+    opt_level: Option<toml::Value>,
+    debug: Option<toml::Value>,
+    split_debuginfo: Option<String>,
+    strip: Option<toml::Value>,
+    debug_assertions: Option<bool>,
+    overflow_checks: Option<bool>,
+    lto: Option<toml::Value>,
+    panic: Option<String>,
+    codegen_units: Option<u32>,
+    rpath: Option<bool>,
+    #[serde(default)]
+    package: BTreeMap<String, TomlProfile>,
+    build_override: Option<Box<TomlProfile>>,
+    // `incremental`, `codegen-backend`, and `trim-paths` are ignored.
+}
+
+/// Parse and resolve all profiles (built-in and custom) from the workspace root
+/// `Cargo.toml` contents.
+pub fn from_manifest(manifest_toml: &str) -> BTreeMap<String, Profile> {
+    let manifest: TomlManifest = toml::from_str(manifest_toml)
+        .expect("Failed to parse `[profile]` tables in workspace `Cargo.toml`");
+
+    let mut partials: BTreeMap<String, PartialProfile> = manifest
+        .profile
+        .into_iter()
+        .map(|(name, toml)| {
+            let partial = PartialProfile::from_toml(&name, toml);
+            (name, partial)
+        })
+        .collect();
+
+    // The built-in profiles always exist.
+    for (name, inherits) in BUILTIN_PROFILES {
+        let partial = partials.entry(name.to_owned()).or_default();
+        match (&partial.inherits, inherits) {
+            (Some(_), None) => panic!(
+                "Cargo.toml: `[profile.{name}]`: `inherits` is not allowed in the built-in `{name}` profile"
+            ),
+            (Some(actual), Some(expected)) if actual != expected => panic!(
+                "Cargo.toml: `[profile.{name}]`: the built-in `{name}` profile must inherit from `{expected}`"
+            ),
+            _ => partial.inherits = inherits.map(str::to_owned),
+        }
+    }
+
+    // Our own settings, from `[package.metadata.nargo.profile.<name>]` and
+    // then `[workspace.metadata.nargo.profile.<name>]`.
+    let nargo_profiles = [
+        ("package", manifest.package.metadata.nargo.profile),
+        ("workspace", manifest.workspace.metadata.nargo.profile),
+    ];
+    for (parent, profiles) in nargo_profiles {
+        for (name, nargo) in profiles {
+            let partial = partials.get_mut(&name).unwrap_or_else(|| {
+                panic!(
+                    "Cargo.toml: `[{parent}.metadata.nargo.profile.{name}]`: profile `{name}` not defined"
+                )
+            });
+            if let Some(x) = nargo.prefer_dynamic {
+                partial.prefer_dynamic = Some(x);
+            }
+        }
+    }
+
+    partials
+        .keys()
+        .map(|name| (name.clone(), resolve(&partials, name, &mut Vec::new())))
+        .collect()
+}
+
+/// The built-in profiles and who they inherit from.
+const BUILTIN_PROFILES: [(&str, Option<&str>); 4] = [
+    ("dev", None),
+    ("release", None),
+    ("test", Some("dev")),
+    ("bench", Some("release")),
+];
+
+/// Recursively resolve the `inherits` chain for profile `name`.
+fn resolve<'a>(
+    partials: &'a BTreeMap<String, PartialProfile>,
+    name: &'a str,
+    stack: &mut Vec<&'a str>,
+) -> Profile {
+    if stack.contains(&name) {
+        panic!(
+            "Cargo.toml: profile `inherits` cycle: {} -> {name}",
+            stack.join(" -> ")
+        );
+    }
+
+    let partial = partials
+        .get(name)
+        .unwrap_or_else(|| panic!("Cargo.toml: profile `{name}` not defined"));
+
+    let inherits = match partial.inherits.as_deref() {
+        Some(inherits) => inherits,
+        None => match name {
+            "dev" => return Profile::dev().merge(partial),
+            "release" => return Profile::release().merge(partial),
+            _ => panic!(
+                "Cargo.toml: `[profile.{name}]`: custom profiles must set `inherits`"
+            ),
+        },
+    };
+
+    stack.push(name);
+    let parent = resolve(partials, inherits, stack);
+    stack.pop();
+
+    parent.merge(partial)
+}
+
+//
+// --- impl Profile ---
+//
+
+impl Profile {
+    fn dev() -> Self {
+        Self {
+            root: "dev".to_owned(),
+            opt_level: "0".to_owned(),
+            debug: "2".to_owned(),
+            split_debuginfo: None,
+            strip: None,
+            debug_assertions: true,
+            overflow_checks: true,
+            lto: "false".to_owned(),
+            panic: "unwind".to_owned(),
+            codegen_units: None,
+            rpath: false,
+            prefer_dynamic: false,
+            build_override: ProfileOverride::build_override_default(),
+            package: BTreeMap::new(),
+        }
+    }
+
+    fn release() -> Self {
+        Self {
+            root: "release".to_owned(),
+            opt_level: "3".to_owned(),
+            debug: "0".to_owned(),
+            split_debuginfo: None,
+            strip: None,
+            debug_assertions: false,
+            overflow_checks: false,
+            lto: "false".to_owned(),
+            panic: "unwind".to_owned(),
+            codegen_units: None,
+            rpath: false,
+            prefer_dynamic: false,
+            build_override: ProfileOverride::build_override_default(),
+            package: BTreeMap::new(),
+        }
+    }
+
+    /// Apply a child profile's settings on top of this (parent) profile.
+    fn merge(mut self, child: &PartialProfile) -> Self {
+        let fields = child.fields.clone();
+        if let Some(x) = fields.opt_level {
+            self.opt_level = x;
+        }
+        if let Some(x) = fields.debug {
+            self.debug = x;
+        }
+        if fields.split_debuginfo.is_some() {
+            self.split_debuginfo = fields.split_debuginfo;
+        }
+        if fields.strip.is_some() {
+            self.strip = fields.strip;
+        }
+        if let Some(x) = fields.debug_assertions {
+            self.debug_assertions = x;
+        }
+        if let Some(x) = fields.overflow_checks {
+            self.overflow_checks = x;
+        }
+        if fields.codegen_units.is_some() {
+            self.codegen_units = fields.codegen_units;
+        }
+        if let Some(x) = &child.lto {
+            self.lto = x.clone();
+        }
+        if let Some(x) = &child.panic {
+            self.panic = x.clone();
+        }
+        if let Some(x) = child.rpath {
+            self.rpath = x;
+        }
+        if let Some(x) = child.prefer_dynamic {
+            self.prefer_dynamic = x;
+        }
+        self.build_override.merge(&child.build_override);
+        for (spec, child_override) in &child.package {
+            self.package
+                .entry(spec.clone())
+                .or_default()
+                .merge(child_override);
+        }
+        self
+    }
+}
+
+//
+// --- impl ProfileOverride ---
+//
+
+impl ProfileOverride {
+    /// Build scripts and proc-macros are usually cheap to run, so by default
+    /// cargo builds them (and their deps) quickly rather than optimized.
+    fn build_override_default() -> Self {
+        Self {
+            opt_level: Some("0".to_owned()),
+            debug: Some("0".to_owned()),
+            codegen_units: Some(256),
+            ..Self::default()
+        }
+    }
+
+    fn merge(&mut self, child: &Self) {
+        let child = child.clone();
+        self.opt_level = child.opt_level.or(self.opt_level.take());
+        self.debug = child.debug.or(self.debug.take());
+        self.split_debuginfo =
+            child.split_debuginfo.or(self.split_debuginfo.take());
+        self.strip = child.strip.or(self.strip.take());
+        self.debug_assertions =
+            child.debug_assertions.or(self.debug_assertions);
+        self.overflow_checks = child.overflow_checks.or(self.overflow_checks);
+        self.codegen_units = child.codegen_units.or(self.codegen_units);
+    }
+
+    fn from_toml(table: &str, toml: TomlProfile) -> Self {
+        let disallowed = [
+            ("inherits", toml.inherits.is_some()),
+            ("lto", toml.lto.is_some()),
+            ("panic", toml.panic.is_some()),
+            ("rpath", toml.rpath.is_some()),
+            ("package", !toml.package.is_empty()),
+            ("build-override", toml.build_override.is_some()),
+        ];
+        for (key, is_set) in disallowed {
+            if is_set {
+                panic!(
+                    "Cargo.toml: `[{table}]`: `{key}` may not be overridden"
+                );
+            }
+        }
+
+        Self::fields_from_toml(table, &toml)
+    }
+
+    fn fields_from_toml(table: &str, toml: &TomlProfile) -> Self {
+        Self {
+            opt_level: toml.opt_level.as_ref().map(|v| opt_level(table, v)),
+            debug: toml.debug.as_ref().map(|v| debug(table, v)),
+            split_debuginfo: toml
+                .split_debuginfo
+                .as_deref()
+                .map(|v| split_debuginfo(table, v)),
+            strip: toml.strip.as_ref().map(|v| strip(table, v)),
+            debug_assertions: toml.debug_assertions,
+            overflow_checks: toml.overflow_checks,
+            codegen_units: toml.codegen_units,
+        }
+    }
+}
+
+//
+// --- impl PartialProfile ---
+//
+
+impl PartialProfile {
+    fn from_toml(name: &str, toml: TomlProfile) -> Self {
+        let table = format!("profile.{name}");
+        let fields = ProfileOverride::fields_from_toml(&table, &toml);
+
+        let build_override = match toml.build_override {
+            Some(build_override) => ProfileOverride::from_toml(
+                &format!("{table}.build-override"),
+                *build_override,
+            ),
+            None => ProfileOverride::default(),
+        };
+
+        let package = toml
+            .package
+            .into_iter()
+            .map(|(spec, toml)| {
+                let override_table = format!("{table}.package.{spec}");
+                (spec, ProfileOverride::from_toml(&override_table, toml))
+            })
+            .collect();
+
+        Self {
+            inherits: toml.inherits,
+            fields,
+            lto: toml.lto.as_ref().map(|v| lto(&table, v)),
+            panic: toml.panic.as_deref().map(|v| panic_strategy(&table, v)),
+            rpath: toml.rpath,
+            prefer_dynamic: None,
+            build_override,
+            package,
+        }
+    }
+}
+
+//
+// --- normalize profile values ---
+//
+
+fn invalid(table: &str, key: &str, value: impl std::fmt::Debug) -> ! {
+    panic!("Cargo.toml: `[{table}]`: invalid `{key}` value: {value:?}")
+}
+
+fn opt_level(table: &str, value: &toml::Value) -> String {
+    match value {
+        toml::Value::Integer(x @ 0..=3) => x.to_string(),
+        toml::Value::String(s)
+            if matches!(s.as_str(), "0" | "1" | "2" | "3" | "s" | "z") =>
+        {
+            s.clone()
+        }
+        _ => invalid(table, "opt-level", value),
+    }
+}
+
+fn debug(table: &str, value: &toml::Value) -> String {
+    let debug = match value {
+        toml::Value::Boolean(false) => "0",
+        toml::Value::Boolean(true) => "2",
+        toml::Value::Integer(0) => "0",
+        toml::Value::Integer(1) => "1",
+        toml::Value::Integer(2) => "2",
+        toml::Value::String(s) => match s.as_str() {
+            "none" => "0",
+            "limited" => "1",
+            "full" => "2",
+            "line-tables-only" => "line-tables-only",
+            "line-directives-only" => "line-directives-only",
+            _ => invalid(table, "debug", value),
+        },
+        _ => invalid(table, "debug", value),
+    };
+    debug.to_owned()
+}
+
+fn split_debuginfo(table: &str, value: &str) -> String {
+    match value {
+        "off" | "packed" | "unpacked" => value.to_owned(),
+        _ => invalid(table, "split-debuginfo", value),
+    }
+}
+
+fn strip(table: &str, value: &toml::Value) -> String {
+    let strip = match value {
+        toml::Value::Boolean(false) => "none",
+        toml::Value::Boolean(true) => "symbols",
+        toml::Value::String(s) => match s.as_str() {
+            "none" | "debuginfo" | "symbols" => s.as_str(),
+            _ => invalid(table, "strip", value),
+        },
+        _ => invalid(table, "strip", value),
+    };
+    strip.to_owned()
+}
+
+fn lto(table: &str, value: &toml::Value) -> String {
+    let lto = match value {
+        toml::Value::Boolean(false) => "false",
+        toml::Value::Boolean(true) => "fat",
+        toml::Value::String(s) => match s.as_str() {
+            "off" | "thin" | "fat" => s.as_str(),
+            _ => invalid(table, "lto", value),
+        },
+        _ => invalid(table, "lto", value),
+    };
+    lto.to_owned()
+}
+
+fn panic_strategy(table: &str, value: &str) -> String {
+    match value {
+        "unwind" | "abort" => value.to_owned(),
+        _ => invalid(table, "panic", value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builtin_profiles() {
+        let profiles = from_manifest("[package]\nname = \"foo\"\n");
+        assert_eq!(
+            profiles.keys().collect::<Vec<_>>(),
+            ["bench", "dev", "release", "test"]
+        );
+        assert_eq!(profiles["dev"], Profile::dev());
+        assert_eq!(profiles["test"], Profile::dev());
+        assert_eq!(profiles["release"], Profile::release());
+        assert_eq!(profiles["bench"], Profile::release());
+    }
+
+    #[test]
+    fn test_custom_profiles() {
+        let manifest = r#"
+[workspace.metadata.nargo.profile.dev]
+prefer-dynamic = true
+
+[profile.dev]
+panic = "abort"
+debug = "line-tables-only"
+
+[profile.dev.package."*"]
+opt-level = 2
+
+[profile.release]
+lto = true
+strip = true
+codegen-units = 1
+
+[profile.release.build-override]
+opt-level = 1
+
+[profile.release.package.foo]
+debug = true
+
+[profile.dist]
+inherits = "release"
+lto = "thin"
+split-debuginfo = "packed"
+
+[profile.dist.package.foo]
+overflow-checks = true
+"#;
+        let profiles = from_manifest(manifest);
+
+        let dev = &profiles["dev"];
+        assert_eq!(dev.panic, "abort");
+        assert_eq!(dev.debug, "line-tables-only");
+        assert!(dev.prefer_dynamic);
+        assert_eq!(dev.package["*"].opt_level.as_deref(), Some("2"));
+
+        // `test` inherits all `dev` settings
+        assert_eq!(&profiles["test"], dev);
+
+        let dist = &profiles["dist"];
+        assert_eq!(dist.root, "release");
+        assert_eq!(dist.opt_level, "3");
+        assert_eq!(dist.lto, "thin");
+        assert!(!dist.prefer_dynamic);
+        assert_eq!(dist.strip.as_deref(), Some("symbols"));
+        assert_eq!(dist.codegen_units, Some(1));
+        assert_eq!(dist.split_debuginfo.as_deref(), Some("packed"));
+        assert_eq!(
+            dist.build_override,
+            ProfileOverride {
+                opt_level: Some("1".to_owned()),
+                debug: Some("0".to_owned()),
+                codegen_units: Some(256),
+                ..ProfileOverride::default()
+            }
+        );
+        assert_eq!(
+            dist.package["foo"],
+            ProfileOverride {
+                debug: Some("2".to_owned()),
+                overflow_checks: Some(true),
+                ..ProfileOverride::default()
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "custom profiles must set `inherits`")]
+    fn test_custom_profile_missing_inherits() {
+        from_manifest("[profile.dist]\nopt-level = 2\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "profile `inherits` cycle")]
+    fn test_custom_profile_inherits_cycle() {
+        from_manifest(
+            "[profile.a]\ninherits = \"b\"\n[profile.b]\ninherits = \"a\"\n",
+        );
+    }
+
+    #[test]
+    fn test_nargo_profile_metadata() {
+        let manifest = r#"
+[package]
+name = "foo"
+
+[package.metadata.nargo.profile.dev]
+prefer-dynamic = true
+
+[package.metadata.nargo.profile.dist]
+prefer-dynamic = true
+
+[profile.dist]
+inherits = "release"
+"#;
+        let profiles = from_manifest(manifest);
+        assert!(profiles["dev"].prefer_dynamic);
+        assert!(profiles["test"].prefer_dynamic);
+        assert!(profiles["dist"].prefer_dynamic);
+        assert!(!profiles["release"].prefer_dynamic);
+
+        // `[workspace.metadata.nargo]` wins over `[package.metadata.nargo]`
+        let manifest = r#"
+[package.metadata.nargo.profile.dev]
+prefer-dynamic = true
+
+[workspace.metadata.nargo.profile.dev]
+prefer-dynamic = false
+"#;
+        let profiles = from_manifest(manifest);
+        assert!(!profiles["dev"].prefer_dynamic);
+    }
+
+    #[test]
+    #[should_panic(expected = "`[workspace.metadata.nargo.profile.dist]`: \
+                               profile `dist` not defined")]
+    fn test_nargo_profile_metadata_undefined() {
+        from_manifest(
+            "[workspace.metadata.nargo.profile.dist]\nprefer-dynamic = true\n",
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "`lto` may not be overridden")]
+    fn test_package_override_lto() {
+        from_manifest("[profile.release.package.foo]\nlto = true\n");
+    }
+}
@@ -0,0 +1,439 @@
+//! Alternative registries, like a private sparse registry.
+//!
+//! `cargo metadata` only gives us each registry's index url. Like cargo, we
+//! read the registry names from the cargo config so we can find their
+//! `CARGO_REGISTRIES_<NAME>_TOKEN` auth tokens. A sparse registry's
+//! `config.json` then tells us where to download its crates from.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    io::Write as _,
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+};
+
+use nargo_core::{
+    error::{Context as _, Result},
+    format_err, fs,
+};
+use serde::Deserialize;
+
+use crate::input::Source;
+
+/// Maps each configured registry's index url to its name.
+#[derive(Default)]
+pub(crate) struct Registries {
+    /// normalized index url -> registry name
+    names: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct TomlConfig {
+    #[serde(default)]
+    registries: BTreeMap<String, TomlRegistry>,
+}
+
+#[derive(Deserialize)]
+struct TomlRegistry {
+    index: Option<String>,
+}
+
+/// A sparse registry's `config.json`.
+#[derive(Deserialize)]
+pub(crate) struct RegistryConfig {
+    /// The crate download url, or a url template like
+    /// `"https://crates.example.com/api/v1/crates/{crate}/{version}/download"`.
+    pub(crate) dl: String,
+}
+
+//
+// --- impl Registries ---
+//
+
+impl Registries {
+    /// Read the registries from any `CARGO_REGISTRIES_<NAME>_INDEX` envs and
+    /// the `[registries.<name>] index = ".."` tables in the cargo config files
+    /// that apply to the workspace at `workspace_root`. Like cargo, the envs
+    /// take precedence, then the configs closest to the workspace.
+    pub(crate) fn from_cargo_config(workspace_root: &Path) -> Self {
+        let mut registries = Self::default();
+
+        for (key, index) in env::vars() {
+            let name = key
+                .strip_prefix("CARGO_REGISTRIES_")
+                .and_then(|rest| rest.strip_suffix("_INDEX"));
+            if let Some(name) = name {
+                let name = name.to_ascii_lowercase().replace('_', "-");
+                registries.add(&name, &index);
+            }
+        }
+
+        let config_dirs = workspace_root
+            .ancestors()
+            .map(|dir| dir.join(".cargo"))
+            .chain(cargo_home());
+        for config_dir in config_dirs {
+            for file_name in ["config.toml", "config"] {
+                let config_path = config_dir.join(file_name);
+                let config = fs::read_file(&config_path)
+                    .with_context(|| config_path.display().to_string())
+                    .expect("Failed to read cargo config");
+                if let Some(config) = config {
+                    let config = String::from_utf8(config)
+                        .with_context(|| config_path.display().to_string())
+                        .expect("Cargo config is not valid UTF-8");
+                    registries.add_config_toml(&config);
+                }
+            }
+        }
+
+        registries
+    }
+
+    fn add_config_toml(&mut self, config_toml: &str) {
+        let config: TomlConfig = toml::from_str(config_toml)
+            .expect("Failed to parse `[registries]` in cargo config");
+        for (name, registry) in config.registries {
+            if let Some(index) = registry.index {
+                self.add(&name, &index);
+            }
+        }
+    }
+
+    /// Add a registry, unless we've already seen its index.
+    fn add(&mut self, name: &str, index: &str) {
+        self.names
+            .entry(normalize_index(index))
+            .or_insert_with(|| name.to_owned());
+    }
+
+    /// The name of the registry with the index at `source`.
+    pub(crate) fn name(&self, source: Source<'_>) -> Option<&str> {
+        self.names
+            .get(&normalize_index(source.0))
+            .map(String::as_str)
+    }
+
+    /// The env that holds the auth token for the registry at `source`, like
+    /// `CARGO_REGISTRIES_MY_REGISTRY_TOKEN` for registry `my-registry`.
+    pub(crate) fn token_env(&self, source: Source<'_>) -> Option<String> {
+        let name = self.name(source)?;
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        Some(format!("CARGO_REGISTRIES_{name}_TOKEN"))
+    }
+
+    /// The auth token for the registry at `source`, if any.
+    pub(crate) fn token(&self, source: Source<'_>) -> Option<String> {
+        env::var(self.token_env(source)?).ok()
+    }
+}
+
+//
+// --- impl RegistryConfig ---
+//
+
+impl RegistryConfig {
+    /// Fetch the `config.json` of the sparse registry at `source`.
+    pub(crate) fn fetch(
+        curl: &Path,
+        source: Source<'_>,
+        token: Option<&str>,
+    ) -> Result<Self> {
+        // ex: "sparse+https://crates.example.com/index/"
+        // -> "https://crates.example.com/index/config.json"
+        let index = source.0.strip_prefix("sparse+").with_context(|| {
+            format!(
+                "Only sparse alternative registries are supported: '{}'",
+                source.0
+            )
+        })?;
+        let url = format!("{}/config.json", index.trim_end_matches('/'));
+
+        let config = curl_get(curl, &url, token, None)?;
+        serde_json::from_slice(&config).with_context(|| {
+            format!("Failed to deserialize registry config: '{url}'")
+        })
+    }
+
+    /// The download url for crate `name` at `version`, filling in the `dl`
+    /// template like cargo.
+    pub(crate) fn download_url(
+        &self,
+        name: &str,
+        version: &semver::Version,
+    ) -> Result<String> {
+        const MARKERS: [&str; 5] = [
+            "{crate}",
+            "{version}",
+            "{prefix}",
+            "{lowerprefix}",
+            "{sha256-checksum}",
+        ];
+
+        let dl = self.dl.as_str();
+        if !MARKERS.iter().any(|marker| dl.contains(marker)) {
+            return Ok(format!("{}/{name}/{version}/download", dl));
+        }
+        if dl.contains("{sha256-checksum}") {
+            return Err(format_err!(
+                "Registry `dl` templates with `{{sha256-checksum}}` are not supported yet: '{dl}'"
+            ));
+        }
+
+        let prefix = crate_prefix(name);
+        Ok(dl
+            .replace("{crate}", name)
+            .replace("{version}", &version.to_string())
+            .replace("{prefix}", &prefix)
+            .replace("{lowerprefix}", &prefix.to_ascii_lowercase()))
+    }
+}
+
+//
+// --- misc ---
+//
+
+/// Registry index urls from the cargo config are bare for git registries, like
+/// `"https://github.com/example/index"`, while `cargo metadata` shows them as
+/// `"registry+https://github.com/example/index"`.
+fn normalize_index(index: &str) -> String {
+    let index = index.trim_end_matches('/');
+    if index.starts_with("sparse+") || index.starts_with("registry+") {
+        index.to_owned()
+    } else {
+        format!("registry+{index}")
+    }
+}
+
+/// The index directory prefix for crate `name`, like `"se/rd"` for `"serde"`.
+fn crate_prefix(name: &str) -> String {
+    match name.len() {
+        1 => "1".to_owned(),
+        2 => "2".to_owned(),
+        3 => format!("3/{}", &name[..1]),
+        _ => format!("{}/{}", &name[..2], &name[2..4]),
+    }
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    match env::var_os("CARGO_HOME") {
+        Some(cargo_home) => Some(PathBuf::from(cargo_home)),
+        None => {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo"))
+        }
+    }
+}
+
+/// `GET` `url` with `curl`, sending the registry auth `token` if we have one.
+/// Writes the response body to `out`, or else returns it.
+pub(crate) fn curl_get(
+    curl: &Path,
+    url: &str,
+    token: Option<&str>,
+    out: Option<&Path>,
+) -> Result<Vec<u8>> {
+    let mut cmd = process::Command::new(curl);
+    cmd.args(["--fail", "--silent", "--show-error", "--location"]);
+    if let Some(out) = out {
+        cmd.arg("--output").arg(out);
+    }
+    // Pass the token header on stdin so it doesn't show up in `ps`.
+    if token.is_some() {
+        cmd.args(["--header", "@-"]);
+    }
+    cmd.arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = cmd.spawn().context("Failed to run `curl`")?;
+    let mut stdin = child.stdin.take().unwrap();
+    if let Some(token) = token {
+        writeln!(stdin, "Authorization: {token}")
+            .context("Failed to write `curl` auth header")?;
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output().context("Failed to run `curl`")?;
+    if !output.status.success() {
+        let status = &output.status;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format_err!(
+            "`curl` failed to fetch '{url}': {status}\n\nstderr:\n{stderr}\n"
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead as _, BufReader, Write as _},
+        net::TcpListener,
+        thread,
+    };
+
+    use nargo_core::which::which;
+
+    use super::*;
+
+    #[test]
+    fn test_registries_from_config_toml() {
+        let mut registries = Registries::default();
+        registries.add_config_toml(
+            r#"
+[registries.my-registry]
+index = "sparse+https://crates.example.com/index/"
+
+[registries.git-registry]
+index = "https://github.com/example/crates-index"
+
+[registries.crates-io]
+protocol = "sparse"
+"#,
+        );
+
+        let source = Source("sparse+https://crates.example.com/index/");
+        assert_eq!(registries.name(source), Some("my-registry"));
+        assert_eq!(
+            registries.token_env(source).as_deref(),
+            Some("CARGO_REGISTRIES_MY_REGISTRY_TOKEN"),
+        );
+
+        let source = Source("registry+https://github.com/example/crates-index");
+        assert_eq!(registries.name(source), Some("git-registry"));
+
+        let source = Source("sparse+https://other.example.com/index/");
+        assert_eq!(registries.name(source), None);
+        assert_eq!(registries.token_env(source), None);
+    }
+
+    #[test]
+    fn test_download_url() {
+        let version = semver::Version::parse("1.0.203").unwrap();
+        let url = |dl: &str, name: &str| {
+            let config = RegistryConfig { dl: dl.to_owned() };
+            config.download_url(name, &version).unwrap()
+        };
+
+        assert_eq!(
+            url("https://crates.example.com/api/v1/crates", "serde"),
+            "https://crates.example.com/api/v1/crates/serde/1.0.203/download",
+        );
+        assert_eq!(
+            url(
+                "https://crates.example.com/{crate}/{crate}-{version}.crate",
+                "serde"
+            ),
+            "https://crates.example.com/serde/serde-1.0.203.crate",
+        );
+        let dl = "https://crates.example.com/{prefix}/{lowerprefix}/{crate}";
+        assert_eq!(url(dl, "a"), "https://crates.example.com/1/1/a");
+        assert_eq!(url(dl, "ab"), "https://crates.example.com/2/2/ab");
+        assert_eq!(url(dl, "Abc"), "https://crates.example.com/3/A/3/a/Abc");
+        assert_eq!(
+            url(dl, "Serde"),
+            "https://crates.example.com/Se/rd/se/rd/Serde"
+        );
+
+        let config = RegistryConfig {
+            dl: "https://crates.example.com/{sha256-checksum}".to_owned(),
+        };
+        assert!(config.download_url("serde", &version).is_err());
+    }
+
+    /// Serve files over HTTP on localhost, like a private sparse registry
+    /// that only answers requests with an `Authorization: <token>` header.
+    /// `files(base_url)` returns each file's path and contents. Returns the
+    /// base url.
+    fn serve_registry(
+        token: &'static str,
+        files: impl FnOnce(&str) -> Vec<(String, Vec<u8>)>,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let files = files(&base_url);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap().to_owned();
+
+                let mut authorized = false;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    if header == format!("Authorization: {token}") {
+                        authorized = true;
+                    }
+                }
+
+                let file =
+                    files.iter().find(|(file_path, _)| *file_path == path);
+                let (status, body) = match (authorized, file) {
+                    (false, _) => ("401 Unauthorized", &[][..]),
+                    (true, Some((_, body))) => ("200 OK", body.as_slice()),
+                    (true, None) => ("404 Not Found", &[][..]),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+
+        base_url
+    }
+
+    #[test]
+    fn test_fetch_from_local_sparse_registry() {
+        let curl =
+            which("curl").expect("Couldn't find `curl` binary in `$PATH`");
+
+        let token = "secret-token";
+        let crate_bytes = b"not really a .crate tarball".to_vec();
+        let base_url = serve_registry(token, |base_url| {
+            let config = format!(
+                r#"{{"dl": "{base_url}/api/v1/crates", "auth-required": true}}"#
+            );
+            vec![
+                ("/index/config.json".to_owned(), config.into_bytes()),
+                (
+                    "/api/v1/crates/foo/1.0.0/download".to_owned(),
+                    crate_bytes.clone(),
+                ),
+            ]
+        });
+        let index = format!("sparse+{base_url}/index/");
+        let source = Source(&index);
+
+        // Private registries need the token
+        assert!(RegistryConfig::fetch(&curl, source, None).is_err());
+
+        let config = RegistryConfig::fetch(&curl, source, Some(token)).unwrap();
+        let version = semver::Version::parse("1.0.0").unwrap();
+        let url = config.download_url("foo", &version).unwrap();
+        assert_eq!(url, format!("{base_url}/api/v1/crates/foo/1.0.0/download"));
+
+        let out = std::env::temp_dir().join(format!(
+            "nargo-metadata-test-registry-{}.crate",
+            std::process::id()
+        ));
+        curl_get(&curl, &url, Some(token), Some(&out)).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), crate_bytes);
+        std::fs::remove_file(&out).unwrap();
+    }
+}
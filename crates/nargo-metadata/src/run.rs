@@ -1,11 +1,16 @@
-use std::{collections::BTreeMap, path::Path};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
 use nargo_core::{fs, time};
 
 use crate::{
     clean,
     input::{self, PkgId},
-    output, prefetch,
+    manifest, output, prefetch, profile,
+    registry::Registries,
+    sources::RecordedSources,
 };
 
 pub(crate) struct Args<'a> {
@@ -14,6 +19,7 @@ pub(crate) struct Args<'a> {
     pub output_metadata: &'a Path,
     pub nix_prefetch: bool,
     pub assume_vendored: bool,
+    pub input_sources: &'a [PathBuf],
     pub check: bool,
 }
 
@@ -40,6 +46,41 @@ pub fn run(args: Args<'_>) {
     let ctx = clean::Context { workspace_root };
     time!("clean input", input.clean(ctx));
 
+    // `cargo metadata` doesn't include profiles, so read them from the
+    // workspace root `Cargo.toml` directly.
+    let workspace_manifest_path = Path::new(workspace_root).join("Cargo.toml");
+    let workspace_manifest = time!(
+        "read workspace Cargo.toml",
+        fs::read_file(&workspace_manifest_path)
+            .expect("Failed to read workspace `Cargo.toml`")
+            .expect("Missing workspace `Cargo.toml`")
+    );
+    let workspace_manifest = String::from_utf8(workspace_manifest)
+        .expect("Workspace `Cargo.toml` is not valid UTF-8");
+    let profiles = time!(
+        "resolve profiles",
+        profile::from_manifest(&workspace_manifest)
+    );
+
+    // `cargo metadata` also doesn't include each target's `harness` setting,
+    // which we need to build workspace package tests, or the `[lints]`.
+    let workspace_lints = manifest::workspace_lints(&workspace_manifest);
+    time!(
+        "read workspace package Cargo.toml's",
+        for pkg in input.packages.iter_mut() {
+            if !pkg.is_workspace_pkg() {
+                continue;
+            }
+            let manifest = fs::read_file(Path::new(pkg.manifest_path))
+                .expect("Failed to read package `Cargo.toml`")
+                .expect("Missing package `Cargo.toml`");
+            let manifest = String::from_utf8(manifest)
+                .expect("Package `Cargo.toml` is not valid UTF-8");
+            manifest::apply_harness(&manifest, &mut pkg.targets);
+            pkg.lints = manifest::lint_flags(&manifest, &workspace_lints);
+        }
+    );
+
     let manifests: BTreeMap<PkgId<'_>, input::Manifest<'_>> = input
         .packages
         .into_iter()
@@ -56,16 +97,31 @@ pub fn run(args: Args<'_>) {
             input.workspace_members,
             input.workspace_default_members,
             input.resolve,
+            profiles,
             input_current_metadata.as_ref(),
             args.assume_vendored,
         ),
     );
 
+    // Record which files each workspace target reads, so `buildGraph.nix` can
+    // narrow its source filter.
+    let recorded_sources = time!(
+        "read --input-sources",
+        RecordedSources::read(args.input_sources)
+    );
+    recorded_sources.apply(&mut output, input_current_metadata.as_ref());
+
     // If we're running outside the nix sandbox, by default we'll ask `nix` to
-    // prefetch each crates.io dependency into the /nix/store and return the
-    // content hash, which we'll pin in the output.
+    // prefetch each crates.io and alternative registry dependency and git repo
+    // checkout into the /nix/store and return the content hash, which we'll
+    // pin in the output. Private registries get their auth tokens from the
+    // same `CARGO_REGISTRIES_<NAME>_TOKEN` envs as cargo.
     if args.nix_prefetch {
-        time!("prefetch", prefetch::prefetch(&mut output));
+        let registries = time!(
+            "read cargo config registries",
+            Registries::from_cargo_config(Path::new(workspace_root))
+        );
+        time!("prefetch", prefetch::prefetch(&mut output, &registries));
     }
 
     // Assert invariants on output.
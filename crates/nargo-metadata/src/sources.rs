@@ -0,0 +1,171 @@
+//! Read the `sources.json` files that `nargo-rustc` records in each workspace
+//! unit's `$out` (see `nargo-rustc/src/sources.rs`), and fold them into each
+//! workspace target's `sources` in `Cargo.metadata.json`.
+//!
+//! `buildGraph.nix` then gives each target a source filter with only the files
+//! it actually read (plus any `.rs` files no target has recorded yet), so
+//! editing an unrelated file no longer rebuilds it.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use nargo_core::fs;
+use serde::Deserialize;
+
+use crate::output;
+
+/// A unit's `$out/sources.json`.
+#[derive(Deserialize)]
+struct UnitSources {
+    package: String,
+    target: UnitTarget,
+    sources: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct UnitTarget {
+    name: String,
+    kind: String,
+}
+
+/// The recorded sources for each `(package name, target name, target kind)`.
+/// Several units of the same target (rmeta, rlib, check, ...) all add to the
+/// same set.
+#[derive(Default)]
+pub(crate) struct RecordedSources {
+    targets: BTreeMap<(String, String, String), BTreeSet<String>>,
+}
+
+impl RecordedSources {
+    /// Read all the `sources.json` at `paths`. Each path is either a
+    /// `sources.json` file, a unit out dir that contains one, or a dir of unit
+    /// out dirs (like a `result` dir, or `target/nargo/store`).
+    pub(crate) fn read(paths: &[PathBuf]) -> Self {
+        let mut this = Self::default();
+        for path in paths {
+            let is_dir = std::fs::metadata(path)
+                .map(|metadata| metadata.is_dir())
+                .unwrap_or_else(|err| {
+                    panic!("Failed to read `{}`: {err}", path.display())
+                });
+            if !is_dir {
+                this.read_file(path);
+                continue;
+            }
+
+            let sources_json = path.join("sources.json");
+            if sources_json.is_file() {
+                this.read_file(&sources_json);
+                continue;
+            }
+
+            let mut unit_dirs = std::fs::read_dir(path)
+                .unwrap_or_else(|err| {
+                    panic!("Failed to read dir `{}`: {err}", path.display())
+                })
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path().join("sources.json"))
+                .filter(|sources_json| sources_json.is_file())
+                .collect::<Vec<_>>();
+            unit_dirs.sort_unstable();
+            for sources_json in unit_dirs {
+                this.read_file(&sources_json);
+            }
+        }
+        this
+    }
+
+    fn read_file(&mut self, path: &Path) {
+        let bytes = fs::read_existing_file(path)
+            .expect("Failed to read unit `sources.json`");
+        let unit: UnitSources =
+            serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                panic!("Failed to deserialize `{}`: {err}", path.display())
+            });
+        let key = (unit.package, unit.target.name, unit.target.kind);
+        self.targets.entry(key).or_default().extend(unit.sources);
+    }
+
+    /// Set each workspace target's `sources`. Targets we don't have any new
+    /// recordings for keep their `sources` from the current
+    /// `Cargo.metadata.json`.
+    pub(crate) fn apply<'a>(
+        &'a self,
+        output: &mut output::Metadata<'a>,
+        current: Option<&'a output::Metadata<'a>>,
+    ) {
+        for (pkg_id, pkg) in &mut output.packages {
+            if !pkg.is_workspace() {
+                continue;
+            }
+            let curr_pkg = current.and_then(|curr| curr.packages.get(pkg_id));
+
+            for target in &mut pkg.targets {
+                let key = (
+                    pkg.name.to_owned(),
+                    target.name.to_owned(),
+                    target.kind.as_str().to_owned(),
+                );
+                target.sources = match self.targets.get(&key) {
+                    Some(sources) => Some(
+                        sources
+                            .iter()
+                            .map(|s| Cow::Borrowed(s.as_str()))
+                            .collect(),
+                    ),
+                    None => curr_pkg
+                        .and_then(|curr_pkg| {
+                            curr_pkg.targets.iter().find(|curr| {
+                                curr.name == target.name
+                                    && curr.kind == target.kind
+                            })
+                        })
+                        .and_then(|curr| curr.sources.clone()),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_merges_units() {
+        let dir = std::env::temp_dir().join(format!(
+            "nargo-metadata-test-sources-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (unit, sources) in [
+            ("aaaa-foo-lib-rmeta-0.1.0", r#"["src/lib.rs"]"#),
+            (
+                "bbbb-foo-lib-test-0.1.0",
+                r#"["src/lib.rs", "src/test.rs"]"#,
+            ),
+        ] {
+            let unit_dir = dir.join(unit);
+            std::fs::create_dir_all(&unit_dir).unwrap();
+            let json = format!(
+                r#"{{"package": "foo", "target": {{"name": "foo", "kind": "lib"}}, "sources": {sources}}}"#
+            );
+            std::fs::write(unit_dir.join("sources.json"), json).unwrap();
+        }
+        // An out dir without any `sources.json`
+        std::fs::create_dir_all(dir.join("cccc-bar-1.0.0")).unwrap();
+
+        let recorded = RecordedSources::read(std::slice::from_ref(&dir));
+        let key = ("foo".to_owned(), "foo".to_owned(), "lib".to_owned());
+        assert_eq!(
+            recorded.targets[&key].iter().collect::<Vec<_>>(),
+            ["src/lib.rs", "src/test.rs"],
+        );
+        assert_eq!(recorded.targets.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
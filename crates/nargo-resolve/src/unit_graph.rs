@@ -93,20 +93,14 @@ impl<'a> UnitGraph<'a> {
 
             let unit_pkg_id = unit.pkg_id;
             let nargo_pkg_id = resolve::PkgId(&pkg_id_map[unit.pkg_id]);
+            // With `cargo build --target <triple>`, cargo compiles build
+            // scripts, proc-macros, and their deps for the host
+            // (`platform: null`), and everything else for `<triple>`. When
+            // cross-compiling, these are different triples, but either way,
+            // any unit with a `platform` is a normal unit.
             let feat_for = match unit.platform {
                 None => resolve::FeatFor::Build,
-                Some(target) if target == host_target => {
-                    resolve::FeatFor::Normal
-                }
-                Some(target) => panic!(
-                    r#"Found unit with unexpected target triple: '{target}', while building
-our feature resolution type from the cargo unit-graph:
-
-    --host-target: {host_target}
-     cargo pkg_id: {unit_pkg_id}
-     nargo pkg_id: {nargo_pkg_id}
-"#,
-                ),
+                Some(_) => resolve::FeatFor::Normal,
             };
             let feats = unit
                 .features
@@ -1,40 +1,83 @@
-#![allow(dead_code)] // TODO(phlip9): remove
-
-use std::str;
+use std::{fmt, str};
 
 /// parsed build_script_build output
 #[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub struct BuildOutput<'a> {
-    // TODO
-    // /// Paths to pass to rustc with the `-L` flag.
-    // pub library_paths: Vec<&'a Path>,
-    // /// Names and link kinds of libraries, suitable for the `-l` flag.
-    // pub library_links: Vec<String>,
-    // /// Linker arguments suitable to be passed to `-C link-arg=<args>`
-    // pub linker_args: Vec<(LinkArgTarget, String)>,
-    //
+    /// Paths to pass to rustc with the `-L` flag.
+    pub library_paths: Vec<LinkSearch<'a>>,
+    /// Names and link kinds of libraries, suitable for the `-l` flag.
+    pub library_links: Vec<LinkLib<'a>>,
+    /// Linker arguments suitable to be passed to `-C link-arg=<args>`
+    pub linker_args: Vec<(LinkArgTarget<'a>, &'a str)>,
     /// Various `--cfg` flags to pass to the compiler.
     pub cfgs: Vec<&'a str>,
     /// Various `--check-cfg` flags to pass to the compiler.
     pub check_cfgs: Vec<&'a str>,
     /// Additional environment variables to run the compiler with.
     pub env: Vec<(&'a str, &'a str)>,
-    //
-    // TODO
-    // /// Metadata to pass to the immediate dependencies.
-    // pub metadata: Vec<(String, String)>,
-    // /// Errors and warnings generated by this build.
-    // ///
-    // /// These are only displayed if this is a "local" package, `-vv` is used, or
-    // /// there is a build error for any target in this package. Note that any log
-    // /// message of severity `Error` will by itself cause a build error, and will
-    // /// cause all log messages to be displayed.
-    // pub log_messages: Vec<LogMessage>,
-
+    /// Metadata to pass to the immediate dependencies' build scripts as
+    /// `DEP_<links>_<KEY>` envs.
+    pub metadata: Vec<(&'a str, &'a str)>,
+    /// Warnings and errors from `cargo::warning` and `cargo::error`, one per
+    /// directive.
+    pub log_messages: Vec<LogMessage<'a>>,
     // NOTE(phlip9): rerun_if_changed and rerun_if_env_changed are intentionally
     // left out, as they are pointless in pure nix builds.
 }
 
+/// A `cargo::rustc-link-search=[KIND=]PATH` directive.
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub struct LinkSearch<'a> {
+    /// One of `dependency`, `crate`, `native`, `framework`, or `all`.
+    pub kind: Option<&'a str>,
+    pub path: &'a str,
+}
+
+/// A `cargo::rustc-link-lib=[KIND[:MODIFIERS]=]NAME[:RENAME]` directive.
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub struct LinkLib<'a> {
+    /// One of `static`, `dylib`, `framework`, or `raw-dylib`.
+    pub kind: Option<&'a str>,
+    /// Comma-separated modifiers, like `+whole-archive,-bundle`.
+    pub modifiers: Option<&'a str>,
+    pub name: &'a str,
+    pub rename: Option<&'a str>,
+}
+
+/// A single `cargo::warning` or `cargo::error` message.
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub struct LogMessage<'a> {
+    pub level: LogLevel,
+    pub message: &'a str,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum LogLevel {
+    Warning,
+    Error,
+}
+
+/// Which targets a `cargo::rustc-link-arg*` directive applies to.
+#[derive(Copy, Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub enum LinkArgTarget<'a> {
+    /// `cargo::rustc-link-arg=FLAG`
+    All,
+    /// `cargo::rustc-cdylib-link-arg=FLAG`
+    Cdylib,
+    /// `cargo::rustc-link-arg-bins=FLAG`
+    Bin,
+    /// `cargo::rustc-link-arg-bin=BIN=FLAG`
+    SingleBin(&'a str),
+    /// `cargo::rustc-link-arg-tests=FLAG`
+    Test,
+    /// `cargo::rustc-link-arg-benches=FLAG`
+    Bench,
+    /// `cargo::rustc-link-arg-examples=FLAG`
+    Example,
+}
+
 //
 // --- impl BuildOutput ---
 //
@@ -42,9 +85,14 @@ pub struct BuildOutput<'a> {
 impl<'a> BuildOutput<'a> {
     pub(crate) fn parse(input: &'a [u8]) -> Self {
         let mut out = Self {
+            library_paths: Vec::new(),
+            library_links: Vec::new(),
+            linker_args: Vec::new(),
             check_cfgs: Vec::new(),
             cfgs: Vec::new(),
             env: Vec::new(),
+            metadata: Vec::new(),
+            log_messages: Vec::new(),
         };
 
         for line in input.split(|b| *b == b'\n') {
@@ -53,35 +101,255 @@ impl<'a> BuildOutput<'a> {
                 None => continue,
             };
 
-            let _ = out.parse_directive(line);
+            if out.parse_log_directive(line).is_none() {
+                let _ = out.parse_directive(line);
+            }
         }
 
         out
     }
 
+    /// Returns `true` if the build script emitted any `cargo::error`.
+    pub(crate) fn has_errors(&self) -> bool {
+        self.log_messages
+            .iter()
+            .any(|msg| msg.level == LogLevel::Error)
+    }
+
+    /// Parse a `cargo::warning=MESSAGE` or `cargo::error=MESSAGE` line.
+    fn parse_log_directive(&mut self, line: &'a str) -> Option<()> {
+        let line = line.strip_prefix("cargo:")?;
+        let line = line.strip_prefix(':').unwrap_or(line);
+
+        let (level, message) = if let Some(x) = line.strip_prefix("warning=") {
+            (LogLevel::Warning, x)
+        } else if let Some(x) = line.strip_prefix("error=") {
+            (LogLevel::Error, x)
+        } else {
+            return None;
+        };
+
+        self.log_messages.push(LogMessage { level, message });
+        Some(())
+    }
+
     fn parse_directive(&mut self, line: &'a str) -> Option<()> {
         let line = line.strip_prefix("cargo:")?;
-        // TODO(phlip9): parse old format cargo:<metadata>=<value>
-        let line = match line.strip_prefix(':') {
-            Some(x) => x,
-            None => line,
+        // new format: `cargo::KEY=VALUE`, old format: `cargo:KEY=VALUE`
+        let (is_new_format, line) = match line.strip_prefix(':') {
+            Some(x) => (true, x),
+            None => (false, line),
         };
 
         let (key, value) = line.split_once('=')?;
 
         match key {
-            // TODO(phlip9): parse the other flags ofc
             "rustc-cfg" => self.cfgs.push(value),
             "rustc-check-cfg" => self.check_cfgs.push(value),
             "rustc-env" => {
                 let (env_key, env_value) = value.split_once('=')?;
                 self.env.push((env_key, env_value));
             }
+            "rustc-link-lib" => self.library_links.push(LinkLib::parse(value)),
+            "rustc-link-search" => {
+                self.library_paths.push(LinkSearch::parse(value))
+            }
+            "rustc-link-arg" => {
+                self.linker_args.push((LinkArgTarget::All, value))
+            }
+            "rustc-link-arg-cdylib" | "rustc-cdylib-link-arg" => {
+                self.linker_args.push((LinkArgTarget::Cdylib, value))
+            }
+            "rustc-link-arg-bins" => {
+                self.linker_args.push((LinkArgTarget::Bin, value))
+            }
+            "rustc-link-arg-bin" => {
+                let (bin, arg) = value.split_once('=').unwrap_or_else(|| {
+                    panic!(
+                        "build script: invalid `cargo::rustc-link-arg-bin` \
+                         directive, expected `BIN=FLAG`: '{value}'"
+                    )
+                });
+                self.linker_args.push((LinkArgTarget::SingleBin(bin), arg))
+            }
+            "rustc-link-arg-tests" => {
+                self.linker_args.push((LinkArgTarget::Test, value))
+            }
+            "rustc-link-arg-benches" => {
+                self.linker_args.push((LinkArgTarget::Bench, value))
+            }
+            "rustc-link-arg-examples" => {
+                self.linker_args.push((LinkArgTarget::Example, value))
+            }
+            "metadata" if is_new_format => {
+                let (meta_key, meta_value) = value.split_once('=')?;
+                self.metadata.push((meta_key, meta_value));
+            }
+            // In the old format, any non-reserved key is metadata.
+            _ if !is_new_format && !Self::RESERVED_KEYS.contains(&key) => {
+                self.metadata.push((key, value));
+            }
             _ => return None,
         }
 
         Some(())
     }
+
+    /// Old format `cargo:KEY=VALUE` keys that we don't handle here but that
+    /// are also definitely not metadata.
+    const RESERVED_KEYS: &'static [&'static str] = &[
+        "rerun-if-changed",
+        "rerun-if-env-changed",
+        "rustc-flags",
+        "warning",
+        "error",
+    ];
+}
+
+//
+// --- impl LogMessage ---
+//
+
+impl LogMessage<'_> {
+    /// Render the message like cargo does, e.g.,
+    /// `warning: foo@0.1.0: some message`.
+    pub(crate) fn render(&self, pkg_label: &str) -> String {
+        let level = match self.level {
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+        };
+        let message = self.message;
+        format!("{level}: {pkg_label}: {message}")
+    }
+}
+
+//
+// --- impl LinkSearch ---
+//
+
+impl<'a> LinkSearch<'a> {
+    const KINDS: &'static [&'static str] =
+        &["dependency", "crate", "native", "framework", "all"];
+
+    fn parse(value: &'a str) -> Self {
+        let (kind, path) = match value.split_once('=') {
+            Some((kind, path)) if Self::KINDS.contains(&kind) => {
+                (Some(kind), path)
+            }
+            // `=` is allowed in the path itself, as long as the prefix isn't
+            // a valid kind.
+            _ => (None, value),
+        };
+
+        if path.is_empty() {
+            panic!(
+                "build script: empty path in `cargo::rustc-link-search` \
+                 directive: '{value}'"
+            );
+        }
+
+        Self { kind, path }
+    }
+}
+
+impl fmt::Display for LinkSearch<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(kind) = self.kind {
+            f.write_str(kind)?;
+            f.write_str("=")?;
+        }
+        f.write_str(self.path)
+    }
+}
+
+//
+// --- impl LinkLib ---
+//
+
+impl<'a> LinkLib<'a> {
+    const KINDS: &'static [&'static str] =
+        &["static", "dylib", "framework", "raw-dylib"];
+    const MODIFIERS: &'static [&'static str] =
+        &["bundle", "verbatim", "whole-archive", "as-needed"];
+
+    fn parse(value: &'a str) -> Self {
+        let invalid = |reason: &str| -> ! {
+            panic!(
+                "build script: invalid `cargo::rustc-link-lib` directive: \
+                 {reason}: '{value}'"
+            )
+        };
+
+        // [KIND[:MODIFIERS]=]NAME[:RENAME]
+        let (kind_modifiers, name_rename) = match value.split_once('=') {
+            Some((kind_modifiers, name_rename)) => {
+                (Some(kind_modifiers), name_rename)
+            }
+            None => (None, value),
+        };
+
+        let (kind, modifiers) = match kind_modifiers {
+            Some(kind_modifiers) => match kind_modifiers.split_once(':') {
+                Some((kind, modifiers)) => (Some(kind), Some(modifiers)),
+                None => (Some(kind_modifiers), None),
+            },
+            None => (None, None),
+        };
+
+        if kind.is_some_and(|kind| !Self::KINDS.contains(&kind)) {
+            invalid("unknown link kind");
+        }
+
+        if let Some(modifiers) = modifiers {
+            for modifier in modifiers.split(',') {
+                let modifier = match modifier
+                    .strip_prefix('+')
+                    .or_else(|| modifier.strip_prefix('-'))
+                {
+                    Some(modifier) => modifier,
+                    None => invalid("link modifier must start with '+' or '-'"),
+                };
+                if !Self::MODIFIERS.contains(&modifier) {
+                    invalid("unknown link modifier");
+                }
+            }
+        }
+
+        let (name, rename) = match name_rename.split_once(':') {
+            Some((name, rename)) => (name, Some(rename)),
+            None => (name_rename, None),
+        };
+
+        if name.is_empty() {
+            invalid("empty library name");
+        }
+
+        Self {
+            kind,
+            modifiers,
+            name,
+            rename,
+        }
+    }
+}
+
+impl fmt::Display for LinkLib<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(kind) = self.kind {
+            f.write_str(kind)?;
+            if let Some(modifiers) = self.modifiers {
+                f.write_str(":")?;
+                f.write_str(modifiers)?;
+            }
+            f.write_str("=")?;
+        }
+        f.write_str(self.name)?;
+        if let Some(rename) = self.rename {
+            f.write_str(":")?;
+            f.write_str(rename)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -103,6 +371,9 @@ cargo:rustc-cfg=std_backtrace
 "#;
         let actual = BuildOutput::parse(anyhow_1_0_86.as_bytes());
         let expected = BuildOutput {
+            library_paths: vec![],
+            library_links: vec![],
+            linker_args: vec![],
             cfgs: vec!["std_backtrace"],
             check_cfgs: vec![
                 "cfg(anyhow_nightly_testing)",
@@ -114,6 +385,8 @@ cargo:rustc-cfg=std_backtrace
                 "cfg(std_backtrace)",
             ],
             env: vec![],
+            metadata: vec![],
+            log_messages: vec![],
         };
         assert_eq!(actual, expected);
 
@@ -121,4 +394,151 @@ cargo:rustc-cfg=std_backtrace
         let actual = BuildOutput::parse(anyhow_1_0_86_new.as_bytes());
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_parse_link() {
+        let libz_sys = r#"cargo:rustc-link-search=native=/nix/store/abc-zlib/lib
+cargo:rustc-link-lib=z
+cargo::rustc-link-lib=static:+whole-archive,-bundle=foo:bar
+cargo::rustc-link-search=/nix/store/def-out/lib=x
+cargo::rustc-link-arg=-Wl,--as-needed
+cargo::rustc-link-arg-bin=my-bin=-Wl,-z,stack-size=4096
+cargo::rustc-link-arg-tests=-lm
+cargo::rustc-cdylib-link-arg=-Wl,-soname,libfoo.so
+"#;
+        let actual = BuildOutput::parse(libz_sys.as_bytes());
+        let expected = BuildOutput {
+            library_paths: vec![
+                LinkSearch {
+                    kind: Some("native"),
+                    path: "/nix/store/abc-zlib/lib",
+                },
+                LinkSearch {
+                    kind: None,
+                    path: "/nix/store/def-out/lib=x",
+                },
+            ],
+            library_links: vec![
+                LinkLib {
+                    kind: None,
+                    modifiers: None,
+                    name: "z",
+                    rename: None,
+                },
+                LinkLib {
+                    kind: Some("static"),
+                    modifiers: Some("+whole-archive,-bundle"),
+                    name: "foo",
+                    rename: Some("bar"),
+                },
+            ],
+            linker_args: vec![
+                (LinkArgTarget::All, "-Wl,--as-needed"),
+                (LinkArgTarget::SingleBin("my-bin"), "-Wl,-z,stack-size=4096"),
+                (LinkArgTarget::Test, "-lm"),
+                (LinkArgTarget::Cdylib, "-Wl,-soname,libfoo.so"),
+            ],
+            cfgs: vec![],
+            check_cfgs: vec![],
+            env: vec![],
+            metadata: vec![],
+            log_messages: vec![],
+        };
+        assert_eq!(actual, expected);
+
+        let search = actual
+            .library_paths
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            search,
+            ["native=/nix/store/abc-zlib/lib", "/nix/store/def-out/lib=x"],
+        );
+        let links = actual
+            .library_links
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(links, ["z", "static:+whole-archive,-bundle=foo:bar"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_link_lib_bad_kind() {
+        BuildOutput::parse(b"cargo::rustc-link-lib=shared=foo\n");
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let libz_sys = r#"cargo:rerun-if-env-changed=LIBZ_SYS_STATIC
+cargo:rustc-link-lib=static=z
+cargo:root=/build/out
+cargo:include=/build/out/include
+cargo::metadata=conf=/build/out/lib/pkgconfig
+cargo::metadata=broken
+cargo::unknown=foo
+cargo:warning=hello
+"#;
+        let actual = BuildOutput::parse(libz_sys.as_bytes());
+        assert_eq!(
+            actual.metadata,
+            [
+                ("root", "/build/out"),
+                ("include", "/build/out/include"),
+                ("conf", "/build/out/lib/pkgconfig"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_parse_log_messages() {
+        let output = r#"cargo::warning=first warning
+cargo::warning=  continues here
+cargo::warning=
+cargo::warning=and here
+cargo:rustc-cfg=foo
+cargo:warning=second warning
+cargo::error=something broke:
+cargo::error=  details
+cargo::warning=third warning
+"#;
+        let actual = BuildOutput::parse(output.as_bytes());
+        assert_eq!(actual.cfgs, ["foo"]);
+        assert!(actual.metadata.is_empty());
+        let warning = |message| LogMessage {
+            level: LogLevel::Warning,
+            message,
+        };
+        let error = |message| LogMessage {
+            level: LogLevel::Error,
+            message,
+        };
+        assert_eq!(
+            actual.log_messages,
+            [
+                warning("first warning"),
+                warning("  continues here"),
+                warning(""),
+                warning("and here"),
+                warning("second warning"),
+                error("something broke:"),
+                error("  details"),
+                warning("third warning"),
+            ],
+        );
+        assert!(actual.has_errors());
+
+        assert_eq!(
+            actual.log_messages[0].render("foo@0.1.0"),
+            "warning: foo@0.1.0: first warning",
+        );
+        assert_eq!(
+            actual.log_messages[5].render("foo@0.1.0"),
+            "error: foo@0.1.0: something broke:",
+        );
+
+        let ok = BuildOutput::parse(b"cargo:warning=hello\n");
+        assert!(!ok.has_errors());
+    }
 }
@@ -1,13 +1,19 @@
 use std::{
     ffi::{OsStr, OsString},
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
-use crate::{run, semver};
+use crate::{
+    diagnostics::FailOn, json, repro, run, semver, test_runner,
+    unit_spec::UnitSpec,
+};
 use nargo_core::{env, logger, trace};
 
+/// Envs for building a single unit. Only used when we don't get a unit spec
+/// (see [`crate::unit_spec`]). Lists are space-separated.
 pub struct ArgsRaw {
+    pub(crate) bin_dep_paths: OsString,
     pub(crate) build_script_dep: OsString,
     pub(crate) crate_type: String,
     pub(crate) dep_crate_names: String,
@@ -15,11 +21,30 @@ pub struct ArgsRaw {
     pub(crate) dep_paths: OsString,
     pub(crate) edition: String,
     pub(crate) features: String,
+    pub(crate) harness: String,
+    pub(crate) host_ar: String,
+    pub(crate) host_cc: String,
+    pub(crate) host_triple: String,
+    pub(crate) is_workspace_pkg: String,
     pub(crate) kind: String,
+    pub(crate) link_dep_paths: OsString,
+    pub(crate) links: String,
+    pub(crate) links_dep_names: String,
+    pub(crate) links_dep_paths: OsString,
+    pub(crate) lint_args: String,
+    pub(crate) lint_fail_on: String,
+    pub(crate) lints: String,
     pub(crate) log: String,
+    pub(crate) lto_needs_object: String,
+    pub(crate) metadata: String,
+    pub(crate) mode: String,
     pub(crate) out: OsString,
+    pub(crate) pipelined: String,
     pub(crate) pkg_name: String,
+    pub(crate) profile: String,
     pub(crate) src: OsString,
+    pub(crate) target_ar: String,
+    pub(crate) target_cc: String,
     pub(crate) target_name: String,
     pub(crate) target_path: OsString,
     pub(crate) target_triple: String,
@@ -28,20 +53,84 @@ pub struct ArgsRaw {
 
 #[derive(Debug)] // TODO(phlip9): remove
 pub struct Args<'a> {
+    pub(crate) bin_deps: Vec<&'a Path>,
     pub(crate) build_script_dep: Option<&'a Path>,
+    /// For a `run-custom-build` unit, the out dir of the unit that compiled
+    /// our `build_script_build` binary. Only settable through a unit spec.
+    pub(crate) build_script_bin: Option<&'a Path>,
+    /// Extra `--cfg`s. Only settable through a unit spec.
+    pub(crate) cfgs: Vec<&'a str>,
     pub(crate) crate_type: &'a str,
     pub(crate) deps: Vec<Dep<'a>>,
     pub(crate) edition: &'a str,
+    /// Extra envs for `rustc`, `rustdoc`, and our build script. Only settable
+    /// through a unit spec.
+    pub(crate) envs: Vec<(&'a str, &'a str)>,
     pub(crate) features: &'a str,
+    pub(crate) harness: bool,
+    /// The `ar` for the host (cargo's host, nix's build platform).
+    pub(crate) host_ar: Option<&'a str>,
+    /// The C compiler for the host, which also links build scripts.
+    pub(crate) host_cc: Option<&'a str>,
+    /// The host target triple, where build scripts and proc-macros run.
+    pub(crate) host_triple: &'a str,
+    /// The incremental compilation cache dir. Only `nargo-build` sets this.
+    pub(crate) incremental: Option<&'a Path>,
+    /// Whether this package was selected to build, like `cargo build -p`.
+    /// Only settable through a unit spec.
+    pub(crate) is_primary_pkg: bool,
+    pub(crate) is_workspace_pkg: bool,
     pub(crate) kind: &'a str,
+    pub(crate) link_deps: Vec<&'a Path>,
+    pub(crate) links: Option<&'a str>,
+    pub(crate) links_deps: Vec<LinksDep<'a>>,
+    /// Extra lint args for `clippy-driver`, like `-D warnings`.
+    pub(crate) lint_args: Vec<&'a str>,
+    pub(crate) lint_fail_on: FailOn,
+    /// The package's `[lints]` table as `rustc` flags.
+    pub(crate) lints: Vec<&'a str>,
     pub(crate) log: logger::Level,
+    pub(crate) lto_needs_object: bool,
+    pub(crate) metadata: Option<&'a str>,
+    pub(crate) mode: &'a str,
     pub(crate) out: &'a Path,
+    /// Only settable through a unit spec.
+    pub(crate) package: PkgMetadata<'a>,
+    pub(crate) pipelined: bool,
     pub(crate) pkg_name: &'a str,
+    pub(crate) profile: &'a str,
+    /// A file with the `rustc --print cfg` output for `target_triple`, which
+    /// a `run-custom-build` unit passes to its build script as
+    /// `CARGO_CFG_<cfg>` envs. Only settable through a unit spec.
+    pub(crate) rustc_target_cfg: Option<&'a Path>,
     pub(crate) src: &'a Path,
+    /// The `ar` for `target_triple`.
+    pub(crate) target_ar: Option<&'a str>,
+    /// The C compiler for `target_triple`, which also links this unit.
+    pub(crate) target_cc: Option<&'a str>,
     pub(crate) target_name: &'a str,
     pub(crate) target_path: &'a Path,
+    /// The target triple this unit runs on. For a build script, the target
+    /// triple it configures the package for.
     pub(crate) target_triple: &'a str,
     pub(crate) version: semver::Version<'a>,
+    /// The source tree that a workspace package's `src` sits in, at the
+    /// package's path in the workspace. Only settable through a unit spec.
+    pub(crate) workspace_src: Option<&'a Path>,
+}
+
+/// The `[package]` metadata that the crate and its build script see as
+/// `CARGO_PKG_<FIELD>` envs.
+#[derive(Debug, Default)]
+pub struct PkgMetadata<'a> {
+    pub(crate) authors: Vec<&'a str>,
+    pub(crate) description: Option<&'a str>,
+    pub(crate) homepage: Option<&'a str>,
+    pub(crate) license: Option<&'a str>,
+    pub(crate) license_file: Option<&'a str>,
+    pub(crate) readme: Option<&'a str>,
+    pub(crate) repository: Option<&'a str>,
+    pub(crate) rust_version: Option<&'a str>,
 }
 
 #[derive(Debug)] // TODO(phlip9): remove
@@ -51,9 +140,67 @@ pub struct Dep<'a> {
     pub(crate) out: &'a Path,
 }
 
+/// A direct dependency with a `links` key, whose build script
+/// `cargo::metadata=KEY=VALUE` output we pass to our own build script as
+/// `DEP_<links>_<KEY>` envs.
+#[derive(Debug)] // TODO(phlip9): remove
+pub struct LinksDep<'a> {
+    pub(crate) links: &'a str,
+    pub(crate) build_script_out: &'a Path,
+}
+
+/// Envs for `nargo-rustc run-test`, which runs a test unit's test binaries.
+pub struct RunTestArgsRaw {
+    pub(crate) harness: String,
+    pub(crate) ignore_failures: String,
+    pub(crate) log: String,
+    pub(crate) out: OsString,
+    pub(crate) pkg_name: String,
+    pub(crate) src: OsString,
+    pub(crate) test_args: String,
+    pub(crate) test_unit: OsString,
+    pub(crate) version: String,
+}
+
+pub struct RunTestArgs<'a> {
+    pub(crate) harness: bool,
+    /// Write the test results and succeed, even if some tests failed.
+    pub(crate) ignore_failures: bool,
+    pub(crate) log: logger::Level,
+    pub(crate) out: &'a Path,
+    pub(crate) pkg_name: &'a str,
+    pub(crate) src: &'a Path,
+    /// Passed to each test binary. `TEST_ARGS` is a JSON list of strings, so
+    /// args can contain spaces.
+    pub(crate) test_args: Vec<String>,
+    /// The test unit's out dir, with the test binaries in `bin/`.
+    pub(crate) unit: &'a Path,
+    pub(crate) version: &'a str,
+}
+
+/// Envs for `nargo-rustc merge-test-results`, which merges the reports from
+/// many `run-test` runs.
+pub struct MergeTestResultsArgsRaw {
+    pub(crate) ignore_failures: String,
+    pub(crate) log: String,
+    pub(crate) name: String,
+    pub(crate) out: OsString,
+    pub(crate) test_results: OsString,
+}
+
+pub struct MergeTestResultsArgs<'a> {
+    pub(crate) ignore_failures: bool,
+    pub(crate) log: logger::Level,
+    pub(crate) name: &'a str,
+    pub(crate) out: &'a Path,
+    /// The `$out` dirs of each `run-test` run.
+    pub(crate) results: Vec<&'a Path>,
+}
+
 impl ArgsRaw {
     pub fn from_env() -> Self {
         Self {
+            bin_dep_paths: env::var_os("BIN_DEP_PATHS").unwrap(),
             build_script_dep: env::var_os("BUILD_SCRIPT_DEP").unwrap(),
             crate_type: env::var("CRATE_TYPE").unwrap(),
             dep_names: env::var("DEP_NAMES").unwrap(),
@@ -61,11 +208,30 @@ impl ArgsRaw {
             dep_paths: env::var_os("DEP_PATHS").unwrap(),
             edition: env::var("EDITION").unwrap(),
             features: env::var("FEATURES").unwrap(),
+            harness: env::var("HARNESS").unwrap(),
+            host_ar: env::var("HOST_AR").unwrap(),
+            host_cc: env::var("HOST_CC").unwrap(),
+            host_triple: env::var("HOST_TRIPLE").unwrap(),
+            is_workspace_pkg: env::var("IS_WORKSPACE_PKG").unwrap(),
             kind: env::var("KIND").unwrap(),
+            link_dep_paths: env::var_os("LINK_DEP_PATHS").unwrap(),
+            links: env::var("LINKS").unwrap(),
+            links_dep_names: env::var("LINKS_DEP_NAMES").unwrap(),
+            links_dep_paths: env::var_os("LINKS_DEP_PATHS").unwrap(),
+            lint_args: env::var("LINT_ARGS").unwrap(),
+            lint_fail_on: env::var("LINT_FAIL_ON").unwrap(),
+            lints: env::var("LINTS").unwrap(),
             log: env::var("LOG").unwrap(),
+            lto_needs_object: env::var("LTO_NEEDS_OBJECT").unwrap(),
+            metadata: env::var("METADATA").unwrap(),
+            mode: env::var("MODE").unwrap(),
             out: env::var_os("out").unwrap(),
+            pipelined: env::var("PIPELINED").unwrap(),
             pkg_name: env::var("PKG_NAME").unwrap(),
+            profile: env::var("PROFILE").unwrap(),
             src: env::var_os("src").unwrap(),
+            target_ar: env::var("TARGET_AR").unwrap(),
+            target_cc: env::var("TARGET_CC").unwrap(),
             target_name: env::var("TARGET_NAME").unwrap(),
             target_path: env::var_os("TARGET_PATH").unwrap(),
             target_triple: env::var("TARGET_TRIPLE").unwrap(),
@@ -81,6 +247,7 @@ impl ArgsRaw {
     /// spawning any threads.
     pub unsafe fn remove_nargo_envs() {
         const REMOVE_ENVS: &[&str] = &[
+            "BIN_DEP_PATHS",
             "BUILD_SCRIPT_DEP",
             "CRATE_TYPE",
             "DEP_NAMES",
@@ -88,11 +255,30 @@ impl ArgsRaw {
             "DEP_PATHS",
             "EDITION",
             "FEATURES",
+            "HARNESS",
+            "HOST_AR",
+            "HOST_CC",
+            "HOST_TRIPLE",
+            "IS_WORKSPACE_PKG",
             "KIND",
+            "LINK_DEP_PATHS",
+            "LINKS",
+            "LINKS_DEP_NAMES",
+            "LINKS_DEP_PATHS",
+            "LINT_ARGS",
+            "LINT_FAIL_ON",
+            "LINTS",
             "LOG",
+            "LTO_NEEDS_OBJECT",
+            "METADATA",
+            "MODE",
             "out",
+            "PIPELINED",
             "PKG_NAME",
+            "PROFILE",
             "src",
+            "TARGET_AR",
+            "TARGET_CC",
             "TARGET_NAME",
             "TARGET_PATH",
             "TARGET_TRIPLE",
@@ -107,6 +293,60 @@ impl ArgsRaw {
     }
 }
 
+impl RunTestArgsRaw {
+    pub fn from_env() -> Self {
+        Self {
+            harness: env::var("HARNESS").unwrap(),
+            ignore_failures: env::var("IGNORE_FAILURES").unwrap(),
+            log: env::var("LOG").unwrap(),
+            out: env::var_os("out").unwrap(),
+            pkg_name: env::var("PKG_NAME").unwrap(),
+            src: env::var_os("src").unwrap(),
+            test_args: env::var("TEST_ARGS").unwrap(),
+            test_unit: env::var_os("TEST_UNIT").unwrap(),
+            version: env::var("version").unwrap(),
+        }
+    }
+
+    /// Clear all nargo-specific envs, so the test binaries don't see them.
+    ///
+    /// # Safety
+    ///
+    /// [`std::env::remove_var`] is not thread-safe. You must call this before
+    /// spawning any threads.
+    pub unsafe fn remove_nargo_envs() {
+        const REMOVE_ENVS: &[&str] = &[
+            "HARNESS",
+            "IGNORE_FAILURES",
+            "LOG",
+            "out",
+            "PKG_NAME",
+            "src",
+            "TEST_ARGS",
+            "TEST_UNIT",
+            "version",
+        ];
+
+        for env in REMOVE_ENVS {
+            unsafe {
+                std::env::remove_var(env);
+            }
+        }
+    }
+}
+
+impl MergeTestResultsArgsRaw {
+    pub fn from_env() -> Self {
+        Self {
+            ignore_failures: env::var("IGNORE_FAILURES").unwrap(),
+            log: env::var("LOG").unwrap(),
+            name: env::var("name").unwrap(),
+            out: env::var_os("out").unwrap(),
+            test_results: env::var_os("TEST_RESULTS").unwrap(),
+        }
+    }
+}
+
 impl<'a> Args<'a> {
     pub fn from_raw(args: &'a ArgsRaw) -> Self {
         let build_script_dep = if args.build_script_dep.is_empty() {
@@ -122,7 +362,10 @@ impl<'a> Args<'a> {
         let log = logger::Level::from_str(&args.log).expect("invalid LOG env");
 
         Self {
+            bin_deps: parse_paths(&args.bin_dep_paths),
             build_script_dep,
+            build_script_bin: None,
+            cfgs: Vec::new(),
             crate_type: &args.crate_type,
             deps: parse_deps(
                 &args.dep_names,
@@ -130,16 +373,134 @@ impl<'a> Args<'a> {
                 &args.dep_paths,
             ),
             edition: &args.edition,
+            envs: Vec::new(),
             features: &args.features,
+            harness: !args.harness.is_empty(),
+            host_ar: (!args.host_ar.is_empty())
+                .then_some(args.host_ar.as_str()),
+            host_cc: (!args.host_cc.is_empty())
+                .then_some(args.host_cc.as_str()),
+            host_triple: &args.host_triple,
+            incremental: None,
+            is_primary_pkg: false,
+            // nix serializes `true` as "1" and `false` as ""
+            is_workspace_pkg: !args.is_workspace_pkg.is_empty(),
             kind: &args.kind,
+            link_deps: parse_paths(&args.link_dep_paths),
+            links: (!args.links.is_empty()).then_some(args.links.as_str()),
+            links_deps: parse_links_deps(
+                &args.links_dep_names,
+                &args.links_dep_paths,
+            ),
+            lint_args: args.lint_args.split_ascii_whitespace().collect(),
+            lint_fail_on: FailOn::from_str(&args.lint_fail_on)
+                .expect("invalid LINT_FAIL_ON env"),
+            lints: args.lints.split_ascii_whitespace().collect(),
             log,
+            lto_needs_object: !args.lto_needs_object.is_empty(),
+            metadata: (!args.metadata.is_empty())
+                .then_some(args.metadata.as_str()),
+            mode: &args.mode,
             out: Path::new(&args.out),
+            package: PkgMetadata::default(),
+            pipelined: !args.pipelined.is_empty(),
             pkg_name: &args.pkg_name,
+            profile: &args.profile,
+            rustc_target_cfg: None,
             src: Path::new(&args.src),
+            target_ar: (!args.target_ar.is_empty())
+                .then_some(args.target_ar.as_str()),
+            target_cc: (!args.target_cc.is_empty())
+                .then_some(args.target_cc.as_str()),
             target_name: &args.target_name,
             target_path: Path::new(&args.target_path),
             target_triple: &args.target_triple,
             version,
+            workspace_src: None,
+        }
+    }
+
+    pub fn from_unit_spec(spec: &'a UnitSpec) -> Self {
+        let version = semver::Version::from_str(&spec.version)
+            .expect("unit spec `version`");
+
+        Self {
+            bin_deps: spec.bin_deps.iter().map(PathBuf::as_path).collect(),
+            build_script_dep: spec.build_script_dep.as_deref(),
+            build_script_bin: spec.build_script_bin.as_deref(),
+            cfgs: spec.cfgs.iter().map(String::as_str).collect(),
+            crate_type: &spec.crate_type,
+            deps: spec
+                .deps
+                .iter()
+                .map(|dep| Dep {
+                    crate_name: &dep.crate_name,
+                    dep_name: &dep.dep_name,
+                    out: &dep.out,
+                })
+                .collect(),
+            edition: &spec.edition,
+            envs: spec
+                .envs
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+            features: &spec.features,
+            harness: spec.harness,
+            host_ar: spec.host_ar.as_deref(),
+            host_cc: spec.host_cc.as_deref(),
+            host_triple: &spec.host_triple,
+            incremental: spec.incremental.as_deref(),
+            is_primary_pkg: spec.is_primary_pkg,
+            is_workspace_pkg: spec.is_workspace_pkg,
+            kind: &spec.kind,
+            link_deps: spec.link_deps.iter().map(PathBuf::as_path).collect(),
+            links: spec.links.as_deref(),
+            links_deps: spec
+                .links_deps
+                .iter()
+                .map(|dep| LinksDep {
+                    links: &dep.links,
+                    build_script_out: &dep.out,
+                })
+                .collect(),
+            lint_args: spec.lint_args.iter().map(String::as_str).collect(),
+            lint_fail_on: FailOn::from_str(&spec.lint_fail_on)
+                .expect("invalid unit spec `lint_fail_on`"),
+            lints: spec.lints.iter().map(String::as_str).collect(),
+            log: logger::Level::from_str(&spec.log)
+                .expect("invalid unit spec `log`"),
+            lto_needs_object: spec.lto_needs_object,
+            metadata: spec.metadata.as_deref(),
+            mode: &spec.mode,
+            out: &spec.out,
+            package: PkgMetadata {
+                authors: spec
+                    .package
+                    .authors
+                    .iter()
+                    .map(String::as_str)
+                    .collect(),
+                description: spec.package.description.as_deref(),
+                homepage: spec.package.homepage.as_deref(),
+                license: spec.package.license.as_deref(),
+                license_file: spec.package.license_file.as_deref(),
+                readme: spec.package.readme.as_deref(),
+                repository: spec.package.repository.as_deref(),
+                rust_version: spec.package.rust_version.as_deref(),
+            },
+            pipelined: spec.pipelined,
+            pkg_name: &spec.pkg_name,
+            profile: &spec.profile,
+            rustc_target_cfg: spec.rustc_target_cfg.as_deref(),
+            src: &spec.src,
+            target_ar: spec.target_ar.as_deref(),
+            target_cc: spec.target_cc.as_deref(),
+            target_name: &spec.target_name,
+            target_path: &spec.target_path,
+            target_triple: &spec.target_triple,
+            version,
+            workspace_src: spec.workspace_src.as_deref(),
         }
     }
 
@@ -151,6 +512,8 @@ impl<'a> Args<'a> {
 
         trace!("args: {self:#?}");
 
+        repro::init(&self.label(), self.out);
+
         run::BuildContext::from_args(self).run()
     }
 
@@ -162,6 +525,49 @@ impl<'a> Args<'a> {
     }
 }
 
+impl<'a> RunTestArgs<'a> {
+    pub fn from_raw(args: &'a RunTestArgsRaw) -> Self {
+        Self {
+            // nix serializes `true` as "1" and `false` as ""
+            harness: !args.harness.is_empty(),
+            ignore_failures: !args.ignore_failures.is_empty(),
+            log: logger::Level::from_str(&args.log).expect("invalid LOG env"),
+            out: Path::new(&args.out),
+            pkg_name: &args.pkg_name,
+            src: Path::new(&args.src),
+            test_args: parse_test_args(&args.test_args),
+            unit: Path::new(&args.test_unit),
+            version: &args.version,
+        }
+    }
+
+    pub fn run(self) {
+        logger::set_level(self.log);
+        test_runner::run(&self)
+    }
+
+    pub fn label(&self) -> String {
+        format!("{}-{}-run-test", self.pkg_name, self.version)
+    }
+}
+
+impl<'a> MergeTestResultsArgs<'a> {
+    pub fn from_raw(args: &'a MergeTestResultsArgsRaw) -> Self {
+        Self {
+            ignore_failures: !args.ignore_failures.is_empty(),
+            log: logger::Level::from_str(&args.log).expect("invalid LOG env"),
+            name: &args.name,
+            out: Path::new(&args.out),
+            results: parse_paths(&args.test_results),
+        }
+    }
+
+    pub fn run(self) {
+        logger::set_level(self.log);
+        test_runner::merge(&self)
+    }
+}
+
 fn parse_deps<'a>(
     dep_names: &'a str,
     dep_crate_names: &'a str,
@@ -208,6 +614,86 @@ fn parse_deps<'a>(
     }
 }
 
+fn parse_test_args(test_args: &str) -> Vec<String> {
+    let args = match json::parse(test_args) {
+        Some(json::Value::Array(args)) => args,
+        _ => panic!("TEST_ARGS is not a JSON list: {test_args:?}"),
+    };
+    args.into_iter()
+        .map(|arg| match arg {
+            json::Value::Str(arg) => arg,
+            _ => panic!("TEST_ARGS is not a list of strings: {test_args:?}"),
+        })
+        .collect()
+}
+
+fn parse_paths(paths: &OsStr) -> Vec<&Path> {
+    if paths.is_empty() {
+        return Vec::new();
+    }
+
+    paths
+        .as_encoded_bytes()
+        .split(|b| *b == b' ')
+        .map(|path| {
+            #[cfg(unix)]
+            let path =
+                <OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(path);
+
+            // Non-unix `OsStr`s aren't raw bytes, so only UTF-8 paths can
+            // round-trip through the space-separated env.
+            #[cfg(not(unix))]
+            let path = OsStr::new(
+                std::str::from_utf8(path).expect("non-UTF-8 path in path list"),
+            );
+
+            Path::new(path)
+        })
+        .collect()
+}
+
+fn parse_links_deps<'a>(
+    links_dep_names: &'a str,
+    links_dep_paths: &'a OsStr,
+) -> Vec<LinksDep<'a>> {
+    if links_dep_names.is_empty() && links_dep_paths.is_empty() {
+        return Vec::new();
+    }
+
+    assert!(!links_dep_names.is_empty() && !links_dep_paths.is_empty());
+
+    let mut links_dep_names = links_dep_names.split(' ');
+    let mut links_dep_paths =
+        links_dep_paths.as_encoded_bytes().split(|b| *b == b' ');
+
+    let mut links_deps = Vec::new();
+
+    loop {
+        match (links_dep_names.next(), links_dep_paths.next()) {
+            (Some(links), Some(path)) => {
+                #[cfg(unix)]
+                let path =
+                    <OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(path);
+
+                // Non-unix `OsStr`s aren't raw bytes, so only UTF-8 paths can
+                // round-trip through the space-separated env.
+                #[cfg(not(unix))]
+                let path = OsStr::new(
+                    std::str::from_utf8(path)
+                        .expect("LINKS_DEP_PATHS contains a non-UTF-8 path"),
+                );
+
+                links_deps.push(LinksDep {
+                    links,
+                    build_script_out: Path::new(path),
+                });
+            }
+            (None, None) => break links_deps,
+            _ => panic!("LINKS_DEP_NAMES and LINKS_DEP_PATHS are uneven"),
+        };
+    }
+}
+
 /// (Linux) Improve perf trace readability by setting the process `comm` value
 /// to include the package name and target kind.
 fn set_process_perf_label(args: &Args<'_>) {
@@ -223,3 +709,17 @@ fn set_process_perf_label(args: &Args<'_>) {
         std::fs::write("/proc/self/comm", comm.as_bytes()).unwrap();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_test_args() {
+        assert_eq!(parse_test_args("[]"), Vec::<String>::new());
+        assert_eq!(
+            parse_test_args(r#"["--skip","foo bar","--exact"]"#),
+            ["--skip", "foo bar", "--exact"],
+        );
+    }
+}
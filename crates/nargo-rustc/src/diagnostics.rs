@@ -0,0 +1,286 @@
+//! Collect `rustc`'s (or `clippy-driver`'s) `--error-format=json` diagnostics.
+//!
+//! We replay each diagnostic's `rendered` text to stderr, so the log looks the
+//! same as with `--error-format=human`, and write the raw diagnostics to
+//! `$out/diagnostics.json` as a JSON array, one `rustc` diagnostic per line.
+
+use std::{path::Path, str::FromStr};
+
+use nargo_core::{error::Error, format_err, fs};
+
+use crate::json;
+
+pub(crate) const DIAGNOSTICS_JSON: &str = "diagnostics.json";
+
+/// When a unit should fail, based on the diagnostics it emitted. Only
+/// workspace packages ever emit warnings, since we build all other packages
+/// with `--cap-lints=allow`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FailOn {
+    /// Fail if there are any errors, like `cargo build`. The default.
+    Error,
+    /// Also fail if there are any warnings, like `-D warnings`, but without
+    /// changing the `rustc` args.
+    Warning,
+    /// Never fail because of diagnostics. `Clippy` units also ignore errors,
+    /// so we can always collect the diagnostics. Dependents of a unit that
+    /// didn't compile will still fail to find it.
+    Never,
+}
+
+/// The diagnostics `rustc` emitted while compiling a unit.
+pub(crate) struct Diagnostics {
+    /// The raw JSON diagnostics, one per line.
+    json: String,
+    /// The diagnostics since the first one that looks like a summary (see
+    /// [`looks_like_summary`]), which we can only tell apart from a real
+    /// diagnostic once `rustc` is done. Each with whether it looks like one.
+    pending: Vec<(String, bool)>,
+    pub errors: u32,
+    pub warnings: u32,
+}
+
+impl FailOn {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+            Self::Never => "never",
+        }
+    }
+}
+
+impl FromStr for FailOn {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "error" => Ok(Self::Error),
+            "warning" => Ok(Self::Warning),
+            "never" => Ok(Self::Never),
+            _ => Err(format_err!("invalid lint fail policy: '{s}'")),
+        }
+    }
+}
+
+impl Diagnostics {
+    pub(crate) fn new() -> Self {
+        Self {
+            json: String::new(),
+            pending: Vec::new(),
+            errors: 0,
+            warnings: 0,
+        }
+    }
+
+    /// Handle a single line of `rustc --error-format=json` stderr. Replays a
+    /// diagnostic's rendered text to stderr and records it.
+    ///
+    /// Returns `false` if the line isn't a diagnostic (e.g., an artifact
+    /// notification or plain text), so the caller can handle it.
+    pub(crate) fn push_line(&mut self, line: &str) -> bool {
+        if !line.starts_with('{') {
+            return false;
+        }
+        if json::top_level_str_field(line, "$message_type").as_deref()
+            != Some("diagnostic")
+        {
+            return false;
+        }
+
+        if let Some(rendered) = json::top_level_str_field(line, "rendered") {
+            eprint!("{rendered}");
+        }
+
+        let level = json::top_level_str_field(line, "level");
+        let is_candidate = looks_like_summary(line, level.as_deref());
+        // The "For more information about this error" note comes after the
+        // summary.
+        let is_note = level.as_deref() == Some("failure-note");
+        if is_candidate || (is_note && !self.pending.is_empty()) {
+            self.pending.push((line.to_owned(), is_candidate));
+            return true;
+        }
+
+        // Anything else means the pending diagnostics weren't summaries.
+        for (pending, _) in std::mem::take(&mut self.pending) {
+            self.record(&pending);
+        }
+        self.record(line);
+        true
+    }
+
+    /// Record the pending diagnostics once we're done reading `rustc`'s
+    /// stderr. If we read it all, the last diagnostic that looks like a
+    /// summary is one, and we drop it, as long as it had some previous
+    /// diagnostics of its level to sum up. Otherwise (ex: a lone
+    /// "unknown codegen option" error), it's a real diagnostic.
+    pub(crate) fn finish(&mut self, reached_eof: bool) {
+        let pending = std::mem::take(&mut self.pending);
+        let summary = pending
+            .iter()
+            .rposition(|(_, is_candidate)| *is_candidate)
+            .filter(|_| reached_eof);
+        for (idx, (line, _)) in pending.iter().enumerate() {
+            if Some(idx) != summary {
+                self.record(line);
+                continue;
+            }
+            let previous =
+                match json::top_level_str_field(line, "level").as_deref() {
+                    Some("error") => self.errors,
+                    _ => self.warnings,
+                };
+            if previous == 0 {
+                self.record(line);
+            }
+        }
+    }
+
+    fn record(&mut self, line: &str) {
+        match json::top_level_str_field(line, "level").as_deref() {
+            Some(level) if level.starts_with("error") => self.errors += 1,
+            Some("warning") => self.warnings += 1,
+            _ => (),
+        }
+
+        self.json
+            .push_str(if self.json.is_empty() { "[\n" } else { ",\n" });
+        self.json.push_str(line);
+    }
+
+    /// Write the recorded diagnostics to `$out/diagnostics.json`.
+    pub(crate) fn write(&self, out: &Path) {
+        let json = if self.json.is_empty() {
+            "[]\n".to_owned()
+        } else {
+            format!("{}\n]\n", self.json)
+        };
+        fs::write_file(&out.join(DIAGNOSTICS_JSON), json.as_bytes())
+            .expect("failed to write $out/diagnostics.json");
+    }
+
+    /// Returns an error message if these diagnostics should fail the unit
+    /// under the `fail_on` policy.
+    pub(crate) fn check(&self, fail_on: FailOn) -> Option<String> {
+        let fails = match fail_on {
+            FailOn::Error => self.errors != 0,
+            FailOn::Warning => self.errors != 0 || self.warnings != 0,
+            FailOn::Never => false,
+        };
+        fails.then(|| {
+            format!(
+                "{} error(s) and {} warning(s) (fail on: {})",
+                self.errors,
+                self.warnings,
+                fail_on.as_str(),
+            )
+        })
+    }
+}
+
+/// Returns `true` for diagnostics shaped like the "aborting due to 2 previous
+/// errors" and "3 warnings emitted" summaries, which aren't real diagnostics.
+/// They have no code, spans, or children, but neither do some real errors,
+/// like a bad `-C` option, so [`Diagnostics::finish`] makes the final call.
+fn looks_like_summary(line: &str, level: Option<&str>) -> bool {
+    if !matches!(level, Some("error" | "warning")) {
+        return false;
+    }
+    let fields = match json::parse(line) {
+        Some(json::Value::Object(fields)) => fields,
+        _ => return false,
+    };
+    let field = |key: &str| {
+        fields
+            .iter()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value)
+    };
+    let is_empty = |key: &str| matches!(field(key), Some(json::Value::Array(values)) if values.is_empty());
+    matches!(field("code"), None | Some(json::Value::Null))
+        && is_empty("spans")
+        && is_empty("children")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_push_line() {
+        let stderr = [
+            r#"{"$message_type":"diagnostic","message":"unused variable: `x`","code":{"code":"unused_variables","explanation":null},"level":"warning","spans":[],"children":[],"rendered":"warning: unused variable: `x`\n"}"#,
+            r#"{"$message_type":"diagnostic","message":"this looks like you are swapping `a` and `b`","code":{"code":"clippy::almost_swapped","explanation":null},"level":"error","spans":[],"children":[],"rendered":"error: this looks like you are swapping `a` and `b`\n"}"#,
+            r#"{"$message_type":"artifact","artifact":"/build/libfoo.rmeta","emit":"metadata"}"#,
+            r#"{"$message_type":"diagnostic","message":"linking with `cc` failed: exit status: 1","code":null,"level":"error","spans":[],"children":[{"message":"undefined reference to `foo'","code":null,"level":"note","spans":[],"children":[],"rendered":null}],"rendered":"error: linking with `cc` failed: exit status: 1\n"}"#,
+            r#"{"$message_type":"diagnostic","message":"1 avertissement émis","code":null,"level":"warning","spans":[],"children":[],"rendered":"warning: 1 avertissement émis\n"}"#,
+            "thread 'rustc' panicked",
+        ];
+
+        let mut diagnostics = Diagnostics::new();
+        let handled = stderr
+            .iter()
+            .map(|line| diagnostics.push_line(line))
+            .collect::<Vec<_>>();
+        diagnostics.finish(true);
+        assert_eq!(handled, [true, true, false, true, true, false]);
+        assert_eq!((diagnostics.errors, diagnostics.warnings), (2, 1));
+        assert_eq!(
+            diagnostics.json,
+            format!("[\n{},\n{},\n{}", stderr[0], stderr[1], stderr[3])
+        );
+
+        assert!(diagnostics.check(FailOn::Error).is_some());
+        assert!(diagnostics.check(FailOn::Never).is_none());
+
+        diagnostics.errors = 0;
+        assert!(diagnostics.check(FailOn::Error).is_none());
+        assert!(diagnostics.check(FailOn::Warning).is_some());
+    }
+
+    #[test]
+    fn test_finish() {
+        let error = r#"{"$message_type":"diagnostic","message":"unknown codegen option: `foo`","code":null,"level":"error","spans":[],"children":[],"rendered":"error: unknown codegen option: `foo`\n\n"}"#;
+        let summary = r#"{"$message_type":"diagnostic","message":"aborting due to 1 previous error","code":null,"level":"error","spans":[],"children":[],"rendered":"error: aborting due to 1 previous error\n\n"}"#;
+        let note = r#"{"$message_type":"diagnostic","message":"For more information about this error, try `rustc --explain E0308`.","code":null,"level":"failure-note","spans":[],"children":[],"rendered":"For more information about this error, try `rustc --explain E0308`.\n"}"#;
+        let warning = r#"{"$message_type":"diagnostic","message":"1 warning emitted","code":null,"level":"warning","spans":[],"children":[],"rendered":"warning: 1 warning emitted\n\n"}"#;
+
+        // A lone spanless, codeless error is a real one.
+        let mut diagnostics = Diagnostics::new();
+        assert!(diagnostics.push_line(error));
+        diagnostics.finish(true);
+        assert_eq!((diagnostics.errors, diagnostics.warnings), (1, 0));
+        assert_eq!(diagnostics.json, format!("[\n{error}"));
+
+        // The summary is dropped, but not the note after it.
+        let mut diagnostics = Diagnostics::new();
+        for line in [error, summary, note] {
+            assert!(diagnostics.push_line(line));
+        }
+        diagnostics.finish(true);
+        assert_eq!((diagnostics.errors, diagnostics.warnings), (1, 0));
+        assert_eq!(diagnostics.json, format!("[\n{error},\n{note}"));
+
+        // A summary needs something to sum up.
+        let mut diagnostics = Diagnostics::new();
+        assert!(diagnostics.push_line(warning));
+        diagnostics.finish(true);
+        assert_eq!((diagnostics.errors, diagnostics.warnings), (0, 1));
+
+        // Only the last one can be the summary.
+        let mut diagnostics = Diagnostics::new();
+        for line in [error, error, summary] {
+            assert!(diagnostics.push_line(line));
+        }
+        diagnostics.finish(true);
+        assert_eq!((diagnostics.errors, diagnostics.warnings), (2, 0));
+
+        // We can't tell without the end of stderr, so we keep everything.
+        let mut diagnostics = Diagnostics::new();
+        for line in [error, summary] {
+            assert!(diagnostics.push_line(line));
+        }
+        diagnostics.finish(false);
+        assert_eq!((diagnostics.errors, diagnostics.warnings), (2, 0));
+    }
+}
@@ -0,0 +1,378 @@
+//! Just enough JSON to pick fields out of `rustc`'s `--error-format=json`
+//! messages (and libtest's `--format json` events), to read unit specs, and to
+//! write our own test reports, since nargo-rustc can't depend on `serde_json`.
+
+/// A fully parsed JSON value. Objects keep their fields in order.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    /// The number's original text, e.g. `256` or `0.5`.
+    Number(String),
+    Str(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+/// Parse a complete JSON document. Returns `None` if `json` isn't valid JSON.
+pub(crate) fn parse(json: &str) -> Option<Value> {
+    let mut p = Parser {
+        s: json.as_bytes(),
+        i: 0,
+    };
+    p.skip_ws();
+    let value = p.parse_value()?;
+    p.skip_ws();
+    (p.i == p.s.len()).then_some(value)
+}
+
+/// Returns the string value of the top-level `key` field in the JSON object
+/// `json`. Returns `None` if `json` isn't an object, the field is missing, or
+/// its value isn't a string (e.g., `null`).
+pub(crate) fn top_level_str_field(json: &str, key: &str) -> Option<String> {
+    let mut p = Parser::seek_top_level_field(json, key)?;
+    if p.peek()? == b'"' {
+        p.parse_str()
+    } else {
+        None
+    }
+}
+
+/// Returns the numeric value of the top-level `key` field in the JSON object
+/// `json`. Returns `None` if the field is missing or isn't a number.
+pub(crate) fn top_level_f64_field(json: &str, key: &str) -> Option<f64> {
+    let mut p = Parser::seek_top_level_field(json, key)?;
+    let start = p.i;
+    p.skip_value()?;
+    let value = std::str::from_utf8(&p.s[start..p.i]).ok()?;
+    value.trim_end().parse::<f64>().ok()
+}
+
+/// Push `s` onto `out` as a quoted and escaped JSON string.
+pub(crate) fn push_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Returns a parser positioned at the start of the top-level `key` field's
+    /// value in the JSON object `json`.
+    fn seek_top_level_field(json: &'a str, key: &str) -> Option<Self> {
+        let mut p = Parser {
+            s: json.as_bytes(),
+            i: 0,
+        };
+
+        p.skip_ws();
+        p.eat(b'{')?;
+        p.skip_ws();
+        if p.eat(b'}').is_some() {
+            return None;
+        }
+
+        loop {
+            p.skip_ws();
+            let field = p.parse_str()?;
+            p.skip_ws();
+            p.eat(b':')?;
+            p.skip_ws();
+
+            if field == key {
+                return Some(p);
+            }
+            p.skip_value()?;
+
+            p.skip_ws();
+            match p.next()? {
+                b',' => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.i).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.i += 1;
+        Some(b)
+    }
+
+    fn eat(&mut self, b: u8) -> Option<()> {
+        if self.peek()? == b {
+            self.i += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.i += 1;
+        }
+    }
+
+    fn eat_literal(&mut self, literal: &str) -> Option<()> {
+        if self.s[self.i..].starts_with(literal.as_bytes()) {
+            self.i += literal.len();
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Parse any JSON value: string, number, literal, array, or object.
+    fn parse_value(&mut self) -> Option<Value> {
+        match self.peek()? {
+            b'"' => self.parse_str().map(Value::Str),
+            b'{' => {
+                self.i += 1;
+                let mut fields = Vec::new();
+                self.skip_ws();
+                if self.eat(b'}').is_some() {
+                    return Some(Value::Object(fields));
+                }
+                loop {
+                    self.skip_ws();
+                    let key = self.parse_str()?;
+                    self.skip_ws();
+                    self.eat(b':')?;
+                    self.skip_ws();
+                    fields.push((key, self.parse_value()?));
+                    self.skip_ws();
+                    match self.next()? {
+                        b',' => continue,
+                        b'}' => return Some(Value::Object(fields)),
+                        _ => return None,
+                    }
+                }
+            }
+            b'[' => {
+                self.i += 1;
+                let mut values = Vec::new();
+                self.skip_ws();
+                if self.eat(b']').is_some() {
+                    return Some(Value::Array(values));
+                }
+                loop {
+                    self.skip_ws();
+                    values.push(self.parse_value()?);
+                    self.skip_ws();
+                    match self.next()? {
+                        b',' => continue,
+                        b']' => return Some(Value::Array(values)),
+                        _ => return None,
+                    }
+                }
+            }
+            b't' => self.eat_literal("true").map(|()| Value::Bool(true)),
+            b'f' => self.eat_literal("false").map(|()| Value::Bool(false)),
+            b'n' => self.eat_literal("null").map(|()| Value::Null),
+            _ => {
+                let start = self.i;
+                while matches!(
+                    self.peek(),
+                    Some(b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')
+                ) {
+                    self.i += 1;
+                }
+                let number =
+                    std::str::from_utf8(&self.s[start..self.i]).ok()?;
+                number.parse::<f64>().ok()?;
+                Some(Value::Number(number.to_owned()))
+            }
+        }
+    }
+
+    /// Skip over any JSON value: string, number, literal, array, or object.
+    fn skip_value(&mut self) -> Option<()> {
+        match self.peek()? {
+            b'"' => self.parse_str().map(|_| ()),
+            b'{' | b'[' => {
+                let mut depth = 0_usize;
+                loop {
+                    match self.peek()? {
+                        b'"' => {
+                            self.parse_str()?;
+                            continue;
+                        }
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                self.i += 1;
+                                return Some(());
+                            }
+                        }
+                        _ => (),
+                    }
+                    self.i += 1;
+                }
+            }
+            _ => {
+                while !matches!(self.peek()?, b',' | b'}' | b']') {
+                    self.i += 1;
+                }
+                Some(())
+            }
+        }
+    }
+
+    /// Parse a JSON string, unescaping it.
+    fn parse_str(&mut self) -> Option<String> {
+        self.eat(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => match self.next()? {
+                    b'"' => out.push(b'"'),
+                    b'\\' => out.push(b'\\'),
+                    b'/' => out.push(b'/'),
+                    b'b' => out.push(0x08),
+                    b'f' => out.push(0x0c),
+                    b'n' => out.push(b'\n'),
+                    b'r' => out.push(b'\r'),
+                    b't' => out.push(b'\t'),
+                    b'u' => {
+                        let c = self.parse_unicode_escape()?;
+                        let mut buf = [0u8; 4];
+                        out.extend_from_slice(
+                            c.encode_utf8(&mut buf).as_bytes(),
+                        );
+                    }
+                    _ => return None,
+                },
+                b => out.push(b),
+            }
+        }
+    }
+
+    /// Parse the `XXXX` after a `\u`, including a trailing `\uXXXX` low
+    /// surrogate if needed.
+    fn parse_unicode_escape(&mut self) -> Option<char> {
+        let hi = self.parse_hex4()?;
+        if !(0xD800..0xDC00).contains(&hi) {
+            return char::from_u32(hi);
+        }
+        self.eat(b'\\')?;
+        self.eat(b'u')?;
+        let lo = self.parse_hex4()?;
+        if !(0xDC00..0xE000).contains(&lo) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00))
+    }
+
+    fn parse_hex4(&mut self) -> Option<u32> {
+        let hex = self.s.get(self.i..self.i + 4)?;
+        let hex = std::str::from_utf8(hex).ok()?;
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        self.i += 4;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_top_level_str_field() {
+        let artifact =
+            r#"{"artifact":"/build/libfoo-1234.rmeta","emit":"metadata"}"#;
+        assert_eq!(
+            top_level_str_field(artifact, "artifact").as_deref(),
+            Some("/build/libfoo-1234.rmeta"),
+        );
+        assert_eq!(
+            top_level_str_field(artifact, "emit").as_deref(),
+            Some("metadata"),
+        );
+        assert_eq!(top_level_str_field(artifact, "missing"), None);
+
+        // Skips over the nested `rendered: null` in `children`
+        let diagnostic = r#"{"$message_type":"diagnostic","message":"x","code":null,"level":"error","spans":[{"a":[1,2,{"b":"}]"}]}],"children":[{"message":"y","rendered":null}],"rendered":"\u001b[0m\u001b[1m\u001b[38;5;9merror\u001b[0m: \"x\"\n\ud83e\udd80\n"}"#;
+        assert_eq!(
+            top_level_str_field(diagnostic, "rendered").as_deref(),
+            Some("\x1b[0m\x1b[1m\x1b[38;5;9merror\x1b[0m: \"x\"\n\u{1f980}\n"),
+        );
+        assert_eq!(
+            top_level_str_field(diagnostic, "level").as_deref(),
+            Some("error"),
+        );
+        assert_eq!(top_level_str_field(diagnostic, "code"), None);
+
+        assert_eq!(top_level_str_field("not json", "rendered"), None);
+        assert_eq!(top_level_str_field("{}", "rendered"), None);
+    }
+
+    #[test]
+    fn test_top_level_f64_field() {
+        let event = r#"{ "type": "test", "name": "a::b", "event": "ok", "exec_time": 0.0123 }"#;
+        assert_eq!(top_level_f64_field(event, "exec_time"), Some(0.0123));
+        assert_eq!(top_level_f64_field(event, "name"), None);
+        assert_eq!(top_level_f64_field(r#"{"passed":3}"#, "passed"), Some(3.0));
+    }
+
+    #[test]
+    fn test_parse() {
+        let json =
+            r#" {"a": [1, -2.5e3, "x y", true, null], "b": {}, "c": [] } "#;
+        assert_eq!(
+            parse(json),
+            Some(Value::Object(vec![
+                (
+                    "a".to_owned(),
+                    Value::Array(vec![
+                        Value::Number("1".to_owned()),
+                        Value::Number("-2.5e3".to_owned()),
+                        Value::Str("x y".to_owned()),
+                        Value::Bool(true),
+                        Value::Null,
+                    ])
+                ),
+                ("b".to_owned(), Value::Object(Vec::new())),
+                ("c".to_owned(), Value::Array(Vec::new())),
+            ]))
+        );
+
+        assert_eq!(parse("[1,]"), None);
+        assert_eq!(parse("{\"a\":1} x"), None);
+        assert_eq!(parse("tru"), None);
+        assert_eq!(parse("-"), None);
+    }
+
+    #[test]
+    fn test_push_str() {
+        let s = "a \"b\"\\\n\t\x1b\u{1f980}";
+        let mut out = String::new();
+        push_str(&mut out, s);
+        assert_eq!(out, "\"a \\\"b\\\"\\\\\\n\\t\\u001b\u{1f980}\"");
+
+        // roundtrips
+        let json = format!("{{\"s\":{out}}}");
+        assert_eq!(top_level_str_field(&json, "s").as_deref(), Some(s));
+    }
+}
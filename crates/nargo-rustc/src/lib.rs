@@ -1,6 +1,15 @@
 mod build_script;
 pub mod cli;
+mod diagnostics;
+mod json;
+mod lto;
+mod profile;
+pub mod repro;
 mod run;
 mod semver;
 mod shell;
+mod sources;
 mod target_cfg;
+mod test_report;
+mod test_runner;
+pub mod unit_spec;
@@ -0,0 +1,125 @@
+//! Decide how each unit participates in link-time optimization (LTO).
+//!
+//! This mirrors cargo's `core/compiler/lto.rs`. The main difference is that we
+//! can't walk the unit graph from inside a single unit's derivation, so
+//! `buildGraph.nix` instead tells each lib unit whether any of its (transitive)
+//! dependents needs real object code from it (`LTO_NEEDS_OBJECT`).
+//!
+//! When LTO is enabled, this lets most rlibs skip codegen entirely and only
+//! emit LLVM bitcode, without ever having to build the same lib twice.
+
+use std::process::Command;
+
+use nargo_core::nargo::CrateType;
+
+#[derive(Copy, Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub(crate) enum Lto<'a> {
+    /// Perform LTO (`fat` or `thin`) over this unit and all its upstream
+    /// crates. Only for final linked artifacts, like bins and cdylibs.
+    Run(&'a str),
+    /// Explicitly disable all LTO, including rustc's default thin-local LTO.
+    Off,
+    /// All dependents perform LTO, so we only need to emit LLVM bitcode.
+    OnlyBitcode,
+    /// Some dependents perform LTO while others (like dylibs) need regular
+    /// object code, so we need both.
+    ObjectAndBitcode,
+    /// No dependents perform LTO, so there's no need to embed bitcode.
+    OnlyObject,
+}
+
+impl<'a> Lto<'a> {
+    /// - `profile_lto`: one of `false`, `off`, `thin`, or `fat`.
+    /// - `dependent_needs_object`: whether any of this unit's (transitive)
+    ///   dependents needs object code.
+    pub(crate) fn new(
+        profile_lto: &'a str,
+        crate_types: &[CrateType],
+        dependent_needs_object: bool,
+    ) -> Self {
+        match profile_lto {
+            "false" => return Self::OnlyObject,
+            "off" => return Self::Off,
+            _ => (),
+        }
+
+        if crate_types.iter().all(CrateType::can_lto) {
+            return Self::Run(profile_lto);
+        }
+
+        let needs_object = crate_types
+            .iter()
+            .any(|crate_type| crate_type.can_lto() || crate_type.is_dynamic());
+        if needs_object {
+            // rustc doesn't support LTO with dylibs, so a pure dylib doesn't
+            // need any bitcode. Mixed crate types like `["rlib", "cdylib"]`
+            // need both.
+            if crate_types.iter().all(|ct| *ct == CrateType::Dylib) {
+                Self::OnlyObject
+            } else {
+                Self::ObjectAndBitcode
+            }
+        } else if dependent_needs_object {
+            Self::ObjectAndBitcode
+        } else {
+            Self::OnlyBitcode
+        }
+    }
+
+    pub(crate) fn add_args(&self, cmd: &mut Command) {
+        match self {
+            Self::Run("fat") => {
+                cmd.arg("-Clto");
+            }
+            Self::Run(lto) => {
+                cmd.arg(format!("-Clto={lto}"));
+            }
+            Self::Off => {
+                cmd.arg("-Clto=off").arg("-Cembed-bitcode=no");
+            }
+            Self::OnlyBitcode => {
+                cmd.arg("-Clinker-plugin-lto");
+            }
+            // rustc embeds bitcode by default
+            Self::ObjectAndBitcode => (),
+            Self::OnlyObject => {
+                cmd.arg("-Cembed-bitcode=no");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lto_new() {
+        use CrateType::*;
+
+        // LTO disabled
+        assert_eq!(Lto::new("false", &[Bin], false), Lto::OnlyObject);
+        assert_eq!(Lto::new("false", &[Lib], true), Lto::OnlyObject);
+        assert_eq!(Lto::new("off", &[Lib], false), Lto::Off);
+
+        // Final artifacts run LTO
+        assert_eq!(Lto::new("thin", &[Bin], false), Lto::Run("thin"));
+        assert_eq!(
+            Lto::new("fat", &[Cdylib, Staticlib], false),
+            Lto::Run("fat")
+        );
+
+        // Plain rlibs only need bitcode, unless a dependent needs objects
+        assert_eq!(Lto::new("thin", &[Lib], false), Lto::OnlyBitcode);
+        assert_eq!(Lto::new("thin", &[Rlib], true), Lto::ObjectAndBitcode);
+
+        // Mixed and dynamic crate types
+        assert_eq!(
+            Lto::new("fat", &[Rlib, Cdylib], false),
+            Lto::ObjectAndBitcode
+        );
+        assert_eq!(Lto::new("fat", &[Dylib], false), Lto::OnlyObject);
+        assert_eq!(Lto::new("fat", &[ProcMacro], false), Lto::ObjectAndBitcode);
+    }
+}
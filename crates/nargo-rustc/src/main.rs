@@ -1,10 +1,34 @@
 use nargo_core::{info_time, logger, time};
 
-use nargo_rustc::cli;
+use std::path::{Path, PathBuf};
+
+use nargo_rustc::{cli, repro, unit_spec::UnitSpec};
 
 fn main() {
-    nargo_core::panic::set_hook();
+    // On failure, also dump a script that reproduces the unit outside of nix
+    nargo_core::panic::set_hook_with(repro::dump);
+
+    let mut args = std::env::args();
+    match args.nth(1).as_deref() {
+        // `buildCrate.nix` passes the unit spec via `passAsFile`
+        None => match UnitSpec::path_from_env() {
+            Some(path) => build_unit(&path),
+            None => build(),
+        },
+        Some("--unit") => {
+            let path = args.next().expect("usage: nargo-rustc --unit <path>");
+            build_unit(&PathBuf::from(path))
+        }
+        Some("run-test") => run_test(),
+        Some("merge-test-results") => merge_test_results(),
+        Some(cmd) => panic!("unknown nargo-rustc subcommand: '{cmd}'"),
+    }
+
+    logger::flush();
+}
 
+/// Build a single unit.
+fn build() {
     let args_raw = time!("read envs", cli::ArgsRaw::from_env());
 
     // SAFETY: `std::env::remove_var` is not thread-safe. Since we do this early
@@ -15,6 +39,40 @@ fn main() {
 
     let label = args.label();
     info_time!(&label, args.run());
+}
 
-    logger::flush();
+/// Build a single unit described by the unit spec at `path`.
+fn build_unit(path: &Path) {
+    let spec = time!("read unit spec", UnitSpec::from_file(path));
+
+    // SAFETY: see `build`
+    time!("remove envs", unsafe { UnitSpec::remove_nargo_envs() });
+
+    let args = time!("parse args", cli::Args::from_unit_spec(&spec));
+
+    let label = args.label();
+    info_time!(&label, args.run());
+}
+
+/// Run a test unit's test binaries and write the results.
+fn run_test() {
+    let args_raw = time!("read envs", cli::RunTestArgsRaw::from_env());
+
+    // SAFETY: see `build`
+    time!("remove envs", unsafe {
+        cli::RunTestArgsRaw::remove_nargo_envs()
+    });
+
+    let args = time!("parse args", cli::RunTestArgs::from_raw(&args_raw));
+
+    let label = args.label();
+    info_time!(&label, args.run());
+}
+
+/// Merge the results from many test runs into a single report.
+fn merge_test_results() {
+    let args_raw = time!("read envs", cli::MergeTestResultsArgsRaw::from_env());
+    let args =
+        time!("parse args", cli::MergeTestResultsArgs::from_raw(&args_raw));
+    info_time!("merge-test-results", args.run());
 }
@@ -0,0 +1,248 @@
+//! Parse the fully resolved build profile passed to us by `buildCrate.nix`.
+//!
+//! The profile is serialized as whitespace-separated `key=value` pairs (one per
+//! line via `lib.generators.toKeyValue`), e.g., `root=release\nopt_level=3\n...`.
+//! See `nargo_metadata::profile::Profile` for the individual fields.
+
+/// A fully resolved build profile for a single unit.
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub(crate) struct Profile<'a> {
+    /// The built-in profile this profile inherits from (`dev` or `release`).
+    pub root: &'a str,
+    pub opt_level: char,
+    /// One of `false`, `off`, `thin`, or `fat`.
+    pub lto: &'a str,
+    // codegen_backend,
+    pub codegen_units: Option<u32>,
+    pub debuginfo: &'a str,
+    pub debug_assertions: bool,
+    pub split_debuginfo: Option<&'a str>,
+    pub overflow_checks: bool,
+    pub rpath: bool,
+    /// Link workspace bins and tests dynamically against `libstd` and Rust
+    /// dylib deps.
+    pub prefer_dynamic: bool,
+    pub panic: &'a str,
+    // incremental,
+    pub strip: &'a str,
+    // rustflags: profile_rustflags,
+    // trim_paths,
+}
+
+impl<'a> Profile<'a> {
+    pub(crate) fn parse(s: &'a str) -> Self {
+        let mut root = None;
+        let mut opt_level = None;
+        let mut lto = None;
+        let mut codegen_units = None;
+        let mut debuginfo = None;
+        let mut debug_assertions = None;
+        let mut split_debuginfo = None;
+        let mut overflow_checks = None;
+        let mut rpath = None;
+        let mut prefer_dynamic = None;
+        let mut panic = None;
+        let mut strip = None;
+
+        for kv in s.split_ascii_whitespace() {
+            let (key, value) = kv.split_once('=').unwrap_or_else(|| {
+                panic!("profile: expected `key=value`, got: '{kv}'")
+            });
+
+            match key {
+                "root" => root = Some(value),
+                "opt_level" => opt_level = Some(parse_opt_level(value)),
+                "lto" => lto = Some(parse_lto(value)),
+                "codegen_units" => {
+                    codegen_units =
+                        Some(value.parse::<u32>().unwrap_or_else(|_| {
+                            panic!(
+                                "profile: invalid `codegen_units`: '{value}'"
+                            )
+                        }))
+                }
+                "debug" => debuginfo = Some(value),
+                "debug_assertions" => {
+                    debug_assertions = Some(parse_bool(key, value))
+                }
+                "split_debuginfo" => split_debuginfo = Some(value),
+                "overflow_checks" => {
+                    overflow_checks = Some(parse_bool(key, value))
+                }
+                "rpath" => rpath = Some(parse_bool(key, value)),
+                "prefer_dynamic" => {
+                    prefer_dynamic = Some(parse_bool(key, value))
+                }
+                "panic" => panic = Some(parse_panic(value)),
+                "strip" => strip = Some(value),
+                _ => panic!("profile: unknown key: '{key}'"),
+            }
+        }
+
+        let debuginfo = debuginfo.unwrap_or("0");
+
+        // Like cargo, strip debuginfo by default if we aren't building with
+        // any debuginfo in the first place.
+        let strip = strip.unwrap_or(if debuginfo == "0" {
+            "debuginfo"
+        } else {
+            "none"
+        });
+
+        Self {
+            root: root.expect("profile: missing `root`"),
+            opt_level: opt_level.expect("profile: missing `opt_level`"),
+            lto: lto.expect("profile: missing `lto`"),
+            codegen_units,
+            debuginfo,
+            debug_assertions: debug_assertions
+                .expect("profile: missing `debug_assertions`"),
+            split_debuginfo,
+            overflow_checks: overflow_checks
+                .expect("profile: missing `overflow_checks`"),
+            rpath: rpath.expect("profile: missing `rpath`"),
+            prefer_dynamic: prefer_dynamic.unwrap_or(false),
+            panic: panic.expect("profile: missing `panic`"),
+            strip,
+        }
+    }
+
+    /// The `PROFILE` env passed to build scripts, which is only ever `debug`
+    /// or `release`, depending on which built-in profile we inherit from.
+    pub(crate) fn build_script_env(&self) -> &'static str {
+        if self.root == "dev" {
+            "debug"
+        } else {
+            "release"
+        }
+    }
+}
+
+fn parse_opt_level(value: &str) -> char {
+    match value {
+        "0" | "1" | "2" | "3" | "s" | "z" => value.chars().next().unwrap(),
+        _ => panic!("profile: invalid `opt_level`: '{value}'"),
+    }
+}
+
+fn parse_lto(value: &str) -> &str {
+    match value {
+        "false" | "off" | "thin" | "fat" => value,
+        _ => panic!("profile: invalid `lto`: '{value}'"),
+    }
+}
+
+fn parse_panic(value: &str) -> &str {
+    match value {
+        "unwind" | "abort" => value,
+        _ => panic!("profile: invalid `panic`: '{value}'"),
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> bool {
+    match value {
+        "true" => true,
+        "false" => false,
+        _ => panic!("profile: invalid `{key}`: '{value}'"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let release = "codegen_units=1 debug=0 debug_assertions=false \
+            lto=thin opt_level=3 overflow_checks=false \
+            panic=abort prefer_dynamic=false root=release rpath=false \
+            split_debuginfo=packed";
+        assert_eq!(
+            Profile::parse(release),
+            Profile {
+                root: "release",
+                opt_level: '3',
+                lto: "thin",
+                codegen_units: Some(1),
+                debuginfo: "0",
+                debug_assertions: false,
+                split_debuginfo: Some("packed"),
+                overflow_checks: false,
+                rpath: false,
+                prefer_dynamic: false,
+                panic: "abort",
+                strip: "debuginfo",
+            }
+        );
+
+        let dev = "debug=line-tables-only debug_assertions=true lto=false \
+            opt_level=0 overflow_checks=true panic=unwind prefer_dynamic=true \
+            root=dev rpath=false";
+        let dev = Profile::parse(dev);
+        assert_eq!(dev.debuginfo, "line-tables-only");
+        assert_eq!(dev.strip, "none");
+        assert_eq!(dev.codegen_units, None);
+        assert!(dev.prefer_dynamic);
+        assert_eq!(dev.build_script_env(), "debug");
+    }
+
+    #[test]
+    #[should_panic(expected = "profile: unknown key: 'opt-level'")]
+    fn test_parse_unknown_key() {
+        Profile::parse("root=dev opt-level=3");
+    }
+
+    /// A valid `dev` profile with `bad` (ex: `lto=true`) replacing its value
+    /// for the same key.
+    fn dev_with(bad: &str) -> String {
+        let dev = "debug=0 debug_assertions=true lto=false opt_level=0 \
+            overflow_checks=true panic=unwind root=dev rpath=false";
+        let key = bad.split_once('=').unwrap().0;
+        dev.split_ascii_whitespace()
+            .map(|kv| match kv.split_once('=') {
+                Some((k, _)) if k == key => bad,
+                _ => kv,
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_dev_with() {
+        Profile::parse(&dev_with("lto=false"));
+        assert!(dev_with("lto=true").contains(" lto=true "));
+    }
+
+    #[test]
+    #[should_panic(expected = "profile: invalid `lto`: 'true'")]
+    fn test_parse_invalid_lto() {
+        Profile::parse(&dev_with("lto=true"));
+    }
+
+    #[test]
+    #[should_panic(expected = "profile: invalid `lto`: ''")]
+    fn test_parse_empty_lto() {
+        Profile::parse(&dev_with("lto="));
+    }
+
+    #[test]
+    #[should_panic(expected = "profile: invalid `panic`: 'immediate-abort'")]
+    fn test_parse_invalid_panic() {
+        Profile::parse(&dev_with("panic=immediate-abort"));
+    }
+
+    #[test]
+    #[should_panic(expected = "profile: invalid `rpath`: 'yes'")]
+    fn test_parse_invalid_rpath() {
+        Profile::parse(&dev_with("rpath=yes"));
+    }
+
+    #[test]
+    #[should_panic(expected = "profile: missing `lto`")]
+    fn test_parse_missing_lto() {
+        Profile::parse(
+            "debug_assertions=true opt_level=0 overflow_checks=true \
+             panic=unwind root=dev rpath=false",
+        );
+    }
+}
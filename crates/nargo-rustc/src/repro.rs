@@ -0,0 +1,326 @@
+//! Build a shell script that reproduces a unit's build outside of nix.
+//!
+//! Just before we run each `rustc`, `rustdoc`, or `build_script_build`, we
+//! record its full environment, working directory, and args, plus the dirs,
+//! dependency symlinks, and files (e.g., build script `out/`) it expects to
+//! find in `$out`. If the unit fails,
+//! the panic hook prints the script and writes it to `$TMPDIR/nargo-repro.sh`,
+//! which `nix build --keep-failed` keeps around.
+//!
+//! The script runs against the same store paths, so running it from a dev
+//! shell reproduces the failure. The real `$out` and `$TMPDIR` only exist
+//! inside the sandbox, so the script puts them under `$PWD/nargo-repro`.
+//! Files in `$out` are inlined into the script, except for binary or very
+//! large ones, which the script only lists in a comment.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+};
+
+use nargo_core::fs;
+
+use crate::shell;
+
+pub(crate) const REPRO_SH: &str = "nargo-repro.sh";
+
+/// Don't inline `$out` files bigger than this into the script.
+const MAX_INLINE_FILE_LEN: u64 = 64 * 1024;
+
+static SCRIPT: Mutex<Option<Script>> = Mutex::new(None);
+
+struct Script {
+    /// Our real `$out`, which the script replaces with `"$out"`.
+    out: String,
+    /// Our real `$TMPDIR`, which the script replaces with `"$tmp"`.
+    tmp: String,
+    text: String,
+}
+
+/// Start a new repro script for the unit `label` that builds into `out`.
+pub(crate) fn init(label: &str, out: &Path) {
+    let text = format!(
+        "#!/bin/sh\n\
+         # Reproduce the `{label}` unit outside of nix. Run this from a dev\n\
+         # shell that can see the same store paths. Binary or large files\n\
+         # in $out aren't recreated; see the `# not recreated` comments.\n\
+         set -eu\n\
+         out=\"$PWD/nargo-repro/out\"\n\
+         tmp=\"$PWD/nargo-repro/tmp\"\n\
+         rm -rf \"$out\"\n\
+         mkdir -p \"$out\" \"$tmp\"\n"
+    );
+    let script = Script {
+        out: out.to_string_lossy().into_owned(),
+        tmp: std::env::temp_dir().to_string_lossy().into_owned(),
+        text,
+    };
+    *SCRIPT.lock().unwrap() = Some(script);
+}
+
+/// Record `cmd`, which we're about to run.
+pub(crate) fn record(cmd: &Command) {
+    let mut guard = SCRIPT.lock().unwrap();
+    let script = match guard.as_mut() {
+        Some(script) => script,
+        None => return,
+    };
+    script.record(cmd);
+}
+
+/// Print the repro script to stderr and write it to `$TMPDIR/nargo-repro.sh`,
+/// if we've recorded anything.
+pub fn dump() {
+    let guard = match SCRIPT.lock() {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+    let script = match guard.as_ref() {
+        Some(script) if script.text.contains("exec env") => script,
+        _ => return,
+    };
+
+    let path = std::env::temp_dir().join(REPRO_SH);
+    let _ = fs::write_file(&path, script.text.as_bytes());
+
+    let mut stderr = std::io::stderr().lock();
+    let _ = writeln!(
+        stderr,
+        "\nTo reproduce this failure outside of nix, run this script (also \
+         written to {}):\n",
+        path.display()
+    );
+    let _ = stderr.write_all(script.text.as_bytes());
+    let _ = stderr.flush();
+}
+
+impl Script {
+    fn record(&mut self, cmd: &Command) {
+        let program = cmd.get_program().to_string_lossy();
+        self.text.push_str(&format!("\n# {program}\n"));
+
+        // Recreate the dirs, symlinks, and files (e.g., `$out/deps`) that
+        // earlier steps set up for this command.
+        let out = PathBuf::from(&self.out);
+        self.push_out_tree(&out);
+
+        // Our own env, plus the command's changes
+        let mut envs = std::env::vars_os()
+            .map(|(key, value)| (key, Some(value)))
+            .collect::<BTreeMap<OsString, Option<OsString>>>();
+        for (key, value) in cmd.get_envs() {
+            envs.insert(key.to_owned(), value.map(ToOwned::to_owned));
+        }
+        envs.remove(&OsString::from("PWD"));
+
+        let cwd = match cmd.get_current_dir() {
+            Some(dir) => dir.to_path_buf(),
+            None => std::env::current_dir().expect("current dir"),
+        };
+
+        self.text.push_str("(cd ");
+        self.push_word(&cwd.to_string_lossy());
+        self.text.push_str(" && exec env -i \\\n");
+        for (key, value) in &envs {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            self.text.push_str("  ");
+            self.text.push_str(&key.to_string_lossy());
+            self.text.push('=');
+            self.push_word(&value.to_string_lossy());
+            self.text.push_str(" \\\n");
+        }
+
+        self.text.push_str("  ");
+        self.push_word(&program);
+        for arg in cmd.get_args() {
+            self.text.push_str(" \\\n    ");
+            self.push_word(&arg.to_string_lossy());
+        }
+        self.text.push_str(")\n");
+    }
+
+    /// `mkdir -p` each dir, `ln -sfn` each symlink, and write each small text
+    /// file under `dir`.
+    fn push_out_tree(&mut self, dir: &Path) {
+        let mut entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>(),
+            Err(_) => return,
+        };
+        entries.sort_unstable();
+
+        for path in entries {
+            let file_type = match std::fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata.file_type(),
+                Err(_) => continue,
+            };
+            if file_type.is_symlink() {
+                let target = match std::fs::read_link(&path) {
+                    Ok(target) => target,
+                    Err(_) => continue,
+                };
+                self.text.push_str("ln -sfn ");
+                self.push_word(&target.to_string_lossy());
+                self.text.push(' ');
+                self.push_word(&path.to_string_lossy());
+                self.text.push('\n');
+            } else if file_type.is_dir() {
+                self.text.push_str("mkdir -p ");
+                self.push_word(&path.to_string_lossy());
+                self.text.push('\n');
+                self.push_out_tree(&path);
+            } else if file_type.is_file() {
+                self.push_out_file(&path);
+            }
+        }
+    }
+
+    /// Write the file at `path` with a `printf`, or note that we can't if it's
+    /// binary or too large.
+    fn push_out_file(&mut self, path: &Path) {
+        let contents = std::fs::metadata(path)
+            .ok()
+            .filter(|metadata| metadata.len() <= MAX_INLINE_FILE_LEN)
+            .and_then(|_| std::fs::read(path).ok())
+            .and_then(|contents| String::from_utf8(contents).ok());
+        match contents {
+            Some(contents) => {
+                self.text.push_str("printf '%s' ");
+                self.text.push_str(&shell::escape(&contents));
+                self.text.push_str(" > ");
+                self.push_word(&path.to_string_lossy());
+                self.text.push('\n');
+            }
+            None => {
+                self.text
+                    .push_str("# not recreated (binary or too large): ");
+                self.push_word(&path.to_string_lossy());
+                self.text.push('\n');
+            }
+        }
+    }
+
+    /// Push `s` as a single shell word, with our real `$out` and `$TMPDIR`
+    /// paths replaced by `"$out"` and `"$tmp"`.
+    fn push_word(&mut self, s: &str) {
+        let mut rest = s;
+        let mut pushed = false;
+        while let Some((idx, len, var)) = self.find_var(rest) {
+            if idx > 0 {
+                self.text.push_str(&shell::escape(&rest[..idx]));
+            }
+            self.text.push_str(var);
+            rest = &rest[idx + len..];
+            pushed = true;
+        }
+        if !rest.is_empty() || !pushed {
+            self.text.push_str(&shell::escape(rest));
+        }
+    }
+
+    /// Find the first whole-path occurrence of `$out` or `$TMPDIR` in `s`,
+    /// preferring the longer one (`$out` may be inside `$TMPDIR`).
+    fn find_var(&self, s: &str) -> Option<(usize, usize, &'static str)> {
+        let vars = [(&self.out, "\"$out\""), (&self.tmp, "\"$tmp\"")];
+        let mut found: Option<(usize, usize, &'static str)> = None;
+        for (path, var) in vars {
+            if path.is_empty() || path == "/" {
+                continue;
+            }
+            let mut start = 0;
+            while let Some(idx) = s[start..].find(path.as_str()) {
+                let idx = start + idx;
+                let end = idx + path.len();
+                let whole_path = s[end..].chars().next().is_none_or(|c| {
+                    !(c.is_alphanumeric() || "-_.".contains(c))
+                });
+                if whole_path {
+                    let better = match found {
+                        Some((f_idx, f_len, _)) => {
+                            idx < f_idx || (idx == f_idx && path.len() > f_len)
+                        }
+                        None => true,
+                    };
+                    if better {
+                        found = Some((idx, path.len(), var));
+                    }
+                    break;
+                }
+                start = idx + 1;
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_push_word() {
+        let mut script = Script {
+            out: "/tmp/e2e/aaaa-foo".to_owned(),
+            tmp: "/tmp".to_owned(),
+            text: String::new(),
+        };
+        for word in [
+            "-Ldependency=/tmp/e2e/aaaa-foo/deps",
+            "/tmp/nargo-pipelined",
+            "/tmp/e2e/aaaa-foobar",
+            "/tmpfoo",
+            "a b",
+            "",
+        ] {
+            script.push_word(word);
+            script.text.push('\n');
+        }
+        assert_eq!(
+            script.text,
+            "-Ldependency=\"$out\"/deps\n\
+             \"$tmp\"/nargo-pipelined\n\
+             \"$tmp\"/e2e/aaaa-foobar\n\
+             /tmpfoo\n\
+             'a b'\n\
+             ''\n"
+        );
+    }
+
+    #[test]
+    fn test_push_out_tree() {
+        let out = std::env::temp_dir()
+            .join(format!("nargo-test-repro-out-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&out);
+        std::fs::create_dir_all(out.join("out")).unwrap();
+        std::os::unix::fs::symlink("/nix/store/aaaa-bar", out.join("bar"))
+            .unwrap();
+        std::fs::write(out.join("out/gen.rs"), "const S: &str = \"it's\";\n")
+            .unwrap();
+        std::fs::write(out.join("out/blob.bin"), [0xff, 0x00]).unwrap();
+
+        let mut script = Script {
+            out: out.to_string_lossy().into_owned(),
+            tmp: "/build".to_owned(),
+            text: String::new(),
+        };
+        script.push_out_tree(&out);
+        std::fs::remove_dir_all(&out).unwrap();
+
+        assert_eq!(
+            script.text,
+            "ln -sfn /nix/store/aaaa-bar \"$out\"/bar\n\
+             mkdir -p \"$out\"/out\n\
+             # not recreated (binary or too large): \"$out\"/out/blob.bin\n\
+             printf '%s' 'const S: &str = \"it'\\''s\";\n' > \"$out\"/out/gen.rs\n"
+        );
+    }
+}
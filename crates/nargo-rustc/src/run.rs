@@ -3,35 +3,111 @@
 use core::str;
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     ffi::{OsStr, OsString},
     fs::File,
     io::Write,
+    io::{BufRead, BufReader},
     path::{self, Path, PathBuf},
-    process::Command,
+    process::{Command, ExitStatus, Stdio},
     str::FromStr,
 };
 
 use nargo_core::{
     fs, logger,
-    nargo::{CrateType, TargetKind},
+    nargo::{CompileMode, CrateType, TargetKind},
     time, trace,
 };
 
 use crate::{
-    build_script::BuildOutput, cli, semver, shell, target_cfg::RustcTargetCfg,
+    build_script::{BuildOutput, LinkArgTarget},
+    cli,
+    diagnostics::{Diagnostics, FailOn},
+    json,
+    lto::Lto,
+    profile::Profile,
+    repro, semver, shell, sources,
+    target_cfg::RustcTargetCfg,
+    test_report::UnitReport,
+    test_runner,
 };
 
+/// Each linkable lib unit records the native library search paths from its
+/// own and its deps' build scripts here, one `[KIND=]PATH` per line.
+const LINK_SEARCH_PATH: &str = "nargo/link-search";
+
 pub(crate) struct BuildContext<'a> {
     pkg_name: &'a str,
+    /// Whether this package is part of the workspace (i.e., not a vendored
+    /// dependency). We only show build script warnings for these.
+    is_workspace_pkg: bool,
+    /// Whether this package was selected to build, like `cargo build -p`.
+    /// These get `CARGO_PRIMARY_PACKAGE=1`.
+    is_primary_pkg: bool,
+    /// The `[package]` metadata for the `CARGO_PKG_<FIELD>` envs.
+    package: cli::PkgMetadata<'a>,
+    /// Whether to fully build this unit or only `check` it. Build scripts and
+    /// proc-macros (and their deps) are always fully built, since they need to
+    /// run on the host.
+    mode: CompileMode,
+    /// Whether a `Test` unit uses the libtest harness (`rustc --test`).
+    /// Doctests always do.
+    harness: bool,
     target: Target<'a>,
     profile: Profile<'a>,
+    /// The target triple this unit runs on. For a build script, the target
+    /// triple of the package it configures.
     target_triple: &'a str,
-    host_profile: Option<Profile<'a>>,
-    host_target_triple: Option<&'a str>,
+    /// The C compiler for `target_triple`, which we also link with.
+    target_cc: Option<&'a str>,
+    target_ar: Option<&'a str>,
+    /// The host target triple. Build scripts are compiled for and run on the
+    /// host, even when cross-compiling.
+    host_triple: &'a str,
+    host_cc: Option<&'a str>,
+    host_ar: Option<&'a str>,
     build_script_dep: Option<&'a Path>,
+    /// For a `RunCustomBuild` unit, the out dir with our compiled
+    /// `build_script_build` binary.
+    build_script_bin: Option<&'a Path>,
+    /// For a `RunCustomBuild` unit, the saved `rustc --print cfg` output for
+    /// `target_triple`.
+    rustc_target_cfg: Option<&'a Path>,
     deps: Vec<Dep<'a>>,
+    links: Option<&'a str>,
+    links_deps: Vec<cli::LinksDep<'a>>,
+    /// The out dirs of our package's bin units, which integration tests and
+    /// benches get as `CARGO_BIN_EXE_<name>` envs.
+    bin_deps: Vec<&'a Path>,
+    /// With pipelining, our direct lib deps only propagate their deps'
+    /// `.rmeta` metadata. Units that link (bins, dylibs, ...) also get the
+    /// out dirs of all their (transitive) rlib deps here.
+    link_deps: Vec<&'a Path>,
+    /// `-C incremental` cache dir, when building outside of nix.
+    incremental: Option<&'a Path>,
+    /// Overrides the `-C metadata` hash, so both halves of a pipelined lib
+    /// build agree on it.
+    metadata: Option<&'a str>,
+    /// Whether we're the rlib (`Build`) half of a pipelined lib. The
+    /// `Metadata` half is always pipelined.
+    pipelined: bool,
+    /// Whether any of this lib unit's dependents needs object code when
+    /// building with LTO. See [`Lto`].
+    lto_needs_object: bool,
+    /// The package's `[lints]` table as `rustc` flags. Only set for workspace
+    /// packages.
+    lints: Vec<&'a str>,
+    /// Extra lint args in `Clippy` mode, like `-D warnings`.
+    lint_args: Vec<&'a str>,
+    /// When a `Clippy` unit fails, based on its diagnostics.
+    lint_fail_on: FailOn,
+    /// Extra `--cfg`s and envs from our unit spec.
+    cfgs: Vec<&'a str>,
+    envs: Vec<(&'a str, &'a str)>,
     src: &'a Path,
+    /// The source tree that a workspace package's `src` sits in. See
+    /// [`crate::unit_spec`].
+    workspace_src: Option<&'a Path>,
     out: &'a Path,
 }
 
@@ -47,26 +123,6 @@ struct Target<'a> {
     features: Vec<&'a str>,
 }
 
-struct Profile<'a> {
-    name: &'a str,
-    opt_level: char,
-    #[allow(dead_code)] // TODO(phlip9): remove
-    lto: &'a str,
-    // codegen_backend,
-    codegen_units: Option<u32>,
-    debuginfo: &'a str,
-    debug_assertions: bool,
-    #[allow(dead_code)] // TODO(phlip9): remove
-    split_debuginfo: Option<&'a str>,
-    overflow_checks: bool,
-    rpath: bool,
-    panic: &'static str,
-    // incremental,
-    strip: &'a str,
-    // rustflags: profile_rustflags,
-    // trim_paths,
-}
-
 struct Dep<'a> {
     /// The dep's name in this crate's Cargo.toml.
     dep_name: &'a str,
@@ -75,7 +131,8 @@ struct Dep<'a> {
     crate_name: &'a str,
     /// The nix store path containing the dep's output artifacts.
     out: &'a Path,
-    /// The relevant linkable libs we've discovered in the dep's out dir.
+    /// The Rust-linkable libs (.rlib, .rmeta, dylib) we've discovered in the
+    /// dep's out dir, ordered by preference (see [`rust_lib_rank`]).
     libs: Vec<String>,
 }
 
@@ -111,37 +168,30 @@ impl<'a> BuildContext<'a> {
             features,
         };
 
-        let profile = Profile {
-            name: "debug",
-            opt_level: '1',
-            lto: "off",
-            codegen_units: None,
-            debuginfo: "0",
-            debug_assertions: true,
-            split_debuginfo: None,
-            overflow_checks: true,
-            rpath: false,
-            panic: "abort",
-            strip: "debuginfo",
-        };
+        let mode = CompileMode::from_str(args.mode).unwrap();
+        assert!(
+            !(mode.is_metadata_only() && target.is_custom_build()),
+            "custom-build targets must always be fully built"
+        );
+        assert!(
+            !(mode == CompileMode::Metadata || args.pipelined)
+                || target.is_lib(),
+            "only lib targets can be pipelined"
+        );
+        assert!(
+            !(mode.is_test() && target.is_custom_build()),
+            "custom-build targets can't be tested"
+        );
+        assert!(
+            !mode.is_run_custom_build() || target.is_custom_build(),
+            "only custom-build targets can be run"
+        );
+        assert!(
+            !(mode.is_doctest() || mode.is_doc()) || target.is_lib(),
+            "only lib targets have doctests or docs"
+        );
 
-        // We need the build profile (nix "build", cargo "host") only when
-        // _building_ the `build_script_build` binary.
-        let host_profile = target.is_custom_build().then_some(Profile {
-            name: "debug",
-            opt_level: '0',
-            lto: "off",
-            codegen_units: None,
-            debuginfo: "0",
-            debug_assertions: true,
-            split_debuginfo: None,
-            overflow_checks: true,
-            rpath: false,
-            panic: "abort",
-            strip: "debuginfo",
-        });
-        let host_target_triple =
-            target.is_custom_build().then_some(args.target_triple);
+        let profile = Profile::parse(args.profile);
 
         let deps = time!(
             "read direct deps",
@@ -150,14 +200,38 @@ impl<'a> BuildContext<'a> {
 
         Self {
             pkg_name: args.pkg_name,
+            is_workspace_pkg: args.is_workspace_pkg,
+            is_primary_pkg: args.is_primary_pkg,
+            package: args.package,
+            mode,
+            harness: args.harness,
             target,
-            host_profile,
-            host_target_triple,
+            host_triple: args.host_triple,
+            host_cc: args.host_cc,
+            host_ar: args.host_ar,
             profile,
             target_triple: args.target_triple,
+            target_cc: args.target_cc,
+            target_ar: args.target_ar,
             build_script_dep: args.build_script_dep,
+            build_script_bin: args.build_script_bin,
+            rustc_target_cfg: args.rustc_target_cfg,
             deps,
+            links: args.links,
+            links_deps: args.links_deps,
+            bin_deps: args.bin_deps,
+            link_deps: args.link_deps,
+            incremental: args.incremental,
+            metadata: args.metadata,
+            pipelined: args.pipelined,
+            lto_needs_object: args.lto_needs_object,
+            lints: args.lints,
+            lint_args: args.lint_args,
+            lint_fail_on: args.lint_fail_on,
+            cfgs: args.cfgs,
+            envs: args.envs,
             src: args.src,
+            workspace_src: args.workspace_src,
             out: args.out,
         }
     }
@@ -165,25 +239,65 @@ impl<'a> BuildContext<'a> {
     pub(crate) fn run(&self) {
         fs::create_dir(self.out).expect("mkdir");
 
+        // Running a build script doesn't compile anything.
+        if self.mode.is_run_custom_build() {
+            self.run_build_script();
+            return;
+        }
+
         // Collect transitive deps into `$out/deps` so `rustc` can find them.
         let tdep_lib_filenames =
             time!("collect transitive deps", self.collect_transitive_deps());
 
-        // Compile unit
-        self.run_rustc();
+        // Parse our package's `build_script_build` output, if we have one.
+        let build_script_output_bytes = self.build_script_dep.map(|dep| {
+            fs::read_file(&dep.join("output"))
+                .expect("failed to read build_script_build output")
+                .expect("missing build_script_build output")
+        });
+        let build_script_output =
+            build_script_output_bytes.as_deref().map(BuildOutput::parse);
+
+        // Collect native library search paths from our build script and all
+        // our deps' build scripts.
+        let link_search = time!(
+            "collect link search paths",
+            self.collect_link_search(build_script_output.as_ref())
+        );
 
-        // For `build.rs` scripts, we also run the `build_script_build`
-        if self.target.is_custom_build() {
-            self.run_build_script();
+        // Compile unit (or build and run its doctests, or document it)
+        match self.mode {
+            CompileMode::Doctest => self
+                .run_rustdoc_test(build_script_output.as_ref(), &link_search),
+            CompileMode::Doc => self.run_rustdoc(
+                build_script_output.as_ref(),
+                &link_search,
+                &tdep_lib_filenames,
+            ),
+            _ => self.run_rustc(build_script_output.as_ref(), &link_search),
+        }
+
+        // Move any cdylib/staticlib outputs into `$out/lib` for C consumers.
+        if self.mode == CompileMode::Build && self.target.has_c_lib() {
+            time!("install c libs", self.install_c_libs());
         }
 
         // For targets that should propagate, also collect our direct deps into
-        // `$out/deps`.
-        if self.target.propagates_deps() {
+        // `$out/deps`. Tests and docs never propagate anything.
+        if self.target.propagates_deps()
+            && matches!(
+                self.mode,
+                CompileMode::Build
+                    | CompileMode::Check
+                    | CompileMode::Clippy
+                    | CompileMode::Metadata
+            )
+        {
             time!(
                 "collect direct deps",
                 self.collect_direct_deps(tdep_lib_filenames)
             );
+            self.write_link_search(&link_search);
         } else {
             let _ = time!(
                 "clear deps dir",
@@ -192,7 +306,32 @@ impl<'a> BuildContext<'a> {
         }
     }
 
-    fn run_rustc(&self) {
+    /// With `prefer_dynamic` in our profile, our workspace bins and tests link
+    /// `libstd` and our Rust dylib deps dynamically. So do the dylibs
+    /// themselves, since `buildGraph.nix` then also builds every normal
+    /// workspace lib as a dylib.
+    fn links_dynamically(&self) -> bool {
+        self.profile.prefer_dynamic
+            && links_dynamically(self.is_workspace_pkg, self.mode, &self.target)
+    }
+
+    /// The store dirs containing `libstd-*.so` and all our (transitive) Rust
+    /// dylib deps, for our rpath.
+    fn dylib_rpaths(&self, target_triple: &str) -> BTreeSet<PathBuf> {
+        let target_libdir = rustc_target_libdir(self.src, target_triple);
+        dylib_rpaths(target_libdir, &self.deps, &self.out.join("deps"))
+    }
+
+    /// True if we're either half of a pipelined lib.
+    fn is_pipelined(&self) -> bool {
+        self.pipelined || self.mode == CompileMode::Metadata
+    }
+
+    fn run_rustc(
+        &self,
+        build_script_output: Option<&BuildOutput<'_>>,
+        link_search: &[String],
+    ) {
         // run: `rustc` compile
 
         // format!("{src}=/build/{pkg_name}-{version}")
@@ -207,35 +346,85 @@ impl<'a> BuildContext<'a> {
 
         let target_path = self.src.join(self.target.path);
 
-        // build the build.rs script with the build profile/target triple, but
-        // run the script with normal profile/target triple
-        let profile = if self.target.is_custom_build() {
-            self.host_profile.as_ref().unwrap()
+        // Build scripts are compiled for the host target triple, with the
+        // build-override profile (see `run_build_script`).
+        let profile = &self.profile;
+        let (target_triple, linker) = if self.target.is_custom_build() {
+            (self.host_triple, self.host_cc)
         } else {
-            &self.profile
+            (self.target_triple, self.target_cc)
         };
-        let target_triple = if self.target.is_custom_build() {
-            self.host_target_triple.as_ref().unwrap()
+
+        let metadata = self.metadata();
+
+        // `cargo clippy` just swaps in `clippy-driver`, which takes the same
+        // args as `rustc`.
+        let program = if self.mode.is_clippy() {
+            "clippy-driver"
         } else {
-            &self.target_triple
+            "rustc"
         };
-
-        let mut cmd = Command::new("rustc");
+        let mut cmd = Command::new(program);
         cmd.current_dir(self.src);
-        cmd.args(["--crate-name", &self.target.crate_name])
-            .args(["--crate-type", self.target.crate_types_str])
-            .args(["--edition", self.target.edition])
+        cmd.args(["--crate-name", &self.target.crate_name]);
+
+        // `--test` builds a libtest harness binary (and implies `--cfg test`)
+        // instead of the target's usual crate types. Tests without a harness
+        // are just plain bins.
+        match self.mode {
+            CompileMode::Test if self.harness => cmd.arg("--test"),
+            CompileMode::Test => cmd.args(["--crate-type", "bin"]),
+            _ => cmd.args(["--crate-type", self.target.crate_types_str]),
+        };
+
+        cmd.args(["--edition", self.target.edition])
             .args([OsStr::new("--remap-path-prefix"), &remap])
             .args(["--target", target_triple])
-            .arg("--error-format=human")
-            .arg("--diagnostic-width=80")
-            .arg("--cap-lints=allow");
+            .arg("--diagnostic-width=80");
+
+        // Like cargo, only show warnings for our own workspace packages.
+        if !self.is_workspace_pkg {
+            cmd.arg("--cap-lints=allow");
+        }
+
+        // We record the diagnostics in `$out/diagnostics.json` (see
+        // `run_rustc_diagnostics`), except in the `Metadata` half of a
+        // pipelined lib. For pipelined libs, we also need rustc to tell us as
+        // soon as it's written each artifact (see `run_rustc_pipelined`).
+        cmd.arg("--error-format=json");
+        if self.is_pipelined() {
+            cmd.arg("--json=artifacts,diagnostic-rendered-ansi");
+        } else {
+            cmd.arg("--json=diagnostic-rendered-ansi");
+        }
 
         //              bins: `-o $out/bin/${target.name}`
         // libs/custom-build: `--out-dir $out`
+        //             check: `--out-dir $out` (only `lib${crate_name}*.rmeta`)
+        //              test: `-o $out/bin/${target.name}-${metadata}`
+        //         pipelined: `--out-dir $TMPDIR/nargo-pipelined` (see
+        //                    `run_rustc_pipelined`)
         //
         // TODO(phlip9): should probably put linkable libs into `lib` dir
         match self.target.kind {
+            _ if self.is_pipelined() => {
+                let out_dir = pipelined_scratch_dir();
+                std::fs::create_dir_all(&out_dir).expect("mkdir");
+                cmd.args([OsStr::new("--out-dir"), out_dir.as_os_str()]);
+            }
+            _ if self.mode.is_check() => {
+                cmd.args([OsStr::new("--out-dir"), self.out.as_os_str()]);
+            }
+            // Like cargo, suffix test binaries with the metadata hash, so the
+            // lib and bin tests of a package don't collide.
+            _ if self.mode.is_test() => {
+                let mut out_file = self.out.join("bin");
+                fs::create_dir(&out_file).expect("mkdir");
+
+                out_file.push(format!("{}-{metadata}", self.target.name));
+
+                cmd.args([OsStr::new("-o"), out_file.as_os_str()]);
+            }
             TargetKind::Bin
             | TargetKind::Test
             | TargetKind::Bench
@@ -260,18 +449,46 @@ impl<'a> BuildContext<'a> {
 
         // TODO(phlip9): `-Zallow-features` for unstable features in config.toml
 
-        // TODO(phlip9): `cargo check` => only `--emit=metadata`
-
-        // TODO(phlip9): can we even do pipelining?
-        // if self.target.requires_upstream_objects() {
-        //     cmd.arg("--emit=link");
-        // } else {
-        //     cmd.arg("--emit=metadata,link");
-        // }
-        cmd.arg("--emit=link");
+        // Pipelining: `buildGraph.nix` splits each rlib unit into two
+        // derivations. The `Metadata` half only keeps the `.rmeta`, which is
+        // all that downstream rlibs need to start compiling. The `Build` half
+        // keeps the `.rlib`, which only final linking units wait on.
+        //
+        // NOTE: both halves must run `rustc` with the exact same args,
+        // otherwise the crate hashes in the `.rmeta` and `.rlib` won't match.
+        // The `Metadata` half also needs `link`, since `rustc` only encodes
+        // the MIR that downstream crates need for codegen when it also does
+        // codegen itself. We just stop it early.
+        let emit = match self.mode {
+            _ if self.is_pipelined() => "metadata,link",
+            CompileMode::Build | CompileMode::Test => "link",
+            // `cargo check` => only `--emit=metadata`. Dependents then
+            // compile against our `lib${crate_name}-${metadata}.rmeta`.
+            CompileMode::Check
+            | CompileMode::Clippy
+            | CompileMode::Metadata => "metadata",
+            CompileMode::Doctest | CompileMode::Doc => {
+                unreachable!("doctests and docs run `rustdoc`")
+            }
+            CompileMode::RunCustomBuild => {
+                unreachable!("running a build script doesn't compile it")
+            }
+        };
+        // Workspace units also record which source files they read. See
+        // `sources.rs`.
+        let mut emit_arg = OsString::from(format!("--emit={emit}"));
+        if self.is_workspace_pkg {
+            emit_arg.push(",dep-info=");
+            emit_arg.push(sources::dep_info_path());
+        }
+        cmd.arg(emit_arg);
 
         // -C prefer-dynamic
-        if self.target.is_proc_macro() || self.target.is_dylib() {
+        let links_dynamically = self.links_dynamically();
+        if self.target.is_proc_macro()
+            || self.target.is_dylib()
+            || links_dynamically
+        {
             cmd.arg("-Cprefer-dynamic");
         }
 
@@ -285,8 +502,20 @@ impl<'a> BuildContext<'a> {
             cmd.arg(format!("-Cpanic={}", profile.panic));
         }
 
-        // TODO(phlip9): LTO
-        cmd.arg("-Cembed-bitcode=no");
+        // -C lto={}, -C linker-plugin-lto, -C embed-bitcode=no
+        //
+        // No codegen in check mode, so LTO is irrelevant.
+        //
+        // Like cargo, test binaries participate in LTO just like bins.
+        if !self.mode.is_check() {
+            let crate_types: &[CrateType] = if self.mode.is_test() {
+                &[CrateType::Bin]
+            } else {
+                &self.target.crate_types
+            };
+            let lto = Lto::new(profile.lto, crate_types, self.lto_needs_object);
+            lto.add_args(&mut cmd);
+        }
 
         // TODO(phlip9): codegen backend
 
@@ -299,9 +528,9 @@ impl<'a> BuildContext<'a> {
             cmd.arg(format!("-Cdebuginfo={}", profile.debuginfo));
 
             // TODO(phlip9): check if target platform supports split debuginfo
-            // if let Some(split_debuginfo) = &profile.split_debuginfo {
-            //
-            // }
+            if let Some(split_debuginfo) = profile.split_debuginfo {
+                cmd.arg(format!("-Csplit-debuginfo={split_debuginfo}"));
+            }
         }
 
         // TODO(phlip9): trim paths
@@ -330,16 +559,310 @@ impl<'a> BuildContext<'a> {
             cmd.arg("-Coverflow-checks=off");
         }
 
-        // TODO(phlip9): any test in unit => `--cfg test`
+        // --cfg feature="{feature}", --check-cfg cfg(feature, values(...))
+        self.args_features(&mut cmd);
+
+        // `[lints]` => --warn=clippy::pedantic, ... then any extra lint args,
+        // so e.g. `-D warnings` takes precedence.
+        cmd.args(&self.lints);
+        if self.mode.is_clippy() {
+            cmd.args(&self.lint_args);
+        }
+
+        cmd.arg(format!("-Cmetadata={metadata}"));
+        if self.target.uses_extra_filename() && !self.mode.is_test() {
+            cmd.arg(format!("-Cextra-filename=-{metadata}"));
+        }
+        if let Some(incremental) = self.incremental {
+            let mut arg = OsString::from("-Cincremental=");
+            arg.push(incremental);
+            cmd.arg(arg);
+        }
+
+        if profile.rpath {
+            cmd.arg("-Crpath");
+        }
+
+        // Dynamically linked bins need to find `libstd-*.so` and our Rust
+        // dylib deps at runtime, so point the rpath at their store paths.
+        if links_dynamically {
+            for dir in self.dylib_rpaths(target_triple) {
+                cmd.arg(format!("-Clink-arg=-Wl,-rpath,{}", dir.display()));
+            }
+        }
+
+        // -C linker={}
+        if let Some(linker) = linker {
+            cmd.arg(format!("-Clinker={linker}"));
+        }
+
+        if profile.strip != "none" {
+            cmd.arg(format!("-Cstrip={}", profile.strip));
+        }
+
+        // TODO(phlip9): handle build std
+
+        // --extern <dep-name>=<lib-path>, -L dependency=..., -L [KIND=]PATH
+        self.args_deps(&mut cmd, link_search);
+
+        // proc-macro -> --extern proc_macro
+        if self.target.is_proc_macro() {
+            cmd.arg("--extern");
+            cmd.arg("proc_macro");
+        }
+
+        // --cfg/--check-cfg/envs/OUT_DIR from our build script
+        self.args_build_script(&mut cmd, build_script_output);
+        self.args_extra_cfgs_envs(&mut cmd);
+
+        // TODO(phlip9): plugins?
+        if let Some(build_script_output) = build_script_output {
+            // Only our own package's build script can link native libs. The
+            // `-l` gets recorded in our rlib, so downstream crates only need
+            // the `-L` search paths.
+            for lib in &build_script_output.library_links {
+                cmd.arg("-l");
+                cmd.arg(lib.to_string());
+            }
+
+            // -C link-arg={}
+            if self.target.requires_upstream_objects() || self.mode.is_test() {
+                for (link_arg_target, link_arg) in
+                    &build_script_output.linker_args
+                {
+                    if self.target.wants_link_arg(*link_arg_target) {
+                        cmd.arg(format!("-Clink-arg={link_arg}"));
+                    }
+                }
+            }
+        }
+
+        cmd.arg(target_path);
+
+        // envs
+        cmd.env("CARGO_CRATE_NAME", self.target.crate_name.as_ref());
+
+        if self.target.is_executable() {
+            cmd.env("CARGO_BIN_NAME", self.target.name);
+        }
+
+        // Integration tests and benches can find our package's bins via
+        // `env!("CARGO_BIN_EXE_<name>")` and get a scratch dir at
+        // `env!("CARGO_TARGET_TMPDIR")`. Since that's baked into the binary,
+        // it can't be our sandbox `$TMPDIR`, which is gone by the time the
+        // test runs. Instead it's a fixed path per unit, which the test runner
+        // creates.
+        if matches!(self.target.kind, TargetKind::Test | TargetKind::Bench) {
+            self.envs_bin_exe(&mut cmd);
+            let target_tmpdir = test_runner::target_tmpdir(
+                self.pkg_name,
+                self.target.version.as_str(),
+                self.metadata(),
+            );
+            cmd.env("CARGO_TARGET_TMPDIR", target_tmpdir);
+        }
+
+        // CARGO_MANIFEST_<...>, CARGO_PKG_<...>, and CARGO_PRIMARY_PACKAGE
+        // envs
+        cmd.envs_cargo_crate(self);
+
+        trace!("{}", cmd.to_string_debug());
+        repro::record(&cmd);
+        logger::flush();
+
+        if self.is_pipelined() {
+            time!("run rustc (pipelined)", self.run_rustc_pipelined(cmd));
+        } else {
+            time!("run rustc", self.run_rustc_diagnostics(cmd));
+        }
+
+        if self.is_workspace_pkg {
+            sources::write(
+                self.pkg_name,
+                self.target.name,
+                self.target.kind.as_str(),
+                self.src,
+                self.workspace_src,
+                self.out,
+            );
+        }
+    }
+
+    /// Build and run our lib target's doctests with `rustdoc --test`. Like
+    /// `cargo test --doc`, `rustdoc` sees the same features, cfgs, and deps
+    /// (including dev-deps and our lib itself) as `rustc` does.
+    ///
+    /// Writes the results to `$out` (see [`crate::test_report`]) and fails if
+    /// any doctest fails.
+    fn run_rustdoc_test(
+        &self,
+        build_script_output: Option<&BuildOutput<'_>>,
+        link_search: &[String],
+    ) {
+        // run: `rustdoc --test`
+
+        let mut cmd = Command::new("rustdoc");
+
+        // Like cargo, pass the target path relative to the package root, so
+        // the doctests get names like `src/lib.rs - foo (line 12)`.
+        cmd.current_dir(self.src)
+            .arg("--test")
+            .arg(self.target.path)
+            .args(["--crate-name", &self.target.crate_name])
+            .args(["--crate-type", self.target.crate_types_str])
+            .args(["--edition", self.target.edition])
+            .args(["--target", self.target_triple])
+            .arg("--cap-lints=allow")
+            .arg("-Zunstable-options")
+            .arg("--test-run-directory")
+            .arg(self.src)
+            .env("RUSTC_BOOTSTRAP", "1");
+
+        // -C linker={}
+        if let Some(linker) = self.target_cc {
+            cmd.arg(format!("-Clinker={linker}"));
+        }
+
+        // Have libtest report each doctest as JSON, like our other tests.
+        for arg in test_runner::LIBTEST_JSON_ARGS {
+            cmd.arg(format!("--test-args={arg}"));
+        }
+
+        // --cfg feature="{feature}", --check-cfg cfg(feature, values(...))
+        self.args_features(&mut cmd);
+
+        // --extern <dep-name>=<lib-path>, -L dependency=..., -L [KIND=]PATH
+        self.args_deps(&mut cmd, link_search);
 
-        // --cfg feature="{feature}"
+        // --cfg/--check-cfg/envs/OUT_DIR from our build script
+        self.args_build_script(&mut cmd, build_script_output);
+        self.args_extra_cfgs_envs(&mut cmd);
+
+        // envs
+        cmd.env("CARGO_CRATE_NAME", self.target.crate_name.as_ref())
+            .envs_cargo_crate(self);
+
+        trace!("{}", cmd.to_string_debug());
+        repro::record(&cmd);
+        logger::flush();
+        eprintln!("   Doc-tests {}", self.target.crate_name);
+
+        let name = format!("{}::doctests", self.pkg_name);
+        let suite = time!(
+            "run rustdoc --test",
+            test_runner::run_suite(cmd, name, "rustdoc", true)
+        );
+
+        let version = self.target.version.as_str();
+        let report = UnitReport {
+            pkg_name: self.pkg_name,
+            version,
+            suites: vec![suite],
+        };
+        report.write(self.out);
+
+        let failed = report.counts().failed;
+        if failed != 0 {
+            panic!("{failed} doctest(s) failed in {}@{version}", self.pkg_name);
+        }
+    }
+
+    /// Generate our lib target's HTML docs in `$out/doc/` with `rustdoc`, using
+    /// the same features, cfgs, and deps as `rustc`.
+    ///
+    /// Each doc unit only documents its own crate. We write its part of the
+    /// shared cross-crate info (search index, etc.) to `$out/doc.parts/` and
+    /// leave it to `mergeDocs` to combine all the doc units into a single doc
+    /// tree with `rustdoc --merge=finalize`.
+    ///
+    /// Since each doc unit only sees its deps' `.rmeta`s, we tell `rustdoc`
+    /// that all our (transitive) deps live next to us in the merged doc tree
+    /// with `--extern-html-root-url <crate>=../`.
+    fn run_rustdoc(
+        &self,
+        build_script_output: Option<&BuildOutput<'_>>,
+        link_search: &[String],
+        tdep_lib_filenames: &BTreeMap<OsString, PathBuf>,
+    ) {
+        // run: `rustdoc`
+
+        let mut cmd = Command::new("rustdoc");
+
+        // Pass the target path relative to the package root, so the source
+        // pages don't leak the nix store path.
+        let mut parts_out_dir = OsString::from("--parts-out-dir=");
+        parts_out_dir.push(self.out.join("doc.parts"));
+        cmd.current_dir(self.src)
+            .arg(self.target.path)
+            .args(["--crate-name", &self.target.crate_name])
+            .args(["--crate-type", self.target.crate_types_str])
+            .args(["--edition", self.target.edition])
+            .args(["--target", self.target_triple])
+            .arg("--cap-lints=allow")
+            .args([OsStr::new("-o"), self.out.join("doc").as_os_str()])
+            .arg("-Zunstable-options")
+            .arg("--merge=none")
+            .arg(parts_out_dir)
+            .env("RUSTC_BOOTSTRAP", "1");
+
+        // --cfg feature="{feature}", --check-cfg cfg(feature, values(...))
+        self.args_features(&mut cmd);
+
+        // --extern <dep-name>=<lib-path>, -L dependency=..., -L [KIND=]PATH
+        self.args_deps(&mut cmd, link_search);
+
+        // proc-macro -> --extern proc_macro
+        if self.target.is_proc_macro() {
+            cmd.arg("--extern");
+            cmd.arg("proc_macro");
+        }
+
+        // --extern-html-root-url <crate>=../
+        let dep_lib_filenames = self
+            .deps
+            .iter()
+            .flat_map(|dep| dep.libs.iter().map(String::as_str))
+            .chain(tdep_lib_filenames.keys().filter_map(|f| f.to_str()));
+        let dep_crate_names = dep_lib_filenames
+            .filter_map(lib_filename_crate_name)
+            .collect::<BTreeSet<_>>();
+        for crate_name in dep_crate_names {
+            cmd.arg("--extern-html-root-url");
+            cmd.arg(format!("{crate_name}=../"));
+        }
+
+        // --cfg/--check-cfg/envs/OUT_DIR from our build script
+        self.args_build_script(&mut cmd, build_script_output);
+        self.args_extra_cfgs_envs(&mut cmd);
+
+        // envs
+        cmd.env("CARGO_CRATE_NAME", self.target.crate_name.as_ref())
+            .envs_cargo_crate(self);
+
+        trace!("{}", cmd.to_string_debug());
+        repro::record(&cmd);
+        logger::flush();
+
+        let status = time!("run rustdoc", cmd.status())
+            .expect("failed to run `rustdoc`");
+        if !status.success() {
+            let code = status.code().unwrap_or(1);
+            panic!("`rustdoc` exited with non-zero exit code: {code}");
+        }
+
+        // Every doc unit would have one, which conflicts when merging.
+        let _ = std::fs::remove_file(self.out.join("doc/.lock"));
+    }
+
+    /// `--cfg feature="{feature}"` for each enabled feature, plus a
+    /// `--check-cfg cfg(feature, values(...))`.
+    fn args_features(&self, cmd: &mut Command) {
         let features = &self.target.features;
         for feature in features {
             cmd.arg("--cfg");
             cmd.arg(format!("feature=\"{feature}\""));
         }
 
-        // --check-cfg cfg(feature, values(...))
         if !features.is_empty() {
             let mut cfg = String::with_capacity(
                 22 + 4 * features.len()
@@ -359,45 +882,22 @@ impl<'a> BuildContext<'a> {
             cmd.arg("--check-cfg");
             cmd.arg(cfg);
         }
+    }
 
-        // TODO(phlip9): is metadata from $out safe?
-        let metadata = self.out.file_name().unwrap().as_encoded_bytes();
-        let metadata = str::from_utf8(&metadata[..8]).unwrap();
-        cmd.arg(format!("-Cmetadata={metadata}"));
-        if self.target.uses_extra_filename() {
-            cmd.arg(format!("-Cextra-filename=-{metadata}"));
-        }
-
-        if profile.rpath {
-            cmd.arg("-Crpath");
-        }
-
-        // TODO(phlip9): -C linker={}
-
-        if profile.strip != "none" {
-            cmd.arg(format!("-Cstrip={}", profile.strip));
-        }
-
-        // TODO(phlip9): handle build std
-
+    /// `--extern` our direct deps, and `-L` our transitive deps and native lib
+    /// search paths.
+    fn args_deps(&self, cmd: &mut Command, link_search: &[String]) {
         // direct deps: --extern <dep-name>=<lib-path>
-        {
-            let mut buf = OsString::new();
-            for dep in &self.deps {
-                cmd.arg("--extern");
-
-                buf.clear();
-                buf.push(OsStr::new(&dep.dep_name.replace('-', "_")));
-                buf.push(OsStr::new("="));
-                buf.push(dep.lib_path());
-                cmd.arg(&buf);
-            }
-        }
-
-        // proc-macro -> --extern proc_macro
-        if self.target.is_proc_macro() {
+        let links_dynamically = self.links_dynamically();
+        let mut buf = OsString::new();
+        for dep in &self.deps {
             cmd.arg("--extern");
-            cmd.arg("proc_macro");
+
+            buf.clear();
+            buf.push(OsStr::new(&dep.dep_name.replace('-', "_")));
+            buf.push(OsStr::new("="));
+            buf.push(dep.extern_lib_path(links_dynamically));
+            cmd.arg(&buf);
         }
 
         // transitive deps: `-L dependency=${out}/deps` (via `collect_transitive_deps`)
@@ -415,87 +915,262 @@ impl<'a> BuildContext<'a> {
             cmd.arg(buf);
         }
 
-        // TODO(phlip9): have build.rs script => parse `<build-script-drv>/out`
-        // 1. add `-l` and `-L` link flags
-        // 2. add `-Clink-arg={}`
-        // 3. plugins?
-        if let Some(build_script_dep) = &self.build_script_dep {
-            let path = build_script_dep.join("output");
-            let bytes = fs::read_file(&path)
-                .expect("failed to read build_script_build output")
-                .expect("missing build_script_build output");
+        // native lib search paths from our and our deps' build scripts:
+        // `-L [KIND=]PATH`
+        for path in link_search {
+            cmd.arg("-L");
+            cmd.arg(path);
+        }
+    }
 
-            let build_script_output = BuildOutput::parse(&bytes);
-            for check_cfg in build_script_output.check_cfgs {
+    /// The extra `--cfg`s and envs from our unit spec, like cargo's
+    /// `RUSTFLAGS="--cfg ..."` and `[env]` config.
+    fn args_extra_cfgs_envs(&self, cmd: &mut Command) {
+        for cfg in &self.cfgs {
+            cmd.arg("--cfg");
+            cmd.arg(cfg);
+        }
+        cmd.envs(self.envs.iter().copied());
+    }
+
+    /// The `--cfg`s, `--check-cfg`s, and envs from our package's build script
+    /// output, plus its `OUT_DIR`.
+    fn args_build_script(
+        &self,
+        cmd: &mut Command,
+        build_script_output: Option<&BuildOutput<'_>>,
+    ) {
+        if let (Some(build_script_dep), Some(build_script_output)) =
+            (&self.build_script_dep, build_script_output)
+        {
+            for check_cfg in &build_script_output.check_cfgs {
                 cmd.arg("--check-cfg");
                 cmd.arg(check_cfg);
             }
-            for cfg in build_script_output.cfgs {
+            for cfg in &build_script_output.cfgs {
                 cmd.arg("--cfg");
                 cmd.arg(cfg);
             }
-            for (key, val) in build_script_output.env {
+            for (key, val) in &build_script_output.env {
                 cmd.env(key, val);
             }
 
             cmd.env("OUT_DIR", build_script_dep.join("out"));
         }
+    }
 
-        cmd.arg(target_path);
+    /// The `-C metadata` hash for this unit. `buildGraph.nix` computes a
+    /// stable hash from the unit's identity, like cargo. Otherwise (ex: when
+    /// run by hand) we fall back to the first 8 chars of our `$out` store path
+    /// hash.
+    fn metadata(&self) -> &'a str {
+        match self.metadata {
+            Some(metadata) => metadata,
+            None => {
+                let metadata = self.out.file_name().unwrap().as_encoded_bytes();
+                str::from_utf8(&metadata[..8]).unwrap()
+            }
+        }
+    }
 
-        // envs
-        cmd.env("CARGO_CRATE_NAME", self.target.crate_name.as_ref())
-            .env("CARGO_MANIFEST_DIR", self.src);
+    /// Tell `cc-rs` (and friends) which C compiler and `ar` to use for the
+    /// host and target triples, via `CC_<triple>` and `AR_<triple>` envs.
+    fn envs_toolchains(&self, cmd: &mut Command) {
+        let toolchains = [
+            (self.host_triple, self.host_cc, self.host_ar),
+            (self.target_triple, self.target_cc, self.target_ar),
+        ];
+        for (triple, cc, ar) in toolchains {
+            let triple = triple.replace('-', "_");
+            if let Some(cc) = cc {
+                cmd.env(format!("CC_{triple}"), cc);
+            }
+            if let Some(ar) = ar {
+                cmd.env(format!("AR_{triple}"), ar);
+            }
+        }
+    }
 
-        if self.target.is_executable() {
-            cmd.env("CARGO_BIN_NAME", self.target.name);
+    /// Set a `CARGO_BIN_EXE_<name>` env for each bin in our package's bin
+    /// units' `$out/bin` dirs.
+    fn envs_bin_exe(&self, cmd: &mut Command) {
+        let mut env_key = OsString::new();
+        for bin_dep in &self.bin_deps {
+            let bin_dir = bin_dep.join("bin");
+            let dir_iter =
+                std::fs::read_dir(&bin_dir).expect("Failed to read bin dep");
+            for dir_entry in dir_iter {
+                let dir_entry =
+                    dir_entry.expect("Failed to read bin dep entry");
+                env_key.clear();
+                env_key.push("CARGO_BIN_EXE_");
+                env_key.push(dir_entry.file_name());
+                cmd.env(&env_key, dir_entry.path());
+            }
         }
+    }
 
-        // TODO(phlip9): set `CARGO_BIN_EXE_` env for tests and benches
+    /// Run `rustc` for one half of a pipelined lib, then copy the artifact we
+    /// want into `$out`. The `Metadata` half kills `rustc` as soon as it's
+    /// written the `.rmeta`. The `Build` half waits for the `.rlib`.
+    ///
+    /// `rustc` reports each artifact as soon as it's written via
+    /// `--json=artifacts`. Any diagnostics we see along the way get replayed
+    /// to stderr and checked against `lint_fail_on`. Only the `Build` half
+    /// records them in `$out/diagnostics.json`, since the `Metadata` half
+    /// never sees anything `rustc` reports after writing the `.rmeta` (ex:
+    /// codegen or linker warnings).
+    fn run_rustc_pipelined(&self, mut cmd: Command) {
+        let want_emit = match self.mode {
+            CompileMode::Metadata => "metadata",
+            _ => "link",
+        };
 
-        // TODO(phlip9): set `CARGO_PRIMARY_PACKAGE=1` if we're a `-p` primary
-        // workspace package target.
+        let mut child = cmd
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to run `rustc`");
+        let stderr = BufReader::new(child.stderr.take().unwrap());
 
-        // TODO(phlip9): set `CARGO_TARGET_TMPDIR` if test or bench unit
+        let mut diagnostics = Diagnostics::new();
+        let mut artifact = None;
+        let mut reached_eof = true;
+        for line in stderr.lines() {
+            let line = line.expect("failed to read `rustc` stderr");
 
-        // CARGO_PKG_<...> envs
-        cmd.envs_cargo_pkg(self);
+            if diagnostics.push_line(&line) {
+                continue;
+            }
+            if !line.starts_with('{') {
+                eprintln!("{line}");
+                continue;
+            }
+            if json::top_level_str_field(&line, "emit").as_deref()
+                == Some(want_emit)
+            {
+                artifact = json::top_level_str_field(&line, "artifact");
+                // The `Build` half keeps reading any remaining diagnostics.
+                if self.mode == CompileMode::Metadata {
+                    reached_eof = false;
+                    break;
+                }
+            }
+        }
+        diagnostics.finish(reached_eof);
 
-        trace!("{}", cmd.to_string_debug());
-        logger::flush();
+        if self.mode != CompileMode::Metadata {
+            diagnostics.write(self.out);
+        }
 
-        let status =
-            time!("run rustc", cmd.status()).expect("failed to run `rustc`");
-        if !status.success() {
+        let artifact = match artifact {
+            Some(artifact) => PathBuf::from(artifact),
+            None => {
+                let status = child.wait().expect("failed to wait on `rustc`");
+                let code = status.code().unwrap_or(1);
+                panic!(
+                    "`rustc` exited before writing our `{want_emit}` \
+                     artifact, with exit code: {code}"
+                );
+            }
+        };
+
+        if self.mode == CompileMode::Metadata {
+            // We only wanted the `.rmeta`; the codegen isn't used.
+            let _ = child.kill();
+            let _ = child.wait();
+            self.check_diagnostics("rustc", None, &diagnostics);
+        } else {
+            let status = child.wait().expect("failed to wait on `rustc`");
+            self.check_diagnostics("rustc", Some(status), &diagnostics);
+        }
+
+        let filename = artifact.file_name().expect("artifact filename");
+        std::fs::copy(&artifact, self.out.join(filename))
+            .expect("failed to copy pipelined artifact into $out");
+    }
+
+    /// Run `rustc` (or `clippy-driver`) with `--error-format=json`, replaying
+    /// its diagnostics to stderr and recording them in
+    /// `$out/diagnostics.json`.
+    fn run_rustc_diagnostics(&self, mut cmd: Command) {
+        let program = cmd.get_program().to_string_lossy().into_owned();
+        let mut child = cmd
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|err| panic!("failed to run `{program}`: {err}"));
+        let stderr = BufReader::new(child.stderr.take().unwrap());
+
+        let mut diagnostics = Diagnostics::new();
+        for line in stderr.lines() {
+            let line = line.expect("failed to read `rustc` stderr");
+            if !diagnostics.push_line(&line) {
+                eprintln!("{line}");
+            }
+        }
+        diagnostics.finish(true);
+        let status = child.wait().expect("failed to wait on `rustc`");
+
+        diagnostics.write(self.out);
+        self.check_diagnostics(&program, Some(status), &diagnostics);
+    }
+
+    /// Fail if `rustc` failed, or if its diagnostics fail our `lint_fail_on`
+    /// policy. Only `Clippy` units can ignore a failed `rustc` with
+    /// [`FailOn::Never`], since they only exist to collect diagnostics.
+    fn check_diagnostics(
+        &self,
+        program: &str,
+        status: Option<ExitStatus>,
+        diagnostics: &Diagnostics,
+    ) {
+        let ignore_failure =
+            self.mode.is_clippy() && self.lint_fail_on == FailOn::Never;
+        let failed = status.filter(|status| !status.success());
+        if let (Some(status), false) = (failed, ignore_failure) {
             let code = status.code().unwrap_or(1);
-            panic!("`rustc` exited with non-zero exit code: {code}");
+            panic!("`{program}` exited with non-zero exit code: {code}");
+        }
+        if let Some(msg) = diagnostics.check(self.lint_fail_on) {
+            panic!("`{program}` reported {msg}");
         }
     }
 
+    /// Run our package's compiled build script, which another (`Build`) unit
+    /// compiled with the build-override profile for the host. Here, our
+    /// `profile` and `target_triple` are the ones it configures the package
+    /// for.
     fn run_build_script(&self) {
         // run:
         // ```
         // OUT_DIR=$out/out \
-        // $out/build_script_build \
+        // $build_script_bin/build_script_build \
         //   1>$out/output \
         //   2>$out/stderr
         // ```
         //
         // TODO(phlip9): faithfully impl <src/cargo/core/compiler/custom_build.rs>
 
-        // Query `rustc` for the cfgs it sets for this target triple. ~17 ms.
-        // TODO(phlip9): cache this in a separate drv and pass in only to
-        // custom-build targets.
-        let rustc_target_cfgs = time!(
-            "rustc --print cfg",
-            RustcTargetCfg::collect(self.target_triple)
-        );
+        let build_script_bin = self
+            .build_script_bin
+            .expect("run-custom-build unit is missing `build_script_bin`");
+
+        // The cfgs `rustc` sets for this target triple. nix and `nargo-build`
+        // query these once per target triple, so we only need to run `rustc`
+        // (~17 ms) if we're run by hand.
+        let rustc_target_cfgs = match self.rustc_target_cfg {
+            Some(path) => RustcTargetCfg::from_file(path),
+            None => time!(
+                "rustc --print cfg",
+                RustcTargetCfg::collect(self.target_triple)
+            ),
+        };
 
         let build_script_stdout = self.out.join("output");
         let build_script_stderr = self.out.join("stderr");
 
-        let mut cmd =
-            Command::new(self.out.join(self.target.crate_name.as_ref()));
+        let mut cmd = Command::new(
+            build_script_bin.join(self.target.crate_name.as_ref()),
+        );
         cmd.current_dir(self.src)
             .stdout(File::create(&build_script_stdout).expect("$out/output"))
             .stderr(File::create(&build_script_stderr).expect("$out/stderr"));
@@ -506,30 +1181,45 @@ impl<'a> BuildContext<'a> {
         let profile = &self.profile;
         let debug = profile.debuginfo != "0";
 
-        // TODO(phlip9): `links`, `DEP_<name>_<key>`, `NUM_JOBS`, `RUSTC_LINKER`
+        // TODO(phlip9): `NUM_JOBS`
         cmd.env("CARGO", "") // TODO
             .env("CARGO_CFG_PANIC", profile.panic)
             .env("CARGO_ENCODED_RUSTFLAGS", "")
             .env("CARGO_MAKEFLAGS", "") // TODO
-            .env("CARGO_MANIFEST_DIR", self.src) // TODO(phlip9): incorrect for workspace
-            .env("CARGO_MANIFEST_LINKS", "") // TODO
             .env("DEBUG", debug.to_string())
-            .env("HOST", self.host_target_triple.as_ref().unwrap())
+            .env("HOST", self.host_triple)
             .env("OPT_LEVEL", profile.opt_level.to_string())
             .env("OUT_DIR", &out_dir)
-            .env("PROFILE", profile.name) // TODO(phlip9): should be base?
+            .env("PROFILE", profile.build_script_env())
             .env("RUSTC", "rustc")
             .env("RUSTDOC", "rustdoc")
             .env("TARGET", self.target_triple);
 
+        if let Some(links) = self.links {
+            cmd.env("CARGO_MANIFEST_LINKS", links);
+        }
+
+        if let Some(linker) = self.target_cc {
+            cmd.env("RUSTC_LINKER", linker);
+        }
+
+        // CC_<triple>, AR_<triple> envs for `cc-rs`
+        self.envs_toolchains(&mut cmd);
+
+        // DEP_<links>_<key>=<value> envs from our direct deps' build scripts
+        self.envs_links_deps(&mut cmd);
+
         // rustc target cfg envs
         rustc_target_cfgs.env_cfgs(|env_key, env_value| {
             cmd.env(env_key, env_value);
         });
 
-        // CARGO_PKG_<...> envs
+        // CARGO_MANIFEST_<...> and CARGO_PKG_<...> envs
         cmd.envs_cargo_pkg(self);
 
+        // Extra envs from our unit spec
+        cmd.envs(self.envs.iter().copied());
+
         // CARGO_FEATURE_<feature>=1 envs
         let mut feature_key = String::new();
         for feature in &self.target.features {
@@ -537,25 +1227,19 @@ impl<'a> BuildContext<'a> {
             feature_key.clear();
             feature_key.reserve_exact(PREFIX.len() + feature.len());
             feature_key.push_str(PREFIX);
-            for c in feature.chars() {
-                let c = c.to_ascii_uppercase();
-                let c = if c == '-' { '_' } else { c };
-                feature_key.push(c);
-            }
+            push_envify(&mut feature_key, feature);
             cmd.env(&feature_key, "1");
         }
 
-        // TODO(phlip9): parse `cargo::error=MESSAGE` and `cargo::warning=MESSAGE`
-        //               then fail build if any errors.
-
         trace!("{}", cmd.to_string_debug());
+        repro::record(&cmd);
 
         let status = time!("run build_script_build", cmd.status())
-            .expect("failed to run `$out/build_script_build`");
+            .expect("failed to run `build_script_build`");
 
         // dump $out/stdout and $out/stderr to our stderr if error or trace mode
         if !status.success() || logger::trace_enabled() {
-            trace!("$out/build_script_build:\nstdout:\n```");
+            trace!("build_script_build:\nstdout:\n```");
             logger::flush();
             dump_file_to_stderr(&build_script_stdout);
             trace!("\n```\nstderr:\n```");
@@ -564,10 +1248,34 @@ impl<'a> BuildContext<'a> {
             trace!("```");
         }
 
+        // Surface any `cargo::warning` and `cargo::error` messages. Like cargo,
+        // we only show warnings for workspace packages, unless the build
+        // script failed, in which case we show everything.
+        let output_bytes = fs::read_file(&build_script_stdout)
+            .expect("failed to read build_script_build output")
+            .expect("missing build_script_build output");
+        let output = BuildOutput::parse(&output_bytes);
+        let has_errors = output.has_errors();
+        if self.is_workspace_pkg || has_errors || !status.success() {
+            let pkg_label =
+                format!("{}@{}", self.pkg_name, self.target.version);
+            logger::flush();
+            for message in &output.log_messages {
+                eprintln!("{}", message.render(&pkg_label));
+            }
+        }
+
         if !status.success() {
             let code = status.code().unwrap_or(1);
             panic!(
-                "`$out/build_script_build` exited with non-zero exit code: {code}"
+                "`build_script_build` exited with non-zero exit code: {code}"
+            );
+        }
+
+        if has_errors {
+            panic!(
+                "`build_script_build` failed due to `cargo::error` \
+                 directive(s) in its output"
             );
         }
 
@@ -575,24 +1283,51 @@ impl<'a> BuildContext<'a> {
         // output and log the rest.
     }
 
+    /// Pass the `cargo::metadata=KEY=VALUE` output from the build scripts of
+    /// our direct deps with a `links = "<links>"` key to our build script as
+    /// `DEP_<links>_<KEY>=VALUE` envs.
+    fn envs_links_deps(&self, cmd: &mut Command) {
+        let mut env_key = String::new();
+        for links_dep in &self.links_deps {
+            let path = links_dep.build_script_out.join("output");
+            let bytes = fs::read_file(&path)
+                .expect("failed to read dep build_script_build output")
+                .expect("missing dep build_script_build output");
+            let output = BuildOutput::parse(&bytes);
+
+            for (key, value) in output.metadata {
+                env_key.clear();
+                env_key.push_str("DEP_");
+                push_envify(&mut env_key, links_dep.links);
+                env_key.push('_');
+                push_envify(&mut env_key, key);
+                cmd.env(&env_key, value);
+            }
+        }
+    }
+
     /// Collect and symlink all deps' unique transitive deps into our
     /// `${out}/deps` dir. This dir will get passed as `-L ...` to `rustc` so it
     /// can locate transitive dep libs during compilation.
     ///
     /// TODO(phlip9): apparently nix builds can't hard link to other store paths
     /// (anymore?)? is this something I can get working again?
-    fn collect_transitive_deps(&self) -> BTreeMap<OsString, &Dep<'_>> {
-        let mut tdep_lib_filenames: BTreeMap<OsString, &Dep> = BTreeMap::new();
+    fn collect_transitive_deps(&self) -> BTreeMap<OsString, PathBuf> {
+        // tdep lib filename -> the dir it's in
+        let mut tdep_lib_filenames: BTreeMap<OsString, PathBuf> =
+            BTreeMap::new();
 
         // Nothing to collect
-        if self.deps.is_empty() {
+        if self.deps.is_empty() && self.link_deps.is_empty() {
             return tdep_lib_filenames;
         }
 
         // Collect all the unique transitive dep libs from each `${dep}/deps`
-        // directory.
-        for dep in &self.deps {
-            let dir_iter = match dep.out.join("deps").read_dir().ok() {
+        // directory, then all the libs in each `${link_dep}` directory.
+        let dep_dirs = self.deps.iter().map(|dep| dep.out.join("deps"));
+        let link_dep_dirs = self.link_deps.iter().map(|dir| dir.to_path_buf());
+        for dir in dep_dirs.chain(link_dep_dirs) {
+            let dir_iter = match dir.read_dir().ok() {
                 Some(x) => x,
                 None => continue,
             };
@@ -606,7 +1341,7 @@ impl<'a> BuildContext<'a> {
 
             for dep_tdep_lib_filename in dep_tdep_lib_filenames {
                 tdep_lib_filenames
-                    .try_insert_stable(dep_tdep_lib_filename, dep);
+                    .try_insert_stable(dep_tdep_lib_filename, dir.clone());
             }
         }
 
@@ -620,11 +1355,10 @@ impl<'a> BuildContext<'a> {
         let mut link_src = PathBuf::new();
         let mut link_dst = PathBuf::new();
 
-        for (tdep_lib_filename, dep) in &tdep_lib_filenames {
-            // link_src = "${dep.out}/deps/${tdep_lib_filename}"
+        for (tdep_lib_filename, dir) in &tdep_lib_filenames {
+            // link_src = "${dir}/${tdep_lib_filename}"
             link_src.clear();
-            link_src.as_mut_os_string().push(dep.out);
-            link_src.push("deps");
+            link_src.push(dir);
             link_src.push(tdep_lib_filename);
 
             let link_src_canon = link_src.canonicalize().expect("canonicalize");
@@ -643,12 +1377,49 @@ impl<'a> BuildContext<'a> {
         tdep_lib_filenames
     }
 
+    /// Move our cdylib and staticlib outputs, like `libfoo-0c1abz9d.so` and
+    /// `libfoo-0c1abz9d.a`, to `$out/lib/libfoo.so` and `$out/lib/libfoo.a`,
+    /// where C consumers expect them. This also keeps them out of our
+    /// dependents' `--extern` and `$out/deps` libs.
+    fn install_c_libs(&self) {
+        // A Rust dylib and a cdylib share the same filename, so if we build
+        // both, `rustc` only keeps one. Leave it for our Rust dependents.
+        let c_dylib = !self.target.is_dylib();
+        let metadata = self.metadata();
+        let lib_dir = self.out.join("lib");
+
+        let mut c_libs = Vec::new();
+        let dir_iter = std::fs::read_dir(self.out).expect("read $out");
+        for dir_entry in dir_iter {
+            let dir_entry = dir_entry.expect("Failed to read $out dir entry");
+            let artifact = match dir_entry.file_name().into_string().ok() {
+                Some(s) => s,
+                None => continue,
+            };
+            if let Some(installed) =
+                c_lib_installed_name(&artifact, metadata, c_dylib)
+            {
+                c_libs.push((artifact, installed));
+            }
+        }
+
+        if c_libs.is_empty() {
+            return;
+        }
+
+        fs::create_dir(&lib_dir).expect("mkdir $out/lib");
+        for (artifact, installed) in c_libs {
+            std::fs::rename(self.out.join(&artifact), lib_dir.join(installed))
+                .expect("Failed to move C lib into $out/lib");
+        }
+    }
+
     /// After compilation, we'll collect our direct deps into our `$out/deps`
     /// dir. We do this after since we pass direct deps into compilation via
     /// precise `--extern <dep-name>=<lib-path>` args.
     fn collect_direct_deps(
         &self,
-        tdep_lib_filenames: BTreeMap<OsString, &Dep>,
+        tdep_lib_filenames: BTreeMap<OsString, PathBuf>,
     ) {
         if self.deps.is_empty() {
             return;
@@ -660,10 +1431,13 @@ impl<'a> BuildContext<'a> {
         let mut target = PathBuf::new();
         let mut symlink = PathBuf::new();
 
-        for dep in &self.deps {
-            // TODO(phlip9): how to handle multiple output libs?
-            let dep_lib = dep.libs.first().unwrap();
-
+        // Link every Rust-linkable lib (ex: both the .rlib and the dylib), so
+        // our dependents can link against whichever they prefer.
+        let dep_libs = self
+            .deps
+            .iter()
+            .flat_map(|dep| dep.libs.iter().map(move |lib| (dep, lib)));
+        for (dep, dep_lib) in dep_libs {
             // skip deps that we've already linked from `collect_transitive_deps`
             if tdep_lib_filenames.contains_key(OsStr::new(&dep_lib)) {
                 continue;
@@ -684,6 +1458,72 @@ impl<'a> BuildContext<'a> {
                 .expect("Failed to symlink direct dep into our $out/deps dir");
         }
     }
+
+    /// Collect the native library search paths (`-L [KIND=]PATH`) emitted by
+    /// our own build script and, transitively, by all our deps' build scripts.
+    ///
+    /// Each linkable lib unit records its full set of search paths in
+    /// `$out/nargo/link-search` (see [`Self::write_link_search`]), so we only
+    /// need to read our direct deps here. Like cargo, a crate that links the
+    /// final binary needs these so the linker can find e.g. `-l dylib=z`
+    /// libs recorded in some upstream rlib.
+    fn collect_link_search(
+        &self,
+        build_script_output: Option<&BuildOutput<'_>>,
+    ) -> Vec<String> {
+        let mut seen: BTreeSet<String> = BTreeSet::new();
+        let mut link_search: Vec<String> = Vec::new();
+        let mut push = |path: String| {
+            if !seen.contains(&path) {
+                seen.insert(path.clone());
+                link_search.push(path);
+            }
+        };
+
+        if let Some(build_script_output) = build_script_output {
+            for path in &build_script_output.library_paths {
+                push(path.to_string());
+            }
+        }
+
+        for dep in &self.deps {
+            let path = dep.out.join(LINK_SEARCH_PATH);
+            let bytes = match fs::read_file(&path)
+                .expect("failed to read dep link search paths")
+            {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            let s = String::from_utf8(bytes)
+                .map_err(|_| ())
+                .expect("dep link search paths are not valid UTF-8");
+            for line in s.lines().filter(|line| !line.is_empty()) {
+                push(line.to_owned());
+            }
+        }
+
+        link_search
+    }
+
+    /// Write our full set of native library search paths to
+    /// `$out/nargo/link-search` (one per line) so dependent crates can pick
+    /// them up.
+    fn write_link_search(&self, link_search: &[String]) {
+        if link_search.is_empty() {
+            return;
+        }
+
+        let mut buf = String::new();
+        for path in link_search {
+            buf.push_str(path);
+            buf.push('\n');
+        }
+
+        let path = self.out.join(LINK_SEARCH_PATH);
+        fs::create_dir(path.parent().unwrap()).expect("mkdir");
+        fs::write_file(&path, buf.as_bytes())
+            .expect("failed to write $out/nargo/link-search");
+    }
 }
 
 //
@@ -699,16 +1539,21 @@ impl Target<'_> {
         self.is_lib() && self.crate_types.contains(&CrateType::Dylib)
     }
 
-    // // TODO(phlip9): -C prefer-dynamic
-    // fn is_cdylib(&self) -> bool {
-    //     self.is_lib()
-    //         && (self.crate_types.iter().any(|x| *x == CrateType::Cdylib))
-    // }
+    fn is_cdylib(&self) -> bool {
+        self.is_lib() && self.crate_types.contains(&CrateType::Cdylib)
+    }
 
     fn is_proc_macro(&self) -> bool {
         self.is_lib() && self.crate_types.contains(&CrateType::ProcMacro)
     }
 
+    /// True if we produce a lib meant for C consumers (cdylib or staticlib).
+    fn has_c_lib(&self) -> bool {
+        self.is_lib()
+            && (self.crate_types.contains(&CrateType::Cdylib)
+                || self.crate_types.contains(&CrateType::Staticlib))
+    }
+
     fn is_executable(&self) -> bool {
         matches!(self.kind, TargetKind::Bin | TargetKind::ExampleBin)
     }
@@ -724,7 +1569,6 @@ impl Target<'_> {
         self.is_lib()
     }
 
-    #[allow(dead_code)] // TODO(phlip9): remove
     fn requires_upstream_objects(&self) -> bool {
         match self.kind {
             TargetKind::Lib | TargetKind::ExampleLib => self
@@ -735,6 +1579,22 @@ impl Target<'_> {
         }
     }
 
+    /// True if a build script `cargo::rustc-link-arg*` directive for
+    /// `link_arg_target` applies to this target.
+    fn wants_link_arg(&self, link_arg_target: LinkArgTarget<'_>) -> bool {
+        match link_arg_target {
+            LinkArgTarget::All => true,
+            LinkArgTarget::Cdylib => self.is_cdylib(),
+            LinkArgTarget::Bin => self.kind == TargetKind::Bin,
+            LinkArgTarget::SingleBin(name) => {
+                self.kind == TargetKind::Bin && self.name == name
+            }
+            LinkArgTarget::Test => self.kind == TargetKind::Test,
+            LinkArgTarget::Bench => self.kind == TargetKind::Bench,
+            LinkArgTarget::Example => self.kind == TargetKind::ExampleBin,
+        }
+    }
+
     /// True if this target type should propagate its deps (and transitive deps)
     /// to a dependent crate.
     fn propagates_deps(&self) -> bool {
@@ -774,17 +1634,24 @@ impl<'a> Dep<'a> {
                 Some(s) => s,
                 None => continue,
             };
-            if !artifact.contains(crate_name) {
+            // Skips non-libs (ex: `diagnostics.json`) and any C-only libs.
+            if !is_rust_lib(&artifact, crate_name) {
                 continue;
             }
 
             libs.push(artifact);
         }
 
-        if libs.len() != 1 {
+        // `read_dir` order isn't stable, so sort by preference then name.
+        libs.sort_unstable_by(|a, b| {
+            (rust_lib_rank(a), a).cmp(&(rust_lib_rank(b), b))
+        });
+
+        if libs.is_empty() {
             panic!(
-                "// TODO(phlip9): how to handle multiple output libs?\n\
-                 libs: {libs:?}"
+                "dep `{dep_name}` has no Rust-linkable lib for crate \
+                 `{crate_name}` in {}",
+                out.display()
             );
         }
 
@@ -796,9 +1663,15 @@ impl<'a> Dep<'a> {
         }
     }
 
-    fn lib_path(&self) -> PathBuf {
-        // TODO(phlip9): how to handle multiple output libs?
-        let lib = self.libs.first().unwrap();
+    /// The lib we pass via `--extern`. When a dep produces several (ex:
+    /// `crate-type = ["rlib", "dylib"]`), prefer the .rlib, then the dylib,
+    /// then the .rmeta. If we're linking dynamically, prefer the dylib.
+    fn extern_lib_path(&self, prefer_dylib: bool) -> PathBuf {
+        let dylib = self.libs.iter().find(|lib| is_dylib_filename(lib));
+        let lib = match dylib {
+            Some(dylib) if prefer_dylib => dylib,
+            _ => &self.libs[0],
+        };
         self.out.join(lib)
     }
 }
@@ -810,6 +1683,7 @@ impl<'a> Dep<'a> {
 trait CommandExt {
     fn to_string_debug(&self) -> String;
     fn envs_cargo_pkg(&mut self, ctx: &BuildContext) -> &mut Self;
+    fn envs_cargo_crate(&mut self, ctx: &BuildContext) -> &mut Self;
 }
 
 impl CommandExt for Command {
@@ -866,24 +1740,41 @@ impl CommandExt for Command {
         out
     }
 
-    /// Add all the `CARGO_PKG_<...>` envs
+    /// Add the `CARGO_MANIFEST_<...>` and all the `CARGO_PKG_<...>` envs.
+    /// Like cargo, unset `[package]` fields are empty.
     fn envs_cargo_pkg(&mut self, ctx: &BuildContext) -> &mut Self {
         let target = &ctx.target;
-        self.env("CARGO_PKG_AUTHORS", "") // TODO
-            .env("CARGO_PKG_DESCRIPTION", "") // TODO
-            .env("CARGO_PKG_HOMEPAGE", "") // TODO
-            .env("CARGO_PKG_LICENSE", "") // TODO
-            .env("CARGO_PKG_LICENSE_FILE", "") // TODO
+        let package = &ctx.package;
+        // Workspace packages keep their position in the workspace (see
+        // `workspace_src`), so paths like `$CARGO_MANIFEST_DIR/../foo` resolve.
+        self.env("CARGO_MANIFEST_DIR", ctx.src)
+            .env("CARGO_MANIFEST_PATH", ctx.src.join("Cargo.toml"))
+            .env("CARGO_PKG_AUTHORS", package.authors.join(":"))
+            .env("CARGO_PKG_DESCRIPTION", package.description.unwrap_or(""))
+            .env("CARGO_PKG_HOMEPAGE", package.homepage.unwrap_or(""))
+            .env("CARGO_PKG_LICENSE", package.license.unwrap_or(""))
+            .env("CARGO_PKG_LICENSE_FILE", package.license_file.unwrap_or(""))
             .env("CARGO_PKG_NAME", ctx.pkg_name)
-            .env("CARGO_PKG_README", "") // TODO
-            .env("CARGO_PKG_REPOSITORY", "") // TODO
-            .env("CARGO_PKG_RUST_VERSION", "") // TODO
+            .env("CARGO_PKG_README", package.readme.unwrap_or(""))
+            .env("CARGO_PKG_REPOSITORY", package.repository.unwrap_or(""))
+            .env("CARGO_PKG_RUST_VERSION", package.rust_version.unwrap_or(""))
             .env("CARGO_PKG_VERSION", target.version.as_str())
             .env("CARGO_PKG_VERSION_MAJOR", target.version.major())
             .env("CARGO_PKG_VERSION_MINOR", target.version.minor())
             .env("CARGO_PKG_VERSION_PATCH", target.version.patch())
             .env("CARGO_PKG_VERSION_PRE", target.version.pre().unwrap_or(""))
     }
+
+    /// Add the [`envs_cargo_pkg`](Self::envs_cargo_pkg) envs, plus
+    /// `CARGO_PRIMARY_PACKAGE=1` if we're a `-p` primary package. Like cargo,
+    /// only `rustc` and `rustdoc` get these, not build scripts.
+    fn envs_cargo_crate(&mut self, ctx: &BuildContext) -> &mut Self {
+        self.envs_cargo_pkg(ctx);
+        if ctx.is_primary_pkg {
+            self.env("CARGO_PRIMARY_PACKAGE", "1");
+        }
+        self
+    }
 }
 
 //
@@ -913,6 +1804,163 @@ impl<K: Ord, V> BTreeMapExt<K, V> for BTreeMap<K, V> {
 // --- misc ---
 //
 
+/// Scratch `--out-dir` for both halves of a pipelined lib, since each only
+/// keeps one of the artifacts (and the `Metadata` half kills `rustc`
+/// mid-codegen).
+fn pipelined_scratch_dir() -> PathBuf {
+    std::env::temp_dir().join("nargo-pipelined")
+}
+
+/// Get the crate name from a lib artifact filename, like
+/// `libfoo_bar-0c1abz9d.rlib` -> `foo_bar`.
+fn lib_filename_crate_name(filename: &str) -> Option<&str> {
+    let (name, _metadata) = filename.strip_prefix("lib")?.rsplit_once('-')?;
+    Some(name)
+}
+
+/// If `artifact` is one of our cdylib or staticlib outputs, like
+/// `libfoo-0c1abz9d.a`, returns the name C consumers expect, like `libfoo.a`.
+/// A dylib only counts when `c_dylib`, since it might be our Rust dylib.
+fn c_lib_installed_name(
+    artifact: &str,
+    metadata: &str,
+    c_dylib: bool,
+) -> Option<String> {
+    let (stem, ext) = artifact.rsplit_once('.')?;
+    let is_c_lib = match ext {
+        "a" | "lib" => true,
+        "so" | "dylib" | "dll" => c_dylib,
+        _ => false,
+    };
+    let stem = stem.strip_suffix(metadata)?.strip_suffix('-')?;
+    (is_c_lib && !stem.is_empty()).then(|| format!("{stem}.{ext}"))
+}
+
+/// True if `filename` is a lib for `crate_name` that Rust code can link
+/// against, like `libfoo_bar-0c1abz9d.rlib`, `.rmeta`, or a dylib `.so`.
+/// Rust libs always have a `-{metadata}` suffix, so C libs like `libfoo.so`
+/// never match.
+fn is_rust_lib(filename: &str, crate_name: &str) -> bool {
+    let name = filename.strip_prefix("lib").unwrap_or(filename);
+    let rest = match name.strip_prefix(crate_name) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    match rest
+        .strip_prefix('-')
+        .and_then(|rest| rest.rsplit_once('.'))
+    {
+        Some((metadata, "rlib" | "rmeta" | "so" | "dylib" | "dll")) => {
+            !metadata.is_empty() && !metadata.contains(['-', '.'])
+        }
+        _ => false,
+    }
+}
+
+/// True if `filename` looks like a dynamic lib, like `libfoo-0c1abz9d.so`.
+fn is_dylib_filename(filename: &str) -> bool {
+    matches!(filename.rsplit_once('.'), Some((_, "so" | "dylib" | "dll")))
+}
+
+/// Run `rustc --print target-libdir`, which is where `libstd-*.so` lives.
+/// Runs from the same dir as our `rustc` compile, so both see the same
+/// toolchain.
+fn rustc_target_libdir(src: &Path, target_triple: &str) -> PathBuf {
+    let output = Command::new("rustc")
+        .current_dir(src)
+        .args(["--target", target_triple, "--print", "target-libdir"])
+        .stderr(Stdio::inherit())
+        .output()
+        .expect("failed to run `rustc --print target-libdir`");
+    if !output.status.success() {
+        panic!("`rustc --print target-libdir` failed: {}", output.status);
+    }
+    let libdir = String::from_utf8(output.stdout)
+        .expect("`rustc --print target-libdir` output is not valid UTF-8");
+    PathBuf::from(libdir.trim_end())
+}
+
+/// Whether a unit links `libstd` and its Rust dylib deps dynamically, if its
+/// profile has `prefer_dynamic`.
+fn links_dynamically(
+    is_workspace_pkg: bool,
+    mode: CompileMode,
+    target: &Target<'_>,
+) -> bool {
+    match mode {
+        CompileMode::Test => is_workspace_pkg,
+        CompileMode::Build => {
+            target.is_dylib()
+                || (is_workspace_pkg
+                    && matches!(
+                        target.kind,
+                        TargetKind::Bin
+                            | TargetKind::Test
+                            | TargetKind::Bench
+                            | TargetKind::ExampleBin
+                    ))
+        }
+        _ => false,
+    }
+}
+
+/// The dirs containing `libstd-*.so` (our `target_libdir`) and all our direct
+/// and transitive Rust dylib deps. Our transitive deps are symlinked into
+/// `tdeps_dir` (see `collect_transitive_deps`), which bins don't keep around,
+/// so we follow the links back to their store paths.
+fn dylib_rpaths(
+    target_libdir: PathBuf,
+    deps: &[Dep<'_>],
+    tdeps_dir: &Path,
+) -> BTreeSet<PathBuf> {
+    let mut dirs = BTreeSet::new();
+    dirs.insert(target_libdir);
+
+    for dep in deps {
+        if dep.libs.iter().any(|lib| is_dylib_filename(lib)) {
+            dirs.insert(dep.out.to_path_buf());
+        }
+    }
+
+    let tdeps = match std::fs::read_dir(tdeps_dir) {
+        Ok(tdeps) => tdeps,
+        Err(_) => return dirs,
+    };
+    for dir_entry in tdeps {
+        let dir_entry = dir_entry.expect("Failed to read $out/deps entry");
+        let is_dylib = dir_entry
+            .file_name()
+            .to_str()
+            .is_some_and(is_dylib_filename);
+        if !is_dylib {
+            continue;
+        }
+        let tdep = dir_entry.path().canonicalize().expect("canonicalize");
+        dirs.insert(tdep.parent().unwrap().to_path_buf());
+    }
+
+    dirs
+}
+
+/// Lower is preferred when choosing which of a dep's libs to `--extern`.
+fn rust_lib_rank(filename: &str) -> u8 {
+    match filename.rsplit_once('.') {
+        Some((_, "rlib")) => 0,
+        Some((_, "rmeta")) => 2,
+        _ => 1,
+    }
+}
+
+/// Push `s` onto `out` as an env var key segment: uppercased with '-' replaced
+/// by '_'.
+fn push_envify(out: &mut String, s: &str) {
+    for c in s.chars() {
+        let c = c.to_ascii_uppercase();
+        let c = if c == '-' { '_' } else { c };
+        out.push(c);
+    }
+}
+
 fn dump_file_to_stderr(path: &Path) {
     let maybe_bytes = fs::read_file(path).unwrap();
     let bytes = maybe_bytes.as_deref().unwrap_or(b"<not-found>".as_slice());
@@ -920,3 +1968,317 @@ fn dump_file_to_stderr(path: &Path) {
     stderr.write_all(bytes).unwrap();
     stderr.flush().unwrap();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::unit_spec::UnitSpec;
+
+    #[test]
+    fn test_c_lib_installed_name() {
+        let metadata = "0c1abz9d";
+        for (artifact, c_dylib, expected) in [
+            ("libfoo-0c1abz9d.a", true, Some("libfoo.a")),
+            ("libfoo-0c1abz9d.so", true, Some("libfoo.so")),
+            ("libfoo-0c1abz9d.dylib", true, Some("libfoo.dylib")),
+            ("foo-0c1abz9d.lib", true, Some("foo.lib")),
+            ("foo-0c1abz9d.dll", true, Some("foo.dll")),
+            ("libfoo_bar-0c1abz9d.a", true, Some("libfoo_bar.a")),
+            // A Rust dylib shares the cdylib's filename.
+            ("libfoo-0c1abz9d.so", false, None),
+            ("libfoo-0c1abz9d.a", false, Some("libfoo.a")),
+            // Rust libs
+            ("libfoo-0c1abz9d.rlib", true, None),
+            ("libfoo-0c1abz9d.rmeta", true, None),
+            // Not ours: no or different metadata
+            ("libfoo.a", true, None),
+            ("libfoo.so", true, None),
+            ("libfoo-deadbeef.a", true, None),
+            ("libfoo0c1abz9d.a", true, None),
+            ("-0c1abz9d.a", true, None),
+            ("diagnostics.json", true, None),
+        ] {
+            assert_eq!(
+                c_lib_installed_name(artifact, metadata, c_dylib).as_deref(),
+                expected,
+                "{artifact} (c_dylib: {c_dylib})",
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_rust_lib() {
+        for (filename, crate_name, expected) in [
+            ("libfoo-0c1abz9d.rlib", "foo", true),
+            ("libfoo-0c1abz9d.rmeta", "foo", true),
+            ("libfoo-0c1abz9d.so", "foo", true),
+            ("libfoo-0c1abz9d.dylib", "foo", true),
+            ("foo-0c1abz9d.dll", "foo", true),
+            ("libfoo_bar-0c1abz9d.rlib", "foo_bar", true),
+            // Other crates that share a prefix
+            ("libfoo_bar-0c1abz9d.rlib", "foo", false),
+            ("libfoo-bar-0c1abz9d.rlib", "foo", false),
+            ("libfoo-0c1abz9d.rlib", "foo_bar", false),
+            ("libfoobar-0c1abz9d.rlib", "foo", false),
+            // C libs, even if they collide with the crate name
+            ("libfoo.a", "foo", false),
+            ("libfoo.so", "foo", false),
+            ("libfoo-0c1abz9d.a", "foo", false),
+            ("lib/libfoo.so", "foo", false),
+            // Not libs
+            ("libfoo-0c1abz9d.d", "foo", false),
+            ("libfoo-.rlib", "foo", false),
+            ("diagnostics.json", "foo", false),
+        ] {
+            assert_eq!(
+                is_rust_lib(filename, crate_name),
+                expected,
+                "{filename} ({crate_name})",
+            );
+        }
+    }
+
+    fn target(kind: TargetKind, crate_types: &[CrateType]) -> Target<'static> {
+        Target {
+            name: "foo",
+            version: semver::Version::from_str("0.1.0").unwrap(),
+            kind,
+            crate_name: Cow::Borrowed("foo"),
+            crate_types: crate_types.to_vec(),
+            crate_types_str: "",
+            path: Path::new("src/lib.rs"),
+            edition: "2021",
+            features: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_links_dynamically() {
+        use CompileMode::{Build, Check, Metadata, Test};
+        let lib = target(TargetKind::Lib, &[CrateType::Lib]);
+        let dylib =
+            target(TargetKind::Lib, &[CrateType::Lib, CrateType::Dylib]);
+        let bin = target(TargetKind::Bin, &[CrateType::Bin]);
+        let test = target(TargetKind::Test, &[CrateType::Bin]);
+        let build_script = target(TargetKind::CustomBuild, &[CrateType::Bin]);
+
+        for (is_workspace_pkg, mode, target, expected) in [
+            (true, Build, &bin, true),
+            (true, Build, &test, true),
+            (true, Test, &lib, true),
+            (true, Test, &bin, true),
+            (true, Build, &dylib, true),
+            // Non-workspace dylibs (ex: deps with `crate-type = ["dylib"]`)
+            // still link their own dylib deps.
+            (false, Build, &dylib, true),
+            (true, Build, &lib, false),
+            (true, Build, &build_script, false),
+            (true, Check, &bin, false),
+            (true, Metadata, &lib, false),
+            (false, Build, &bin, false),
+            (false, Test, &lib, false),
+        ] {
+            assert_eq!(
+                links_dynamically(is_workspace_pkg, mode, target),
+                expected,
+                "{:?} {mode:?} (workspace: {is_workspace_pkg})",
+                target.kind,
+            );
+        }
+    }
+
+    #[test]
+    fn test_dylib_rpaths() {
+        let tmp = std::env::temp_dir()
+            .join(format!("nargo-test-dylib-rpaths-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&tmp);
+        let mkdir = |dir: &str, files: &[&str]| {
+            let dir = tmp.join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            for file in files {
+                std::fs::write(dir.join(file), b"").unwrap();
+            }
+            dir.canonicalize().unwrap()
+        };
+        let a = mkdir("a", &["liba-aa.rlib", "liba-aa.so"]);
+        let b = mkdir("b", &["libb-bb.rlib"]);
+        let c = mkdir("c", &["libc-cc.rlib", "libc-cc.so"]);
+        let d = mkdir("d", &["libd-dd.rlib"]);
+        let tdeps = mkdir("out/deps", &[]);
+        for lib in [c.join("libc-cc.so"), d.join("libd-dd.rlib")] {
+            fs::symlink(&lib, &tdeps.join(lib.file_name().unwrap())).unwrap();
+        }
+
+        let deps = [
+            Dep {
+                dep_name: "a",
+                crate_name: "a",
+                out: &a,
+                libs: vec!["liba-aa.rlib".to_owned(), "liba-aa.so".to_owned()],
+            },
+            Dep {
+                dep_name: "b",
+                crate_name: "b",
+                out: &b,
+                libs: vec!["libb-bb.rlib".to_owned()],
+            },
+        ];
+        let libdir =
+            PathBuf::from("/rustc/lib/rustlib/x86_64-unknown-linux-gnu/lib");
+        assert_eq!(
+            dylib_rpaths(libdir.clone(), &deps, &tdeps),
+            BTreeSet::from([libdir.clone(), a.clone(), c]),
+        );
+
+        // Bins don't always have a `$out/deps` dir.
+        assert_eq!(
+            dylib_rpaths(libdir.clone(), &deps, &tmp.join("missing")),
+            BTreeSet::from([libdir, a]),
+        );
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn test_rustc_target_libdir() {
+        let output = Command::new("rustc").arg("-vV").output().unwrap();
+        let version = String::from_utf8(output.stdout).unwrap();
+        let host = version
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .unwrap();
+
+        let libdir = rustc_target_libdir(Path::new("."), host);
+        assert!(libdir.is_absolute(), "{}", libdir.display());
+
+        // `libstd-<hash>.so` is what dynamically linked bins need at runtime.
+        if cfg!(target_os = "linux") {
+            let has_libstd = std::fs::read_dir(&libdir).unwrap().any(|entry| {
+                let filename = entry.unwrap().file_name();
+                let filename = filename.to_str().unwrap();
+                filename.starts_with("libstd-") && filename.ends_with(".so")
+            });
+            assert!(has_libstd, "no libstd-*.so in {}", libdir.display());
+        }
+    }
+
+    #[test]
+    fn test_rust_lib_rank() {
+        let mut libs = [
+            "libfoo-0c1abz9d.rmeta",
+            "libfoo-0c1abz9d.so",
+            "libfoo-0c1abz9d.rlib",
+        ];
+        libs.sort_by_key(|lib| rust_lib_rank(lib));
+        assert_eq!(
+            libs,
+            [
+                "libfoo-0c1abz9d.rlib",
+                "libfoo-0c1abz9d.so",
+                "libfoo-0c1abz9d.rmeta",
+            ]
+        );
+        assert_eq!(rust_lib_rank("foo-0c1abz9d.dll"), 1);
+        assert_eq!(rust_lib_rank("libfoo-0c1abz9d.dylib"), 1);
+    }
+
+    /// The envs a unit spec with `"is_primary_pkg": <is_primary_pkg>` sets
+    /// on `rustc` (or `rustdoc`) and on its build script.
+    fn cargo_envs(
+        is_primary_pkg: bool,
+    ) -> [BTreeMap<String, Option<String>>; 2] {
+        let json = format!(
+            r#"{{
+                "schema_version": 2,
+                "pkg_name": "foo",
+                "version": "0.1.0-rc.1",
+                "src": "/nix/store/aaaa-foo",
+                "target": {{
+                    "name": "foo",
+                    "kind": "lib",
+                    "crate_types": ["lib"],
+                    "path": "src/lib.rs",
+                    "edition": "2021"
+                }},
+                "mode": "build",
+                "metadata": null,
+                "is_workspace_pkg": true,
+                "is_primary_pkg": {is_primary_pkg},
+                "package": {{
+                    "authors": ["Jane Doe <jane@example.com>", "John Doe"],
+                    "description": "Does foo",
+                    "license": "MIT",
+                    "rust_version": "1.80"
+                }},
+                "profile": {{
+                    "root": "dev", "opt_level": "0", "debug": "0",
+                    "debug_assertions": true, "overflow_checks": true,
+                    "lto": "false", "panic": "unwind", "rpath": false
+                }},
+                "target_triple": "x86_64-unknown-linux-gnu",
+                "host_triple": "x86_64-unknown-linux-gnu"
+            }}"#
+        );
+        let spec = UnitSpec::parse(&json, || PathBuf::from("/out"));
+        let ctx = BuildContext::from_args(cli::Args::from_unit_spec(&spec));
+
+        let envs = |cmd: &Command| {
+            cmd.get_envs()
+                .map(|(key, value)| {
+                    let key = key.to_str().unwrap().to_owned();
+                    let value = value.map(|v| v.to_str().unwrap().to_owned());
+                    (key, value)
+                })
+                .collect()
+        };
+        [
+            envs(Command::new("rustc").envs_cargo_crate(&ctx)),
+            envs(Command::new("build_script_build").envs_cargo_pkg(&ctx)),
+        ]
+    }
+
+    #[test]
+    fn test_envs_cargo_pkg() {
+        let env = |envs: &BTreeMap<String, Option<String>>, key: &str| {
+            envs.get(key).cloned().flatten()
+        };
+
+        let [rustc, build_script] = cargo_envs(true);
+        assert_eq!(env(&rustc, "CARGO_PRIMARY_PACKAGE").as_deref(), Some("1"));
+        for envs in [&rustc, &build_script] {
+            assert_eq!(
+                env(envs, "CARGO_MANIFEST_PATH").as_deref(),
+                Some("/nix/store/aaaa-foo/Cargo.toml")
+            );
+            assert_eq!(
+                env(envs, "CARGO_PKG_AUTHORS").as_deref(),
+                Some("Jane Doe <jane@example.com>:John Doe")
+            );
+            assert_eq!(
+                env(envs, "CARGO_PKG_DESCRIPTION").as_deref(),
+                Some("Does foo")
+            );
+            assert_eq!(env(envs, "CARGO_PKG_LICENSE").as_deref(), Some("MIT"));
+            assert_eq!(env(envs, "CARGO_PKG_HOMEPAGE").as_deref(), Some(""));
+            assert_eq!(
+                env(envs, "CARGO_PKG_RUST_VERSION").as_deref(),
+                Some("1.80")
+            );
+            assert_eq!(
+                env(envs, "CARGO_PKG_VERSION_PRE").as_deref(),
+                Some("rc.1")
+            );
+        }
+        // Like cargo, build scripts never get `CARGO_PRIMARY_PACKAGE`.
+        assert_eq!(env(&build_script, "CARGO_PRIMARY_PACKAGE"), None);
+
+        let [rustc, build_script] = cargo_envs(false);
+        assert_eq!(env(&rustc, "CARGO_PRIMARY_PACKAGE"), None);
+        assert_eq!(env(&build_script, "CARGO_PRIMARY_PACKAGE"), None);
+        // Every package gets its manifest path, like cargo.
+        assert_eq!(
+            env(&rustc, "CARGO_MANIFEST_PATH").as_deref(),
+            Some("/nix/store/aaaa-foo/Cargo.toml")
+        );
+    }
+}
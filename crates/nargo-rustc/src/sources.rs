@@ -0,0 +1,235 @@
+//! Record the exact package source files that a workspace unit read, from
+//! `rustc --emit=dep-info`, in `$out/sources.json`.
+//!
+//! This includes every module file plus anything pulled in with
+//! `include_str!` or `include_bytes!`, as package-relative paths. Files in the
+//! rest of the workspace get `../` paths, like `../README.md`. Files outside
+//! the workspace (like a build script's `OUT_DIR`) are left out.
+//!
+//! `nargo-metadata --input-sources` folds these back into
+//! `Cargo.metadata.json`, so `buildGraph.nix` can give each target a tighter
+//! source filter.
+//!
+//! ```json
+//! {
+//!   "package": "foo",
+//!   "target": { "name": "foo", "kind": "lib" },
+//!   "sources": ["data/words.txt", "src/lib.rs", "src/util.rs"]
+//! }
+//! ```
+
+use std::{
+    collections::BTreeSet,
+    path::{Component, Path, PathBuf},
+};
+
+use nargo_core::fs;
+
+use crate::json;
+
+pub(crate) const SOURCES_JSON: &str = "sources.json";
+
+/// Where we ask `rustc` to write its dep-info. Like the pipelined scratch
+/// dir, this is the same path for both halves of a pipelined lib, so they run
+/// `rustc` with the same args.
+pub(crate) fn dep_info_path() -> PathBuf {
+    std::env::temp_dir().join("nargo-dep-info.d")
+}
+
+/// Read the dep-info that `rustc` wrote and record our package's sources in
+/// `$out/sources.json`. Does nothing if `rustc` didn't get far enough to write
+/// it.
+pub(crate) fn write(
+    pkg_name: &str,
+    target_name: &str,
+    kind: &str,
+    src: &Path,
+    workspace_src: Option<&Path>,
+    out: &Path,
+) {
+    let dep_info = match fs::read_file(&dep_info_path())
+        .expect("failed to read `rustc` dep-info")
+    {
+        Some(dep_info) => dep_info,
+        None => return,
+    };
+    let dep_info = String::from_utf8(dep_info)
+        .expect("`rustc` dep-info is not valid UTF-8");
+
+    let sources = parse_dep_info(&dep_info)
+        .into_iter()
+        .filter_map(|dep| package_relative(src, workspace_src, &dep))
+        .collect::<BTreeSet<_>>();
+
+    let mut json = String::with_capacity(256);
+    json.push_str("{\n  \"package\": ");
+    json::push_str(&mut json, pkg_name);
+    json.push_str(",\n  \"target\": {\"name\": ");
+    json::push_str(&mut json, target_name);
+    json.push_str(", \"kind\": ");
+    json::push_str(&mut json, kind);
+    json.push_str("},\n  \"sources\": [");
+    for (i, source) in sources.iter().enumerate() {
+        if i != 0 {
+            json.push(',');
+        }
+        json.push_str("\n    ");
+        json::push_str(&mut json, source);
+    }
+    json.push_str("\n  ]\n}\n");
+
+    fs::write_file(&out.join(SOURCES_JSON), json.as_bytes())
+        .expect("failed to write $out/sources.json");
+}
+
+/// The input files in a Makefile-style dep-info file. `rustc` writes an empty
+/// rule (`path:`) for each one, with spaces escaped as `\ `.
+fn parse_dep_info(dep_info: &str) -> Vec<String> {
+    dep_info
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.strip_suffix(':'))
+        .filter(|dep| !dep.is_empty())
+        .map(|dep| dep.replace("\\ ", " "))
+        .collect()
+}
+
+/// `dep` relative to the package dir `src`, with `/` separators, or `None` if
+/// it's outside the workspace source tree `workspace_src` (or just the package,
+/// without one). Relative `dep`s are relative to `src`, since that's where we
+/// run `rustc`.
+fn package_relative(
+    src: &Path,
+    workspace_src: Option<&Path>,
+    dep: &str,
+) -> Option<String> {
+    let root = workspace_src.unwrap_or(src);
+    // The package dir, relative to `root`.
+    let pkg_parts = normal_parts(src.strip_prefix(root).ok()?)?;
+
+    let dep = Path::new(dep);
+    let (mut parts, dep) = if dep.is_absolute() {
+        (Vec::new(), dep.strip_prefix(root).ok()?)
+    } else {
+        (pkg_parts.clone(), dep)
+    };
+    for component in dep.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                parts.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    let common = pkg_parts
+        .iter()
+        .zip(&parts)
+        .take_while(|(pkg_part, part)| pkg_part == part)
+        .count();
+    if common == parts.len() {
+        return None;
+    }
+    let mut relative = vec![".."; pkg_parts.len() - common];
+    relative.extend_from_slice(&parts[common..]);
+    Some(relative.join("/"))
+}
+
+/// The `/`-separated parts of a relative path without any `..`.
+fn normal_parts(path: &Path) -> Option<Vec<&str>> {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_dep_info() {
+        let dep_info = "\
+/build/nargo-dep-info.d: src/lib.rs src/a.rs src/../data.txt src/sub/bin\\ dat
+
+/build/nargo-pipelined/libfoo-abcd.rmeta: src/lib.rs src/a.rs src/../data.txt src/sub/bin\\ dat
+
+src/lib.rs:
+src/a.rs:
+src/../data.txt:
+src/sub/bin\\ dat:
+
+# env-dep:CARGO_PKG_NAME=foo
+";
+        assert_eq!(
+            parse_dep_info(dep_info),
+            [
+                "src/lib.rs",
+                "src/a.rs",
+                "src/../data.txt",
+                "src/sub/bin dat"
+            ],
+        );
+    }
+
+    #[test]
+    fn test_package_relative() {
+        let src = Path::new("/nix/store/aaaa-foo");
+        let cases = [
+            ("src/lib.rs", Some("src/lib.rs")),
+            ("src/../data.txt", Some("data.txt")),
+            ("./src/sub/mod.rs", Some("src/sub/mod.rs")),
+            ("/nix/store/aaaa-foo/src/gen.rs", Some("src/gen.rs")),
+            ("/nix/store/bbbb-foo-build/out/gen.rs", None),
+            ("src/../../other/lib.rs", None),
+        ];
+        for (dep, expected) in cases {
+            assert_eq!(
+                package_relative(src, None, dep).as_deref(),
+                expected,
+                "{dep}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_package_relative_in_workspace() {
+        let workspace_src = Path::new("/nix/store/aaaa-source");
+        let src = Path::new("/nix/store/aaaa-source/crates/foo");
+        let cases = [
+            ("src/lib.rs", Some("src/lib.rs")),
+            ("src/../../../README.md", Some("../../README.md")),
+            ("../bar/src/shared.rs", Some("../bar/src/shared.rs")),
+            (
+                "/nix/store/aaaa-source/assets/logo.png",
+                Some("../../assets/logo.png"),
+            ),
+            (
+                "/nix/store/aaaa-source/crates/foo/src/gen.rs",
+                Some("src/gen.rs"),
+            ),
+            ("/nix/store/bbbb-foo-build/out/gen.rs", None),
+            ("../../../outside.rs", None),
+            ("..", None),
+        ];
+        for (dep, expected) in cases {
+            assert_eq!(
+                package_relative(src, Some(workspace_src), dep).as_deref(),
+                expected,
+                "{dep}"
+            );
+        }
+
+        // The root package of the workspace.
+        assert_eq!(
+            package_relative(workspace_src, Some(workspace_src), "src/lib.rs")
+                .as_deref(),
+            Some("src/lib.rs"),
+        );
+    }
+}
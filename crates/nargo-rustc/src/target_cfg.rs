@@ -1,6 +1,12 @@
-//! Run `rustc --target=<target> --print cfg` and parse the output.
+//! Run `rustc --target=<target> --print cfg` (or read its saved output) and
+//! parse the output.
 
-use std::process::{Command, Stdio};
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use nargo_core::fs;
 
 pub(crate) struct RustcTargetCfg {
     output: String,
@@ -36,6 +42,20 @@ impl RustcTargetCfg {
         Self { output }
     }
 
+    /// Read the saved `rustc --target=<target> --print cfg` output at `path`.
+    pub(crate) fn from_file(path: &Path) -> Self {
+        let output = fs::read_file(path)
+            .expect("failed to read rustc target cfg")
+            .unwrap_or_else(|| {
+                panic!("missing rustc target cfg: {}", path.display())
+            });
+        let output = String::from_utf8(output)
+            .map_err(|_| ())
+            .expect("rustc target cfg is not valid UTF-8");
+
+        Self { output }
+    }
+
     /// Parses the rustc output and returns an iterator over the (cfg, value)
     /// pairs.
     ///
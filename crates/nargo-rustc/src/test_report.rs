@@ -0,0 +1,486 @@
+//! Machine-readable test results.
+//!
+//! Each test run writes a `results.json` and a JUnit XML `junit.xml` report to
+//! its `$out`. [`merge`] then combines the reports from many test runs into a
+//! single workspace report.
+//!
+//! `results.json` looks like:
+//!
+//! ```json
+//! {
+//!   "package": "foo",
+//!   "version": "0.1.0",
+//!   "passed": 1,
+//!   "failed": 1,
+//!   "ignored": 0,
+//!   "suites": [
+//!     {
+//!       "name": "foo::it",
+//!       "passed": 1,
+//!       "failed": 1,
+//!       "ignored": 0,
+//!       "exec_time": 0.012,
+//!       "tests": [
+//!         {"name": "works", "outcome": "passed", "exec_time": 0.001},
+//!         {"name": "fails", "outcome": "failed", "exec_time": 0.01, "stdout": "..."}
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! A merged report has the same totals, with each test run's report under
+//! `"units"` instead of `"suites"`.
+
+use std::{fmt::Write as _, path::Path};
+
+use nargo_core::fs;
+
+use crate::json;
+
+pub(crate) const RESULTS_JSON: &str = "results.json";
+pub(crate) const JUNIT_XML: &str = "junit.xml";
+
+/// The results from running all the test binaries in a single test unit.
+pub(crate) struct UnitReport<'a> {
+    pub pkg_name: &'a str,
+    pub version: &'a str,
+    pub suites: Vec<Suite>,
+}
+
+/// The results from running a single test binary.
+pub(crate) struct Suite {
+    /// `<pkg_name>::<test binary name>`
+    pub name: String,
+    pub exec_time: Option<f64>,
+    pub cases: Vec<Case>,
+}
+
+pub(crate) struct Case {
+    pub name: String,
+    pub outcome: Outcome,
+    pub exec_time: Option<f64>,
+    /// The captured output of a failed test.
+    pub stdout: Option<String>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub(crate) struct Counts {
+    pub passed: u64,
+    pub failed: u64,
+    pub ignored: u64,
+}
+
+//
+// --- impl UnitReport ---
+//
+
+impl UnitReport<'_> {
+    pub(crate) fn counts(&self) -> Counts {
+        let mut counts = Counts::default();
+        for suite in &self.suites {
+            counts.add(suite.counts());
+        }
+        counts
+    }
+
+    /// Write `$out/results.json` and `$out/junit.xml`.
+    pub(crate) fn write(&self, out: &Path) {
+        fs::write_file(&out.join(RESULTS_JSON), self.to_json().as_bytes())
+            .expect("failed to write $out/results.json");
+        fs::write_file(&out.join(JUNIT_XML), self.to_junit().as_bytes())
+            .expect("failed to write $out/junit.xml");
+    }
+
+    fn to_json(&self) -> String {
+        let mut out = String::with_capacity(1024);
+        out.push_str("{\n  \"package\": ");
+        json::push_str(&mut out, self.pkg_name);
+        out.push_str(",\n  \"version\": ");
+        json::push_str(&mut out, self.version);
+        out.push_str(",\n  ");
+        self.counts().push_json(&mut out);
+        out.push_str(",\n  \"suites\": [");
+
+        for (i, suite) in self.suites.iter().enumerate() {
+            if i != 0 {
+                out.push(',');
+            }
+            out.push_str("\n    {\"name\": ");
+            json::push_str(&mut out, &suite.name);
+            out.push_str(", ");
+            suite.counts().push_json(&mut out);
+            if let Some(exec_time) = suite.exec_time {
+                let _ = write!(out, ", \"exec_time\": {exec_time}");
+            }
+            out.push_str(", \"tests\": [");
+
+            for (j, case) in suite.cases.iter().enumerate() {
+                if j != 0 {
+                    out.push(',');
+                }
+                out.push_str("\n      {\"name\": ");
+                json::push_str(&mut out, &case.name);
+                out.push_str(", \"outcome\": \"");
+                out.push_str(case.outcome.as_str());
+                out.push('"');
+                if let Some(exec_time) = case.exec_time {
+                    let _ = write!(out, ", \"exec_time\": {exec_time}");
+                }
+                if let Some(stdout) = &case.stdout {
+                    out.push_str(", \"stdout\": ");
+                    json::push_str(&mut out, stdout);
+                }
+                out.push('}');
+            }
+
+            if !suite.cases.is_empty() {
+                out.push_str("\n    ");
+            }
+            out.push_str("]}");
+        }
+
+        if !self.suites.is_empty() {
+            out.push_str("\n  ");
+        }
+        out.push_str("]\n}\n");
+        out
+    }
+
+    fn to_junit(&self) -> String {
+        let mut suites = String::with_capacity(1024);
+        for suite in &self.suites {
+            suite.push_junit(&mut suites);
+        }
+        junit_document(self.pkg_name, self.counts(), &suites)
+    }
+}
+
+//
+// --- impl Suite ---
+//
+
+impl Suite {
+    pub(crate) fn counts(&self) -> Counts {
+        let mut counts = Counts::default();
+        for case in &self.cases {
+            match case.outcome {
+                Outcome::Passed => counts.passed += 1,
+                Outcome::Failed => counts.failed += 1,
+                Outcome::Ignored => counts.ignored += 1,
+            }
+        }
+        counts
+    }
+
+    fn push_junit(&self, out: &mut String) {
+        let counts = self.counts();
+        out.push_str("  <testsuite name=\"");
+        push_xml_escaped(out, &self.name);
+        let _ = write!(
+            out,
+            "\" tests=\"{}\" failures=\"{}\" skipped=\"{}\"",
+            counts.total(),
+            counts.failed,
+            counts.ignored,
+        );
+        if let Some(exec_time) = self.exec_time {
+            let _ = write!(out, " time=\"{exec_time:.3}\"");
+        }
+        out.push_str(">\n");
+
+        for case in &self.cases {
+            out.push_str("    <testcase classname=\"");
+            push_xml_escaped(out, &self.name);
+            out.push_str("\" name=\"");
+            push_xml_escaped(out, &case.name);
+            out.push('"');
+            if let Some(exec_time) = case.exec_time {
+                let _ = write!(out, " time=\"{exec_time:.3}\"");
+            }
+
+            match case.outcome {
+                Outcome::Passed => out.push_str("/>\n"),
+                Outcome::Ignored => {
+                    out.push_str(">\n      <skipped/>\n    </testcase>\n")
+                }
+                Outcome::Failed => {
+                    out.push_str(">\n      <failure message=\"test failed\"");
+                    match &case.stdout {
+                        Some(stdout) => {
+                            out.push('>');
+                            push_xml_escaped(out, stdout);
+                            out.push_str("</failure>\n");
+                        }
+                        None => out.push_str("/>\n"),
+                    }
+                    out.push_str("    </testcase>\n");
+                }
+            }
+        }
+
+        out.push_str("  </testsuite>\n");
+    }
+}
+
+//
+// --- impl Outcome ---
+//
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Passed => "passed",
+            Self::Failed => "failed",
+            Self::Ignored => "ignored",
+        }
+    }
+}
+
+//
+// --- impl Counts ---
+//
+
+impl Counts {
+    pub(crate) fn total(&self) -> u64 {
+        self.passed + self.failed + self.ignored
+    }
+
+    fn add(&mut self, other: Self) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.ignored += other.ignored;
+    }
+
+    fn push_json(&self, out: &mut String) {
+        let _ = write!(
+            out,
+            "\"passed\": {}, \"failed\": {}, \"ignored\": {}",
+            self.passed, self.failed, self.ignored,
+        );
+    }
+
+    /// Read the totals from a `results.json`.
+    fn from_results_json(results_json: &str) -> Option<Self> {
+        let field = |key| {
+            json::top_level_f64_field(results_json, key).map(|x| x as u64)
+        };
+        Some(Self {
+            passed: field("passed")?,
+            failed: field("failed")?,
+            ignored: field("ignored")?,
+        })
+    }
+}
+
+//
+// --- merge ---
+//
+
+/// Merge the `results.json` and `junit.xml` reports from each test run in
+/// `unit_dirs` into a single report in `out`. Returns the merged totals.
+pub(crate) fn merge(name: &str, unit_dirs: &[&Path], out: &Path) -> Counts {
+    let mut counts = Counts::default();
+    let mut units_json = String::with_capacity(4096);
+    let mut suites_xml = String::with_capacity(4096);
+
+    for (i, unit_dir) in unit_dirs.iter().enumerate() {
+        let results_json = read_to_string(&unit_dir.join(RESULTS_JSON));
+        let unit_counts = Counts::from_results_json(&results_json)
+            .unwrap_or_else(|| {
+                panic!(
+                    "invalid test results: '{}'",
+                    unit_dir.join(RESULTS_JSON).display()
+                )
+            });
+        counts.add(unit_counts);
+
+        if i != 0 {
+            units_json.push(',');
+        }
+        units_json.push('\n');
+        units_json.push_str(results_json.trim_end());
+
+        let junit_xml = read_to_string(&unit_dir.join(JUNIT_XML));
+        suites_xml.push_str(junit_testsuites(&junit_xml).unwrap_or_else(
+            || {
+                panic!(
+                    "invalid JUnit XML report: '{}'",
+                    unit_dir.join(JUNIT_XML).display()
+                )
+            },
+        ));
+    }
+
+    let mut results_json = String::with_capacity(units_json.len() + 128);
+    results_json.push_str("{\n\"name\": ");
+    json::push_str(&mut results_json, name);
+    results_json.push_str(",\n");
+    counts.push_json(&mut results_json);
+    results_json.push_str(",\n\"units\": [");
+    results_json.push_str(&units_json);
+    results_json.push_str("\n]\n}\n");
+
+    fs::write_file(&out.join(RESULTS_JSON), results_json.as_bytes())
+        .expect("failed to write $out/results.json");
+    let junit_xml = junit_document(name, counts, &suites_xml);
+    fs::write_file(&out.join(JUNIT_XML), junit_xml.as_bytes())
+        .expect("failed to write $out/junit.xml");
+
+    counts
+}
+
+/// Wrap the `<testsuite>` elements in `suites` in a JUnit XML document.
+fn junit_document(name: &str, counts: Counts, suites: &str) -> String {
+    let mut out = String::with_capacity(suites.len() + 256);
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<testsuites name=\"");
+    push_xml_escaped(&mut out, name);
+    let _ = writeln!(
+        out,
+        "\" tests=\"{}\" failures=\"{}\" skipped=\"{}\">",
+        counts.total(),
+        counts.failed,
+        counts.ignored,
+    );
+    out.push_str(suites);
+    out.push_str("</testsuites>\n");
+    out
+}
+
+/// The `<testsuite>` elements inside a [`junit_document`].
+fn junit_testsuites(junit_xml: &str) -> Option<&str> {
+    let start = junit_xml.find("<testsuites")?;
+    let start = start + junit_xml[start..].find('\n')? + 1;
+    let end = junit_xml.rfind("</testsuites>")?;
+    junit_xml.get(start..end)
+}
+
+/// Push `s` onto `out`, escaped for use in XML text or attribute values.
+/// Control chars aren't allowed in XML 1.0, so those (like ANSI color codes)
+/// get replaced.
+fn push_xml_escaped(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if (c as u32) < 0x20 => out.push(char::REPLACEMENT_CHARACTER),
+            c => out.push(c),
+        }
+    }
+}
+
+fn read_to_string(path: &Path) -> String {
+    let bytes = fs::read_file(path)
+        .expect("failed to read test results")
+        .unwrap_or_else(|| {
+            panic!("missing test results: '{}'", path.display())
+        });
+    String::from_utf8(bytes)
+        .map_err(|_| ())
+        .expect("test results are not valid UTF-8")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn report() -> UnitReport<'static> {
+        UnitReport {
+            pkg_name: "foo",
+            version: "0.1.0",
+            suites: vec![Suite {
+                name: "foo::it".to_owned(),
+                exec_time: Some(0.0125),
+                cases: vec![
+                    Case {
+                        name: "works".to_owned(),
+                        outcome: Outcome::Passed,
+                        exec_time: Some(0.001),
+                        stdout: None,
+                    },
+                    Case {
+                        name: "fails".to_owned(),
+                        outcome: Outcome::Failed,
+                        exec_time: None,
+                        stdout: Some("left: 2 < \"3\"\n\x1b[0m".to_owned()),
+                    },
+                    Case {
+                        name: "slow".to_owned(),
+                        outcome: Outcome::Ignored,
+                        exec_time: None,
+                        stdout: None,
+                    },
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_unit_report() {
+        let report = report();
+        let counts = Counts {
+            passed: 1,
+            failed: 1,
+            ignored: 1,
+        };
+        assert_eq!(report.counts(), counts);
+
+        let results_json = report.to_json();
+        assert_eq!(
+            results_json,
+            r#"{
+  "package": "foo",
+  "version": "0.1.0",
+  "passed": 1, "failed": 1, "ignored": 1,
+  "suites": [
+    {"name": "foo::it", "passed": 1, "failed": 1, "ignored": 1, "exec_time": 0.0125, "tests": [
+      {"name": "works", "outcome": "passed", "exec_time": 0.001},
+      {"name": "fails", "outcome": "failed", "stdout": "left: 2 < \"3\"\n\u001b[0m"},
+      {"name": "slow", "outcome": "ignored"}
+    ]}
+  ]
+}
+"#
+        );
+        assert_eq!(Counts::from_results_json(&results_json), Some(counts));
+
+        let junit_xml = report.to_junit();
+        assert_eq!(
+            junit_xml,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="foo" tests="3" failures="1" skipped="1">
+  <testsuite name="foo::it" tests="3" failures="1" skipped="1" time="0.013">
+    <testcase classname="foo::it" name="works" time="0.001"/>
+    <testcase classname="foo::it" name="fails">
+      <failure message="test failed">left: 2 &lt; &quot;3&quot;
+�[0m</failure>
+    </testcase>
+    <testcase classname="foo::it" name="slow">
+      <skipped/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+        assert_eq!(
+            junit_testsuites(&junit_xml),
+            Some(
+                &junit_xml[junit_xml.find("  <testsuite ").unwrap()
+                    ..junit_xml.rfind("</testsuites>").unwrap()]
+            ),
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! Run the test binaries of a single test unit, like `cargo test`.
+//!
+//! Test binaries using the libtest harness run with `--format json`, so we can
+//! record each test's outcome. Since that's still unstable, we also set
+//! `RUSTC_BOOTSTRAP=1`. Tests without a harness just pass or fail as a whole.
+//!
+//! Either way, we replay a `cargo test`-like log to stderr and write the
+//! results to `$out` (see [`crate::test_report`]).
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+use nargo_core::{fs, logger, time, trace};
+
+use crate::{
+    cli, json,
+    test_report::{Case, Outcome, Suite, UnitReport},
+};
+
+/// Makes libtest print each test event as a JSON line to stdout. Needs
+/// `RUSTC_BOOTSTRAP=1`.
+pub(crate) const LIBTEST_JSON_ARGS: [&str; 3] =
+    ["-Zunstable-options", "--format=json", "--report-time"];
+
+/// The scratch dir integration tests and benches get at
+/// `env!("CARGO_TARGET_TMPDIR")`. Like cargo's `target/tmp`, it's shared
+/// between runs of the same unit, which we tell apart by its `-C metadata`
+/// hash. It must be the same path at compile and run time, so it can't live in
+/// any one sandbox's `$TMPDIR`.
+pub(crate) fn target_tmpdir(
+    pkg_name: &str,
+    version: &str,
+    metadata: &str,
+) -> PathBuf {
+    Path::new("/tmp/nargo-target-tmp")
+        .join(format!("{pkg_name}-{version}-{metadata}"))
+}
+
+pub(crate) fn run(args: &cli::RunTestArgs<'_>) {
+    fs::create_dir(args.out).expect("mkdir");
+
+    let mut test_bins = std::fs::read_dir(args.unit.join("bin"))
+        .expect("Failed to read test unit `bin` dir")
+        .map(|dir_entry| dir_entry.expect("Failed to read test bin").path())
+        .collect::<Vec<_>>();
+    test_bins.sort_unstable();
+
+    let mut report = UnitReport {
+        pkg_name: args.pkg_name,
+        version: args.version,
+        suites: Vec::with_capacity(test_bins.len()),
+    };
+
+    for test_bin in &test_bins {
+        let suite = time!("run test binary", run_test_bin(args, test_bin));
+        report.suites.push(suite);
+    }
+
+    report.write(args.out);
+
+    let counts = report.counts();
+    if counts.failed != 0 {
+        let msg = format!(
+            "{} test(s) failed in {}@{}",
+            counts.failed, args.pkg_name, args.version
+        );
+        if args.ignore_failures {
+            logger::flush();
+            eprintln!("warning: {msg}");
+        } else {
+            panic!("{msg}");
+        }
+    }
+}
+
+fn run_test_bin(args: &cli::RunTestArgs<'_>, test_bin: &Path) -> Suite {
+    let bin_name = test_bin
+        .file_name()
+        .and_then(|name| name.to_str())
+        .expect("test binary name is not valid UTF-8");
+    let name = format!("{}::{}", args.pkg_name, strip_metadata(bin_name));
+
+    // Test binaries are always named `${target.name}-${metadata}`.
+    let (_, metadata) = bin_name
+        .rsplit_once('-')
+        .expect("test binary name has no metadata hash");
+    let target_tmpdir = target_tmpdir(args.pkg_name, args.version, metadata);
+    std::fs::create_dir_all(&target_tmpdir)
+        .expect("failed to create CARGO_TARGET_TMPDIR");
+
+    // Like cargo, run tests from the package root with some of the same envs
+    // the test was compiled with.
+    let mut cmd = Command::new(test_bin);
+    cmd.current_dir(args.src)
+        .env("CARGO_MANIFEST_DIR", args.src)
+        .env("CARGO_MANIFEST_PATH", args.src.join("Cargo.toml"))
+        .env("CARGO_PKG_NAME", args.pkg_name)
+        .env("CARGO_PKG_VERSION", args.version)
+        .env("CARGO_TARGET_TMPDIR", &target_tmpdir);
+
+    if args.harness {
+        cmd.args(LIBTEST_JSON_ARGS).env("RUSTC_BOOTSTRAP", "1");
+    }
+    cmd.args(&args.test_args);
+
+    logger::flush();
+    eprintln!("     Running {bin_name}");
+    trace!("{cmd:?}");
+
+    run_suite(cmd, name, bin_name, args.harness)
+}
+
+/// Run a test binary (or `rustdoc --test`) and collect its results into a
+/// [`Suite`]. With `harness`, `cmd` must already have the libtest
+/// `--format json` args. Otherwise the whole run is a single test case named
+/// `bin_name`.
+pub(crate) fn run_suite(
+    mut cmd: Command,
+    name: String,
+    bin_name: &str,
+    harness: bool,
+) -> Suite {
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run test binary");
+    let stdout = child.stdout.take().unwrap();
+
+    let mut suite = Suite {
+        name,
+        exec_time: None,
+        cases: Vec::new(),
+    };
+
+    if harness {
+        read_libtest_json(stdout, &mut suite);
+    } else {
+        let mut output = String::new();
+        BufReader::new(stdout)
+            .read_to_string(&mut output)
+            .expect("failed to read test binary output");
+        eprint!("{output}");
+        suite.cases.push(Case {
+            name: bin_name.to_owned(),
+            outcome: Outcome::Passed,
+            exec_time: None,
+            stdout: Some(output),
+        });
+    }
+
+    let status = child.wait().expect("failed to wait on test binary");
+    finish_suite(&mut suite, bin_name, status);
+    print_summary(&suite);
+    suite
+}
+
+/// Read libtest's `--format json` events from the test binary's stdout into
+/// `suite`, replaying the usual human-readable log to stderr.
+fn read_libtest_json(stdout: impl Read, suite: &mut Suite) {
+    for line in BufReader::new(stdout).lines() {
+        let line = line.expect("failed to read test binary output");
+
+        // Tests run with `--nocapture` print straight to stdout
+        let ty = match json::top_level_str_field(&line, "type") {
+            Some(ty) => ty,
+            None => {
+                eprintln!("{line}");
+                continue;
+            }
+        };
+        let event = json::top_level_str_field(&line, "event");
+        let test_name = json::top_level_str_field(&line, "name");
+        let exec_time = json::top_level_f64_field(&line, "exec_time");
+
+        match (ty.as_str(), event.as_deref(), test_name) {
+            ("suite", Some("started"), _) => {
+                let count = json::top_level_f64_field(&line, "test_count")
+                    .unwrap_or(0.0);
+                eprintln!("\nrunning {count} tests");
+            }
+            ("suite", Some(_), _) => suite.exec_time = exec_time,
+            ("test", Some(event), Some(name)) => {
+                let outcome = match event {
+                    "ok" => Outcome::Passed,
+                    "failed" => Outcome::Failed,
+                    "ignored" => Outcome::Ignored,
+                    // "started", "timeout" (a warning)
+                    _ => continue,
+                };
+                eprintln!("test {name} ... {}", outcome_label(outcome));
+
+                let stdout = if outcome == Outcome::Failed {
+                    json::top_level_str_field(&line, "stdout")
+                } else {
+                    None
+                };
+                suite.cases.push(Case {
+                    name,
+                    outcome,
+                    exec_time,
+                    stdout,
+                });
+            }
+            ("bench", _, Some(name)) => {
+                let median =
+                    json::top_level_f64_field(&line, "median").unwrap_or(0.0);
+                let deviation = json::top_level_f64_field(&line, "deviation")
+                    .unwrap_or(0.0);
+                eprintln!(
+                    "test {name} ... bench: {median} ns/iter (+/- {deviation})"
+                );
+                suite.cases.push(Case {
+                    name,
+                    outcome: Outcome::Passed,
+                    exec_time: None,
+                    stdout: None,
+                });
+            }
+            _ => trace!("unknown libtest event: {line}"),
+        }
+    }
+}
+
+/// Record a failure if the test binary itself failed (e.g., crashed, or a
+/// test without a harness exited non-zero) without reporting any failed
+/// tests.
+fn finish_suite(suite: &mut Suite, bin_name: &str, status: ExitStatus) {
+    if status.success() {
+        return;
+    }
+    if suite
+        .cases
+        .iter()
+        .any(|case| case.outcome == Outcome::Failed)
+    {
+        return;
+    }
+
+    let msg = format!("test binary `{bin_name}` failed: {status}");
+    match suite.cases.iter_mut().find(|case| case.name == bin_name) {
+        Some(case) => {
+            case.outcome = Outcome::Failed;
+            let stdout = case.stdout.get_or_insert_with(String::new);
+            stdout.push_str(&msg);
+        }
+        None => suite.cases.push(Case {
+            name: bin_name.to_owned(),
+            outcome: Outcome::Failed,
+            exec_time: None,
+            stdout: Some(msg),
+        }),
+    }
+}
+
+/// Print the failures and the `test result: ...` line, like libtest does.
+fn print_summary(suite: &Suite) {
+    let failures = suite
+        .cases
+        .iter()
+        .filter(|case| case.outcome == Outcome::Failed)
+        .collect::<Vec<_>>();
+
+    if !failures.is_empty() {
+        eprintln!("\nfailures:\n");
+        for case in &failures {
+            if let Some(stdout) = &case.stdout {
+                eprintln!("---- {} stdout ----\n{stdout}", case.name);
+            }
+        }
+        eprintln!("\nfailures:");
+        for case in &failures {
+            eprintln!("    {}", case.name);
+        }
+    }
+
+    let counts = suite.counts();
+    let result = if counts.failed == 0 { "ok" } else { "FAILED" };
+    eprintln!(
+        "\ntest result: {result}. {} passed; {} failed; {} ignored\n",
+        counts.passed, counts.failed, counts.ignored,
+    );
+}
+
+fn outcome_label(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Passed => "ok",
+        Outcome::Failed => "FAILED",
+        Outcome::Ignored => "ignored",
+    }
+}
+
+/// Test binaries are named `<target-name>-<metadata>`. Strip the metadata
+/// hash suffix.
+fn strip_metadata(bin_name: &str) -> &str {
+    match bin_name.rsplit_once('-') {
+        Some((name, metadata))
+            if (metadata.len() == 8 || metadata.len() == 16)
+                && metadata.bytes().all(|b| b.is_ascii_alphanumeric()) =>
+        {
+            name
+        }
+        _ => bin_name,
+    }
+}
+
+/// Merge the reports of many test runs into a single report in `$out`.
+pub(crate) fn merge(args: &cli::MergeTestResultsArgs<'_>) {
+    fs::create_dir(args.out).expect("mkdir");
+
+    let counts = time!(
+        "merge test results",
+        crate::test_report::merge(args.name, &args.results, args.out)
+    );
+
+    logger::flush();
+    eprintln!(
+        "{}: {} passed; {} failed; {} ignored",
+        args.name, counts.passed, counts.failed, counts.ignored,
+    );
+
+    if counts.failed != 0 && !args.ignore_failures {
+        panic!("{} test(s) failed", counts.failed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_libtest_json() {
+        let stdout = r#"{ "type": "suite", "event": "started", "test_count": 3 }
+{ "type": "test", "event": "started", "name": "fails" }
+{ "type": "test", "event": "started", "name": "works" }
+println from a --nocapture test
+{ "type": "test", "name": "works", "event": "ok", "exec_time": 0.001 }
+{ "type": "test", "name": "fails", "event": "failed", "exec_time": 0.002, "stdout": "assertion failed\n" }
+{ "type": "test", "event": "ignored", "name": "slow" }
+{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 1, "measured": 0, "filtered_out": 0, "exec_time": 0.01 }
+"#;
+        let mut suite = Suite {
+            name: "foo::it".to_owned(),
+            exec_time: None,
+            cases: Vec::new(),
+        };
+        read_libtest_json(stdout.as_bytes(), &mut suite);
+
+        assert_eq!(suite.exec_time, Some(0.01));
+        let cases = suite
+            .cases
+            .iter()
+            .map(|case| {
+                (
+                    case.name.as_str(),
+                    outcome_label(case.outcome),
+                    case.stdout.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cases,
+            [
+                ("works", "ok", None),
+                ("fails", "FAILED", Some("assertion failed\n")),
+                ("slow", "ignored", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strip_metadata() {
+        assert_eq!(strip_metadata("it-0c1abz9d"), "it");
+        assert_eq!(strip_metadata("foo-bar-0c1abz9d"), "foo-bar");
+        assert_eq!(strip_metadata("it-3f2a9c41d07be865"), "it");
+        assert_eq!(strip_metadata("foo-bar"), "foo-bar");
+    }
+
+    #[test]
+    fn test_target_tmpdir() {
+        // Each unit of a package gets its own scratch dir.
+        assert_eq!(
+            target_tmpdir("foo", "0.1.0", "0c1abz9d"),
+            Path::new("/tmp/nargo-target-tmp/foo-0.1.0-0c1abz9d"),
+        );
+        assert_ne!(
+            target_tmpdir("foo", "0.1.0", "0c1abz9d"),
+            target_tmpdir("foo", "0.1.0", "3f2a9c41"),
+        );
+    }
+}
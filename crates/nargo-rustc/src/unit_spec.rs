@@ -0,0 +1,586 @@
+//! Read a unit's whole build description from a single JSON "unit spec" file,
+//! instead of the individual envs in [`crate::cli::ArgsRaw`].
+//!
+//! `buildCrate.nix` writes the spec via `passAsFile = ["unit"]`, so nix hands
+//! us its path in the `unitPath` env. We can also run by hand with
+//! `nargo-rustc --unit <path>`. Unlike the envs, lists are real JSON arrays, so
+//! paths may contain spaces.
+//!
+//! The schema is versioned by the top-level `schema_version` field. We reject
+//! any other version, unknown fields, and fields with the wrong type.
+//!
+//! ```json
+//! {
+//!   "schema_version": 2,
+//!   "pkg_name": "foo",
+//!   "version": "0.1.0",
+//!   "src": "/nix/store/...-source/crates/foo",
+//!   "workspace_src": "/nix/store/...-source",  // workspace packages only
+//!   "out": "/nix/store/...-foo-lib-0.1.0",   // default: `$out`
+//!   "log": "trace",                          // default: "info"
+//!   "incremental": "/.../target/nargo/incremental/foo-lib-0.1.0-0c1abz9d0c1abz9d",
+//!   "target": {
+//!     "name": "foo",
+//!     "kind": "lib",
+//!     "crate_types": ["lib"],
+//!     "path": "src/lib.rs",
+//!     "edition": "2021",
+//!     "harness": true,                       // default: false
+//!     "features": ["default", "std"]         // default: []
+//!   },
+//!   "mode": "build",
+//!   "pipelined": false,
+//!   "metadata": "0c1abz9d0c1abz9d",          // or null
+//!   "is_workspace_pkg": true,
+//!   "is_primary_pkg": true,                  // `CARGO_PRIMARY_PACKAGE`
+//!   "package": {                             // `CARGO_PKG_<FIELD>` envs
+//!     "authors": ["Jane Doe <jane@example.com>"],
+//!     "description": "Does foo",
+//!     "homepage": null,
+//!     "license": "MIT OR Apache-2.0",
+//!     "license_file": null,
+//!     "readme": "README.md",
+//!     "repository": "https://github.com/example/foo",
+//!     "rust_version": "1.80"
+//!   },
+//!   "lto_needs_object": false,
+//!   "profile": { "root": "dev", "opt_level": "0", ... },
+//!   "target_triple": "x86_64-unknown-linux-gnu",
+//!   "target_cc": "/nix/store/.../bin/cc",     // or null
+//!   "target_ar": null,
+//!   "host_triple": "x86_64-unknown-linux-gnu",
+//!   "host_cc": null,
+//!   "host_ar": null,
+//!   "deps": [{ "dep_name": "bar", "crate_name": "bar", "out": "..." }],
+//!   "bin_deps": [],
+//!   "build_script_dep": null,
+//!   "build_script_bin": null,                // `run-custom-build` only
+//!   "rustc_target_cfg": null,                // `run-custom-build` only
+//!   "link_deps": [],
+//!   "links": null,
+//!   "links_deps": [{ "links": "z", "out": "..." }],
+//!   "lints": ["--warn=unused"],
+//!   "lint_args": [],
+//!   "lint_fail_on": "error",
+//!   "cfgs": ["tokio_unstable"],              // extra `--cfg`s
+//!   "envs": { "FOO": "bar" }                 // extra compile-time envs
+//! }
+//! ```
+//!
+//! All fields other than `schema_version`, `pkg_name`, `version`, `src`,
+//! `target` (`name`, `kind`, `crate_types`, `path`, `edition`), `mode`,
+//! `profile`, `target_triple`, and `host_triple` are optional.
+//!
+//! Build scripts take two units. A `build` unit compiles `build_script_build`
+//! with the build-override `profile` for `host_triple`. A `run-custom-build`
+//! unit then runs it, with `build_script_bin` pointing at the compiled unit's
+//! out dir, and `rustc_target_cfg` at a file with the `rustc --print cfg`
+//! output for `target_triple`. Without `rustc_target_cfg`, we run `rustc` to
+//! get it.
+//!
+//! A workspace package's `src` keeps its position in the workspace: it's the
+//! package's dir inside `workspace_src`, a copy of the workspace with just the
+//! files the unit may read. So `$CARGO_MANIFEST_DIR/../foo` works like it does
+//! with cargo, and we can record sources outside the package.
+//!
+//! nix never sets `incremental`: it's only for `nargo-build`, which builds
+//! workspace crates outside of the sandbox and keeps their incremental
+//! compilation caches between builds.
+
+use std::path::{Path, PathBuf};
+
+use nargo_core::{env, fs};
+
+use crate::json::Value;
+
+/// The only `schema_version` we understand.
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+
+/// A parsed and validated unit spec. Like [`crate::cli::ArgsRaw`], this owns
+/// everything that [`crate::cli::Args`] borrows.
+#[derive(Debug)]
+pub struct UnitSpec {
+    pub(crate) bin_deps: Vec<PathBuf>,
+    pub(crate) build_script_bin: Option<PathBuf>,
+    pub(crate) build_script_dep: Option<PathBuf>,
+    pub(crate) cfgs: Vec<String>,
+    /// The target's crate types, comma-separated.
+    pub(crate) crate_type: String,
+    pub(crate) deps: Vec<SpecDep>,
+    pub(crate) edition: String,
+    pub(crate) envs: Vec<(String, String)>,
+    /// The target's features, comma-separated.
+    pub(crate) features: String,
+    pub(crate) harness: bool,
+    pub(crate) host_ar: Option<String>,
+    pub(crate) host_cc: Option<String>,
+    pub(crate) host_triple: String,
+    pub(crate) incremental: Option<PathBuf>,
+    pub(crate) is_primary_pkg: bool,
+    pub(crate) is_workspace_pkg: bool,
+    pub(crate) kind: String,
+    pub(crate) link_deps: Vec<PathBuf>,
+    pub(crate) links: Option<String>,
+    pub(crate) links_deps: Vec<SpecLinksDep>,
+    pub(crate) lint_args: Vec<String>,
+    pub(crate) lint_fail_on: String,
+    pub(crate) lints: Vec<String>,
+    pub(crate) log: String,
+    pub(crate) lto_needs_object: bool,
+    pub(crate) metadata: Option<String>,
+    pub(crate) mode: String,
+    pub(crate) out: PathBuf,
+    pub(crate) package: SpecPackage,
+    pub(crate) pipelined: bool,
+    pub(crate) pkg_name: String,
+    /// The profile as `key=value` pairs, like the `PROFILE` env.
+    pub(crate) profile: String,
+    pub(crate) rustc_target_cfg: Option<PathBuf>,
+    pub(crate) src: PathBuf,
+    pub(crate) target_ar: Option<String>,
+    pub(crate) target_cc: Option<String>,
+    pub(crate) target_name: String,
+    pub(crate) target_path: PathBuf,
+    pub(crate) target_triple: String,
+    pub(crate) version: String,
+    pub(crate) workspace_src: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SpecDep {
+    pub(crate) crate_name: String,
+    pub(crate) dep_name: String,
+    pub(crate) out: PathBuf,
+}
+
+/// The `[package]` metadata from `Cargo.toml`. All optional.
+#[derive(Debug, Default)]
+pub(crate) struct SpecPackage {
+    pub(crate) authors: Vec<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) homepage: Option<String>,
+    pub(crate) license: Option<String>,
+    pub(crate) license_file: Option<String>,
+    pub(crate) readme: Option<String>,
+    pub(crate) repository: Option<String>,
+    pub(crate) rust_version: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SpecLinksDep {
+    pub(crate) links: String,
+    pub(crate) out: PathBuf,
+}
+
+/// The fields of a JSON object we haven't read yet. Any fields left over at
+/// the end are unknown to our schema.
+struct Fields {
+    /// The object's path in the spec, like `target`, for error messages.
+    /// Empty for the top-level object.
+    path: &'static str,
+    fields: Vec<(String, Value)>,
+}
+
+impl UnitSpec {
+    /// The path to the unit spec that nix gave us via `passAsFile`, if any.
+    pub fn path_from_env() -> Option<PathBuf> {
+        env::var_os("unitPath").ok().map(PathBuf::from)
+    }
+
+    /// Read and validate the unit spec at `path`. If the spec doesn't set
+    /// `out`, we use the `$out` env.
+    pub fn from_file(path: &Path) -> Self {
+        let json = fs::read_file(path)
+            .expect("failed to read unit spec")
+            .unwrap_or_else(|| panic!("missing unit spec: {}", path.display()));
+        let json =
+            String::from_utf8(json).expect("unit spec is not valid UTF-8");
+        Self::parse(&json, || {
+            env::var_os("out")
+                .map(PathBuf::from)
+                .expect("unit spec: missing `out`")
+        })
+    }
+
+    /// Clear the nargo-specific envs that nix sets in unit spec mode.
+    ///
+    /// # Safety
+    ///
+    /// [`std::env::remove_var`] is not thread-safe. You must call this before
+    /// spawning any threads.
+    pub unsafe fn remove_nargo_envs() {
+        const REMOVE_ENVS: &[&str] = &["out", "src", "unit", "unitPath"];
+
+        for env in REMOVE_ENVS {
+            unsafe {
+                std::env::remove_var(env);
+            }
+        }
+    }
+
+    pub(crate) fn parse(
+        json: &str,
+        default_out: impl FnOnce() -> PathBuf,
+    ) -> Self {
+        let value =
+            crate::json::parse(json).expect("unit spec is not valid JSON");
+        let mut spec = Fields::new("", value);
+
+        let schema_version = spec.required("schema_version", as_u32);
+        if schema_version != SCHEMA_VERSION {
+            panic!(
+                "unit spec: unsupported `schema_version`: {schema_version} \
+                 (expected {SCHEMA_VERSION})"
+            );
+        }
+
+        let mut target = Fields::new("target", spec.required("target", Some));
+        let target_name = target.required("name", as_string);
+        let kind = target.required("kind", as_string);
+        let crate_type = target.required("crate_types", as_strings).join(",");
+        let target_path = target.required("path", as_path);
+        let edition = target.required("edition", as_string);
+        let harness = target.optional("harness", as_bool).unwrap_or(false);
+        let features = target
+            .optional("features", as_strings)
+            .unwrap_or_default()
+            .join(",");
+        target.finish();
+
+        let this = Self {
+            bin_deps: spec.list("bin_deps", as_path),
+            build_script_bin: spec.optional("build_script_bin", as_path),
+            build_script_dep: spec.optional("build_script_dep", as_path),
+            cfgs: spec.list("cfgs", as_string),
+            crate_type,
+            deps: spec.list("deps", as_dep),
+            edition,
+            envs: spec.optional("envs", as_envs).unwrap_or_default(),
+            features,
+            harness,
+            host_ar: spec.optional("host_ar", as_string),
+            host_cc: spec.optional("host_cc", as_string),
+            host_triple: spec.required("host_triple", as_string),
+            incremental: spec.optional("incremental", as_path),
+            is_primary_pkg: spec.flag("is_primary_pkg"),
+            is_workspace_pkg: spec.flag("is_workspace_pkg"),
+            kind,
+            link_deps: spec.list("link_deps", as_path),
+            links: spec.optional("links", as_string),
+            links_deps: spec.list("links_deps", as_links_dep),
+            lint_args: spec.list("lint_args", as_string),
+            lint_fail_on: spec
+                .optional("lint_fail_on", as_string)
+                .unwrap_or_default(),
+            lints: spec.list("lints", as_string),
+            log: spec
+                .optional("log", as_string)
+                .unwrap_or_else(|| "info".to_owned()),
+            lto_needs_object: spec.flag("lto_needs_object"),
+            metadata: spec.optional("metadata", as_string),
+            mode: spec.required("mode", as_string),
+            out: spec.optional("out", as_path).unwrap_or_else(default_out),
+            package: spec.optional("package", as_package).unwrap_or_default(),
+            pipelined: spec.flag("pipelined"),
+            pkg_name: spec.required("pkg_name", as_string),
+            profile: spec.required("profile", as_profile),
+            rustc_target_cfg: spec.optional("rustc_target_cfg", as_path),
+            src: spec.required("src", as_path),
+            target_ar: spec.optional("target_ar", as_string),
+            target_cc: spec.optional("target_cc", as_string),
+            target_name,
+            target_path,
+            target_triple: spec.required("target_triple", as_string),
+            version: spec.required("version", as_string),
+            workspace_src: spec.optional("workspace_src", as_path),
+        };
+        spec.finish();
+        this
+    }
+}
+
+impl Fields {
+    fn new(path: &'static str, value: Value) -> Self {
+        match value {
+            Value::Object(fields) => Self { path, fields },
+            _ if path.is_empty() => panic!("unit spec must be a JSON object"),
+            _ => panic!("unit spec: `{path}` must be an object"),
+        }
+    }
+
+    /// The full path of the field `key`, like `target.kind`.
+    fn field_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{key}", self.path)
+        }
+    }
+
+    /// Remove and return the field `key`, treating `null` as missing.
+    fn take(&mut self, key: &str) -> Option<Value> {
+        let idx = self.fields.iter().position(|(k, _)| k == key)?;
+        match self.fields.remove(idx).1 {
+            Value::Null => None,
+            value => Some(value),
+        }
+    }
+
+    fn optional<T>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(Value) -> Option<T>,
+    ) -> Option<T> {
+        let value = self.take(key)?;
+        Some(f(value).unwrap_or_else(|| {
+            panic!("unit spec: `{}` has the wrong type", self.field_path(key))
+        }))
+    }
+
+    fn required<T>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(Value) -> Option<T>,
+    ) -> T {
+        self.optional(key, f).unwrap_or_else(|| {
+            panic!(
+                "unit spec: missing required field `{}`",
+                self.field_path(key)
+            )
+        })
+    }
+
+    /// An optional bool that defaults to `false`.
+    fn flag(&mut self, key: &str) -> bool {
+        self.optional(key, as_bool).unwrap_or(false)
+    }
+
+    /// An optional list that defaults to empty.
+    fn list<T>(&mut self, key: &str, f: impl Fn(Value) -> Option<T>) -> Vec<T> {
+        self.optional(key, |value| match value {
+            Value::Array(values) => values.into_iter().map(f).collect(),
+            _ => None,
+        })
+        .unwrap_or_default()
+    }
+
+    /// Panic if there are any fields we didn't read.
+    fn finish(self) {
+        if let Some((key, _)) = self.fields.first() {
+            panic!("unit spec: unknown field `{}`", self.field_path(key));
+        }
+    }
+}
+
+fn as_string(value: Value) -> Option<String> {
+    match value {
+        Value::Str(s) => Some(s),
+        _ => None,
+    }
+}
+
+fn as_path(value: Value) -> Option<PathBuf> {
+    as_string(value).map(PathBuf::from)
+}
+
+fn as_bool(value: Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(b),
+        _ => None,
+    }
+}
+
+fn as_u32(value: Value) -> Option<u32> {
+    match value {
+        Value::Number(n) => n.parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_strings(value: Value) -> Option<Vec<String>> {
+    match value {
+        Value::Array(values) => values.into_iter().map(as_string).collect(),
+        _ => None,
+    }
+}
+
+fn as_dep(value: Value) -> Option<SpecDep> {
+    if !matches!(value, Value::Object(_)) {
+        return None;
+    }
+    let mut dep = Fields::new("deps[]", value);
+    let spec_dep = SpecDep {
+        crate_name: dep.required("crate_name", as_string),
+        dep_name: dep.required("dep_name", as_string),
+        out: dep.required("out", as_path),
+    };
+    dep.finish();
+    Some(spec_dep)
+}
+
+fn as_package(value: Value) -> Option<SpecPackage> {
+    if !matches!(value, Value::Object(_)) {
+        return None;
+    }
+    let mut package = Fields::new("package", value);
+    let spec_package = SpecPackage {
+        authors: package.list("authors", as_string),
+        description: package.optional("description", as_string),
+        homepage: package.optional("homepage", as_string),
+        license: package.optional("license", as_string),
+        license_file: package.optional("license_file", as_string),
+        readme: package.optional("readme", as_string),
+        repository: package.optional("repository", as_string),
+        rust_version: package.optional("rust_version", as_string),
+    };
+    package.finish();
+    Some(spec_package)
+}
+
+fn as_links_dep(value: Value) -> Option<SpecLinksDep> {
+    if !matches!(value, Value::Object(_)) {
+        return None;
+    }
+    let mut dep = Fields::new("links_deps[]", value);
+    let spec_dep = SpecLinksDep {
+        links: dep.required("links", as_string),
+        out: dep.required("out", as_path),
+    };
+    dep.finish();
+    Some(spec_dep)
+}
+
+fn as_envs(value: Value) -> Option<Vec<(String, String)>> {
+    match value {
+        Value::Object(fields) => fields
+            .into_iter()
+            .map(|(key, value)| Some((key, as_string(value)?)))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Serialize a profile object as `key=value` pairs, which is what
+/// [`crate::profile::Profile::parse`] reads from the `PROFILE` env.
+fn as_profile(value: Value) -> Option<String> {
+    let fields = match value {
+        Value::Object(fields) => fields,
+        _ => return None,
+    };
+    let mut out = String::new();
+    for (key, value) in fields {
+        let value = match value {
+            Value::Null => continue,
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) | Value::Str(n) => n,
+            Value::Array(_) | Value::Object(_) => return None,
+        };
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(&key);
+        out.push('=');
+        out.push_str(&value);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SPEC: &str = r#"{
+        "schema_version": 2,
+        "pkg_name": "foo",
+        "version": "0.1.0",
+        "src": "/nix/store/aaaa-source/foo src",
+        "workspace_src": "/nix/store/aaaa-source",
+        "target": {
+            "name": "foo",
+            "kind": "lib",
+            "crate_types": ["rlib", "cdylib"],
+            "path": "src/lib.rs",
+            "edition": "2021",
+            "features": ["default", "std"]
+        },
+        "mode": "build",
+        "metadata": null,
+        "profile": {"root": "dev", "opt_level": "0", "codegen_units": 256, "debug_assertions": true, "split_debuginfo": null},
+        "target_triple": "x86_64-unknown-linux-gnu",
+        "host_triple": "x86_64-unknown-linux-gnu",
+        "deps": [{"dep_name": "bar-baz", "crate_name": "bar", "out": "/nix/store/bbbb-my bar"}],
+        "envs": {"FOO": "a b"}
+    }"#;
+
+    #[test]
+    fn test_parse() {
+        let spec = UnitSpec::parse(SPEC, || PathBuf::from("/out"));
+        assert_eq!(spec.src, Path::new("/nix/store/aaaa-source/foo src"));
+        assert_eq!(
+            spec.workspace_src.as_deref(),
+            Some(Path::new("/nix/store/aaaa-source"))
+        );
+        assert_eq!(spec.out, Path::new("/out"));
+        assert_eq!(spec.crate_type, "rlib,cdylib");
+        assert_eq!(spec.features, "default,std");
+        assert!(!spec.harness);
+        assert_eq!(spec.metadata, None);
+        assert_eq!(spec.log, "info");
+        assert_eq!(
+            spec.profile,
+            "root=dev opt_level=0 codegen_units=256 debug_assertions=true"
+        );
+        assert_eq!(spec.deps.len(), 1);
+        assert_eq!(spec.deps[0].dep_name, "bar-baz");
+        assert_eq!(spec.deps[0].out, Path::new("/nix/store/bbbb-my bar"));
+        assert_eq!(spec.envs, [("FOO".to_owned(), "a b".to_owned())]);
+    }
+
+    #[test]
+    fn test_parse_run_custom_build() {
+        let spec = SPEC
+            .replace("\"kind\": \"lib\"", "\"kind\": \"custom-build\"")
+            .replace("\"mode\": \"build\"", "\"mode\": \"run-custom-build\"")
+            .replace(
+                "\"envs\"",
+                "\"build_script_bin\": \"/nix/store/cccc-foo-custom-build-0.1.0\", \
+                 \"rustc_target_cfg\": \"/nix/store/dddd-rustc-target-cfg\", \
+                 \"envs\"",
+            );
+        let spec = UnitSpec::parse(&spec, || PathBuf::from("/out"));
+        assert_eq!(spec.mode, "run-custom-build");
+        assert_eq!(
+            spec.build_script_bin.as_deref(),
+            Some(Path::new("/nix/store/cccc-foo-custom-build-0.1.0")),
+        );
+        assert_eq!(
+            spec.rustc_target_cfg.as_deref(),
+            Some(Path::new("/nix/store/dddd-rustc-target-cfg")),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported `schema_version`: 1")]
+    fn test_parse_schema_version() {
+        let spec =
+            SPEC.replace("\"schema_version\": 2", "\"schema_version\": 1");
+        UnitSpec::parse(&spec, || PathBuf::from("/out"));
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown field `target.crate_type`")]
+    fn test_parse_unknown_field() {
+        let spec = SPEC.replace(
+            "\"crate_types\"",
+            "\"crate_type\": \"lib\", \"crate_types\"",
+        );
+        UnitSpec::parse(&spec, || PathBuf::from("/out"));
+    }
+
+    #[test]
+    #[should_panic(expected = "`deps` has the wrong type")]
+    fn test_parse_wrong_type() {
+        let spec = SPEC.replace("\"deps\": [", "\"deps\": [1, ");
+        UnitSpec::parse(&spec, || PathBuf::from("/out"));
+    }
+}
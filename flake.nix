@@ -53,6 +53,7 @@
         garnix-check-shards = self.tests.${system}.garnix-check-shards;
       in
         {
+          nargo-build = nargoLib.nargo-build;
           nargo-metadata = nargoLib.nargo-metadata;
           nargo-resolve = nargoLib.nargo-resolve;
           nargo-rustc = nargoLib.nargo-rustc;
@@ -7,15 +7,36 @@
 }:
 #
 {
+  buildCc,
   buildPlatform,
   crateSrc,
+  # For workspace packages, the copy of the workspace that `crateSrc` sits in.
+  workspaceSrc ? null,
   hostPlatform,
   pkgMetadata,
   rustc,
   cc,
+  clippy,
   target,
 }:
 #
+let
+  # The platform this unit runs on (for a build script: the platform it
+  # configures its package for), and its C toolchain. Build scripts themselves
+  # are always compiled for (and linked with the `buildCc` of) the
+  # `buildPlatform`, i.e., cargo's "host".
+  targetPlatform =
+    if target.for_build
+    then buildPlatform
+    else hostPlatform;
+  targetCc =
+    if target.for_build
+    then buildCc
+    else cc;
+
+  ccPath = cc: "${cc}/bin/${cc.targetPrefix}cc";
+  arPath = cc: "${cc.bintools}/bin/${cc.targetPrefix}ar";
+in
 lib.extendDerivation
 # validate
 true
@@ -25,42 +46,126 @@ true
   target = target;
 }
 # derivation
-(builtins.derivation {
-  name = "${pkgMetadata.name}-${target.kind}-${pkgMetadata.version}";
-  version = pkgMetadata.version;
+(builtins.derivation ({
+    name = "${pkgMetadata.name}-${target.kind}${
+      if target.mode == "metadata"
+      then "-rmeta"
+      else if target.mode == "test"
+      then "-test"
+      else if target.mode == "doctest"
+      then "-doctest"
+      else if target.mode == "doc"
+      then "-doc"
+      else if target.mode == "clippy"
+      then "-clippy"
+      else if target.mode == "run-custom-build"
+      then "-run"
+      else ""
+    }-${pkgMetadata.version}";
+    version = pkgMetadata.version;
+
+    builder = "${nargo-rustc}/bin/nargo-rustc";
 
-  builder = "${nargo-rustc}/bin/nargo-rustc";
+    outputs = ["out"];
+    src = crateSrc;
+    system = buildPlatform.system;
 
-  outputs = ["out"];
-  src = crateSrc;
-  system = buildPlatform.system;
+    # The unit spec, as JSON. nix writes it to a file and passes its path to
+    # `nargo-rustc` in the `unitPath` env. See `nargo-rustc/src/unit_spec.rs`
+    # for the schema.
+    passAsFile = ["unit"];
+    unit = builtins.toJSON {
+      schema_version = 2;
+      pkg_name = pkgMetadata.name;
+      version = pkgMetadata.version;
+      src = crateSrc;
+      workspace_src = workspaceSrc;
+      log = "trace";
+      target = {
+        name = target.name;
+        kind = target.kind;
+        crate_types = target.crate_types;
+        path = target.path;
+        edition = target.edition;
+        harness = target.harness;
+        features = builtins.attrNames target.features;
+      };
+      mode = target.mode;
+      pipelined = target.pipelined;
+      # The `-C metadata` hash (see `buildGraph.nix` `_unitMetadata`).
+      metadata = target.metadata;
+      # Only workspace packages lack a `source`.
+      is_workspace_pkg = !(pkgMetadata ? source);
+      is_primary_pkg = target.is_primary_pkg or false;
+      # The `[package]` metadata for the `CARGO_PKG_<FIELD>` envs.
+      package = {
+        authors = pkgMetadata.authors or [];
+        description = pkgMetadata.description or null;
+        homepage = pkgMetadata.homepage or null;
+        license = pkgMetadata.license or null;
+        license_file = pkgMetadata.license_file or null;
+        readme = pkgMetadata.readme or null;
+        repository = pkgMetadata.repository or null;
+        rust_version = pkgMetadata.rust_version or null;
+      };
+      lto_needs_object = target.lto_needs_object;
+      # The resolved profile.
+      profile = target.profile;
+      target_triple = targetPlatform.rust.rustcTarget;
+      target_cc = ccPath targetCc;
+      target_ar = arPath targetCc;
+      # cargo's "host" is nix's `buildPlatform`.
+      host_triple = buildPlatform.rust.rustcTarget;
+      host_cc = ccPath buildCc;
+      host_ar = arPath buildCc;
+      deps =
+        builtins.map (dep: {
+          dep_name = dep.dep_name;
+          crate_name = dep.crate_name;
+          out = dep.unit;
+        })
+        target.deps;
+      bin_deps = target.bin_deps;
+      build_script_dep = target.build_script_dep;
+      # Only for `run-custom-build` units.
+      build_script_bin = target.build_script_bin or null;
+      rustc_target_cfg = target.rustc_target_cfg or null;
+      link_deps = target.link_deps;
+      links = pkgMetadata.links or null;
+      links_deps =
+        builtins.map (dep: {
+          links = dep.links;
+          out = dep.unit;
+        })
+        target.links_deps;
+      # The package's `[lints]` table as `rustc` flags, from `nargo-metadata`.
+      lints = pkgMetadata.lints or [];
+      lint_args = target.lint_args;
+      lint_fail_on = target.lint_fail_on;
+      cfgs = target.cfgs or [];
+      envs = target.envs or {};
+    };
 
-  # Envs
-  BUILD_SCRIPT_DEP = target.build_script_dep;
-  CRATE_TYPE = builtins.concatStringsSep "," target.crate_types;
-  DEP_NAMES = builtins.map (dep: dep.dep_name) target.deps;
-  DEP_CRATE_NAMES = builtins.map (dep: dep.crate_name) target.deps;
-  DEP_PATHS = builtins.map (dep: dep.unit) target.deps;
-  EDITION = target.edition;
-  FEATURES = builtins.concatStringsSep "," (builtins.attrNames target.features);
-  KIND = target.kind;
-  LOG = "trace";
-  # TODO(phlip9): need to place `rustc` in depsBuildBuild vs depsBuildHost (?)
-  # depending on target/kind/etc.
-  # TODO(phlip9): remove `cc` when everything gets provided by rustup toolchains?
-  PATH = "${rustc}/bin:${cc}/bin";
-  PKG_NAME = pkgMetadata.name;
-  TARGET_NAME = target.name;
-  TARGET_PATH = target.path;
-  TARGET_TRIPLE = hostPlatform.rust.rustcTarget;
+    # Envs
+    # TODO(phlip9): need to place `rustc` in depsBuildBuild vs depsBuildHost (?)
+    # depending on target/kind/etc.
+    # TODO(phlip9): remove `cc` when everything gets provided by rustup toolchains?
+    PATH =
+      if target.mode == "clippy"
+      then "${clippy}/bin:${rustc}/bin:${targetCc}/bin"
+      else "${rustc}/bin:${targetCc}/bin";
 
-  # Reduce build time wasted looking for substitutions that don't exist.
-  # TODO(phlip9): make this configurable/overridable?
-  #
-  # > If this attribute is set to `false`, then Nix will always build this
-  # > derivation (locally or remotely); it will not try to substitute its outputs.
-  # > This is useful for derivations that are cheaper to build than to substitute.
-  # >
-  # > This can be ignored by setting `always-allow-substitutes` to `true`.
-  allowSubstitutes = false;
-})
+    # Reduce build time wasted looking for substitutions that don't exist.
+    # TODO(phlip9): make this configurable/overridable?
+    #
+    # > If this attribute is set to `false`, then Nix will always build this
+    # > derivation (locally or remotely); it will not try to substitute its outputs.
+    # > This is useful for derivations that are cheaper to build than to substitute.
+    # >
+    # > This can be ignored by setting `always-allow-substitutes` to `true`.
+    allowSubstitutes = false;
+  }
+  // lib.optionalAttrs (target.clippy_conf_dir != null) {
+    # Only when the package has no `clippy.toml` of its own.
+    CLIPPY_CONF_DIR = target.clippy_conf_dir;
+  }))
@@ -26,10 +26,23 @@
   #     normal = {
   #       bin-nargo-metadata = buildCrate { .. };
   #       lib = buildCrate { .. };
+  #       doc-lib = buildCrate { .. };
+  #       doctest-lib = buildCrate { .. };
+  #       test-bin-nargo-metadata = buildCrate { .. };
+  #       test-lib = buildCrate { .. };
   #     };
   #   };
   # };
   # ```
+  #
+  # Workspace packages also get a `test-<unit>` unit for each lib, bin, test,
+  # and bench target, which builds the target's test binary. Libs with doctests
+  # also get a `doctest-lib` unit, which builds and runs them. These need the
+  # packages' dev deps, so only build them with
+  # `resolve.resolveFeatures { devDeps = true; .. }`.
+  #
+  # Every normal lib and proc-macro also gets a `doc-lib` unit, which
+  # generates its HTML docs with `rustdoc`. See `mergeDocs`.
   buildGraph = {
     # Path to cargo workspace root directory.
     workspacePath,
@@ -55,6 +68,34 @@
     #
     # Ex: `[ "age-plugin" "rage" ]`
     rootPkgIds ? metadata.workspace_default_members,
+    # The name of the profile to build with, from the resolved profiles in
+    # `Cargo.metadata.json`. Like `cargo build --profile <name>`.
+    #
+    # Ex: `"dev"`, `"release"`, or some custom `[profile.<name>]`
+    profile ? "dev",
+    # The compile mode for all normal units, either `"build"`, `"check"`, or
+    # `"clippy"`. Like `cargo build` vs `cargo check` vs `cargo clippy`.
+    #
+    # In `"check"` mode, units only emit `.rmeta` metadata, which is much
+    # faster than a full build. Build scripts, proc-macros, and their deps
+    # still need to run on the host, so they're always fully built.
+    #
+    # `"clippy"` mode checks the workspace units with `clippy-driver` instead,
+    # and saves their diagnostics to `$out/diagnostics.json`. All other
+    # normal units are only checked.
+    mode ? "build",
+    # Extra args for `clippy-driver` in `"clippy"` mode. Like the args after
+    # `cargo clippy -- ...`.
+    # Ex: `["-D" "warnings"]`, `["-W" "clippy::pedantic"]`
+    lintArgs ? [],
+    # When a workspace unit fails because of its diagnostics: on any `"error"`
+    # (like cargo), on any `"warning"` (like `-D warnings`), or `"never"`. Only
+    # `"clippy"` units can ignore errors with `"never"`.
+    #
+    # Every unit saves its diagnostics to `$out/diagnostics.json` (for
+    # pipelined libs, only the rlib half), but only workspace units ever have
+    # warnings, since all other packages build with `--cap-lints=allow`.
+    lintFailOn ? "error",
     # The package set with all features resolved, from `resolve.resolveFeatures`.
     # ```
     # {
@@ -71,10 +112,74 @@
     buildCfgs = targetCfg.platformToCfgs buildPlatform;
     hostCfgs = targetCfg.platformToCfgs hostPlatform;
 
+    # `rustc` runs on the `buildPlatform` and needs the std for both the
+    # `buildPlatform` (build scripts, proc-macros) and the `hostPlatform`
+    # (everything else). `cc` links `hostPlatform` units, while `buildCc` links
+    # build scripts and all other `buildPlatform` units.
     # TODO(phlip9): should be an arg?
-    # TODO(phlip9): choose right package set by build/hostTarget?
-    rustc = pkgsCross.rustc.unwrapped;
+    rustc = pkgsCross.buildPackages.rustc.unwrapped;
     cc = pkgsCross.stdenv.cc;
+    buildCc = pkgsCross.buildPackages.stdenv.cc;
+    clippy = pkgsCross.buildPackages.clippy;
+
+    # Like `cargo clippy`, only workspace units get linted. Their deps just
+    # get checked.
+    normalMode =
+      if builtins.elem mode ["build" "check"]
+      then mode
+      else if mode == "clippy"
+      then "check"
+      else throw "nargo: error: unsupported compile mode `${mode}`, expected \"build\", \"check\", or \"clippy\"";
+
+    # `clippy-driver` looks for a `clippy.toml` in the package dir and its
+    # parents. Since each workspace package's source is isolated in its own
+    # store path, we pass in the workspace root's config explicitly.
+    workspaceClippyConfDir =
+      if mode == "clippy" && builtins.isPath workspacePath && _hasClippyConf workspacePath
+      then
+        lib.fileset.toSource {
+          root = workspacePath;
+          fileset = lib.fileset.unions [
+            (lib.fileset.maybeMissing (workspacePath + "/clippy.toml"))
+            (lib.fileset.maybeMissing (workspacePath + "/.clippy.toml"))
+          ];
+        }
+      else null;
+
+    selectedProfile =
+      (metadata.profiles or {}).${profile}
+      or (throw "nargo: error: profile `${profile}` is not defined in `Cargo.metadata.json`");
+
+    # With LTO enabled, plain rlibs only need to contain LLVM bitcode, unless
+    # some (transitive) dependent needs real object code from them, like a
+    # `dylib` or a mixed `["rlib" "cdylib"]` lib. These are the
+    # `"<name>@<version>"` of all the normal lib units that need both.
+    ltoNeedsObject =
+      if builtins.elem selectedProfile.lto ["false" "off"]
+      then {}
+      else _ltoNeedsObject pkgs;
+
+    # Like cargo, test units use the `test` profile for `dev` and the `bench`
+    # profile for `release`. Custom profiles are used as-is.
+    # TODO(phlip9): cargo also builds all the deps of test units with the
+    # test profile and `panic = "unwind"`.
+    testProfileName = {
+      dev = "test";
+      release = "bench";
+    }.${profile} or profile;
+    selectedTestProfile = (metadata.profiles or {}).${testProfileName} or selectedProfile;
+
+    # Build scripts get their target's cfgs as `CARGO_CFG_<cfg>` envs. We only
+    # run `rustc --print cfg` once per target triple, and pass the output to
+    # each `run-custom-build` unit as a file.
+    rustcTargetCfg = platform: let
+      triple = platform.rust.rustcTarget;
+    in
+      pkgsCross.buildPackages.runCommandLocal "rustc-target-cfg-${triple}" {} ''
+        ${rustc}/bin/rustc --target ${triple} --print cfg > $out
+      '';
+    buildRustcTargetCfg = rustcTargetCfg buildPlatform;
+    hostRustcTargetCfg = rustcTargetCfg hostPlatform;
 
     # TODO(phlip9): discover target cfgs with `rustc --print=cfg`. Do we also
     # need some fancy fixpoint iteration a la
@@ -85,21 +190,29 @@
         pkgId: resolvedPkg: let
           pkgMetadata = metadataPkgs.${pkgId};
 
+          # `{src, workspaceSrc}`, where only workspace packages have a
+          # `workspaceSrc`.
           crateSrc =
             # Internal: place each workspace package into its own store path.
             # Isolating each package is a prerequisite for perfect builds,
             # otherwise touching one workspace crate will cause all others to
             # also recompile.
             if !(pkgMetadata ? source)
-            then (_srcForWorkspacePkg workspacePath pkgMetadata.path)
+            then (_srcForWorkspacePkg workspacePath pkgMetadata)
             # External: if we're using `craneLib.vendorCargoDeps`, we should
             # have a `path` attr that contains the vendored crate source.
             else if (pkgMetadata ? path)
-            then pkgMetadata.path
+            then {
+              src = pkgMetadata.path;
+              workspaceSrc = null;
+            }
             # External: if we're using `nargo-metadata --nix-prefetch`, we
-            # should have a pinned crates.io `hash` attr.
+            # should have a pinned crates.io or git checkout `hash` attr.
             else if (pkgMetadata ? hash)
-            then (vendorCargoDep pkgMetadata)
+            then {
+              src = vendorCargoDep pkgMetadata;
+              workspaceSrc = null;
+            }
             #
             else throw "nargo: error: unsure how to get crate source for package: ${pkgId}";
         in
@@ -110,78 +223,356 @@
                 then buildCfgs
                 else hostCfgs;
 
+              isWorkspacePkg = !(pkgMetadata ? source);
+
+              # A package's own `clippy.toml` takes precedence.
+              clippyConfDir =
+                if workspaceClippyConfDir != null && !(_hasClippyConf (workspacePath + "/${pkgMetadata.path}"))
+                then workspaceClippyConfDir
+                else null;
+
+              # Only normal lib, bin, test, and bench targets in workspace
+              # packages get a test unit.
+              isTestableTarget = target:
+                isWorkspacePkg
+                && featFor == "normal"
+                && builtins.elem target.kind ["lib" "bin" "test" "bench"];
+
+              # Like cargo, only libs with a linkable crate type have
+              # doctests.
+              isDoctestableTarget = target:
+                isTestableTarget target
+                && target.kind == "lib"
+                && (target.doctest or true)
+                && (builtins.any (t: builtins.elem t ["lib" "rlib" "dylib" "proc-macro"]) target.crate_types);
+
+              # Like `cargo doc`, document all the normal libs, plus the
+              # proc-macros, which only ever appear as "build" deps.
+              isDocTarget = target:
+                target.kind == "lib"
+                && (target.doc or true)
+                && (featFor == "normal" || builtins.elem "proc-macro" target.crate_types);
+
               pkgUnits =
                 builtins.listToAttrs
-                (builtins.map (target: let
-                    kind = target.kind;
-
-                    isLibKind = kind == "lib";
-                    isBuildKind = kind == "custom-build";
-                    isProcMacroKind = builtins.elem "proc-macro" target.crate_types;
-
-                    unitName =
-                      if isLibKind || isBuildKind || isProcMacroKind
-                      then kind
-                      else "${kind}-${target.name}";
-
-                    maybePkgUnitsCustomBuild = pkgUnits.custom-build or null;
-
-                    # the build-script unit (or null), but only if we're not the
-                    # build-script itself. we separate this case from the other
-                    # deps since it gets handled very differently.
-                    buildScriptDep =
-                      if isBuildKind
-                      then null
-                      else maybePkgUnitsCustomBuild;
-
-                    # bins, examples, tests, etc... depend on the lib target if
-                    # it exists. Notably the lib target must also be linkable.
-                    maybePkgUnitsLinkableLib = let
-                      pkgLibUnit = pkgUnits.lib;
-                      pkgLibTarget = pkgLibUnit.target;
-                    in
-                      if
-                        (pkgUnits ? lib)
-                        # only if lib has a "linkable" output
-                        && (builtins.any (t: t == "lib" || t == "proc-macro" || t == "dylib" || t == "rlib") pkgLibTarget.crate_types)
-                      then [(_mkTargetDep pkgLibTarget.crate_name pkgLibUnit pkgLibTarget)]
-                      else [];
-
-                    # non-build script dependencies on other units within the
-                    # same package.
-                    intraPkgUnitDeps =
-                      if isLibKind || isBuildKind
-                      then []
-                      else maybePkgUnitsLinkableLib;
-
-                    # Dependencies on other lib/proc-macro units in other packages.
-                    interPkgUnitDeps = _pkgDeps pkgs pkgMetadata resolvedPkg featFor cfgs resolvedPkgFeatFor.deps target;
-
-                    buildTarget = {
-                      name = target.name;
-                      kind = kind;
-                      is_proc_macro = isProcMacroKind;
-                      crate_types = target.crate_types;
-                      crate_name = builtins.replaceStrings ["-"] ["_"] target.name;
-                      path = target.path;
-                      edition = target.edition;
-                      features = resolvedPkg.${featFor}.feats;
-                      build_script_dep = buildScriptDep;
-                      deps = intraPkgUnitDeps ++ interPkgUnitDeps;
-                    };
-                  in {
-                    name = unitName;
-                    value = buildCrate {
-                      buildPlatform = buildPlatform;
-                      cc = cc;
-                      crateSrc = crateSrc;
-                      hostPlatform = hostPlatform;
+                (builtins.concatMap (
+                    target:
+                      [(mkPkgUnit target null)]
+                      ++ (
+                        if isTestableTarget target
+                        then [(mkPkgUnit target "test")]
+                        else []
+                      )
+                      ++ (
+                        if isDoctestableTarget target
+                        then [(mkPkgUnit target "doctest")]
+                        else []
+                      )
+                      ++ (
+                        if isDocTarget target
+                        then [(mkPkgUnit target "doc")]
+                        else []
+                      )
+                  )
+                  pkgMetadata.targets);
+
+              # Build the `{ name = unitName; value = unit; }` for `target`.
+              # If `variant` is `"test"`, `"doctest"`, or `"doc"`, this is the
+              # target's test, doctest, or doc unit instead.
+              mkPkgUnit = target: variant: let
+                kind = target.kind;
+
+                isTest = variant == "test" || variant == "doctest";
+                isDoctest = variant == "doctest";
+                isDoc = variant == "doc";
+
+                isLibKind = kind == "lib";
+                isBuildKind = kind == "custom-build";
+                isProcMacroKind = builtins.elem "proc-macro" target.crate_types;
+
+                targetUnitName =
+                  if isLibKind || isBuildKind || isProcMacroKind
+                  then kind
+                  else "${kind}-${target.name}";
+
+                unitName =
+                  if variant != null
+                  then "${variant}-${targetUnitName}"
+                  else targetUnitName;
+
+                maybePkgUnitsCustomBuild = pkgUnits.custom-build or null;
+
+                # the build-script unit (or null), but only if we're not the
+                # build-script itself. we separate this case from the other
+                # deps since it gets handled very differently.
+                buildScriptDep =
+                  if isBuildKind
+                  then null
+                  else maybePkgUnitsCustomBuild;
+
+                # bins, examples, tests, etc... depend on the lib target if
+                # it exists. Notably the lib target must also be linkable.
+                maybePkgUnitsLinkableLib = let
+                  pkgLibUnit = pkgUnits.lib;
+                  pkgLibTarget = pkgLibUnit.target;
+                in
+                  if
+                    (pkgUnits ? lib)
+                    # only if lib has a "linkable" output
+                    && (builtins.any (t: t == "lib" || t == "proc-macro" || t == "dylib" || t == "rlib") pkgLibTarget.crate_types)
+                  then [(_mkTargetDep pkgLibTarget.crate_name pkgLibUnit pkgLibTarget)]
+                  else [];
+
+                # non-build script dependencies on other units within the
+                # same package. Doctests also depend on their own lib.
+                intraPkgUnitDeps =
+                  if (isLibKind && !isDoctest) || isBuildKind
+                  then []
+                  else maybePkgUnitsLinkableLib;
+
+                # Dependencies on other lib/proc-macro units in other packages.
+                interPkgUnitDeps = _pkgDeps pkgs pkgMetadata resolvedPkg featFor cfgs resolvedPkgFeatFor.deps isTest target;
+
+                # Integration tests and benches get our package's bins
+                # as `CARGO_BIN_EXE_<name>` envs.
+                binDeps =
+                  if variant == "test" && builtins.elem kind ["test" "bench"]
+                  then
+                    builtins.map (name: pkgUnits.${name}) (
+                      builtins.filter (name: lib.hasPrefix "bin-" name) (builtins.attrNames pkgUnits)
+                    )
+                  else [];
+
+                # Build scripts get `DEP_<links>_<key>` envs from the build
+                # scripts of their direct deps that declare a `links` key.
+                linksDeps =
+                  if isBuildKind
+                  then _pkgLinksDeps pkgs pkgMetadata resolvedPkg featFor cfgs resolvedPkgFeatFor.deps
+                  else [];
+
+                # Build scripts, proc-macros, and all their deps (i.e., the
+                # "build" feature set) get the `build-override` profile.
+                #
+                # Like cargo, test binaries always unwind.
+                unitProfile =
+                  if isTest
+                  then (_resolveProfile selectedTestProfile pkgMetadata false) // {panic = "unwind";}
+                  else _resolveProfile selectedProfile pkgMetadata (featFor == "build");
+
+                # The `build_script_build` binary itself is always built
+                # with the `build-override` profile, though it still sees
+                # the normal profile in its `PROFILE`, `OPT_LEVEL`, etc...
+                # envs when it runs.
+                hostProfile =
+                  if isBuildKind
+                  then _resolveProfile selectedProfile pkgMetadata true
+                  else null;
+
+                unitMode =
+                  if variant != null
+                  then variant
+                  else if featFor == "build" || isBuildKind
+                  then "build"
+                  else if mode == "clippy" && isWorkspacePkg
+                  then "clippy"
+                  else normalMode;
+                isClippy = unitMode == "clippy";
+
+                # Only normal lib units ever participate in LTO as an
+                # upstream crate.
+                ltoNeedsObjectUnit =
+                  isLibKind
+                  && variant == null
+                  && featFor == "normal"
+                  && ltoNeedsObject ? "${pkgMetadata.name}@${pkgMetadata.version}";
+
+                # Pipelining: compiling an rlib only needs its deps'
+                # `.rmeta` metadata. So we split each plain rlib unit into
+                # a separate `rmeta` unit, which stops `rustc` as soon as
+                # it's written the `.rmeta`, and the full rlib unit.
+                # Downstream rlibs then only wait on our `rmeta` unit,
+                # while units that link (bins, dylibs, proc-macros, build
+                # scripts, ...) wait on the rlibs of all their transitive
+                # deps.
+                # With `prefer_dynamic`, every normal workspace rlib also
+                # builds as a Rust dylib, which our workspace bins and tests
+                # then link instead. Like any other dylib, it isn't pipelined.
+                # Non-workspace deps stay rlibs (and are linked statically
+                # into our dylibs), since `#![no_std]` crates can't be dylibs
+                # and we can't tell them apart from the metadata.
+                isDynamicLib =
+                  (unitProfile.prefer_dynamic or false)
+                  && isWorkspacePkg
+                  && unitMode == "build"
+                  && isLibKind
+                  && featFor == "normal"
+                  && (builtins.all (t: t == "lib" || t == "rlib") target.crate_types);
+                unitCrateTypes =
+                  if isDynamicLib
+                  then target.crate_types ++ ["dylib"]
+                  else target.crate_types;
+
+                isPipelined =
+                  (unitMode == "build")
+                  && isLibKind
+                  && (builtins.all (t: t == "lib" || t == "rlib") unitCrateTypes);
+
+                unitDeps = intraPkgUnitDeps ++ interPkgUnitDeps;
+
+                # Only non-pipelined units ever need to link. Pipelined
+                # libs only propagate `.rmeta`s in their `$out/deps`, so
+                # we also have to pass in all the transitive rlib units.
+                linkDeps =
+                  if builtins.elem unitMode ["build" "test" "doctest"] && !isPipelined
+                  then _pipelinedLinkDeps unitDeps
+                  else [];
+
+                buildTarget = {
+                  name = target.name;
+                  kind = kind;
+                  is_proc_macro = isProcMacroKind;
+                  crate_types = unitCrateTypes;
+                  crate_name = builtins.replaceStrings ["-"] ["_"] target.name;
+                  path = target.path;
+                  edition = target.edition;
+                  # Whether `cargo test` builds this target as a test.
+                  test = target.test or (builtins.elem kind ["lib" "bin" "test"]);
+                  harness = target.harness or true;
+                  features = resolvedPkg.${featFor}.feats;
+                  build_script_dep = buildScriptDep;
+                  bin_deps = binDeps;
+                  # Like pipelined libs, docs only need their deps' `.rmeta`.
+                  deps =
+                    if isPipelined || isDoc
+                    then builtins.map _pipelinedDep unitDeps
+                    else unitDeps;
+                  link_deps = linkDeps;
+                  links_deps = linksDeps;
+                  profile = unitProfile;
+                  lto_needs_object = ltoNeedsObjectUnit;
+                  # Whether this unit runs on the `buildPlatform`, i.e., it's
+                  # part of the "build" feature set. Custom-build units are
+                  # always compiled for the `buildPlatform`, but still
+                  # configure their package for the `hostPlatform` if it's a
+                  # "normal" package.
+                  for_build = featFor == "build";
+                  # Like `cargo build -p`, the selected workspace packages get
+                  # `CARGO_PRIMARY_PACKAGE=1`.
+                  is_primary_pkg = isWorkspacePkg && builtins.elem pkgId rootPkgIds;
+                  mode = unitMode;
+                  pipelined = false;
+                  metadata = _unitMetadata {
+                    pkgId = pkgId;
+                    pkgMetadata = pkgMetadata;
+                    rustc = rustc;
+                    buildPlatform = buildPlatform;
+                    hostPlatform = hostPlatform;
+                    featFor = featFor;
+                    variant = variant;
+                    unitProfile = unitProfile;
+                    hostProfile = hostProfile;
+                    target = target;
+                    features = resolvedPkg.${featFor}.feats;
+                    deps = unitDeps;
+                    buildScriptDep = buildScriptDep;
+                  };
+                  lint_args =
+                    if isClippy
+                    then lintArgs
+                    else [];
+                  lint_fail_on =
+                    if isWorkspacePkg
+                    then lintFailOn
+                    else "error";
+                  clippy_conf_dir =
+                    if isClippy
+                    then clippyConfDir
+                    else null;
+                };
+
+                # Once `nargo-metadata --input-sources` has recorded which
+                # files a workspace target reads, its plain and doc units only
+                # see those files (see `_workspaceTargetFileset`). Tests can read
+                # fixtures at runtime and build scripts can read anything, so
+                # they keep the whole package.
+                unitCrateSrc =
+                  if isWorkspacePkg && !isTest && !isBuildKind && builtins.isPath workspacePath
+                  then _srcForWorkspaceTarget workspacePath pkgMetadata target
+                  else crateSrc;
+
+                mkUnit = unitTarget:
+                  buildCrate {
+                    buildPlatform = buildPlatform;
+                    buildCc = buildCc;
+                    cc = cc;
+                    clippy = clippy;
+                    crateSrc = unitCrateSrc.src;
+                    workspaceSrc = unitCrateSrc.workspaceSrc;
+                    hostPlatform = hostPlatform;
+                    pkgMetadata = pkgMetadata;
+                    rustc = rustc;
+                    target = unitTarget;
+                  };
+
+                # Both halves of a pipelined lib must use the same
+                # `-C metadata`, otherwise their crate hashes won't match.
+                # `_unitMetadata` doesn't depend on the mode, so they do.
+                rmetaUnit = mkUnit (buildTarget // {mode = "metadata";});
+                rlibUnit =
+                  mkUnit (buildTarget // {pipelined = true;})
+                  // {rmeta = rmetaUnit;};
+
+                # Build scripts: one unit compiles `build_script_build` for
+                # the `buildPlatform` with the `build-override` profile, and
+                # another runs it with our profile and target. Only the run
+                # unit depends on those, so every variant of the package
+                # with the same features shares one compiled build script.
+                #
+                # Dependents only ever see the run unit, which has the
+                # script's `output` and `OUT_DIR`.
+                buildScriptBinUnit = mkUnit (buildTarget
+                  // {
+                    profile = hostProfile;
+                    for_build = true;
+                    links_deps = [];
+                    metadata = _unitMetadata {
+                      pkgId = pkgId;
                       pkgMetadata = pkgMetadata;
                       rustc = rustc;
-                      target = buildTarget;
+                      buildPlatform = buildPlatform;
+                      hostPlatform = hostPlatform;
+                      featFor = "build";
+                      variant = null;
+                      unitProfile = hostProfile;
+                      hostProfile = null;
+                      target = target;
+                      features = resolvedPkg.${featFor}.feats;
+                      deps = unitDeps;
+                      buildScriptDep = null;
                     };
-                  })
-                  pkgMetadata.targets);
+                  });
+                runBuildScriptUnit =
+                  mkUnit (buildTarget
+                    // {
+                      mode = "run-custom-build";
+                      deps = [];
+                      link_deps = [];
+                      build_script_bin = buildScriptBinUnit;
+                      rustc_target_cfg =
+                        if featFor == "build"
+                        then buildRustcTargetCfg
+                        else hostRustcTargetCfg;
+                    })
+                  // {build_script_bin = buildScriptBinUnit;};
+              in {
+                name = unitName;
+                value =
+                  if isPipelined
+                  then rlibUnit
+                  else if isBuildKind
+                  then runBuildScriptUnit
+                  else mkUnit buildTarget;
+              };
             in
               pkgUnits
           )
@@ -191,23 +582,25 @@
   in
     pkgs;
 
-  _pkgDeps = pkgs: pkgMetadata: resolvedPkg: featFor: cfgs: activatedDeps: target: let
+  # Test units (`isTest`) also get the package's dev deps.
+  _pkgDeps = pkgs: pkgMetadata: resolvedPkg: featFor: cfgs: activatedDeps: isTest: target: let
     deps = pkgMetadata.deps;
     depPkgIds = builtins.attrNames deps;
 
     kind = target.kind;
     isBuildKind = kind == "custom-build";
 
-    # TODO(phlip9): support dev deps
     depFeatForNoProcMacro =
       if isBuildKind
       then "build"
       else featFor;
 
-    desiredDepKind =
+    desiredDepKinds =
       if isBuildKind
-      then "build"
-      else null;
+      then ["build"]
+      else if isTest
+      then [null "dev"]
+      else [null];
   in
     builtins.concatMap
     (
@@ -226,7 +619,7 @@
         relevantPkgDepKinds =
           builtins.filter (
             pkgDepKind:
-              ((pkgDepKind.kind or null) == desiredDepKind)
+              (builtins.elem (pkgDepKind.kind or null) desiredDepKinds)
               # only select optional deps that are activated
               && ((pkgDepKind.optional or false) -> activatedDeps ? ${pkgDepName})
               # make sure the dep is activated for this target cfg
@@ -237,18 +630,221 @@
         depUnit = pkgUnitsByFeatFor.${depFeatFor}.lib;
       in
         if relevantPkgDepKinds != []
-        # TODO(phlip9): build scripts: for each dep that has a `links` key, also
-        # depend on dep's build script
-        then [(_mkTargetDep pkgDepName depUnit depUnit.target)]
+        then [
+          (_mkTargetDep pkgDepName depUnit depUnit.target
+            // {
+              # The dep's `doc-lib` unit, if it has one. `buildPackage`
+              # follows these to document all the deps, like `cargo doc`.
+              doc = pkgUnitsByFeatFor.${depFeatFor}.doc-lib or null;
+            })
+        ]
         else []
     )
     depPkgIds;
 
+  # The build-script units of all the direct, normal deps of a package that
+  # declare a `links` key. Their `cargo::metadata=KEY=VALUE` output becomes our
+  # build script's `DEP_<links>_<KEY>` envs.
+  _pkgLinksDeps = pkgs: pkgMetadata: resolvedPkg: featFor: cfgs: activatedDeps:
+    builtins.concatMap (
+      dep: let
+        depUnit = dep.unit;
+        depBuildScript = depUnit.target.build_script_dep;
+      in
+        if (depUnit.metadata ? links) && depBuildScript != null
+        then [
+          {
+            links = depUnit.metadata.links;
+            unit = depBuildScript;
+          }
+        ]
+        else []
+    )
+    (_pkgDeps pkgs pkgMetadata resolvedPkg featFor cfgs activatedDeps false {kind = "lib";});
+
+  # Apply the selected profile's overrides for a single package. Like cargo, the
+  # precedence (from lowest to highest) is:
+  # 1. `[profile.<name>]`
+  # 2. `[profile.<name>.build-override]` (only for `isBuild` units)
+  # 3. `[profile.<name>.package."*"]` (only for non-workspace packages)
+  # 4. `[profile.<name>.package.<pkg-name>]`
+  # 5. `[profile.<name>.package."<pkg-name>@<version>"]`
+  #
+  # Also like cargo, build scripts, proc-macros, and their deps always unwind
+  # and never use LTO.
+  _resolveProfile = profile: pkgMetadata: isBuild: let
+    overrides = profile.package or {};
+    isWorkspacePkg = !(pkgMetadata ? source);
+  in
+    (builtins.removeAttrs profile ["build_override" "package"])
+    // (
+      if isBuild
+      then
+        profile.build_override
+        // {
+          lto = "false";
+          panic = "unwind";
+        }
+      else {}
+    )
+    // (
+      if isWorkspacePkg
+      then {}
+      else overrides."*" or {}
+    )
+    // (overrides.${pkgMetadata.name} or {})
+    // (overrides."${pkgMetadata.name}@${pkgMetadata.version}" or {});
+
+  # The unit's `-C metadata` hash. Like cargo's `Metadata`, it only depends on
+  # the unit's identity: package id, version, source, target, enabled
+  # features, profile, target triples, `rustc` version, and its deps' hashes.
+  # It's stable across unrelated changes to the derivation (ex: the source
+  # contents), so symbol names and filenames are too. Two versions of the same
+  # crate always hash differently.
+  #
+  # The mode (`build`, `check`, `metadata`, ...) is left out, so both halves of
+  # a pipelined lib agree.
+  _unitMetadata = {
+    pkgId,
+    pkgMetadata,
+    rustc,
+    buildPlatform,
+    hostPlatform,
+    featFor,
+    variant,
+    unitProfile,
+    hostProfile,
+    target,
+    features,
+    deps,
+    buildScriptDep,
+  }: let
+    depMetadata = builtins.map (dep: dep.unit.target.metadata) deps;
+    buildScriptMetadata =
+      if buildScriptDep != null
+      then [buildScriptDep.target.metadata]
+      else [];
+    identity = {
+      package = {
+        id = pkgId;
+        name = pkgMetadata.name;
+        version = pkgMetadata.version;
+        source = pkgMetadata.source or null;
+      };
+      target = {
+        name = target.name;
+        kind = target.kind;
+        crate_types = target.crate_types;
+      };
+      featFor = featFor;
+      variant = variant;
+      features = builtins.attrNames features;
+      profile = unitProfile;
+      host_profile = hostProfile;
+      build_triple = buildPlatform.rust.rustcTarget;
+      host_triple = hostPlatform.rust.rustcTarget;
+      rustc = rustc.version;
+      deps = builtins.sort builtins.lessThan (depMetadata ++ buildScriptMetadata);
+    };
+  in
+    builtins.substring 0 16 (builtins.hashString "sha256" (builtins.toJSON identity));
+
+  # Point a pipelined lib's dep at the dep's `rmeta` unit, if it has one. We
+  # also keep the full rlib unit around in `link`, for `_pipelinedLinkDeps`.
+  _pipelinedDep = dep:
+    if dep.unit ? rmeta
+    then
+      dep
+      // {
+        unit = dep.unit.rmeta;
+        link = dep.unit;
+      }
+    else dep;
+
+  # All the transitive rlib units that we can only reach through pipelined lib
+  # deps. Units that link need these in addition to their direct deps.
+  _pipelinedLinkDeps = deps: let
+    depItems = deps:
+      builtins.concatMap (
+        dep:
+          if dep ? link
+          then [
+            {
+              key = dep.link.outPath;
+              unit = dep.link;
+            }
+          ]
+          else []
+      )
+      deps;
+
+    closure = builtins.genericClosure {
+      startSet = builtins.concatMap (dep: depItems dep.unit.target.deps) deps;
+      operator = item: depItems item.unit.target.deps;
+    };
+  in
+    builtins.map (item: item.unit) closure;
+
+  # Returns the set of `"<name>@<version>"` for all normal lib units that are a
+  # (transitive) dependency of a lib unit that needs object code. Mirrors
+  # `cargo::core::compiler::lto::generate`.
+  #
+  # NOTE: this must only look at `target.crate_types` and `target.deps`, since
+  # each unit's derivation depends on this set.
+  _ltoNeedsObject = pkgs: let
+    canLto = crateType: builtins.elem crateType ["bin" "cdylib" "staticlib"];
+    isDynamic = crateType: builtins.elem crateType ["dylib" "cdylib" "proc-macro"];
+    needsObject = crateTypes:
+      (builtins.any (t: canLto t || isDynamic t) crateTypes)
+      && !(builtins.all canLto crateTypes);
+
+    startUnits =
+      builtins.concatMap (
+        pkgUnitsByFeatFor: let
+          libUnit = pkgUnitsByFeatFor.normal.lib or null;
+        in
+          if libUnit != null && needsObject libUnit.target.crate_types
+          then [libUnit]
+          else []
+      )
+      (builtins.attrValues pkgs);
+
+    # proc-macros and their deps are always built without LTO, so we can stop
+    # there.
+    depItems = unit:
+      builtins.concatMap (
+        dep:
+          if dep.unit.target.is_proc_macro
+          then []
+          else [
+            {
+              key = "${dep.unit.metadata.name}@${dep.unit.metadata.version}";
+              unit = dep.unit;
+            }
+          ]
+      )
+      unit.target.deps;
+
+    closure = builtins.genericClosure {
+      startSet = builtins.concatMap depItems startUnits;
+      operator = item: depItems item.unit;
+    };
+  in
+    builtins.listToAttrs (builtins.map (item: {
+        name = item.key;
+        value = null;
+      })
+      closure);
+
   _isActivatedForPlatform = cfgs: pkgDepKind:
     if ! (pkgDepKind ? target)
     then true
     else targetCfg.evalCfgExpr cfgs (targetCfg.parseTargetCfgExpr pkgDepKind.target);
 
+  _hasClippyConf = dir:
+    builtins.pathExists (dir + "/clippy.toml")
+    || builtins.pathExists (dir + "/.clippy.toml");
+
   _pkgContainsProcMacroTarget = pkg:
     (pkg ? build) && pkg.build.lib.target.is_proc_macro;
 
@@ -262,31 +858,102 @@
   # little more granularity than just vendoring the whole package workspace path
   # so we can handle workspaces with a top-level root package.
   #
+  # The package's files (plus any files elsewhere in the workspace that its
+  # targets recorded in their `sources`) keep their position in the workspace,
+  # so paths like `$CARGO_MANIFEST_DIR/../foo` resolve like they do with cargo.
+  # Returns `{src, workspaceSrc}`: the package's dir and the root of the copy.
+  #
   # NOTE: filtering only works with local paths. this does no filtering on e.g.
   # a src from a derivation.
-  _srcForWorkspacePkg = workspacePath: pkgWorkspaceRelPath: let
-    pkgWorkspacePath = workspacePath + "/${pkgWorkspaceRelPath}";
-
-    CargoToml = pkgWorkspacePath + "/Cargo.toml";
-    buildrs = pkgWorkspacePath + "/build.rs";
-    src = pkgWorkspacePath + "/src";
-    benches = pkgWorkspacePath + "/benches";
-    examples = pkgWorkspacePath + "/examples";
-    tests = pkgWorkspacePath + "/tests";
+  _srcForWorkspacePkg = workspacePath: pkgMetadata: let
+    pkgWorkspacePath = workspacePath + "/${pkgMetadata.path}";
+    outsidePkgSources =
+      builtins.filter (lib.hasPrefix "../")
+      (builtins.concatMap (t: t.sources or []) pkgMetadata.targets);
   in
     # TODO(phlip9): filtering on derivation src?
     if builtins.isPath workspacePath
     then
-      lib.fileset.toSource {
-        root = pkgWorkspacePath;
-        fileset = lib.fileset.unions [
-          CargoToml
-          src
-          (lib.fileset.maybeMissing buildrs)
-          (lib.fileset.maybeMissing benches)
-          (lib.fileset.maybeMissing examples)
-          (lib.fileset.maybeMissing tests)
-        ];
-      }
-    else pkgWorkspacePath;
+      _workspaceSrc workspacePath pkgMetadata (lib.fileset.unions [
+        (_workspacePkgFileset pkgWorkspacePath)
+        (_sourcesFileset pkgWorkspacePath outsidePkgSources)
+      ])
+    else {
+      src = "${workspacePath}/${pkgMetadata.path}";
+      workspaceSrc = workspacePath;
+    };
+
+  # Copy `fileset` into the store, rooted at the workspace, and return the
+  # package's dir in the copy as `src`, and the copy as `workspaceSrc`.
+  _workspaceSrc = workspacePath: pkgMetadata: fileset: let
+    workspaceSrc = "${lib.fileset.toSource {
+      root = workspacePath;
+      fileset = fileset;
+    }}";
+  in {
+    src =
+      if pkgMetadata.path == "."
+      then workspaceSrc
+      else "${workspaceSrc}/${pkgMetadata.path}";
+    workspaceSrc = workspaceSrc;
+  };
+
+  # The recorded `sources` of a workspace package's target(s), relative to the
+  # package dir, as a fileset.
+  _sourcesFileset = pkgWorkspacePath: sources:
+    lib.fileset.unions (builtins.map (source: lib.fileset.maybeMissing (pkgWorkspacePath + "/${source}")) sources);
+
+  # All the files in a workspace package that could affect its build, ex: an
+  # `include_str!("../README.md")` or a `#[path]` module outside `src/`.
+  #
+  # Like `cargo package`, that's the whole package dir, minus the `target`
+  # dir, hidden dirs like `.git`, and any nested packages. Keep in sync with
+  # `crates/nargo-build/src/executor.rs::hash_pkg_src`.
+  _workspacePkgFileset = pkgWorkspacePath: let
+    excludedDirs = isRoot: dir:
+      builtins.concatLists (lib.mapAttrsToList (
+        name: type: let
+          path = dir + "/${name}";
+        in
+          if type != "directory"
+          then []
+          else if (isRoot && name == "target") || lib.hasPrefix "." name || builtins.pathExists (path + "/Cargo.toml")
+          then [path]
+          else excludedDirs false path
+      ) (builtins.readDir dir));
+  in
+    lib.fileset.difference pkgWorkspacePath (lib.fileset.unions (excludedDirs true pkgWorkspacePath));
+
+  # Like `_srcForWorkspacePkg`, but narrowed for a single `target`, once it has
+  # recorded `sources` in `Cargo.metadata.json`. See
+  # `_workspaceTargetFileset`.
+  _srcForWorkspaceTarget = workspacePath: pkgMetadata: target:
+    if target ? sources
+    then _workspaceSrc workspacePath pkgMetadata (_workspaceTargetFileset workspacePath pkgMetadata target)
+    else _srcForWorkspacePkg workspacePath pkgMetadata;
+
+  # The files that `target` read the last time it was built, i.e., its
+  # `sources`, plus a safety net: `Cargo.toml`, clippy's config, and any `.rs`
+  # files that no target has recorded yet, so adding a new module works
+  # before the sources are recorded again.
+  #
+  # Everything else, like the README or a module that only another target
+  # uses, is left out, so editing it no longer rebuilds `target`. A new
+  # `include_str!` of some other unrecorded file needs another
+  # `nargo-metadata --input-sources` run before it builds.
+  _workspaceTargetFileset = workspacePath: pkgMetadata: target: let
+    pkgWorkspacePath = workspacePath + "/${pkgMetadata.path}";
+    sourcesFileset = _sourcesFileset pkgWorkspacePath;
+
+    pkgFileset = _workspacePkgFileset pkgWorkspacePath;
+    pkgRsFiles = lib.fileset.intersection pkgFileset (lib.fileset.fileFilter (file: file.hasExt "rs") pkgWorkspacePath);
+    recordedByAnyTarget = sourcesFileset (builtins.concatMap (t: t.sources or []) pkgMetadata.targets);
+  in
+    lib.fileset.unions [
+      (pkgWorkspacePath + "/Cargo.toml")
+      (lib.fileset.maybeMissing (pkgWorkspacePath + "/clippy.toml"))
+      (lib.fileset.maybeMissing (pkgWorkspacePath + "/.clippy.toml"))
+      (sourcesFileset ([target.path] ++ target.sources))
+      (lib.fileset.difference pkgRsFiles recordedByAnyTarget)
+    ];
 }
@@ -1,7 +1,10 @@
 {
   buildGraph,
   lib,
+  mergeDocs,
+  mergeTestResults,
   resolve,
+  runTest,
   pkgs,
 }:
 #
@@ -32,7 +35,21 @@
   # `pkgsBuildBuild` is for `buildPlatform` and
   # `pkgsBuildTarget` is for `hostPlatform`
   pkgsCross ? pkgs.pkgsCross.${hostTarget},
-  # TODO(phlip9): select profile (--release, --profile <name>)
+  # The profile to build with. Like `cargo build --profile <name>`.
+  # Ex: `"dev"`, `"release"`, or some custom `[profile.<name>]`.
+  profile ? "dev",
+  # Either fully `"build"` the selected targets, only `"check"` them (emit
+  # `.rmeta` metadata), or lint the workspace packages with `"clippy"`. Like
+  # `cargo build` vs `cargo check` vs `cargo clippy`.
+  mode ? "build",
+  # In `"clippy"` mode, extra args for `clippy-driver`, like the args after
+  # `cargo clippy -- ...`. Ex: `["-D" "warnings"]`
+  lintArgs ? [],
+  # Fail the build on any `"error"`, any `"warning"` (like `-D warnings`), or
+  # `"never"` in the workspace packages' diagnostics. Only `"clippy"` mode can
+  # ignore errors. Each unit saves its diagnostics to `$out/diagnostics.json`
+  # either way.
+  lintFailOn ? "error",
   #
   # # Package Selection:
   #
@@ -55,7 +72,26 @@
   # You can also set this arg to a list of specific binaries to build
   # Ex: `true`, `false`, `[ "nargo-rustc" "nargo-metadata" ]`.
   bins ? true,
-  # TODO(phlip9): --examples, --tests, --benches, --all-targets
+  # Build the test binaries for all the selected packages' testable targets:
+  # libs, bins, integration tests, and any other targets with `test = true`.
+  # Like `cargo test --no-run`.
+  tests ? false,
+  # Build the test binaries for all the selected packages' benches. Like
+  # `cargo bench --no-run`.
+  benches ? false,
+  # Also run the selected `tests` and `benches`, failing the build if any test
+  # fails. Like `cargo test` or `cargo bench`. With `tests`, this also runs the
+  # selected libs' doctests. The results from all the test runs get merged into
+  # a single `results.json` and `junit.xml` report.
+  runTests ? false,
+  # With `runTests`, still write the test report (and succeed) if some tests
+  # fail. Like `cargo test --no-fail-fast`, but without the failing exit code.
+  # Failing doctests still fail the build.
+  ignoreTestFailures ? false,
+  # Also generate the HTML docs for the selected packages' libs and all their
+  # deps' libs, merged into a single `$out/doc` tree. Like `cargo doc`.
+  doc ? false,
+  # TODO(phlip9): --examples, --all-targets
   #
   # # Feature Selection:
   #
@@ -84,18 +120,21 @@
   #     normal = {
   #       bin-nargo-metadata = buildCrate { .. };
   #       lib = buildCrate { .. };
+  #       test-lib = buildCrate { .. };
   #     };
   #   };
   # };
   # ```
   builtCrates ?
     buildGraph.buildGraph {
-      inherit workspacePath metadataPath metadata buildTarget buildPlatform hostTarget hostPlatform pkgsCross;
+      inherit workspacePath metadataPath metadata buildTarget buildPlatform hostTarget hostPlatform pkgsCross profile mode lintArgs lintFailOn;
       rootPkgIds = packages;
       resolved = resolve.resolveFeatures {
         inherit metadata noDefaultFeatures buildTarget buildPlatform hostTarget hostPlatform;
         rootPkgIds = packages;
         rootFeatures = features;
+        # Tests need dev deps
+        devDeps = tests || benches;
       };
     },
 }:
@@ -103,15 +142,47 @@
 let
   allTargets = (lib == true) && (bins == true);
 
-  # Build the list of all selected package targets.
-  selectedPkgs = builtins.concatMap (pkgId: selectPkgFeatFor builtCrates.${pkgId}) packages;
+  # Build the list of all selected package targets, plus the merged test report
+  # if `runTests` and the merged docs if `doc`.
+  selectedPkgs =
+    selectedUnits
+    ++ (
+      if doc && selectedDocUnits != []
+      then [(mergeDocs {units = selectedDocUnits;})]
+      else []
+    )
+    ++ (
+      if runTests && selectedTestRuns != []
+      then [
+        (mergeTestResults {
+          results = selectedTestRuns;
+          ignoreFailures = ignoreTestFailures;
+        })
+      ]
+      else []
+    );
+  selectedUnits = builtins.concatMap (pkgId: selectPkgFeatFor builtCrates.${pkgId}) packages;
+  selectedTestRuns = builtins.concatMap (pkgId: selectPkgFeatForTestRuns builtCrates.${pkgId}) packages;
   selectPkgFeatFor = pkgFeatFor:
     if !(pkgFeatFor ? normal)
     then []
-    else selectPkgFeatForTargets pkgFeatFor.normal;
+    else
+      (selectPkgFeatForTargets pkgFeatFor.normal)
+      ++ (
+        if runTests
+        then []
+        else selectPkgFeatForTests pkgFeatFor.normal
+      );
+  selectPkgFeatForTestRuns = pkgFeatFor:
+    if !(runTests && pkgFeatFor ? normal)
+    then []
+    else (selectPkgFeatForTests pkgFeatFor.normal) ++ (selectPkgFeatForDoctests pkgFeatFor.normal);
   selectPkgFeatForTargets = pkgTargets:
     if allTargets
-    then builtins.attrValues pkgTargets
+    then
+      builtins.map
+      (targetName: pkgTargets.${targetName})
+      (builtins.filter (targetName: !(stringStartsWith "test-" targetName || stringStartsWith "doctest-" targetName || stringStartsWith "doc-" targetName)) (builtins.attrNames pkgTargets))
     else
       builtins.map
       (targetName: pkgTargets.${targetName})
@@ -123,6 +194,70 @@ let
       && stringStartsWith "bin-" targetName
       && builtins.any (bin: "bin-${bin}" == targetName) bins);
 
+  # The selected test units, or their test runs if `runTests`. A bench that's
+  # only selected by `benches` runs in bench mode (`--bench`).
+  selectPkgFeatForTests = pkgTargets:
+    builtins.concatMap (
+      targetName: let
+        unit = pkgTargets.${targetName};
+        isSelectedTest = tests && unit.target.test;
+        isSelectedBench = benches && unit.target.kind == "bench";
+        testArgs =
+          if !isSelectedTest && unit.target.harness
+          then ["--bench"]
+          else [];
+      in
+        if !(isSelectedTest || isSelectedBench)
+        then []
+        else if runTests
+        then [
+          (runTest {
+            inherit unit testArgs;
+            ignoreFailures = ignoreTestFailures;
+          })
+        ]
+        else [unit]
+    )
+    (builtins.filter (stringStartsWith "test-") (builtins.attrNames pkgTargets));
+
+  # The selected libs' doctest units, which build and run their doctests
+  # directly. Like `cargo test --no-run`, `tests` alone doesn't build them.
+  selectPkgFeatForDoctests = pkgTargets:
+    if !tests
+    then []
+    else
+      builtins.map
+      (targetName: pkgTargets.${targetName})
+      (builtins.filter (stringStartsWith "doctest-") (builtins.attrNames pkgTargets));
+
+  # The selected packages' `doc-lib` units, plus the `doc-lib` units of all
+  # their (transitive) lib deps.
+  selectedDocUnits = let
+    docItems = units:
+      builtins.map (unit: {
+        key = unit.outPath;
+        inherit unit;
+      })
+      units;
+    depDocUnits = unit:
+      builtins.concatMap (dep:
+        if (dep.doc or null) != null
+        then [dep.doc]
+        else [])
+      unit.target.deps;
+    closure = builtins.genericClosure {
+      startSet = docItems (builtins.concatMap (pkgId: let
+          docUnit = builtCrates.${pkgId}.normal.doc-lib or null;
+        in
+          if docUnit != null
+          then [docUnit]
+          else [])
+        packages);
+      operator = item: docItems (depDocUnits item.unit);
+    };
+  in
+    builtins.map (item: item.unit) closure;
+
   # `str.startsWith(prefix)`
   stringStartsWith = prefix: str:
     (builtins.stringLength str >= builtins.stringLength prefix)
@@ -141,6 +276,9 @@ in
       Current target filters:
       lib = ${builtins.toJSON lib}
       bins = ${builtins.toJSON bins}
+      tests = ${builtins.toJSON tests}
+      doc = ${builtins.toJSON doc}
+      benches = ${builtins.toJSON benches}
       packages = ${builtins.toJSON packages}
     ''
   else if numSelected == 1
@@ -17,6 +17,18 @@ lib.makeScope pkgs.newScope (self: {
   # the high-level interface to build rust packages.
   buildPackage = self.callPackage ./buildPackage.nix {};
 
+  # run a test unit's test binaries, like `cargo test`.
+  runTest = self.callPackage ./runTest.nix {};
+
+  # merge the results of many `runTest`s into a single workspace report.
+  mergeTestResults = self.callPackage ./mergeTestResults.nix {};
+
+  # merge the docs from many `doc-lib` units into a single doc tree.
+  mergeDocs = self.callPackage ./mergeDocs.nix {};
+
+  # A Rust binary that builds a workspace locally, without nix.
+  nargo-build = self.callPackage ./nargo-build.nix {};
+
   # The Rust binary used to generate the `Cargo.metadata.json` file.
   nargo-metadata = self.callPackage ./nargo-metadata.nix {};
 
@@ -38,15 +50,51 @@ lib.makeScope pkgs.newScope (self: {
   # Rust `cfg(...)` expression parser and evaluator.
   targetCfg = import ./targetCfg.nix {inherit lib;};
 
-  # Vendor a single crates.io package from its Cargo.metadata.json definition.
+  # Vendor a single crates.io, alternative registry, or git package from its
+  # Cargo.metadata.json definition.
   vendorCargoDep = pkg:
-  # TODO(phlip9): first check that this is a crates.io package
-    pkgs.fetchzip {
-      # Matches `nargo_metadata::output::Package::prefetch_name()`
-      name = "crate-${pkg.name}-${pkg.version}";
-      # Matches `nargo_metadata::output::Package::prefetch_url()`
-      url = "https://static.crates.io/crates/${pkg.name}/${pkg.version}/download";
-      hash = pkg.hash;
-      extension = "tar.gz";
+    if pkg ? git
+    then let
+      # Matches `nargo_metadata::prefetch::nix_prefetch_git()`. Every package
+      # in the same repo checkout shares this one fetch.
+      checkout = builtins.fetchGit {
+        url = pkg.git.url;
+        rev = pkg.git.rev;
+        allRefs = true;
+        submodules = true;
+        narHash = pkg.hash;
+      };
+    in
+      if pkg.git ? subdir
+      then "${checkout}/${pkg.git.subdir}"
+      else "${checkout}"
+    else
+      pkgs.fetchzip ({
+          # Matches `nargo_metadata::output::Package::prefetch_name()`
+          name = "crate-${pkg.name}-${pkg.version}";
+          # Matches `nargo_metadata::output::Package::prefetch_url()`.
+          url = pkg.url or "https://static.crates.io/crates/${pkg.name}/${pkg.version}/download";
+          hash = pkg.hash;
+          extension = "tar.gz";
+        }
+        // self._registryAuth pkg);
+
+  # Private registry crates authenticate their download with the same
+  # `CARGO_REGISTRIES_<NAME>_TOKEN` env as cargo. Fixed-output derivations
+  # can read it as an impure env, which comes from the nix-daemon's (or remote
+  # builder's) environment, not the caller's. Cargo registries want the raw
+  # token in an `Authorization` header rather than basic auth, so we only use
+  # `netrcPhase` to write a header file for `curl`.
+  _registryAuth = pkg:
+    lib.optionalAttrs (pkg ? token_env) {
+      netrcImpureEnvVars = [pkg.token_env];
+      netrcPhase = ''
+        touch netrc
+        token="''${${pkg.token_env}:-}"
+        if [ -n "$token" ]; then
+          printf 'Authorization: %s\n' "$token" > registry-auth
+          curlOpts="$curlOpts --header @$PWD/registry-auth"
+        fi
+      '';
     };
 })
@@ -0,0 +1,39 @@
+#
+# `nargoLib.mergeDocs` - merge the per-crate docs from many `doc-lib` units into
+# a single `$out/doc` tree, like `target/doc` after `cargo doc`.
+#
+# Each doc unit only documents its own crate and writes its part of the shared
+# cross-crate info (search index, trait impls, ...) to `doc.parts/`. Here we
+# symlink all the crate docs into one tree and have `rustdoc --merge=finalize`
+# write the combined search index and shared files. This is cheap, so only
+# the changed crates' doc units ever need to rebuild.
+#
+{
+  runCommandLocal,
+  rustc,
+}:
+#
+{
+  name ? "docs",
+  # The `doc-lib` units to merge.
+  units,
+}:
+#
+runCommandLocal name {
+  nativeBuildInputs = [rustc.unwrapped];
+  inherit units;
+} ''
+  set -euo pipefail
+
+  mkdir -p "$out/doc"
+  includePartsDirs=()
+  for unit in $units; do
+    cp -rs "$unit/doc/." "$out/doc/"
+    # `cp` keeps the read-only store dir permissions
+    chmod -R u+w "$out/doc"
+    includePartsDirs+=("--include-parts-dir=$unit/doc.parts")
+  done
+
+  RUSTC_BOOTSTRAP=1 rustdoc -Zunstable-options --merge=finalize \
+    "''${includePartsDirs[@]}" -o "$out/doc"
+''
@@ -0,0 +1,33 @@
+#
+# `nargoLib.mergeTestResults` - merge the reports from many `runTest` runs into
+# a single workspace `$out/results.json` and `$out/junit.xml`.
+#
+{
+  nargo-rustc,
+  stdenv,
+}:
+#
+{
+  name ? "test-results",
+  # The `runTest` derivations to merge.
+  results,
+  # Still write the merged report (and succeed) if some tests failed.
+  ignoreFailures ? false,
+}:
+#
+builtins.derivation {
+  inherit name;
+
+  builder = "${nargo-rustc}/bin/nargo-rustc";
+  args = ["merge-test-results"];
+
+  outputs = ["out"];
+  system = stdenv.buildPlatform.system;
+
+  # Envs
+  IGNORE_FAILURES = ignoreFailures;
+  LOG = "info";
+  TEST_RESULTS = results;
+
+  allowSubstitutes = false;
+}
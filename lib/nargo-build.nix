@@ -0,0 +1,14 @@
+# This derivation builds the `nargo-build` binary, which builds a workspace on
+# the local filesystem without nix, for a faster dev loop.
+#
+# cargo build -p nargo-build --bin nargo-build
+{
+  buildPackage,
+  pkgs,
+}:
+buildPackage {
+  workspacePath = ../.;
+  pkgsCross = pkgs;
+  packages = ["nargo-build"];
+  bins = ["nargo-build"];
+}
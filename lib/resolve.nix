@@ -34,6 +34,9 @@
     # If true, don't enable the "default" features for the selected workspace
     # packages.
     noDefaultFeatures ? false,
+    # If true, also activate the `[dev-dependencies]` of the `rootPkgIds`, so
+    # we can build their tests. Like `cargo test` vs `cargo build`.
+    devDeps ? false,
     # The build platform, as a rust target triple.
     # Ex: "x86_64-unknown-linux-gnu", "aarch64-linux-android"
     buildTarget,
@@ -61,6 +64,15 @@
       pkgs = metadata.packages;
       buildCfgs = targetCfg.platformToCfgs buildPlatform;
       hostCfgs = targetCfg.platformToCfgs hostPlatform;
+      # The packages whose dev deps we activate.
+      devDepPkgIds =
+        if devDeps
+        then builtins.listToAttrs (builtins.map (pkgId: {
+            name = pkgId;
+            value = null;
+          })
+          rootPkgIds)
+        else {};
       # This will be `resolved` from each previous recursion.
       prevResolved = null;
     };
@@ -446,8 +458,9 @@
           builtins.filter
           (
             pkgDepKind:
-            # # Always ignore dev deps
-              ((pkgDepKind.kind or null) != "dev")
+            # # Ignore dev deps, unless we're testing this root package
+              (((pkgDepKind.kind or null) != "dev")
+                || (featFor == "normal" && ctx.devDepPkgIds ? ${pkgId}))
               # Check caller's filter
               && (depFilter pkgDepName pkgDepKind)
               # Check target `cfg()` etc
@@ -0,0 +1,44 @@
+#
+# `nargoLib.runTest` - run the test binaries of a single test unit, like
+# `cargo test`.
+#
+# Writes each test's outcome to `$out/results.json` and a JUnit XML report to
+# `$out/junit.xml`. The derivation fails if any test fails, unless
+# `ignoreFailures` is set.
+#
+{nargo-rustc}:
+#
+{
+  # A `test-*` unit from `buildGraph`.
+  unit,
+  # Extra args to pass to the test binary, as-is (no shell splitting).
+  # Ex: `["--skip" "slow_test"]`, `["--bench"]`
+  testArgs ? [],
+  # Still write the results (and succeed) if some tests fail. Useful for
+  # collecting a report with `mergeTestResults`.
+  ignoreFailures ? false,
+}:
+#
+builtins.derivation {
+  name = "${unit.name}-run";
+  version = unit.metadata.version;
+
+  builder = "${nargo-rustc}/bin/nargo-rustc";
+  args = ["run-test"];
+
+  outputs = ["out"];
+  # Like cargo, run tests from the package root.
+  src = unit.src;
+  system = unit.system;
+
+  # Envs
+  HARNESS = unit.target.harness;
+  IGNORE_FAILURES = ignoreFailures;
+  LOG = "info";
+  PKG_NAME = unit.metadata.name;
+  TEST_ARGS = builtins.toJSON testArgs;
+  TEST_UNIT = unit;
+
+  # Test runs are always cheaper to re-run than to substitute.
+  allowSubstitutes = false;
+}
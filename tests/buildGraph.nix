@@ -0,0 +1,137 @@
+{
+  lib,
+  nargoLib,
+}: let
+  inherit (nargoLib) buildGraph;
+in {
+  # Pin `_unitMetadata` to the same `-C metadata` that `nargo-build` computes.
+  # Keep in sync with `crates/nargo-build/src/graph.rs::test_unit_metadata_golden`.
+  unit-metadata-golden = {assertEq, ...}: let
+    pkgMetadata = {
+      name = "itoa";
+      version = "1.0.18";
+      source = "crates.io";
+    };
+    profile = {
+      root = "dev";
+      opt_level = "0";
+      debug = "2";
+      debug_assertions = true;
+      overflow_checks = true;
+      lto = "false";
+      panic = "unwind";
+      rpath = false;
+      prefer_dynamic = false;
+      build_override = {
+        opt_level = "0";
+        debug = "0";
+        codegen_units = 256;
+      };
+    };
+    platform = {rust.rustcTarget = "x86_64-unknown-linux-gnu";};
+  in
+    assertEq (buildGraph._unitMetadata {
+      pkgId = "itoa@1.0.18";
+      pkgMetadata = pkgMetadata;
+      rustc = {version = "1.86.0";};
+      buildPlatform = platform;
+      hostPlatform = platform;
+      featFor = "normal";
+      variant = null;
+      unitProfile = buildGraph._resolveProfile profile pkgMetadata false;
+      hostProfile = null;
+      target = {
+        name = "itoa";
+        kind = "lib";
+        crate_types = ["lib"];
+      };
+      features = {};
+      deps = [];
+      buildScriptDep = null;
+    })
+    "702df694145bd8b9";
+
+  # Each target of `target-sources` only sees the files it recorded in its
+  # `sources`, plus `Cargo.toml` and `src/unrecorded.rs`, a module the lib
+  # gained after the sources were last recorded. The README and the modules
+  # that only other targets use are left out. The lib also reads
+  # `../shared/banner.txt`, outside the package.
+  target-sources = {assertEq, ...}: let
+    workspacePath = ./buildGraph;
+    pkgMetadata = {
+      name = "target-sources";
+      path = "target-sources";
+      targets = [
+        {
+          name = "target_sources";
+          kind = "lib";
+          path = "src/lib.rs";
+          sources = ["src/lib.rs" "src/greeting.txt" "../shared/banner.txt"];
+        }
+        {
+          name = "target-sources";
+          kind = "bin";
+          path = "src/main.rs";
+          sources = ["src/main.rs"];
+        }
+        {
+          name = "tool";
+          kind = "bin";
+          path = "src/bin/tool/main.rs";
+          sources = ["src/bin/tool/main.rs" "src/bin/tool/helper.rs"];
+        }
+      ];
+    };
+    workspaceFiles = fileset:
+      builtins.sort builtins.lessThan (builtins.map
+        (path: lib.path.removePrefix workspacePath path)
+        (lib.fileset.toList fileset));
+    targetFiles = target: workspaceFiles (buildGraph._workspaceTargetFileset workspacePath pkgMetadata target);
+    noSources = {
+      name = "target_sources";
+      kind = "lib";
+      path = "src/lib.rs";
+    };
+  in {
+    # The whole package, minus hidden dirs and the nested package.
+    pkg = assertEq (workspaceFiles (buildGraph._workspacePkgFileset (workspacePath + "/target-sources"))) [
+      "./target-sources/Cargo.toml"
+      "./target-sources/README.md"
+      "./target-sources/src/bin/tool/helper.rs"
+      "./target-sources/src/bin/tool/main.rs"
+      "./target-sources/src/greeting.txt"
+      "./target-sources/src/lib.rs"
+      "./target-sources/src/main.rs"
+      "./target-sources/src/unrecorded.rs"
+    ];
+    lib = assertEq (targetFiles (builtins.elemAt pkgMetadata.targets 0)) [
+      "./shared/banner.txt"
+      "./target-sources/Cargo.toml"
+      "./target-sources/src/greeting.txt"
+      "./target-sources/src/lib.rs"
+      "./target-sources/src/unrecorded.rs"
+    ];
+    bin-tool = assertEq (targetFiles (builtins.elemAt pkgMetadata.targets 2)) [
+      "./target-sources/Cargo.toml"
+      "./target-sources/src/bin/tool/helper.rs"
+      "./target-sources/src/bin/tool/main.rs"
+      "./target-sources/src/unrecorded.rs"
+    ];
+    # Without recorded `sources`, the target sees the whole package.
+    no-sources =
+      assertEq (buildGraph._srcForWorkspaceTarget workspacePath pkgMetadata noSources)
+      (buildGraph._srcForWorkspacePkg workspacePath pkgMetadata);
+    # The package keeps its position in the workspace, so the lib's
+    # `$CARGO_MANIFEST_DIR/../shared/banner.txt` resolves.
+    manifest-dir = let
+      crateSrc = buildGraph._srcForWorkspaceTarget workspacePath pkgMetadata (builtins.elemAt pkgMetadata.targets 0);
+    in
+      assertEq [
+        crateSrc.src
+        (builtins.readFile "${crateSrc.src}/../shared/banner.txt")
+      ] [
+        "${crateSrc.workspaceSrc}/target-sources"
+        "hi\n"
+      ];
+  };
+}
@@ -0,0 +1,4 @@
+#[allow(dead_code)]
+pub fn shout(s: &str) -> String {
+    s.trim_end().to_uppercase()
+}
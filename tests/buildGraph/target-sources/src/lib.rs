@@ -0,0 +1,12 @@
+// Added after `sources` were last recorded.
+mod unrecorded;
+
+pub const GREETING: &str = include_str!("greeting.txt");
+
+// Outside the package, but still in the workspace.
+pub const BANNER: &str =
+    include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../shared/banner.txt"));
+
+pub fn greet() -> String {
+    unrecorded::shout(GREETING)
+}
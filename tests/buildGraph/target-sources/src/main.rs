@@ -0,0 +1,3 @@
+fn main() {
+    println!("{}", target_sources::greet());
+}
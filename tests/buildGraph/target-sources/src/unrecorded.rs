@@ -0,0 +1,3 @@
+pub fn shout(s: &str) -> String {
+    s.trim_end().to_uppercase()
+}
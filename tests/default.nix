@@ -13,8 +13,12 @@
 
   resolve = import ./resolve.nix {inherit nargoLib;};
 
+  buildGraph = import ./buildGraph.nix {inherit lib nargoLib;};
+
   targetCfg = import ./targetCfg.nix {inherit lib nargoLib;};
 
+  vendorCargoDep = import ./vendorCargoDep.nix {inherit lib nargoLib pkgs;};
+
   packages = {
     nargo-metadata = nargoLib.nargo-metadata;
     nargo-resolve = nargoLib.nargo-resolve;
@@ -23,11 +27,14 @@
 
   checks = _mkTestGroup "" {
     targetCfg = targetCfg;
+    buildGraph = buildGraph;
+    vendorCargoDep = vendorCargoDep;
     examples = builtins.mapAttrs (_: value:
       builtins.intersectAttrs {
         metadataDrv = null;
         checkResolveFeatures = null;
         build = null;
+        runpath = null;
       }
       value)
     examples;
@@ -37,8 +37,12 @@
     # dependent derivation).
     metadata = fixupMetadataIFDPathContext metadataNoCtx metadataDrv;
 
+    # Cross-compile with a different nixpkgs instance, like
+    # `pkgs.pkgsCross.aarch64-multiplatform`.
+    pkgsCross = args.pkgsCross or pkgs;
+
     buildTarget = pkgs.buildPlatform.rust.rustcTarget;
-    hostTarget = args.hostTarget or buildTarget;
+    hostTarget = pkgsCross.hostPlatform.rust.rustcTarget;
 
     resolved = nargoLib.resolve.resolveFeatures {
       inherit metadata buildTarget hostTarget;
@@ -113,12 +117,39 @@
       version = "0.0.0";
       workspacePath = src;
       metadata = metadata;
-      pkgsCross = pkgs;
+      pkgsCross = pkgsCross;
+
+      lib = args.lib or false;
+      bins = args.bins or true;
+    };
+
+    # Like `buildInner`, but only `cargo check` the selected targets.
+    checkInner = nargoLib.buildPackage {
+      pname = name;
+      version = "0.0.0";
+      workspacePath = src;
+      metadata = metadata;
+      pkgsCross = pkgsCross;
+      mode = "check";
 
       lib = args.lib or false;
       bins = args.bins or true;
     };
 
+    # Like `buildInner`, but build and run the selected packages' tests.
+    testInner = nargoLib.buildPackage {
+      pname = name;
+      version = "0.0.0";
+      workspacePath = src;
+      metadata = metadata;
+      pkgsCross = pkgsCross;
+      tests = true;
+      runTests = true;
+
+      lib = false;
+      bins = false;
+    };
+
     # Wrap `buildInner` with `lazyDerivation` to improve test collection time.
     # Otherwise we have to do some hefty IFD and eval to get the final top-level
     # derivation from `buildGraph`, just to check if the attr is a derivation.
@@ -126,12 +157,20 @@
       derivation = buildInner;
       meta = {};
     };
+    check = lib.lazyDerivation {
+      derivation = checkInner;
+      meta = {};
+    };
+    test = lib.lazyDerivation {
+      derivation = testInner;
+      meta = {};
+    };
 
     # `nargoLib.buildGraph`
     buildGraph = nargoLib.buildGraph.buildGraph {
       workspacePath = src;
       metadata = metadata;
-      pkgsCross = pkgs;
+      pkgsCross = pkgsCross;
       buildTarget = buildTarget;
       hostTarget = hostTarget;
       resolved = resolved;
@@ -212,9 +251,62 @@ in {
   dependency-v3 = mkLocalExample {src = ./dependency-v3;};
   hello-world-bin = mkLocalExample {src = ./hello-world-bin;};
   pkg-targets = mkLocalExample {src = ./pkg-targets;};
+  # With `prefer-dynamic`, every normal lib also builds as a Rust dylib, which
+  # `app` then links dynamically, along with `libstd`. Check that each RUNPATH
+  # points at the right store paths and that `app` actually runs.
+  prefer-dynamic = let
+    example = mkLocalExample {src = ./prefer-dynamic;};
+    units = example.buildGraph;
+    app = units.app.normal.bin-app;
+    greet = units.greet.normal.lib;
+  in
+    example
+    // {
+      runpath =
+        pkgs.runCommandLocal "prefer-dynamic-runpath" {
+          nativeBuildInputs = [pkgs.binutils-unwrapped];
+        } ''
+          set -euo pipefail
+
+          runpath() {
+            readelf -d "$1" | sed -n 's/.*(RUNPATH).*\[\(.*\)\]$/\1/p'
+          }
+          # assert_runpath <elf> <dir>...
+          assert_runpath() {
+            local elf="$1" rp
+            shift
+            rp="$(runpath "$elf")"
+            echo "$elf RUNPATH: $rp"
+            for dir in "$@"; do
+              if [[ ":$rp:" != *":$dir:"* ]]; then
+                echo "error: $elf RUNPATH is missing $dir"
+                exit 1
+              fi
+            done
+            if ! readelf -d "$elf" | grep -q 'NEEDED.*libstd-'; then
+              echo "error: $elf doesn't link libstd dynamically"
+              exit 1
+            fi
+          }
+
+          # `itoa` is `#![no_std]`, so it stays an rlib linked into `greet`.
+          assert_runpath ${app}/bin/app ${greet}
+          assert_runpath ${greet}/libgreet-*.so
+
+          output="$(${app}/bin/app)"
+          if [[ "$output" != "hello 42" ]]; then
+            echo "error: unexpected output: '$output'"
+            exit 1
+          fi
+
+          touch $out
+        '';
+    };
+  # The build script (and its build-dep) must still run on the build
+  # platform, while the bin is cross-compiled with the gnu cross toolchain.
   cross = mkLocalExample {
     src = ./cross;
-    hostTarget = "x86_64-unknown-linux-musl";
+    pkgsCross = pkgs.pkgsCross.aarch64-multiplatform;
   };
 
   #
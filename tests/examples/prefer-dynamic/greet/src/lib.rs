@@ -0,0 +1,4 @@
+pub fn greet(n: u32) -> String {
+    let mut buf = itoa::Buffer::new();
+    format!("hello {}", buf.format(n))
+}
@@ -0,0 +1,56 @@
+{
+  lib,
+  nargoLib,
+  pkgs,
+}: let
+  inherit (nargoLib) vendorCargoDep _registryAuth;
+
+  cratesIoPkg = {
+    name = "itoa";
+    version = "1.0.18";
+    source = "crates.io";
+    hash = lib.fakeHash;
+  };
+  privatePkg = {
+    name = "baz";
+    version = "1.0.0";
+    source = "sparse+https://crates.example.com/index/";
+    hash = lib.fakeHash;
+    url = "https://crates.example.com/api/v1/crates/baz/1.0.0/download";
+    token_env = "CARGO_REGISTRIES_MY_REGISTRY_TOKEN";
+  };
+
+  # Run the private registry's `netrcPhase` like `fetchurl` does, without
+  # fetching anything.
+  runNetrcPhase = name: token: check:
+    pkgs.runCommand "registry-auth-${name}" {} ''
+      ${lib.optionalString (token != null) "export CARGO_REGISTRIES_MY_REGISTRY_TOKEN=${token}"}
+      curlOpts=""
+      ${(_registryAuth privatePkg).netrcPhase}
+      [[ -f netrc ]]
+      ${check}
+      touch $out
+    '';
+in {
+  registry-auth = {
+    crates-io = {assertEq, ...}:
+      assertEq ((vendorCargoDep cratesIoPkg).postHook or null) null;
+
+    impure-env = {assertEq, ...}:
+      assertEq
+      (builtins.elem privatePkg.token_env (vendorCargoDep privatePkg).impureEnvVars)
+      true;
+
+    header = runNetrcPhase "header" "secret" ''
+      [[ "$(cat registry-auth)" == "Authorization: secret" ]]
+      [[ "$curlOpts" == " --header @$PWD/registry-auth" ]]
+    '';
+
+    # Without a token we still try the download, e.g. for a registry that
+    # only needs auth for its API.
+    no-token = runNetrcPhase "no-token" null ''
+      [[ ! -e registry-auth ]]
+      [[ -z "$curlOpts" ]]
+    '';
+  };
+}